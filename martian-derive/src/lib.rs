@@ -4,15 +4,22 @@
 /// - Handle default values for FileType
 /// - Repo wide reorganization
 extern crate proc_macro;
-use martian::{utils, MartianPrimaryType, StageKind, Volatile, MARTIAN_TOKENS};
-use quote::quote;
+use martian::{
+    check_field_name, utils, MartianBlanketType, MartianPrimaryType, StageKind, Threads,
+    Volatile,
+};
+use quote::{quote, quote_spanned};
 use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
 use std::str::FromStr;
+use syn::spanned::Spanned;
 use syn::{Data, DeriveInput, Error, Fields, Ident, ImplItem, ItemImpl, ItemStruct, Type};
 
 const ATTR_NOT_ON_TRAIT_IMPL_ERROR: &'static str = r#"The attribute #[make_mro] should only be applied to `martian::MartianMain` or `martian::MartianStage` trait implementation of a stage struct"#;
 const MARTIAN_MAIN_TRAIT: &'static str = "MartianMain";
 const MARTIAN_STAGE_TRAIT: &'static str = "MartianStage";
+const ASYNC_MARTIAN_MAIN_TRAIT: &str = "AsyncMartianMain";
+const ASYNC_MARTIAN_STAGE_TRAIT: &str = "AsyncMartianStage";
 const STAGE_INPUT_IDENT: &'static str = "StageInputs";
 const STAGE_OUTPUT_IDENT: &'static str = "StageOutputs";
 const CHUNK_INPUT_IDENT: &'static str = "ChunkInputs";
@@ -20,11 +27,31 @@ const CHUNK_OUTPUT_IDENT: &'static str = "ChunkOutputs";
 
 const MARTIAN_STRUCT_NOT_ON_NAMED_STRUCT_ERROR: &'static str =
     r#"#[derive(MartianStruct)] can only be used on structs with named fields."#;
+const MRO_FIELD_ATTR_ERROR: &str =
+    r#"Expecting #[mro(rename = "name")], #[mro(skip)] or #[mro(flatten)]"#;
 
 /// When this attribute is applied to the `MartianMain` or `MartianStage` trait implementation of
 /// a stage struct, it derives the trait `MroMaker` to the stage struct, which lets you generate
 /// the mro corresponding to the stage.
 ///
+/// With the `tokio` feature of `martian`, it can also be applied to an `AsyncMartianMain` or
+/// `AsyncMartianStage` implementation. For an `AsyncMartianStage`, it also implements
+/// `MartianStage`, which runs the futures of the stage.
+///
+/// The stage and chunk inputs and outputs are the fields of the associated types, which
+/// implement `MartianStruct`. The attribute optionally takes comma separated `key = value`
+/// pairs, e.g `#[make_mro(mem_gb = 4, threads = 2)]`:
+/// - `mem_gb`, `vmem_gb`, `threads` and `volatile = strict` go in the `using` section
+/// - `stage_name = MY_STAGE` overrides the stage name, which is otherwise the name of the
+///   stage struct in SHOUTY_SNAKE_CASE
+/// - `stage_key = my_stage` overrides the key of the stage in the adapter, used in the
+///   `src comp` line of the mro and by `martian_stages!`, which is otherwise the name of
+///   the stage struct in snake_case
+/// - `preflight = true` marks a preflight stage, which is not required to have outputs
+///
+/// The first non empty line of the doc comment on the impl is written as a comment above
+/// the stage.
+///
 /// For examples on how to use it and customize, take a look at `tests/test_full_mro.rs`
 #[proc_macro_attribute]
 pub fn make_mro(
@@ -52,21 +79,8 @@ pub fn make_mro(
     if let Some(ref name) = parsed_attr.stage_name {
         let expected = utils::to_shouty_snake_case(name);
         if expected != *name {
-            let span = attr
-                .into_iter()
-                .filter(|tt| {
-                    if let proc_macro::TokenTree::Ident(ref ident) = tt {
-                        ident.to_string() == "stage_name"
-                    } else {
-                        false
-                    }
-                })
-                .next()
-                .unwrap()
-                .span()
-                .into();
             return syn::Error::new(
-                span,
+                attr_key_span(attr, "stage_name"),
                 format!(
                     "`stage_name` needs to be in SHOUTY_SNAKE_CASE (without any surrounding quotes). Found {}, use {} instead",
                     name, expected
@@ -77,6 +91,22 @@ pub fn make_mro(
         }
     }
 
+    // Similarly, the stage key needs to be in snake_case
+    if let Some(ref key) = parsed_attr.stage_key {
+        let expected = utils::to_snake_case(key);
+        if expected != *key {
+            return syn::Error::new(
+                attr_key_span(attr, "stage_key"),
+                format!(
+                    "`stage_key` needs to be in snake_case (without any surrounding quotes). Found {}, use {} instead",
+                    key, expected
+                ),
+            )
+            .to_compile_error()
+            .into();
+        }
+    }
+
     let mem_gb_quote = parsed_attr
         .mem_gb
         .map(|x| quote![mem_gb: Some(#x),])
@@ -85,16 +115,38 @@ pub fn make_mro(
         .vmem_gb
         .map(|x| quote![vmem_gb: Some(#x),])
         .unwrap_or(quote![]);
-    let threads_quote = parsed_attr
-        .threads
-        .map(|x| quote![threads: Some(#x),])
-        .unwrap_or(quote![]);
+    let threads_quote = match parsed_attr.threads.map(Threads::try_from) {
+        Some(Ok(Threads::Fixed(x))) => quote![threads: Some(::martian::Threads::Fixed(#x)),],
+        Some(Ok(Threads::AllCores)) => quote![threads: Some(::martian::Threads::AllCores),],
+        Some(Err(e)) => {
+            return syn::Error::new(attr_key_span(attr, "threads"), e)
+                .to_compile_error()
+                .into();
+        }
+        None => quote![],
+    };
     let volatile_quote = match parsed_attr.volatile {
         Some(k) => match k {
             Volatile::Strict => quote![volatile: Some(::martian::Volatile::Strict),],
         },
         None => quote![volatile: None,],
     };
+    let stage_key_fn = match parsed_attr.stage_key {
+        Some(ref key) => quote![
+            fn stage_key() -> Option<String> {
+                Some(String::from(#key))
+            }
+        ],
+        None => quote![],
+    };
+    let preflight_fn = match parsed_attr.preflight {
+        Some(preflight) => quote![
+            fn preflight() -> bool {
+                #preflight
+            }
+        ],
+        None => quote![],
+    };
     let using_attributes_fn = quote![
         fn using_attributes() -> ::martian::MroUsing {
             ::martian::MroUsing {
@@ -137,6 +189,12 @@ pub fn make_mro(
         Ok(t) => t,
         Err(e) => return e.to_compile_error().into(),
     };
+    let is_async_stage = trait_path
+        .segments
+        .iter()
+        .last()
+        .map(|segment| segment.ident == ASYNC_MARTIAN_STAGE_TRAIT)
+        .unwrap_or(false);
 
     // ::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::
     // STEP 3
@@ -188,8 +246,29 @@ pub fn make_mro(
     // ::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::
     // STEP 5
     // ::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::
+    // The first line of the doc comment on the trait impl is the description
+    // of the stage, which goes in `fn description() -> Option<&'static str>`
+    let description_fn = match doc_description(&item_impl.attrs) {
+        Some(description) => quote![
+            fn description() -> Option<&'static str> {
+                Some(#description)
+            }
+        ],
+        None => quote![],
+    };
+
+    // ::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::
+    // STEP 6
+    // ::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::
     // Stitch the quotes together
     let (impl_generics, _, where_clause) = item_impl.generics.split_for_impl();
+    // An `AsyncMartianStage` can't have a blanket `MartianStage` impl, since it
+    // would overlap the one for `MartianMain`, so it is generated here
+    let async_stage_impl = if is_async_stage {
+        async_stage_quote(&stage_struct, &item_impl.generics)
+    } else {
+        quote![]
+    };
     let item_clone2 = proc_macro2::TokenStream::from(item_clone);
     let final_token = quote![
         #item_clone2
@@ -197,13 +276,116 @@ pub fn make_mro(
         impl #impl_generics ::martian::MroMaker for #stage_struct #where_clause {
             #stage_var_fn
             #stage_name_fn
+            #stage_key_fn
             #using_attributes_fn
+            #preflight_fn
+            #description_fn
         }
+        #async_stage_impl
     ]
     .into();
     final_token
 }
 
+// `MartianStage` for a stage implementing `AsyncMartianStage`, which runs the
+// futures using `martian::block_on`
+fn async_stage_quote(stage_struct: &Type, generics: &syn::Generics) -> proc_macro2::TokenStream {
+    let (impl_generics, _, where_clause) = generics.split_for_impl();
+    let stage = quote![<Self as ::martian::MartianStage>];
+    let async_stage = quote![<Self as ::martian::AsyncMartianStage>];
+    let result = quote![::std::result::Result];
+    let rover = quote![::martian::MartianRover];
+    let run_fns = quote![
+        fn split(&self, args: #stage::StageInputs, rover: #rover)
+            -> #result<::martian::StageDef<#stage::ChunkInputs>, ::martian::Error> {
+            let threads = rover.get_threads();
+            ::martian::block_on(threads, #async_stage::split(self, args, rover))?
+        }
+        fn main(&self, args: #stage::StageInputs, split_args: #stage::ChunkInputs, rover: #rover)
+            -> #result<#stage::ChunkOutputs, ::martian::Error> {
+            let threads = rover.get_threads();
+            ::martian::block_on(threads, #async_stage::main(self, args, split_args, rover))?
+        }
+        fn join(
+            &self,
+            args: #stage::StageInputs,
+            chunk_defs: Vec<#stage::ChunkInputs>,
+            chunk_outs: Vec<#stage::ChunkOutputs>,
+            rover: #rover,
+        ) -> #result<#stage::StageOutputs, ::martian::Error> {
+            let threads = rover.get_threads();
+            ::martian::block_on(threads, #async_stage::join(self, args, chunk_defs, chunk_outs, rover))?
+        }
+    ];
+    quote![
+        #[automatically_derived]
+        impl #impl_generics ::martian::MartianStage for #stage_struct #where_clause {
+            type StageInputs = #async_stage::StageInputs;
+            type StageOutputs = #async_stage::StageOutputs;
+            type ChunkInputs = #async_stage::ChunkInputs;
+            type ChunkOutputs = #async_stage::ChunkOutputs;
+            #run_fns
+            fn max_runtime(&self) -> Option<::std::time::Duration> {
+                #async_stage::max_runtime(self)
+            }
+        }
+    ]
+}
+
+// Span of the `key` in the attribute tokens of `#[make_mro(..)]`. The key is
+// known to be present, since it was parsed
+fn attr_key_span(attr: proc_macro::TokenStream, key: &str) -> proc_macro2::Span {
+    attr.into_iter()
+        .find(|tt| match tt {
+            proc_macro::TokenTree::Ident(ident) => ident.to_string() == key,
+            _ => false,
+        })
+        .unwrap()
+        .span()
+        .into()
+}
+
+// First non empty line of the doc comment in the attributes
+fn doc_description(attrs: &[syn::Attribute]) -> Option<String> {
+    attrs
+        .iter()
+        .filter(|attr| attr.path.is_ident("doc"))
+        .filter_map(|attr| match attr.parse_meta() {
+            Ok(syn::Meta::NameValue(syn::MetaNameValue {
+                lit: syn::Lit::Str(doc),
+                ..
+            })) => Some(doc.value().trim().to_string()),
+            _ => None,
+        })
+        .find(|line| !line.is_empty())
+}
+
+// The whole doc comment in the attributes, without the leading and trailing blank lines
+fn doc_comment(attrs: &[syn::Attribute]) -> Option<String> {
+    let lines: Vec<String> = attrs
+        .iter()
+        .filter(|attr| attr.path.is_ident("doc"))
+        .filter_map(|attr| match attr.parse_meta() {
+            Ok(syn::Meta::NameValue(syn::MetaNameValue {
+                lit: syn::Lit::Str(doc),
+                ..
+            })) => {
+                let line = doc.value();
+                Some(
+                    line.strip_prefix(' ')
+                        .unwrap_or(&line)
+                        .trim_end()
+                        .to_string(),
+                )
+            }
+            _ => None,
+        })
+        .collect();
+    let start = lines.iter().position(|line| !line.is_empty())?;
+    let end = lines.iter().rposition(|line| !line.is_empty())?;
+    Some(lines[start..=end].join("\n"))
+}
+
 #[derive(Default)]
 struct AssociatedTypeBuilder {
     stage_inputs: Option<Type>,
@@ -293,14 +475,26 @@ impl AssociatedTypeBuilder {
 // Identify which trait impl the attribute is applied to among `MartianMain`
 // and `MartianStage`. If we find that this is applied to a different trait,
 // return an error.
+// Whether any of the `type_params` appear within `ty`
+fn uses_type_params(ty: &Type, type_params: &HashSet<Ident>) -> bool {
+    fn visit(tokens: proc_macro2::TokenStream, type_params: &HashSet<Ident>) -> bool {
+        tokens.into_iter().any(|token| match token {
+            proc_macro2::TokenTree::Ident(ref ident) => type_params.contains(ident),
+            proc_macro2::TokenTree::Group(ref group) => visit(group.stream(), type_params),
+            _ => false,
+        })
+    }
+    visit(quote![#ty], type_params)
+}
+
 fn parse_which_trait(trait_path: syn::Path) -> Result<StageKind, Error> {
     let mut last_ident = String::from("");
     let span = trait_path.segments[0].ident.span();
     for segment in trait_path.segments {
-        if segment.ident == MARTIAN_MAIN_TRAIT {
+        if segment.ident == MARTIAN_MAIN_TRAIT || segment.ident == ASYNC_MARTIAN_MAIN_TRAIT {
             return Ok(StageKind::MainOnly);
         }
-        if segment.ident == MARTIAN_STAGE_TRAIT {
+        if segment.ident == MARTIAN_STAGE_TRAIT || segment.ident == ASYNC_MARTIAN_STAGE_TRAIT {
             return Ok(StageKind::WithSplit);
         }
         last_ident = segment.ident.to_string();
@@ -364,12 +558,52 @@ attr_parse!(
     threads: i16,
     vmem_gb: i16,
     volatile: Volatile,
-    stage_name: String
+    stage_name: String,
+    stage_key: String,
+    preflight: bool
 );
 
 /// Structs which are used as associated types in `MartianMain` or `MartianStage`
 /// traits need to implement `MartianStruct`. You can derive it using `#[derive(MartianStruct)]`
-#[proc_macro_derive(MartianStruct, attributes(mro_retain))]
+///
+/// Use `#[mro_retain]` on a stage output to list it in the `retain` block, or
+/// `#[mro_retain(strict)]` to mark it `@strict` there.
+///
+/// The martian type of a field is inferred from the Rust type. It can be forced to a
+/// builtin martian type using `#[mro_type = "float"]` (or `"float[]"` for an array),
+/// which is useful for fields deserialized into flexible holders such as
+/// `serde_json::Number`.
+///
+/// Use `#[mro_default = 5]` on a stage input to give it a default value in the mro
+/// (`in int count = 5`). Integer, float, bool and string literals are supported.
+/// Other values can be set using `MroField::with_default`.
+///
+/// Use `#[mro(rename = "name")]` to name the mro field differently from the Rust field,
+/// for example `in_` for a field `r#in` since `in` is a martian keyword (the mro name of
+/// a raw identifier is the identifier without `r#`), and `#[mro(skip)]` to leave an
+/// internal field out of the mro. Since the json is produced by serde, they need to be
+/// paired with the same `#[serde(rename = "name")]` or `#[serde(skip)]`. No other serde
+/// attributes are allowed on the fields.
+///
+/// Use `#[mro(flatten)]` along with `#[serde(flatten)]` on a field whose type implements
+/// `MartianStruct` to list its fields directly, instead of a single `map` field.
+///
+/// The doc comment of a field is its documentation (see `MroField::with_doc`), which is
+/// written above the field in the mro with `MakeMroOptions::field_docs`.
+///
+/// Generic structs are supported, e.g `struct Outs<F: MartianFileType> { bam: F }` to share
+/// the outputs between stages writing different filetypes. The fields whose type involves
+/// a type parameter are required to implement `AsMartianBlanketType` (or `MartianStruct` if
+/// flattened) in the generated impl.
+///
+/// Use `#[mro_struct]` on a struct to also use it as a field type, which is a martian
+/// `struct` (martian 4.0) named after the Rust struct. The struct declaration is listed
+/// in the header of the generated mro. Without it, custom types which derive
+/// `MartianType` are a plain `map`.
+#[proc_macro_derive(
+    MartianStruct,
+    attributes(mro_retain, mro_type, mro_default, mro_struct, mro)
+)]
 pub fn martian_struct(item: proc_macro::TokenStream) -> proc_macro::TokenStream {
     // ::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::
     // STEP 1
@@ -407,49 +641,272 @@ pub fn martian_struct(item: proc_macro::TokenStream) -> proc_macro::TokenStream
     // ::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::
     // Generate tokenstream for `MroField` calls for each field
     // Make sure that none of the field names are martian keywords.
-    // Parse the #[mro_retian] attributes attached to the field, and make sure
-    // that no serde field attributes are used
+    // Parse the #[mro_retian], #[mro_type], #[mro_default] and #[mro(..)] attributes attached
+    // to the field, and make sure that the only serde field attributes are the ones matching
+    // #[mro(rename = "..")], #[mro(skip)] and #[mro(flatten)]. The mro field names should be unique.
     let mut vec_inner = Vec::new();
-    let blacklist: HashSet<String> = MARTIAN_TOKENS.iter().map(|x| x.to_string()).collect();
+    let mut names = HashSet::new();
+    // Field types which involve a type parameter, e.g `F` or `Vec<F>` for a struct generic
+    // over a filetype `F: MartianFileType`, are bounded in the where clause of the impl
+    let type_params: HashSet<Ident> = item_struct
+        .generics
+        .type_params()
+        .map(|param| param.ident.clone())
+        .collect();
+    let mut bounds = Vec::new();
     for field in fields {
-        let name = field.ident.clone().unwrap().to_string();
+        // The json key of a raw identifier such as `r#type` is `type`
+        let mut name = field
+            .ident
+            .clone()
+            .unwrap()
+            .to_string()
+            .trim_start_matches("r#")
+            .to_string();
         let mut retain = false;
+        let mut strict = false;
+        let mut mro_type = None;
+        let mut mro_default = None;
+        let mut mro_rename = None;
+        let mut mro_skip = None;
+        let mut serde_rename = None;
+        let mut serde_skip = false;
+        let mut mro_flatten = None;
+        let mut serde_flatten = false;
+        let mut serde_other = false;
         for attr in &field.attrs {
             if let Ok(meta) = attr.parse_meta() {
                 match meta {
                     syn::Meta::Word(ref attr_ident) if attr_ident == "mro_retain" => {
                         retain = true;
                     }
+                    syn::Meta::List(ref list) if list.ident == "mro_retain" => {
+                        match list.nested.iter().collect::<Vec<_>>().as_slice() {
+                            [syn::NestedMeta::Meta(syn::Meta::Word(ref word))] if word == "strict" => {
+                                retain = true;
+                                strict = true;
+                            }
+                            _ => {
+                                return syn::Error::new_spanned(list, "Expecting #[mro_retain] or #[mro_retain(strict)]")
+                                    .to_compile_error()
+                                    .into();
+                            }
+                        }
+                    }
+                    syn::Meta::NameValue(ref name_value) if name_value.ident == "mro_type" => {
+                        let ty_str = match name_value.lit {
+                            syn::Lit::Str(ref lit_str) => lit_str.value(),
+                            _ => {
+                                return syn::Error::new_spanned(name_value, "Expecting a string literal here, for example #[mro_type = \"float\"]")
+                                    .to_compile_error()
+                                    .into();
+                            }
+                        };
+                        if let Err(e) = ty_str.parse::<MartianBlanketType>() {
+                            return syn::Error::new_spanned(name_value, format!("Invalid mro_type `{}`. {}", ty_str, e))
+                                .to_compile_error()
+                                .into();
+                        }
+                        mro_type = Some(ty_str);
+                    }
+                    syn::Meta::NameValue(ref name_value) if name_value.ident == "mro_default" => {
+                        match name_value.lit {
+                            syn::Lit::Int(_)
+                            | syn::Lit::Float(_)
+                            | syn::Lit::Bool(_)
+                            | syn::Lit::Str(_) => mro_default = Some(name_value.lit.clone()),
+                            _ => {
+                                return syn::Error::new_spanned(name_value, "Expecting an integer, float, bool or string literal here, for example #[mro_default = 5]")
+                                    .to_compile_error()
+                                    .into();
+                            }
+                        }
+                    }
+                    syn::Meta::List(ref list) if list.ident == "mro" => {
+                        match list.nested.iter().collect::<Vec<_>>().as_slice() {
+                            [syn::NestedMeta::Meta(syn::Meta::Word(ref word))]
+                                if word == "skip" =>
+                            {
+                                mro_skip = Some(list.clone());
+                            }
+                            [syn::NestedMeta::Meta(syn::Meta::Word(ref word))]
+                                if word == "flatten" =>
+                            {
+                                mro_flatten = Some(list.clone());
+                            }
+                            [syn::NestedMeta::Meta(syn::Meta::NameValue(ref name_value))]
+                                if name_value.ident == "rename" =>
+                            {
+                                match name_value.lit {
+                                    syn::Lit::Str(ref lit_str) => {
+                                        mro_rename = Some((lit_str.value(), list.clone()))
+                                    }
+                                    _ => {
+                                        return syn::Error::new_spanned(list, MRO_FIELD_ATTR_ERROR)
+                                            .to_compile_error()
+                                            .into();
+                                    }
+                                }
+                            }
+                            _ => {
+                                return syn::Error::new_spanned(list, MRO_FIELD_ATTR_ERROR)
+                                    .to_compile_error()
+                                    .into();
+                            }
+                        }
+                    }
                     syn::Meta::List(ref list) if list.ident == "serde" => {
-                        return syn::Error::new_spanned(field, "Cannot use serde attributes here. This might be okay, but it's hard to guarantee that deriving MartianStruct would work correctly when using serde attributes.")
-                            .to_compile_error()
-                            .into();
+                        // Only #[serde(rename = "..")], #[serde(skip)] and #[serde(flatten)] are allowed,
+                        // and only along with the matching #[mro(..)], checked below
+                        match list.nested.iter().collect::<Vec<_>>().as_slice() {
+                            [syn::NestedMeta::Meta(syn::Meta::Word(ref word))]
+                                if word == "skip" =>
+                            {
+                                serde_skip = true;
+                            }
+                            [syn::NestedMeta::Meta(syn::Meta::Word(ref word))]
+                                if word == "flatten" =>
+                            {
+                                serde_flatten = true;
+                            }
+                            [syn::NestedMeta::Meta(syn::Meta::NameValue(syn::MetaNameValue {
+                                ref ident,
+                                lit: syn::Lit::Str(ref lit_str),
+                                ..
+                            }))] if ident == "rename" => {
+                                serde_rename = Some(lit_str.value());
+                            }
+                            _ => serde_other = true,
+                        }
                     }
                     _ => {}
                 }
             }
         }
-        if blacklist.contains(&name) {
-            return syn::Error::new(
-                field.ident.unwrap().span(),
-                format!(
-                    "Field name {} is not allowed here since it is a martian keyword",
-                    name
-                ),
-            )
-            .to_compile_error()
-            .into();
+        let mro_name = mro_rename.as_ref().map(|(mro_name, _)| mro_name);
+        if serde_other
+            || (serde_rename.is_some() && serde_rename.as_ref() != mro_name)
+            || (serde_skip && mro_skip.is_none())
+            || (serde_flatten && mro_flatten.is_none())
+        {
+            return syn::Error::new_spanned(field, "Cannot use serde attributes here. This might be okay, but it's hard to guarantee that deriving MartianStruct would work correctly when using serde attributes.")
+                .to_compile_error()
+                .into();
+        }
+        if let Some(ref list) = mro_skip {
+            if !serde_skip {
+                return syn::Error::new_spanned(
+                    list,
+                    "#[mro(skip)] needs a matching #[serde(skip)], so that the field is not part of the json either",
+                )
+                .to_compile_error()
+                .into();
+            }
+            continue;
+        }
+        // The fields of a flattened struct are listed in place of the field itself
+        if let Some(ref list) = mro_flatten {
+            if !serde_flatten {
+                return syn::Error::new_spanned(
+                    list,
+                    "#[mro(flatten)] needs a matching #[serde(flatten)], so that the fields are flattened in the json as well",
+                )
+                .to_compile_error()
+                .into();
+            }
+            if retain || mro_type.is_some() || mro_default.is_some() || mro_rename.is_some() {
+                return syn::Error::new_spanned(
+                    field,
+                    "#[mro(flatten)] cannot be combined with other mro attributes. Use them on the fields of the flattened struct instead.",
+                )
+                .to_compile_error()
+                .into();
+            }
+            let ty = field.ty;
+            if uses_type_params(&ty, &type_params) {
+                bounds.push(quote![#ty: ::martian::MartianStruct]);
+            }
+            vec_inner.push(quote_spanned! {ty.span()=>
+                fields.extend(<#ty as ::martian::MartianStruct>::mro_fields());
+            });
+            continue;
+        }
+        if let Some((ref mro_name, ref list)) = mro_rename {
+            if serde_rename.as_ref() != Some(mro_name) {
+                return syn::Error::new_spanned(
+                    list,
+                    format!("#[mro(rename = \"{0}\")] needs a matching #[serde(rename = \"{0}\")], so that the json key is the mro field name", mro_name),
+                )
+                .to_compile_error()
+                .into();
+            }
+        }
+        // Errors about the mro field name point at the rename, if any
+        let name_error = |message: String| match mro_rename {
+            Some((_, ref list)) => syn::Error::new_spanned(list, message),
+            None => syn::Error::new(field.ident.as_ref().unwrap().span(), message),
+        };
+        if let Some((ref mro_name, _)) = mro_rename {
+            name = mro_name.clone();
+        }
+        if let Err(e) = check_field_name(&name) {
+            return name_error(e).to_compile_error().into();
+        }
+        if !names.insert(name.clone()) {
+            return name_error(format!("Duplicate mro field name {}", name))
+                .to_compile_error()
+                .into();
         }
         let ty = field.ty;
-        vec_inner.push(if retain {
+        if mro_type.is_none() && uses_type_params(&ty, &type_params) {
+            bounds.push(quote![#ty: ::martian::AsMartianBlanketType]);
+        }
+        let forced_type = mro_type.is_some();
+        let blanket_type = match mro_type {
+            Some(ty_str) => quote![
+                <::martian::MartianBlanketType as ::std::str::FromStr>::from_str(#ty_str).unwrap()
+            ],
+            // Spanned at the field type, so that a type which does not implement
+            // `AsMartianBlanketType` is reported at the offending field
+            None => quote_spanned! {ty.span()=>
+                <#ty as ::martian::AsMartianBlanketType>::as_martian_blanket_type()
+            },
+        };
+        let mro_field = if strict {
             quote![
-                <::martian::MroField>::retained(#name, <#ty as ::martian::AsMartianBlanketType>::as_martian_blanket_type())
+                <::martian::MroField>::strict_retained(#name, #blanket_type)
+            ]
+        } else if retain {
+            quote![
+                <::martian::MroField>::retained(#name, #blanket_type)
             ]
         } else {
             quote![
-                <::martian::MroField>::new(#name, <#ty as ::martian::AsMartianBlanketType>::as_martian_blanket_type())
+                <::martian::MroField>::new(#name, #blanket_type)
             ]
-        });
+        };
+        let mro_field = match mro_default {
+            Some(lit) => quote![#mro_field.with_default(#lit)],
+            None => mro_field,
+        };
+        let mro_field = match doc_comment(&field.attrs) {
+            Some(doc) => quote![#mro_field.with_doc(#doc)],
+            None => mro_field,
+        };
+        // The Rust types of the filetypes are used to detect two types declaring
+        // the same filetype, and their validators check the files in the outputs
+        let mro_field = if forced_type {
+            mro_field
+        } else {
+            quote![#mro_field.with_filetype_rust_types(
+                <#ty as ::martian::AsMartianBlanketType>::filetype_rust_types()
+            ).with_filetype_validators(
+                <#ty as ::martian::AsMartianBlanketType>::filetype_validators()
+            )]
+        };
+        vec_inner.push(quote![
+            fields.push(#mro_field);
+        ]);
     }
 
     // ::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::
@@ -457,19 +914,60 @@ pub fn martian_struct(item: proc_macro::TokenStream) -> proc_macro::TokenStream
     // ::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::
     // Generate the `impl MartianStruct` token stream
     // Handle generics in the struct
-    let (impl_generics, ty_generics, where_clause) = item_struct.generics.split_for_impl();
+    let mut generics = item_struct.generics.clone();
+    {
+        let where_clause = generics.make_where_clause();
+        for bound in bounds {
+            where_clause.predicates.push(syn::parse_quote![#bound]);
+        }
+    }
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
     let item_ident = item_struct.ident.clone();
-    let final_token = quote![
+    let mut final_token = quote![
         #[automatically_derived]
         impl #impl_generics ::martian::MartianStruct for #item_ident #ty_generics #where_clause {
             fn mro_fields() -> Vec<::martian::MroField> {
-                vec![
-                    #(#vec_inner),*
-                ]
+                #[allow(unused_mut)]
+                let mut fields = Vec::new();
+                #(#vec_inner)*
+                fields
             }
         }
     ];
 
+    // ::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::
+    // STEP 5
+    // ::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::
+    // With #[mro_struct], the struct is also a martian struct type
+    let mut mro_struct = false;
+    for attr in &item_struct.attrs {
+        match attr.parse_meta() {
+            Ok(syn::Meta::Word(ref attr_ident)) if attr_ident == "mro_struct" => {
+                mro_struct = true;
+            }
+            Ok(ref meta) if meta.name() == "mro_struct" => {
+                return syn::Error::new_spanned(attr, "Expecting #[mro_struct]")
+                    .to_compile_error()
+                    .into();
+            }
+            _ => {}
+        }
+    }
+    if mro_struct {
+        let struct_name = item_ident.to_string();
+        final_token.extend(quote![
+            #[automatically_derived]
+            impl #impl_generics ::martian::AsMartianPrimaryType for #item_ident #ty_generics #where_clause {
+                fn as_martian_primary_type() -> ::martian::MartianPrimaryType {
+                    ::martian::MartianPrimaryType::Struct(::martian::StructDef::new(
+                        #struct_name,
+                        <Self as ::martian::MartianStruct>::mro_fields(),
+                    ))
+                }
+            }
+        ]);
+    }
+
     proc_macro::TokenStream::from(final_token)
 }
 
@@ -579,7 +1077,116 @@ pub fn martian_type(item: proc_macro::TokenStream) -> proc_macro::TokenStream {
     }
 }
 
-/// A macro to define a new struct that implements `MartianFileType` trait
+const MARTIAN_STRING_ENUM_ERROR: &str = "MartianStringEnum can only be derived on an enum with at least one variant, where none of the variants have any fields.";
+
+/// Fieldless enums used as stage inputs or outputs can derive `MartianStringEnum`.
+/// The enum is a `string` in the mro, with the variant names as the values, and
+/// deserializing a string which is not a variant name fails with an error listing
+/// the variants. The derive also implements `AsMartianPrimaryType`, `FromStr`,
+/// `Display`, `Serialize` and `Deserialize`, so do not derive the serde traits.
+/// ```rust
+/// use martian_derive::MartianStringEnum;
+/// #[derive(Debug, PartialEq, MartianStringEnum)]
+/// enum Chemistry {
+///     SC3Pv2,
+///     SC3Pv3,
+/// }
+/// fn main() {
+///     use martian::MartianStringEnum;
+///     assert_eq!(Chemistry::VARIANTS, &["SC3Pv2", "SC3Pv3"]);
+///     assert_eq!("SC3Pv3".parse::<Chemistry>(), Ok(Chemistry::SC3Pv3));
+///     assert_eq!(Chemistry::SC3Pv2.to_string(), "SC3Pv2");
+/// }
+/// ```
+#[proc_macro_derive(MartianStringEnum)]
+pub fn martian_string_enum(item: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = syn::parse_macro_input!(item as DeriveInput);
+    let ident = input.ident.clone();
+
+    // Make sure that this is an enum with only unit variants
+    let variants: Vec<_> = match input.data {
+        Data::Enum(ref enum_data)
+            if !enum_data.variants.is_empty()
+                && enum_data.variants.iter().all(|v| v.fields == Fields::Unit) =>
+        {
+            enum_data.variants.iter().map(|v| v.ident.clone()).collect()
+        }
+        _ => {
+            return syn::Error::new_spanned(input, MARTIAN_STRING_ENUM_ERROR)
+                .to_compile_error()
+                .into();
+        }
+    };
+    let names: Vec<_> = variants.iter().map(|v| v.to_string()).collect();
+    let type_name = ident.to_string();
+    let from_str_arms: Vec<_> = variants
+        .iter()
+        .zip(&names)
+        .map(|(variant, name)| quote![#name => Ok(#ident::#variant),])
+        .collect();
+    let display_arms: Vec<_> = variants
+        .iter()
+        .zip(&names)
+        .map(|(variant, name)| quote![#ident::#variant => #name,])
+        .collect();
+
+    quote![
+        #[automatically_derived]
+        impl ::martian::MartianStringEnum for #ident {
+            const VARIANTS: &'static [&'static str] = &[#(#names),*];
+        }
+        #[automatically_derived]
+        impl ::martian::AsMartianPrimaryType for #ident {
+            fn as_martian_primary_type() -> ::martian::MartianPrimaryType {
+                ::martian::MartianPrimaryType::Str
+            }
+        }
+        #[automatically_derived]
+        impl ::std::str::FromStr for #ident {
+            type Err = String;
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                match s {
+                    #(#from_str_arms)*
+                    _ => Err(format!(
+                        "Invalid value `{}` for {}. Expected one of {}",
+                        s,
+                        #type_name,
+                        <#ident as ::martian::MartianStringEnum>::VARIANTS.join(", ")
+                    )),
+                }
+            }
+        }
+        #[automatically_derived]
+        impl ::std::fmt::Display for #ident {
+            fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+                f.write_str(match self {
+                    #(#display_arms)*
+                })
+            }
+        }
+        #[automatically_derived]
+        impl ::serde::Serialize for #ident {
+            fn serialize<S: ::serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.collect_str(self)
+            }
+        }
+        #[automatically_derived]
+        impl<'de> ::serde::Deserialize<'de> for #ident {
+            fn deserialize<D: ::serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                let s = <String as ::serde::Deserialize>::deserialize(deserializer)?;
+                s.parse().map_err(::serde::de::Error::custom)
+            }
+        }
+    ]
+    .into()
+}
+
+/// A macro to define a new struct that implements `MartianFileType` trait. When
+/// the extension is `json` or ends with `.json`, it also implements `FileTypeIO`
+/// so that any serializable value can be written to and read from the file, and
+/// `LazyWrite` and `LazyRead` to stream the items of a json array. The json files
+/// in the outputs of a stage are checked to be well-formed (see
+/// `MartianFileType::validate`).
 ///
 /// Because this is a procedural macro, as of now, you can only define it
 /// outside functions because they cannopt be expanded to statements.
@@ -651,64 +1258,265 @@ pub fn martian_filetype(item: proc_macro::TokenStream) -> proc_macro::TokenStrea
     // ::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::
     // STEP 6
     // ::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::
+    // Check that the extension is valid, see `check_extension`
+    let extension = match check_extension(extension, "in the martian_filetype! macro") {
+        Ok(extension) => extension,
+        Err(message) => {
+            return syn::Error::new_spanned(item2, message)
+                .to_compile_error()
+                .into()
+        }
+    };
+
+    // ::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::
+    // STEP 7
+    // ::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::
+    // Now we are ready to actually generate the code.
+    let impls = filetype_impls(&struct_ident, &extension, None);
+    quote![
+        #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+        pub struct #struct_ident(::std::path::PathBuf);
+        #impls
+    ]
+    .into()
+}
+
+const MARTIAN_FILETYPE_DERIVE_ERROR: &str = "MartianFileType can only be derived on a tuple struct with a single field, which is a PathBuf, and without any generics. Specify the extension with #[martian_filetype = \"ext\"].";
+
+/// Newtypes around a `PathBuf` can derive `MartianFileType`, with the extension in
+/// the `#[martian_filetype = "..."]` attribute. This is the same as the struct
+/// defined by `martian_filetype!`, except that the struct can have other derives,
+/// attributes and doc comments of its own. The derive also implements `AsRef<Path>`,
+/// `From`, `AsMartianPrimaryType`, `Display`, `Serialize` and `Deserialize`, so do not
+/// derive the serde traits. The optional `#[martian_validate = "..."]` attribute names
+/// a `fn(&Path) -> Result<(), Error>` implementing `MartianFileType::validate`.
+/// ```rust
+/// use martian_derive::MartianFileType;
+/// use martian::types::MartianFileType;
+/// /// An hdf5 file
+/// #[derive(Debug, Clone, PartialEq, MartianFileType)]
+/// #[martian_filetype = "h5"]
+/// #[martian_validate = "validate_h5"]
+/// pub struct H5File(std::path::PathBuf);
+/// fn validate_h5(path: &std::path::Path) -> Result<(), martian::Error> {
+///     martian::types::validate_magic_bytes(path, b"\x89HDF\r\n\x1a\n")
+/// }
+/// fn main() {
+///     assert_eq!(H5File::extension(), "h5");
+///     let file = H5File::new("/path/to/folder", "matrix");
+///     assert_eq!(file.to_string(), "/path/to/folder/matrix.h5");
+/// }
+/// ```
+#[proc_macro_derive(MartianFileType, attributes(martian_filetype, martian_validate))]
+pub fn martian_filetype_derive(item: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = syn::parse_macro_input!(item as DeriveInput);
+    let ident = input.ident.clone();
+
+    // Make sure that this is a tuple struct with a single field and no generics
+    match input.data {
+        Data::Struct(ref struct_data)
+            if input.generics.params.is_empty()
+                && match struct_data.fields {
+                    Fields::Unnamed(ref fields) => fields.unnamed.len() == 1,
+                    _ => false,
+                } => {}
+        _ => {
+            return syn::Error::new_spanned(input, MARTIAN_FILETYPE_DERIVE_ERROR)
+                .to_compile_error()
+                .into();
+        }
+    }
+
+    // Find the extension in the #[martian_filetype = "..."] attribute
+    let mut extensions = input
+        .attrs
+        .iter()
+        .filter(|attr| attr.path.is_ident("martian_filetype"));
+    let attr = match (extensions.next(), extensions.next()) {
+        (Some(attr), None) => attr,
+        (_, Some(duplicate)) => {
+            return syn::Error::new_spanned(
+                duplicate,
+                "The #[martian_filetype] attribute should only be specified once",
+            )
+            .to_compile_error()
+            .into();
+        }
+        (None, None) => {
+            return syn::Error::new_spanned(input, MARTIAN_FILETYPE_DERIVE_ERROR)
+                .to_compile_error()
+                .into();
+        }
+    };
+    let extension = match attr.parse_meta() {
+        Ok(syn::Meta::NameValue(syn::MetaNameValue {
+            lit: syn::Lit::Str(ref lit_str),
+            ..
+        })) => format!("\"{}\"", lit_str.value()),
+        _ => {
+            return syn::Error::new_spanned(
+                attr,
+                "Specify the extension as #[martian_filetype = \"ext\"]",
+            )
+            .to_compile_error()
+            .into();
+        }
+    };
+    let extension = match check_extension(&extension, "in #[martian_filetype]") {
+        Ok(extension) => extension,
+        Err(message) => {
+            return syn::Error::new_spanned(attr, message)
+                .to_compile_error()
+                .into()
+        }
+    };
+
+    // The function in the optional #[martian_validate = "..."] attribute
+    let mut validate = None;
+    for attr in input
+        .attrs
+        .iter()
+        .filter(|attr| attr.path.is_ident("martian_validate"))
+    {
+        let path = match attr.parse_meta() {
+            Ok(syn::Meta::NameValue(syn::MetaNameValue {
+                lit: syn::Lit::Str(ref lit_str),
+                ..
+            })) if validate.is_none() => lit_str.parse::<syn::Path>().ok(),
+            _ => None,
+        };
+        match path {
+            Some(path) => validate = Some(path),
+            None => {
+                return syn::Error::new_spanned(
+                    attr,
+                    "Specify the function validating the files once as #[martian_validate = \"path::to::function\"]",
+                )
+                .to_compile_error()
+                .into();
+            }
+        }
+    }
+
+    let impls = filetype_impls(&ident, &extension, validate);
+    quote![
+        #impls
+        #[automatically_derived]
+        impl ::std::fmt::Display for #ident {
+            fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+                ::std::fmt::Display::fmt(&self.0.display(), f)
+            }
+        }
+        #[automatically_derived]
+        impl ::serde::Serialize for #ident {
+            fn serialize<S: ::serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                ::serde::Serialize::serialize(&self.0, serializer)
+            }
+        }
+        #[automatically_derived]
+        impl<'de> ::serde::Deserialize<'de> for #ident {
+            fn deserialize<D: ::serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                ::serde::Deserialize::deserialize(deserializer).map(#ident)
+            }
+        }
+    ]
+    .into()
+}
+
+// The extension within the double quotes of `quoted_extension`, if it is a valid
+// extension: not empty, without a leading or trailing dot(.), and alphanumeric
+// (internal dots(.) are okay) starting with an alphabet. `context` says where the
+// extension is in the error message.
+fn check_extension(quoted_extension: &str, context: &str) -> Result<String, String> {
     // Make sure that the string literal within the quotes in the extension
-    // is not empty. Generate a compiler error otherwise.
+    // is not empty.
     let chars_within_quotes: Vec<_> = {
-        let mut chars: Vec<_> = extension.chars().skip(1).collect();
+        let mut chars: Vec<_> = quoted_extension.chars().skip(1).collect();
         chars.pop();
         chars
     };
     if chars_within_quotes.is_empty() {
-        return syn::Error::new_spanned(
-                item2,
-                "The extension for a filetype cannot be empty. Consider using a PathBuf for filenames without any extension."
-            )
-            .to_compile_error()
-            .into();
+        return Err("The extension for a filetype cannot be empty. Consider using a PathBuf for filenames without any extension.".to_string());
     }
 
-    // ::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::
-    // STEP 7
-    // ::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::
     // Make sure that the extension specified but does not start or end with a
-    // dot (.). Generate a compiler error otherwise.
+    // dot (.).
     if chars_within_quotes[0] == '.' {
-        return syn::Error::new_spanned(
-            item2,
-            "No need to specify the leading dot(.) in the extension",
-        )
-        .to_compile_error()
-        .into();
+        return Err("No need to specify the leading dot(.) in the extension".to_string());
     }
     if *chars_within_quotes.last().unwrap() == '.' {
-        return syn::Error::new_spanned(item2, "Extensions cannot end in a dot(.)")
-            .to_compile_error()
-            .into();
+        return Err("Extensions cannot end in a dot(.)".to_string());
     }
 
-    // ::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::
-    // STEP 8
-    // ::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::
     // Make sure that the extension is ascii alphanumeric or a dot (.). We have
     // already checked for leading/trailing dots
     for (i, c) in chars_within_quotes.iter().enumerate() {
         if !((i > 0 && (c.is_ascii_alphanumeric() || *c == '.')) || c.is_ascii_alphabetic()) {
-            return syn::Error::new_spanned(
-                    item2,
-                    format!("The extension `{}` in the martian_filetype! macro should be alphanumeric (internal dots(.) are okay) starting with an alphabet.\n\tFound invalid character `{}` at position {}", extension, c, i))
-                .to_compile_error()
-                .into();
+            return Err(format!("The extension `{}` {} should be alphanumeric (internal dots(.) are okay) starting with an alphabet.\n\tFound invalid character `{}` at position {}", quoted_extension, context, c, i));
         }
     }
+    Ok(chars_within_quotes.iter().collect())
+}
 
-    // ::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::
-    // STEP 9
-    // ::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::
-    // Now we are ready to actually generate the code.
-    let extension: String = chars_within_quotes.iter().collect();
+// The impls of a filetype `struct #struct_ident(PathBuf)` with the `extension`,
+// for `martian_filetype!` and `#[derive(MartianFileType)]`. `validate` is the
+// function implementing `MartianFileType::validate`, if any.
+fn filetype_impls(
+    struct_ident: &Ident,
+    extension: &str,
+    validate: Option<syn::Path>,
+) -> proc_macro2::TokenStream {
+    // A separate quote, to stay within the recursion limit of quote!
+    let filetype_rust_types_fn = quote![
+        fn filetype_rust_types() -> Vec<(String, String)> {
+            vec![(
+                String::from(<#struct_ident as ::martian::MartianFileType>::extension()),
+                String::from(::std::any::type_name::<#struct_ident>()),
+            )]
+        }
+        fn filetype_validators() -> Vec<::martian::types::FiletypeValidator> {
+            vec![::martian::types::FiletypeValidator::of::<#struct_ident>()]
+        }
+    ];
+    // Json files are checked to be well-formed json by default
+    let is_json = extension == "json" || extension.ends_with(".json");
+    let validate = match validate {
+        Some(validate) => Some(quote![#validate]),
+        None if is_json => Some(quote![::martian::types::validate_json]),
+        None => None,
+    };
+    let validate_fn = match validate {
+        Some(validate) => quote![
+            fn validate(path: &::std::path::Path) -> Result<(), ::martian::Error> {
+                #validate(path)
+            }
+        ],
+        None => quote![],
+    };
+    // Json files can be read and written out of the box
+    let file_type_io_impl = if is_json {
+        quote![
+            #[automatically_derived]
+            impl<T> ::martian::FileTypeIO<T> for #struct_ident
+            where
+                T: ::serde::Serialize + ::serde::de::DeserializeOwned,
+            {
+                fn read_from<R: ::std::io::Read>(reader: R) -> Result<T, ::martian::Error> {
+                    ::martian::types::read_json(reader)
+                }
+                fn write_into<W: ::std::io::Write>(writer: W, item: &T) -> Result<(), ::martian::Error> {
+                    ::martian::types::write_json(writer, item)
+                }
+            }
+            #[automatically_derived]
+            impl<T: ::serde::Serialize> ::martian::LazyWrite<T> for #struct_ident {}
+            #[automatically_derived]
+            impl<T: ::serde::de::DeserializeOwned> ::martian::LazyRead<T> for #struct_ident {}
+        ]
+    } else {
+        quote![]
+    };
     quote![
-        #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
-        pub struct #struct_ident(::std::path::PathBuf);
         #[automatically_derived]
         impl ::martian::MartianFileType for #struct_ident {
             fn extension() -> &'static str {
@@ -718,15 +1526,12 @@ pub fn martian_filetype(item: proc_macro::TokenStream) -> proc_macro::TokenStrea
                 file_path: impl ::std::convert::AsRef<::std::path::Path>,
                 file_name: impl ::std::convert::AsRef<::std::path::Path>,
             ) -> Self {
-                let mut path = ::std::path::PathBuf::from(file_path.as_ref());
-                path.push(file_name);
-                let full_extension = match path.extension() {
-                    Some(ext) => format!("{}.{}", ext.to_string_lossy(), Self::extension()),
-                    None => Self::extension().to_string(),
-                };
-                path.set_extension(full_extension);
+                let path = ::std::path::PathBuf::from(file_path.as_ref()).join(file_name);
+                // Don't add the extension, or the parts of it, which the name already has
+                let path = ::martian::types::with_extension(path, Self::extension());
                 #struct_ident(path)
             }
+            #validate_fn
         }
         #[automatically_derived]
         impl ::std::convert::AsRef<::std::path::Path> for #struct_ident {
@@ -748,9 +1553,10 @@ pub fn martian_filetype(item: proc_macro::TokenStream) -> proc_macro::TokenStrea
             fn as_martian_primary_type() -> ::martian::MartianPrimaryType {
                 ::martian::MartianPrimaryType::FileType(String::from(<#struct_ident as ::martian::MartianFileType>::extension()))
             }
+            #filetype_rust_types_fn
         }
+        #file_type_io_impl
     ]
-    .into()
 }
 
 #[cfg(test)]
@@ -766,6 +1572,7 @@ mod tests {
         t.compile_fail("tests/ui_make_mro/*.rs");
         t.compile_fail("tests/ui_martian_struct/*.rs");
         t.compile_fail("tests/ui_martian_type/*.rs");
+        t.compile_fail("tests/ui_martian_string_enum/*.rs");
         t.compile_fail("tests/ui_martian_filetype/*.rs");
     }
 
@@ -795,6 +1602,14 @@ mod tests {
                     ..Default::default()
                 }
         );
+        assert!(
+            "preflight=true".parse::<MakeMroAttr>().unwrap()
+                == MakeMroAttr {
+                    preflight: Some(true),
+                    ..Default::default()
+                }
+        );
+        assert!("preflight=yes".parse::<MakeMroAttr>().is_err());
         assert!(
             "stage_name=MY_STAGE".parse::<MakeMroAttr>().unwrap()
                 == MakeMroAttr {
@@ -802,5 +1617,15 @@ mod tests {
                     ..Default::default()
                 }
         );
+        assert!(
+            "stage_name=MY_STAGE, stage_key=my_stage"
+                .parse::<MakeMroAttr>()
+                .unwrap()
+                == MakeMroAttr {
+                    stage_name: Some("MY_STAGE".into()),
+                    stage_key: Some("my_stage".into()),
+                    ..Default::default()
+                }
+        );
     }
 }
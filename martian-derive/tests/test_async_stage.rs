@@ -0,0 +1,71 @@
+use martian::{AsyncMartianStage, Error, MartianRover, MartianStage, MroMaker, StageDef};
+use martian_derive::{make_mro, MartianStruct};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Serialize, Deserialize, MartianStruct)]
+pub struct SI {
+    values: Vec<f64>,
+}
+
+#[derive(Serialize, Deserialize, MartianStruct)]
+pub struct SO {
+    sum: f64,
+}
+
+#[derive(Clone, Serialize, Deserialize, MartianStruct)]
+pub struct CI {
+    value: f64,
+}
+
+#[derive(Serialize, Deserialize, MartianStruct)]
+pub struct CO {
+    square: f64,
+}
+
+pub struct SumSquares;
+
+#[make_mro(mem_gb = 2)]
+impl AsyncMartianStage for SumSquares {
+    type StageInputs = SI;
+    type StageOutputs = SO;
+    type ChunkInputs = CI;
+    type ChunkOutputs = CO;
+
+    async fn split(&self, args: SI, _: MartianRover) -> Result<StageDef<CI>, Error> {
+        Ok(args.values.into_iter().map(|value| CI { value }).collect())
+    }
+
+    async fn main(&self, _: SI, chunk: CI, _: MartianRover) -> Result<CO, Error> {
+        let square = square(chunk.value).await;
+        Ok(CO { square })
+    }
+
+    async fn join(
+        &self,
+        _: SI,
+        _: Vec<CI>,
+        chunk_outs: Vec<CO>,
+        _: MartianRover,
+    ) -> Result<SO, Error> {
+        Ok(SO {
+            sum: chunk_outs.iter().map(|out| out.square).sum(),
+        })
+    }
+}
+
+// Some async work
+async fn square(value: f64) -> f64 {
+    std::future::ready(value * value).await
+}
+
+#[test]
+fn test_async_stage() {
+    assert_eq!(SumSquares::stage_name(), "SUM_SQUARES");
+    assert!(SumSquares::chunk_in_and_out().is_some());
+    let outs = SumSquares
+        .test_run_tmpdir(SI {
+            values: vec![1.0, 2.0, 3.0],
+        })
+        .unwrap();
+    assert_eq!(outs.sum, 14.0);
+}
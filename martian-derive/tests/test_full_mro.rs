@@ -64,7 +64,7 @@ fn test_main_only() {
 
     let expected = include_str!("mro/test_main_only.mro");
 
-    assert_eq!(SumSquares::mro("adapter", "sum_squares"), expected);
+    assert_eq!(SumSquares::mro("adapter", "sum_squares").unwrap(), expected);
 }
 
 #[test]
@@ -92,7 +92,7 @@ fn test_main_only_generic_associated_type() {
 
     let expected = include_str!("mro/test_main_only.mro");
 
-    assert_eq!(SumSquares::mro("adapter", "sum_squares"), expected);
+    assert_eq!(SumSquares::mro("adapter", "sum_squares").unwrap(), expected);
 }
 
 #[test]
@@ -124,7 +124,7 @@ fn test_main_only_generic_stage_struct() {
     let expected = include_str!("mro/test_main_only.mro");
 
     assert_eq!(
-        SumSquares::<Vec<f32>>::mro("adapter", "sum_squares"),
+        SumSquares::<Vec<f32>>::mro("adapter", "sum_squares").unwrap(),
         expected
     );
 }
@@ -172,7 +172,7 @@ fn test_empty_split() {
 
     let expected = include_str!("mro/test_empty_split.mro");
 
-    assert_eq!(ChunkReads::mro("my_adapter", "chunker"), expected)
+    assert_eq!(ChunkReads::mro("my_adapter", "chunker").unwrap(), expected)
 }
 
 #[test]
@@ -222,7 +222,10 @@ fn test_non_empty_split() {
 
     let expected = include_str!("mro/test_non_empty_split.mro");
 
-    assert_eq!(ChunkerStage::mro("my_adapter", "chunker"), expected)
+    assert_eq!(
+        ChunkerStage::mro("my_adapter", "chunker").unwrap(),
+        expected
+    )
 }
 
 #[test]
@@ -252,7 +255,7 @@ fn test_with_filetype() {
 
     let expected = include_str!("mro/test_with_filetype.mro");
 
-    assert_eq!(SumSquares::mro("adapter", "sum_squares"), expected);
+    assert_eq!(SumSquares::mro("adapter", "sum_squares").unwrap(), expected);
 }
 
 #[test]
@@ -297,7 +300,7 @@ fn test_with_custom_type() {
     let expected = include_str!("mro/test_with_custom_type.mro");
 
     assert_eq!(
-        DetectChemistry::mro("adapter", "detect_chemistry"),
+        DetectChemistry::mro("adapter", "detect_chemistry").unwrap(),
         expected
     );
 }
@@ -353,7 +356,10 @@ fn test_retain() {
 
     let expected = include_str!("mro/test_retain.mro");
 
-    assert_eq!(SortByPos::mro("adapter", "sort_reads_by_pos"), expected);
+    assert_eq!(
+        SortByPos::mro("adapter", "sort_reads_by_pos").unwrap(),
+        expected
+    );
 }
 
 #[test]
@@ -380,5 +386,324 @@ fn test_main_only_full_name() {
 
     let expected = include_str!("mro/test_main_only.mro");
 
-    assert_eq!(SumSquares::mro("adapter", "sum_squares"), expected);
+    assert_eq!(SumSquares::mro("adapter", "sum_squares").unwrap(), expected);
+}
+
+#[test]
+fn test_void_outputs() {
+    #[derive(Clone, Serialize, Deserialize, MartianStruct)]
+    pub struct SI {
+        values: Vec<f64>,
+    }
+
+    pub struct CheckValues;
+
+    #[make_mro]
+    impl MartianMain for CheckValues {
+        type StageInputs = SI;
+        type StageOutputs = MartianVoid;
+
+        fn main(&self, _: SI, _: MartianRover) -> Result<MartianVoid, Error> {
+            Ok(MartianVoid::new())
+        }
+    }
+
+    let expected = include_str!("mro/test_void_outputs.mro");
+    assert_eq!(
+        CheckValues::mro("adapter", "check_values").unwrap(),
+        expected
+    );
+
+    // The _outs file would be an empty json object
+    let outs = CheckValues
+        .test_run_tmpdir(SI {
+            values: vec![1.0, 2.0],
+        })
+        .unwrap();
+    assert!(martian::utils::obj_encode(&outs).unwrap().is_empty());
+
+    // A stage without outputs fails the strict check, unless it is a preflight
+    assert!(CheckValues::stage_mro("adapter", "check_values")
+        .check_outputs()
+        .is_err());
+
+    pub struct CheckValuesPreflight;
+
+    #[make_mro(preflight = true)]
+    impl MartianMain for CheckValuesPreflight {
+        type StageInputs = SI;
+        type StageOutputs = MartianVoid;
+
+        fn main(&self, _: SI, _: MartianRover) -> Result<MartianVoid, Error> {
+            Ok(MartianVoid::new())
+        }
+    }
+    assert!(
+        CheckValuesPreflight::stage_mro("adapter", "check_values_preflight")
+            .check_outputs()
+            .is_ok()
+    );
+}
+
+#[test]
+fn test_description() {
+    #[derive(Clone, Serialize, Deserialize, MartianStruct)]
+    pub struct SI {
+        values: Vec<f64>,
+    }
+
+    #[derive(Clone, Serialize, Deserialize, MartianStruct)]
+    pub struct SO {
+        sum: f64,
+    }
+
+    pub struct SumSquares;
+
+    ///
+    /// Sum of the squares of the values
+    ///
+    /// Only the first line ends up in the mro.
+    #[make_mro]
+    impl MartianMain for SumSquares {
+        type StageInputs = SI;
+        type StageOutputs = SO;
+
+        fn main(&self, args: SI, _: MartianRover) -> Result<SO, Error> {
+            Ok(SO {
+                sum: args.values.iter().map(|x| x * x).sum(),
+            })
+        }
+    }
+
+    assert_eq!(
+        SumSquares::description(),
+        Some("Sum of the squares of the values")
+    );
+    let expected = include_str!("mro/test_description.mro");
+    assert_eq!(SumSquares::mro("adapter", "sum_squares").unwrap(), expected);
+}
+
+#[test]
+fn test_struct_type() {
+    #[derive(Clone, Serialize, Deserialize, MartianStruct)]
+    #[mro_struct]
+    pub struct ReadPair {
+        r1: FastqFile,
+        r2: Option<FastqFile>,
+    }
+
+    #[derive(Clone, Serialize, Deserialize, MartianStruct)]
+    #[mro_struct]
+    pub struct Sample {
+        name: String,
+        reads: Vec<ReadPair>,
+    }
+
+    #[derive(Clone, Serialize, Deserialize, MartianStruct)]
+    pub struct SI {
+        sample: Sample,
+        reference: ReadPair,
+    }
+
+    #[derive(Clone, Serialize, Deserialize, MartianStruct)]
+    pub struct SO {
+        summary: JsonFile,
+    }
+
+    pub struct CountReads;
+
+    #[make_mro]
+    impl MartianMain for CountReads {
+        type StageInputs = SI;
+        type StageOutputs = SO;
+
+        fn main(&self, _: SI, _: MartianRover) -> Result<SO, Error> {
+            unimplemented!()
+        }
+    }
+
+    let expected = include_str!("mro/test_struct_type.mro");
+    assert_eq!(CountReads::mro("adapter", "count_reads").unwrap(), expected);
+}
+
+#[test]
+fn test_default_values() {
+    #[derive(Clone, Serialize, Deserialize, MartianStruct)]
+    pub struct SI {
+        values: Vec<f64>,
+        #[mro_default = 2.5]
+        scale: f64,
+        #[mro_default = 10]
+        max_count: i32,
+        #[mro_default = true]
+        skip_empty: bool,
+        #[mro_default = "sum"]
+        mode: String,
+    }
+
+    #[derive(Clone, Serialize, Deserialize, MartianStruct)]
+    pub struct SO {
+        sum: f64,
+    }
+
+    pub struct SumSquares;
+
+    #[make_mro]
+    impl MartianMain for SumSquares {
+        type StageInputs = SI;
+        type StageOutputs = SO;
+
+        fn main(&self, args: SI, _: MartianRover) -> Result<SO, Error> {
+            Ok(SO {
+                sum: args.values.iter().map(|x| args.scale * x * x).sum(),
+            })
+        }
+    }
+
+    let expected = include_str!("mro/test_default_values.mro");
+    assert_eq!(SumSquares::mro("adapter", "sum_squares").unwrap(), expected);
+}
+
+mod registry_stages {
+    use super::*;
+
+    #[derive(Serialize, Deserialize, MartianStruct)]
+    pub struct SumSquaresStageInputs {
+        values: Vec<f64>,
+    }
+    #[derive(Serialize, Deserialize, MartianStruct)]
+    pub struct SumSquaresStageOutputs {
+        sum_sq: f64,
+    }
+
+    pub struct SumSquares;
+
+    #[make_mro]
+    impl MartianMain for SumSquares {
+        type StageInputs = SumSquaresStageInputs;
+        type StageOutputs = SumSquaresStageOutputs;
+
+        fn main(&self, _: Self::StageInputs, _: MartianRover) -> Result<Self::StageOutputs, Error> {
+            unimplemented!()
+        }
+    }
+
+    pub struct ReportSums;
+
+    #[make_mro(stage_name = WRITE_REPORT)]
+    impl MartianMain for ReportSums {
+        type StageInputs = SumSquaresStageOutputs;
+        type StageOutputs = MartianVoid;
+
+        fn main(&self, _: Self::StageInputs, _: MartianRover) -> Result<Self::StageOutputs, Error> {
+            unimplemented!()
+        }
+    }
+
+    pub struct Summarize;
+
+    #[make_mro(stage_name = SUMMARIZE_SUMS, stage_key = summarize_sums)]
+    impl MartianMain for Summarize {
+        type StageInputs = SumSquaresStageOutputs;
+        type StageOutputs = MartianVoid;
+
+        fn main(&self, _: Self::StageInputs, _: MartianRover) -> Result<Self::StageOutputs, Error> {
+            unimplemented!()
+        }
+    }
+}
+
+#[test]
+fn test_martian_stages() {
+    let (stage_registry, mro_registry) = martian::martian_stages![
+        registry_stages::SumSquares,
+        registry_stages::ReportSums,
+        registry_stages::Summarize,
+    ];
+    let mut keys: Vec<_> = stage_registry.keys().cloned().collect();
+    keys.sort();
+    assert_eq!(keys, vec!["report_sums", "sum_squares", "summarize_sums"]);
+
+    // The keys match the src lines of the mro, even with a custom stage name or key
+    let src_keys: Vec<_> = mro_registry
+        .iter()
+        .map(|stage_mro| {
+            let mro = stage_mro.to_string();
+            let src = mro.lines().find(|line| line.contains("src comp")).unwrap();
+            src.rsplit(' ')
+                .next()
+                .unwrap()
+                .trim_end_matches("\",")
+                .to_string()
+        })
+        .collect();
+    assert_eq!(
+        src_keys,
+        vec!["sum_squares", "report_sums", "summarize_sums"]
+    );
+    assert_eq!(mro_registry[1].stage_name(), "WRITE_REPORT");
+    assert_eq!(mro_registry[2].stage_name(), "SUMMARIZE_SUMS");
+
+    // The adapter name is the name of the crate outside of a binary target
+    for stage_mro in &mro_registry {
+        assert_eq!(stage_mro.adapter_name(), "martian-derive");
+    }
+}
+
+mod conflicting_filetypes {
+    use super::*;
+
+    // A second Rust type for the `txt` filetype
+    martian_filetype! {TextFile, "txt"}
+
+    #[derive(Serialize, Deserialize, MartianStruct)]
+    pub struct WriteNotesStageOutputs {
+        notes: TxtFile,
+    }
+
+    pub struct WriteNotes;
+
+    #[make_mro]
+    impl MartianMain for WriteNotes {
+        type StageInputs = MartianVoid;
+        type StageOutputs = WriteNotesStageOutputs;
+
+        fn main(&self, _: Self::StageInputs, _: MartianRover) -> Result<Self::StageOutputs, Error> {
+            unimplemented!()
+        }
+    }
+
+    #[derive(Serialize, Deserialize, MartianStruct)]
+    pub struct ReadNotesStageInputs {
+        notes: Vec<TextFile>,
+    }
+
+    pub struct ReadNotes;
+
+    #[make_mro]
+    impl MartianMain for ReadNotes {
+        type StageInputs = ReadNotesStageInputs;
+        type StageOutputs = MartianVoid;
+
+        fn main(&self, _: Self::StageInputs, _: MartianRover) -> Result<Self::StageOutputs, Error> {
+            unimplemented!()
+        }
+    }
+}
+
+#[test]
+fn test_conflicting_filetypes() {
+    use conflicting_filetypes::{ReadNotes, WriteNotes};
+    let registry = vec![
+        WriteNotes::stage_mro("adapter", "write_notes"),
+        ReadNotes::stage_mro("adapter", "read_notes"),
+    ];
+    assert_eq!(
+        martian::validate_mro_registry(&registry)
+            .unwrap_err()
+            .to_string(),
+        "Filetype txt is declared by both the Rust types test_full_mro::TxtFile (stage WRITE_NOTES) \
+         and test_full_mro::conflicting_filetypes::TextFile (stage READ_NOTES)"
+    );
+    assert!(martian::validate_mro_registry(&registry[..1]).is_ok());
 }
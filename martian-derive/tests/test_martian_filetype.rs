@@ -46,4 +46,216 @@ fn test_new() {
         FqTarGzFile::new("/some/folder/", "foo").as_ref(),
         &PathBuf::from("/some/folder/foo.fastq.tar.gz")
     );
+
+    // The extension is not added twice
+    assert_eq!(
+        TxtFile::new("/some/folder", "file.txt").as_ref(),
+        &PathBuf::from("/some/folder/file.txt")
+    );
+    assert_eq!(
+        FqLz4File::new("/some/folder", "foo.fastq.lz4").as_ref(),
+        &PathBuf::from("/some/folder/foo.fastq.lz4")
+    );
+    assert_eq!(
+        FqLz4File::new("/some/folder", "foo.lz4").as_ref(),
+        &PathBuf::from("/some/folder/foo.lz4.fastq.lz4")
+    );
+
+    // Only the missing parts of a multi-part extension are added
+    assert_eq!(
+        FqLz4File::new("/some/folder", "foo.fastq").as_ref(),
+        &PathBuf::from("/some/folder/foo.fastq.lz4")
+    );
+    assert_eq!(
+        FqTarGzFile::new("/some/folder", "foo.fastq").as_ref(),
+        &PathBuf::from("/some/folder/foo.fastq.tar.gz")
+    );
+    assert_eq!(
+        FqTarGzFile::new("/some/folder", "foo.fastq.tar").as_ref(),
+        &PathBuf::from("/some/folder/foo.fastq.tar.gz")
+    );
+    assert_eq!(
+        FqTarGzFile::new("/some/folder", "foo.tar.gz").as_ref(),
+        &PathBuf::from("/some/folder/foo.tar.gz.fastq.tar.gz")
+    );
+}
+
+#[test]
+fn test_typed_path() {
+    use martian::types::TypedPath;
+    use martian::{AsMartianBlanketType, MartianStruct, MroField};
+    use martian_derive::MartianStruct;
+
+    #[derive(Debug, MartianStruct, Deserialize)]
+    struct ReadsArgs {
+        reads: TypedPath<FqLz4File>,
+    }
+    assert_eq!(
+        ReadsArgs::mro_fields(),
+        vec![MroField::new(
+            "reads",
+            TypedPath::<FqLz4File>::as_martian_blanket_type()
+        )]
+    );
+    assert_eq!(ReadsArgs::mro_fields()[0].to_string(), "fastq.lz4 reads");
+
+    let args: ReadsArgs = serde_json::from_str(r#"{"reads": "/data/r1.fastq.lz4"}"#).unwrap();
+    assert_eq!(args.reads, TypedPath::from(FqLz4File::new("/data", "r1")));
+    let err = serde_json::from_str::<ReadsArgs>(r#"{"reads": "/data/r1.fastq"}"#).unwrap_err();
+    assert!(err
+        .to_string()
+        .starts_with("Expected a `fastq.lz4` file, found /data/r1.fastq"));
+}
+
+martian_filetype! {JsonFile, "json"}
+martian_filetype! {SummaryJsonFile, "summary.json"}
+
+#[test]
+fn test_json_file_io() {
+    use martian::FileTypeIO;
+    use std::collections::HashMap;
+
+    let dir = tempdir::TempDir::new("test_json_file_io").unwrap();
+    let counts: HashMap<String, i64> = vec![("a".to_string(), 1), ("b".to_string(), 2)]
+        .into_iter()
+        .collect();
+    let file = JsonFile::new(dir.path(), "counts");
+    file.write(&counts).unwrap();
+    let read: HashMap<String, i64> = file.read().unwrap();
+    assert_eq!(read, counts);
+
+    let file = SummaryJsonFile::new(dir.path(), "sample");
+    file.write(&vec![1.5, 2.5]).unwrap();
+    let values: Vec<f64> = file.read().unwrap();
+    assert_eq!(values, vec![1.5, 2.5]);
+}
+
+#[test]
+fn test_json_file_lazy() {
+    use martian::{LazyRead, LazyWrite};
+
+    let dir = tempdir::TempDir::new("test_json_file_lazy").unwrap();
+    let file = JsonFile::new(dir.path(), "values");
+    let mut writer = file.lazy_writer().unwrap();
+    for i in 0..10u32 {
+        writer.write_item(&i).unwrap();
+    }
+    writer.finish().unwrap();
+    let values: Vec<u32> = file
+        .lazy_reader()
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    assert_eq!(values, (0..10).collect::<Vec<_>>());
+}
+
+#[test]
+fn test_gzip_json_file() {
+    use martian::{
+        FileTypeIO, Gzip, MartianBlanketType, MartianPrimaryType, MartianStruct, MroField,
+    };
+    use martian_derive::MartianStruct;
+
+    #[allow(dead_code)]
+    #[derive(MartianStruct)]
+    struct Outs {
+        summary: Gzip<JsonFile>,
+        parts: Vec<Gzip<JsonFile>>,
+    }
+    assert_eq!(
+        Outs::mro_fields(),
+        vec![
+            MroField::new(
+                "summary",
+                MartianBlanketType::Primary(MartianPrimaryType::FileType("json.gz".into()))
+            ),
+            MroField::new(
+                "parts",
+                MartianBlanketType::Array(MartianPrimaryType::FileType("json.gz".into()))
+            ),
+        ]
+    );
+
+    let dir = tempdir::TempDir::new("test_gzip_json_file").unwrap();
+    let file: Gzip<JsonFile> = Gzip::new(dir.path(), "summary");
+    assert_eq!(file.as_ref(), dir.path().join("summary.json.gz"));
+    file.write(&vec![1, 2, 3]).unwrap();
+    let values: Vec<i32> = file.read().unwrap();
+    assert_eq!(values, vec![1, 2, 3]);
+}
+
+/// An hdf5 matrix
+#[derive(Debug, Clone, PartialEq, martian_derive::MartianFileType)]
+#[martian_filetype = "h5"]
+pub struct H5File(PathBuf);
+
+#[derive(Debug, PartialEq, martian_derive::MartianFileType)]
+#[martian_filetype = "metrics.json"]
+struct MetricsJsonFile(PathBuf);
+
+#[derive(martian_derive::MartianFileType)]
+#[martian_filetype = "bam"]
+#[martian_validate = "validate_bam"]
+struct BamFile(PathBuf);
+
+fn validate_bam(path: &std::path::Path) -> Result<(), martian::Error> {
+    martian::types::validate_magic_bytes(path, &[0x1f, 0x8b])
+}
+
+#[test]
+fn test_derive_martian_filetype() {
+    use martian::{AsMartianPrimaryType, FileTypeIO, MartianPrimaryType};
+
+    assert_eq!(H5File::extension(), "h5");
+    assert_eq!(
+        H5File::new("/some/folder", "matrix"),
+        H5File(PathBuf::from("/some/folder/matrix.h5"))
+    );
+    assert_eq!(
+        H5File::new("/some/folder", "matrix.h5"),
+        H5File::from("/some/folder/matrix.h5")
+    );
+    assert_eq!(
+        H5File::as_martian_primary_type(),
+        MartianPrimaryType::FileType("h5".into())
+    );
+
+    let file = H5File::new("/some/folder", "matrix");
+    assert_eq!(file.to_string(), "/some/folder/matrix.h5");
+    assert_eq!(
+        serde_json::to_string(&file).unwrap(),
+        r#""/some/folder/matrix.h5""#
+    );
+    assert_eq!(
+        serde_json::from_str::<H5File>(r#""/some/folder/matrix.h5""#).unwrap(),
+        file
+    );
+
+    let dir = tempdir::TempDir::new("test_derive_martian_filetype").unwrap();
+    let file = MetricsJsonFile::new(dir.path(), "sample");
+    assert_eq!(file.as_ref(), dir.path().join("sample.metrics.json"));
+    file.write(&vec![1.5]).unwrap();
+    let values: Vec<f64> = file.read().unwrap();
+    assert_eq!(values, vec![1.5]);
+}
+
+#[test]
+fn test_filetype_validate() {
+    use martian::{AsMartianBlanketType, Gzip};
+
+    let dir = tempdir::TempDir::new("test_filetype_validate").unwrap();
+    let path = dir.path().join("file");
+    std::fs::write(&path, r#"{"reads": 10"#).unwrap();
+    assert!(JsonFile::validate(&path).is_err());
+    assert!(MetricsJsonFile::validate(&path).is_err());
+    assert!(TxtFile::validate(&path).is_ok());
+    assert!(BamFile::validate(&path).is_err());
+    std::fs::write(&path, r#"{"reads": 10}"#).unwrap();
+    assert!(JsonFile::validate(&path).is_ok());
+
+    let validators = <Option<Vec<Gzip<JsonFile>>>>::filetype_validators();
+    assert_eq!(validators.len(), 1);
+    assert_eq!(validators[0].extension(), "json.gz");
+    assert!(validators[0].validate(&path).is_err());
+    assert_eq!(<Vec<BamFile>>::filetype_validators()[0].extension(), "bam");
 }
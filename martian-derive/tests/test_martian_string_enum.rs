@@ -0,0 +1,41 @@
+use martian::MartianBlanketType::*;
+use martian::MartianPrimaryType::*;
+use martian::{AsMartianBlanketType, MartianStringEnum};
+use martian_derive::MartianStringEnum;
+
+#[derive(Debug, Clone, Copy, PartialEq, MartianStringEnum)]
+enum Chemistry {
+    SCvdj,
+    SC5Ppe,
+    SC3Pv3,
+}
+
+#[test]
+fn test_string_enum() {
+    assert_eq!(Chemistry::as_martian_blanket_type(), Primary(Str));
+    assert_eq!(Option::<Chemistry>::as_martian_blanket_type(), Primary(Str));
+    assert_eq!(Chemistry::VARIANTS, &["SCvdj", "SC5Ppe", "SC3Pv3"]);
+    assert_eq!(Chemistry::SC5Ppe.to_string(), "SC5Ppe");
+    assert_eq!("SC3Pv3".parse::<Chemistry>(), Ok(Chemistry::SC3Pv3));
+    assert_eq!(
+        "SC3Pv2".parse::<Chemistry>(),
+        Err("Invalid value `SC3Pv2` for Chemistry. Expected one of SCvdj, SC5Ppe, SC3Pv3".into())
+    );
+}
+
+#[test]
+fn test_string_enum_serde() {
+    assert_eq!(
+        serde_json::to_string(&vec![Chemistry::SCvdj, Chemistry::SC3Pv3]).unwrap(),
+        r#"["SCvdj","SC3Pv3"]"#
+    );
+    assert_eq!(
+        serde_json::from_str::<Chemistry>(r#""SC5Ppe""#).unwrap(),
+        Chemistry::SC5Ppe
+    );
+    let err = serde_json::from_str::<Chemistry>(r#""auto""#).unwrap_err();
+    assert!(err
+        .to_string()
+        .starts_with("Invalid value `auto` for Chemistry. Expected one of SCvdj, SC5Ppe, SC3Pv3"));
+    assert!(serde_json::from_str::<Chemistry>("1").is_err());
+}
@@ -20,6 +20,24 @@ fn test_simple_vec() {
 }
 
 martian_filetype! {TxtFile, "txt"}
+#[test]
+fn test_nested_vec() {
+    #[allow(dead_code)]
+    #[derive(MartianStruct)]
+    struct Groupings {
+        barcode_groups: Vec<Vec<String>>,
+        files: Vec<Vec<TxtFile>>,
+        cube: Vec<Vec<Vec<i32>>>,
+    }
+    let expected = vec![
+        MroField::new("barcode_groups", NestedArray(Str, 2)),
+        MroField::new("files", NestedArray(FileType("txt".into()), 2)),
+        MroField::new("cube", NestedArray(Int, 3)),
+    ];
+    assert_eq!(expected, Groupings::mro_fields());
+    assert_eq!(expected[1].to_string(), "txt[][] files");
+}
+
 #[test]
 fn test_generic() {
     #[derive(MartianStruct)]
@@ -54,10 +72,65 @@ fn test_generic() {
     );
     assert_eq!(
         Generic::<HashMap<String, f32>>::mro_fields(),
+        vec![MroField::new(
+            "param",
+            Primary(MapOf(Box::new(Primary(Float))))
+        )]
+    );
+    assert_eq!(
+        Generic::<HashMap<String, martian::Json>>::mro_fields(),
         vec![MroField::new("param", Primary(Map))]
     );
 }
 
+#[test]
+fn test_untyped_map_values() {
+    // Values without a martian type need an explicit untyped `map`
+    #[derive(MartianStruct)]
+    #[allow(dead_code)]
+    struct Ranges {
+        #[mro_type = "map"]
+        ranges: HashMap<String, (u32, u32)>,
+        counts: HashMap<String, u32>,
+    }
+    assert_eq!(
+        Ranges::mro_fields(),
+        vec![
+            MroField::new("ranges", Primary(Map)),
+            MroField::new("counts", Primary(MapOf(Box::new(Primary(Int))))),
+        ]
+    );
+    assert_eq!(Ranges::mro_fields()[0].to_string(), "map ranges");
+}
+
+martian_filetype! {BamFile, "bam"}
+
+#[test]
+fn test_generic_filetype() {
+    use martian::types::TypedPath;
+    use martian::MartianFileType;
+
+    #[allow(dead_code)]
+    #[derive(MartianStruct)]
+    struct Outs<F: MartianFileType> {
+        #[mro_retain]
+        output: F,
+        chunks: Vec<F>,
+        input: Option<TypedPath<F>>,
+        total: i64,
+    }
+    let fields = |ext: &str| {
+        vec![
+            MroField::retained("output", Primary(FileType(ext.into()))),
+            MroField::new("chunks", Array(FileType(ext.into()))),
+            MroField::new("input", Primary(FileType(ext.into()))),
+            MroField::new("total", Primary(Int)),
+        ]
+    };
+    assert_eq!(Outs::<TxtFile>::mro_fields(), fields("txt"));
+    assert_eq!(Outs::<BamFile>::mro_fields(), fields("bam"));
+}
+
 #[test]
 fn test_generic_two() {
     #[allow(dead_code)]
@@ -88,3 +161,151 @@ fn test_retain() {
     let expected = vec![MroField::retained("values", Array(Float))];
     assert_eq!(expected, SimpleVec::mro_fields())
 }
+
+#[test]
+fn test_strict_retain() {
+    #[allow(dead_code)]
+    #[derive(MartianStruct)]
+    struct Outs {
+        #[mro_retain]
+        values: Vec<f64>,
+        #[mro_retain(strict)]
+        summary: f64,
+    }
+    let expected = vec![
+        MroField::retained("values", Array(Float)),
+        MroField::strict_retained("summary", Primary(Float)),
+    ];
+    assert_eq!(expected, Outs::mro_fields())
+}
+
+#[test]
+fn test_mro_type() {
+    #[allow(dead_code)]
+    #[derive(MartianStruct)]
+    struct Forced {
+        #[mro_type = "float"]
+        count: u64,
+        #[mro_retain]
+        #[mro_type = "float[]"]
+        counts: Vec<u64>,
+        total: u64,
+    }
+    let expected = vec![
+        MroField::new("count", Primary(Float)),
+        MroField::retained("counts", Array(Float)),
+        MroField::new("total", Primary(Int)),
+    ];
+    assert_eq!(expected, Forced::mro_fields())
+}
+
+#[test]
+fn test_rename_skip() {
+    #[derive(Debug, PartialEq, Serialize, Deserialize, MartianStruct)]
+    struct Args {
+        #[mro(rename = "split_reads")]
+        #[serde(rename = "split_reads")]
+        split: bool,
+        #[mro_retain]
+        #[mro(rename = "out_dir")]
+        #[serde(rename = "out_dir")]
+        dir: PathBuf,
+        #[mro(skip)]
+        #[serde(skip)]
+        cache: Vec<String>,
+    }
+    let expected = vec![
+        MroField::new("split_reads", Primary(Bool)),
+        MroField::retained("out_dir", Primary(Path)),
+    ];
+    assert_eq!(expected, Args::mro_fields());
+
+    let args = Args {
+        split: true,
+        dir: PathBuf::from("/files"),
+        cache: vec!["ignored".into()],
+    };
+    assert_eq!(
+        serde_json::to_value(&args).unwrap(),
+        serde_json::json!({"split_reads": true, "out_dir": "/files"})
+    );
+}
+
+#[test]
+fn test_raw_identifier() {
+    #[derive(Serialize, Deserialize, MartianStruct)]
+    struct Args {
+        r#type: String,
+        #[mro(rename = "in_")]
+        #[serde(rename = "in_")]
+        r#in: bool,
+    }
+    let expected = vec![
+        MroField::new("type", Primary(Str)),
+        MroField::new("in_", Primary(Bool)),
+    ];
+    assert_eq!(expected, Args::mro_fields());
+}
+
+#[test]
+fn test_flatten() {
+    #[derive(Debug, PartialEq, Serialize, Deserialize, MartianStruct)]
+    struct Reference {
+        reference_path: PathBuf,
+        #[mro_retain]
+        genome: String,
+    }
+    #[derive(Debug, PartialEq, Serialize, Deserialize, MartianStruct)]
+    struct Args {
+        sample_id: String,
+        #[mro(flatten)]
+        #[serde(flatten)]
+        reference: Reference,
+        num_reads: i64,
+    }
+    let expected = vec![
+        MroField::new("sample_id", Primary(Str)),
+        MroField::new("reference_path", Primary(Path)),
+        MroField::retained("genome", Primary(Str)),
+        MroField::new("num_reads", Primary(Int)),
+    ];
+    assert_eq!(expected, Args::mro_fields());
+
+    let args: Args = serde_json::from_value(serde_json::json!({
+        "sample_id": "s1",
+        "reference_path": "/ref",
+        "genome": "GRCh38",
+        "num_reads": 10,
+    }))
+    .unwrap();
+    assert_eq!(args.reference.genome, "GRCh38");
+}
+
+#[test]
+fn test_field_docs() {
+    #[allow(dead_code)]
+    #[derive(MartianStruct)]
+    struct Outs {
+        /// Sum of the squares.
+        ///
+        ///   Indented lines are kept as is.
+        ///
+        sum: f64,
+        #[mro_retain]
+        /// Written by the join
+        summary: TxtFile,
+        count: i64,
+    }
+    let fields = Outs::mro_fields();
+    assert_eq!(
+        fields[0].doc(),
+        Some("Sum of the squares.\n\n  Indented lines are kept as is.")
+    );
+    assert_eq!(fields[1].doc(), Some("Written by the join"));
+    assert_eq!(fields[2].doc(), None);
+    assert_eq!(
+        fields[1],
+        MroField::retained("summary", Primary(FileType("txt".into())))
+            .with_doc("Written by the join")
+    );
+}
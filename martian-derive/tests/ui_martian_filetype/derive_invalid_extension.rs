@@ -0,0 +1,7 @@
+use martian_derive::MartianFileType;
+
+#[derive(MartianFileType)]
+#[martian_filetype = "h5-ext"]
+struct H5File(std::path::PathBuf);
+
+fn main() {}
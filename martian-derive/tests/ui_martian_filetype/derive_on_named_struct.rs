@@ -0,0 +1,9 @@
+use martian_derive::MartianFileType;
+
+#[derive(MartianFileType)]
+#[martian_filetype = "h5"]
+struct H5File {
+	path: std::path::PathBuf,
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use martian_derive::MartianStringEnum;
+
+#[derive(MartianStringEnum)]
+enum Invalid {
+	StrVariant,
+	MapVariant(u32),
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use martian_derive::MartianStruct;
+
+#[derive(MartianStruct)]
+struct InvalidMroType {
+    #[mro_type = "double"] // Not a martian type
+    num_reads: u64,
+}
+
+fn main() {}
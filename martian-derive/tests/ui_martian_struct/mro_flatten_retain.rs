@@ -0,0 +1,18 @@
+use martian_derive::MartianStruct;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, MartianStruct)]
+struct Reference {
+    reference_path: String,
+}
+
+#[derive(Serialize, Deserialize, MartianStruct)]
+struct FlattenRetain {
+    num_reads: i64,
+    #[mro_retain]
+    #[mro(flatten)]
+    #[serde(flatten)]
+    reference: Reference,
+}
+
+fn main() {}
@@ -0,0 +1,15 @@
+use martian_derive::MartianStruct;
+
+#[derive(MartianStruct)]
+struct Reference {
+    reference_path: String,
+}
+
+#[derive(MartianStruct)]
+struct FlattenWithoutSerde {
+    num_reads: i64,
+    #[mro(flatten)] // The json would still have a "reference" object
+    reference: Reference,
+}
+
+fn main() {}
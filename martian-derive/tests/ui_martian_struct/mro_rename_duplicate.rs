@@ -0,0 +1,12 @@
+use martian_derive::MartianStruct;
+use serde::Serialize;
+
+#[derive(Serialize, MartianStruct)]
+struct DuplicateName {
+    num_reads: i64,
+    #[mro(rename = "num_reads")]
+    #[serde(rename = "num_reads")]
+    reads: i64,
+}
+
+fn main() {}
@@ -0,0 +1,10 @@
+use martian_derive::MartianStruct;
+
+#[derive(MartianStruct)]
+struct RenameWithoutSerde {
+    num_reads: i64,
+    #[mro(rename = "split_reads")] // The json key would still be "split"
+    split: bool,
+}
+
+fn main() {}
@@ -0,0 +1,10 @@
+use martian_derive::MartianStruct;
+
+#[derive(MartianStruct)]
+struct SkipWithoutSerde {
+    num_reads: i64,
+    #[mro(skip)] // Martian would never set this field in the args
+    cache: Vec<String>,
+}
+
+fn main() {}
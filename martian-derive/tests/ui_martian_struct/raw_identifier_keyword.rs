@@ -0,0 +1,9 @@
+use martian_derive::MartianStruct;
+
+#[derive(MartianStruct)]
+struct WithRawIdentifier {
+    r#type: String, // Fine, `type` is not a martian keyword
+    r#in: String,   // This should trigger a compile error
+}
+
+fn main() {}
@@ -0,0 +1,8 @@
+use martian::prelude::*;
+
+mod sum_squares;
+
+// The `martian`, `mro` and `list-stages` subcommands are provided by `martian::cli`
+fn main() -> Result<(), Error> {
+    martian::cli::run(martian_stages![sum_squares::SumSquares])
+}
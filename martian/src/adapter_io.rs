@@ -0,0 +1,293 @@
+//! Where the adapter writes the stage log and the stage errors. When the stage is
+//! run by mrp, the monitor process opens the `_log` and `_errors` files of the
+//! stage as the file descriptors 3 and 4. When the binary runs outside of mrp
+//! (tests, debugging), these descriptors are usually not open, or are something
+//! else entirely, so the `_log` and `_errors` files in the metadata directory of
+//! the stage are used instead.
+
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::mem::ManuallyDrop;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// File descriptor of the `_log` file opened by mrp
+const LOG_FD: RawFd = 3;
+/// File descriptor of the `_errors` file opened by mrp
+const ERRORS_FD: RawFd = 4;
+
+type Writer = Box<dyn Write + Send>;
+
+/// Handle to a log or error sink. Clones write to the same sink. Anything
+/// written after `close` is dropped.
+#[derive(Clone)]
+pub(crate) struct IoSink(Arc<Mutex<Option<Writer>>>);
+
+impl IoSink {
+    pub(crate) fn new(writer: impl Write + Send + 'static) -> Self {
+        IoSink(Arc::new(Mutex::new(Some(Box::new(writer)))))
+    }
+
+    /// The file at `path`, which is only created on the first write
+    pub(crate) fn lazy_file(path: PathBuf) -> Self {
+        IoSink::new(LazyFile::new(path))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Option<Writer>> {
+        // A panic while writing must not prevent reporting the panic
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Flush and drop the writer for every clone of the sink, which closes the
+    /// file descriptor opened by mrp
+    pub(crate) fn close(&self) {
+        if let Some(mut writer) = self.lock().take() {
+            let _ = writer.flush();
+        }
+    }
+}
+
+impl Write for IoSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self.lock() {
+            Some(ref mut writer) => writer.write(buf),
+            None => Ok(buf.len()),
+        }
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        // Don't interleave the bytes of concurrent writes
+        match *self.lock() {
+            Some(ref mut writer) => writer.write_all(buf),
+            None => Ok(()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self.lock() {
+            Some(ref mut writer) => writer.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl fmt::Debug for IoSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("IoSink")
+    }
+}
+
+/// A file which is only created on the first write. Martian treats a stage
+/// with an `_errors` file as failed, even if it is empty.
+struct LazyFile {
+    path: PathBuf,
+    file: Option<File>,
+}
+
+impl LazyFile {
+    fn new(path: PathBuf) -> Self {
+        LazyFile { path, file: None }
+    }
+}
+
+impl Write for LazyFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.file.is_none() {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+            self.file = Some(file);
+        }
+        self.file.as_mut().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.file {
+            Some(ref mut file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Take ownership of the file descriptor if it is open on the file at `path`,
+/// which is how mrp passes the `_log` and `_errors` files. Any other descriptor,
+/// e.g one inherited from the shell running a test, is left alone.
+fn adopt_fd(fd: RawFd, path: &Path) -> Option<File> {
+    if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+        return None;
+    }
+    // Not closed when dropped, unless it is adopted
+    let file = ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+    let (fd_meta, path_meta) = (file.metadata().ok()?, std::fs::metadata(path).ok()?);
+    if fd_meta.dev() == path_meta.dev() && fd_meta.ino() == path_meta.ino() {
+        Some(ManuallyDrop::into_inner(file))
+    } else {
+        None
+    }
+}
+
+/// The injected writer if there is one, otherwise the file descriptor opened
+/// by mrp on the file at `path`, otherwise the file at `path`. The file
+/// descriptor is left alone if there is an injected writer.
+fn resolve_sink(injected: Option<IoSink>, fd: Option<RawFd>, path: PathBuf) -> IoSink {
+    if let Some(sink) = injected {
+        return sink;
+    }
+    match fd.and_then(|fd| adopt_fd(fd, &path)) {
+        Some(file) => IoSink::new(file),
+        None => IoSink::lazy_file(path),
+    }
+}
+
+/// The log and error sinks of the stage, see `RunOptions::io`. By default, these
+/// are the file descriptors opened by mrp, falling back to the `_log` and
+/// `_errors` files in the metadata directory of the stage. Either of them can
+/// be replaced by any writer, e.g to capture the log in a test:
+/// ```rust
+/// use martian::AdapterIo;
+/// let io = AdapterIo::new().log_writer(std::io::stdout());
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct AdapterIo {
+    log: Option<IoSink>,
+    errors: Option<IoSink>,
+}
+
+impl AdapterIo {
+    pub fn new() -> Self {
+        AdapterIo::default()
+    }
+
+    /// Write the stage log to `writer`
+    pub fn log_writer(mut self, writer: impl Write + Send + 'static) -> Self {
+        self.log = Some(IoSink::new(writer));
+        self
+    }
+
+    /// Write the stage errors (failed assertions, panics, errors returned by
+    /// the stage) to `writer`
+    pub fn error_writer(mut self, writer: impl Write + Send + 'static) -> Self {
+        self.errors = Some(IoSink::new(writer));
+        self
+    }
+
+    /// The log sink and the error sink of the stage whose metadata directory
+    /// is `metadata_path`
+    pub(crate) fn resolve(self, metadata_path: &Path) -> (IoSink, IoSink) {
+        let log = resolve_sink(self.log, Some(LOG_FD), metadata_path.join("_log"));
+        let errors = resolve_sink(self.errors, Some(ERRORS_FD), metadata_path.join("_errors"));
+        (log, errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Metadata;
+
+    /// A writer whose output can be read back after it is moved into a sink
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedBuf {
+        fn contents(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    #[test]
+    fn test_resolve_sink_injected() {
+        let tmp_dir = tempdir::TempDir::new("test_resolve_sink_injected").unwrap();
+        let buf = SharedBuf::default();
+        let mut sink = resolve_sink(
+            Some(IoSink::new(buf.clone())),
+            None,
+            tmp_dir.path().join("_log"),
+        );
+        sink.write_all(b"hello").unwrap();
+        assert_eq!(buf.contents(), "hello");
+        assert!(!tmp_dir.path().join("_log").exists());
+    }
+
+    #[test]
+    fn test_resolve_sink_file() {
+        let tmp_dir = tempdir::TempDir::new("test_resolve_sink_file").unwrap();
+        let errors_path = tmp_dir.path().join("_errors");
+        let mut sink = resolve_sink(None, None, errors_path.clone());
+        sink.flush().unwrap();
+        // The file is only created when something is written
+        assert!(!errors_path.exists());
+        sink.write_all(b"ASSERT: first\n").unwrap();
+        sink.clone().write_all(b"ASSERT: second\n").unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&errors_path).unwrap(),
+            "ASSERT: first\nASSERT: second\n"
+        );
+    }
+
+    #[test]
+    fn test_metadata_log_to_injected_writer() {
+        let log = SharedBuf::default();
+        let errors = SharedBuf::default();
+        let io = AdapterIo::new()
+            .log_writer(log.clone())
+            .error_writer(errors.clone());
+        let (log_sink, error_sink) = io.resolve(Path::new("/STAGE/fork0/chnk0"));
+        let args = [
+            "sum_squares",
+            "main",
+            "/STAGE/fork0/chnk0",
+            "/files",
+            "/run",
+        ];
+        let args = args.iter().map(|s| s.to_string()).collect();
+        let mut md = Metadata::with_sinks(args, log_sink, error_sink);
+        md.log("info", "hello").unwrap();
+        assert!(log.contents().ends_with(" [info] hello"));
+        md.write_errors("ASSERT: failed").unwrap();
+        assert_eq!(errors.contents(), "ASSERT: failed");
+
+        // Nothing is written after the stage completed, from any clone
+        let clone = md.clone();
+        md.complete();
+        clone.write_errors("too late").unwrap();
+        assert_eq!(errors.contents(), "ASSERT: failed");
+    }
+
+    #[test]
+    fn test_adopt_fd() {
+        use std::os::unix::io::{AsRawFd, IntoRawFd};
+        let tmp_dir = tempdir::TempDir::new("test_adopt_fd").unwrap();
+        let errors_path = tmp_dir.path().join("_errors");
+        let other_path = tmp_dir.path().join("other");
+        std::fs::write(&other_path, "").unwrap();
+        let fd = File::create(&errors_path).unwrap().into_raw_fd();
+
+        // A descriptor on another file is left open
+        assert!(adopt_fd(fd, &other_path).is_none());
+        assert!(adopt_fd(fd, &tmp_dir.path().join("missing")).is_none());
+        assert_ne!(unsafe { libc::fcntl(fd, libc::F_GETFD) }, -1);
+
+        let mut file = adopt_fd(fd, &errors_path).unwrap();
+        assert_eq!(file.as_raw_fd(), fd);
+        file.write_all(b"ASSERT: failed").unwrap();
+        drop(file);
+        assert_eq!(
+            std::fs::read_to_string(&errors_path).unwrap(),
+            "ASSERT: failed"
+        );
+    }
+}
@@ -0,0 +1,162 @@
+//! Stages written as async functions, enabled by the `tokio` feature. The adapter
+//! runs the future of the split, each chunk main and the join to completion on
+//! a tokio runtime with as many worker threads as the threads allotted to the
+//! stage, so that stages doing network or heavy async I/O do not need to set up
+//! a runtime themselves.
+//!
+//! An `AsyncMartianMain` is a `MartianMain`. For an `AsyncMartianStage`, the
+//! `MartianStage` implementation is generated by `#[make_mro]`, which has to be
+//! applied to the `AsyncMartianStage` implementation.
+
+use crate::mro::{MartianStruct, MroMaker};
+use crate::stage::{MartianMain, MartianRover, StageDef};
+use failure::Error;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::future::Future;
+use std::time::Duration;
+
+/// Async version of `MartianMain`
+/// ```ignore
+/// #[make_mro(threads = 4)]
+/// impl AsyncMartianMain for Download {
+///     type StageInputs = DownloadInputs;
+///     type StageOutputs = DownloadOutputs;
+///     async fn main(&self, args: DownloadInputs, rover: MartianRover) -> Result<DownloadOutputs, Error> {
+///         ...
+///     }
+/// }
+/// ```
+pub trait AsyncMartianMain: MroMaker {
+    type StageInputs: Serialize + DeserializeOwned + MartianStruct;
+    type StageOutputs: Serialize + DeserializeOwned + MartianStruct;
+
+    fn main(
+        &self,
+        args: Self::StageInputs,
+        rover: MartianRover,
+    ) -> impl Future<Output = Result<Self::StageOutputs, Error>>;
+
+    /// See `MartianStage::max_runtime`
+    fn max_runtime(&self) -> Option<Duration> {
+        None
+    }
+}
+
+impl<T> MartianMain for T
+where
+    T: AsyncMartianMain,
+{
+    type StageInputs = <T as AsyncMartianMain>::StageInputs;
+    type StageOutputs = <T as AsyncMartianMain>::StageOutputs;
+
+    fn main(
+        &self,
+        args: Self::StageInputs,
+        rover: MartianRover,
+    ) -> Result<Self::StageOutputs, Error> {
+        let threads = rover.get_threads();
+        block_on(threads, AsyncMartianMain::main(self, args, rover))?
+    }
+
+    fn max_runtime(&self) -> Option<Duration> {
+        AsyncMartianMain::max_runtime(self)
+    }
+}
+
+/// Async version of `MartianStage`. `#[make_mro]` implements `MartianStage` for
+/// the stage by running these futures using `block_on`.
+pub trait AsyncMartianStage: MroMaker {
+    type StageInputs: Serialize + DeserializeOwned + MartianStruct;
+    type StageOutputs: Serialize + DeserializeOwned + MartianStruct;
+    type ChunkInputs: Serialize + DeserializeOwned + MartianStruct;
+    type ChunkOutputs: Serialize + DeserializeOwned + MartianStruct;
+
+    fn split(
+        &self,
+        args: Self::StageInputs,
+        rover: MartianRover,
+    ) -> impl Future<Output = Result<StageDef<Self::ChunkInputs>, Error>>;
+
+    fn main(
+        &self,
+        args: Self::StageInputs,
+        split_args: Self::ChunkInputs,
+        rover: MartianRover,
+    ) -> impl Future<Output = Result<Self::ChunkOutputs, Error>>;
+
+    fn join(
+        &self,
+        args: Self::StageInputs,
+        chunk_defs: Vec<Self::ChunkInputs>,
+        chunk_outs: Vec<Self::ChunkOutputs>,
+        rover: MartianRover,
+    ) -> impl Future<Output = Result<Self::StageOutputs, Error>>;
+
+    /// See `MartianStage::max_runtime`
+    fn max_runtime(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// Run `future` to completion on a new multi threaded tokio runtime with `threads`
+/// worker threads, at least one
+pub fn block_on<F: Future>(threads: usize, future: F) -> Result<F::Output, Error> {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(threads.max(1))
+        .enable_all()
+        .build()?;
+    Ok(runtime.block_on(future))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mro::{InAndOut, MroUsing};
+    use crate::stage::{MartianStage, Resource};
+    use crate::types::MartianVoid;
+
+    struct CountWorkers;
+
+    impl MroMaker for CountWorkers {
+        fn stage_name() -> String {
+            "COUNT_WORKERS".into()
+        }
+        fn stage_in_and_out() -> InAndOut {
+            InAndOut::default()
+        }
+        fn chunk_in_and_out() -> Option<InAndOut> {
+            None
+        }
+        fn using_attributes() -> MroUsing {
+            MroUsing::default()
+        }
+    }
+
+    impl AsyncMartianMain for CountWorkers {
+        type StageInputs = MartianVoid;
+        type StageOutputs = MartianVoid;
+
+        async fn main(&self, _: MartianVoid, rover: MartianRover) -> Result<MartianVoid, Error> {
+            let workers = tokio::runtime::Handle::current().metrics().num_workers();
+            assert_eq!(workers, rover.get_threads());
+            let task = tokio::spawn(async { 42 });
+            assert_eq!(task.await?, 42);
+            Ok(MartianVoid::new())
+        }
+    }
+
+    #[test]
+    fn test_async_martian_main() {
+        let tmp_dir = tempdir::TempDir::new("test_async_martian_main").unwrap();
+        let resource = Resource::new().mem_gb(1).threads(3).vmem_gb(2);
+        let rover = MartianRover::new(tmp_dir.path(), resource);
+        MartianMain::main(&CountWorkers, MartianVoid::new(), rover).unwrap();
+        CountWorkers.test_run_tmpdir(MartianVoid::new()).unwrap();
+    }
+
+    #[test]
+    fn test_block_on() {
+        assert_eq!(block_on(0, async { 1 + 1 }).unwrap(), 2);
+    }
+}
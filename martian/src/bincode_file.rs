@@ -0,0 +1,312 @@
+//! Binary files of any serializable type using bincode, enabled by the `bincode`
+//! feature. They are much smaller and faster to write and read than json, which
+//! makes them a better fit for the intermediate data passed from the chunks to
+//! the join.
+
+use crate::mro::{AsMartianPrimaryType, MartianPrimaryType};
+use crate::types::{
+    generic_filetype_impls, with_extension, FileTypeIO, FiletypeValidator, MartianFileType,
+};
+use failure::{format_err, Error};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+
+/// A `.bincode` file holding a `T`, which is read and written using `FileTypeIO`.
+/// The items of a `BincodeFile<Vec<T>>` can also be streamed one at a time
+/// using `lazy_writer` and `lazy_reader`.
+/// ```ignore
+/// let file: BincodeFile<Vec<Record>> = rover.make_path("records");
+/// let mut writer = file.lazy_writer()?;
+/// for record in records {
+///     writer.write_item(&record)?;
+/// }
+/// writer.finish()?;
+/// let records: Vec<Record> = file.read()?;
+/// ```
+pub struct BincodeFile<T> {
+    path: PathBuf,
+    content: PhantomData<fn() -> T>,
+}
+
+impl<T> MartianFileType for BincodeFile<T> {
+    fn extension() -> &'static str {
+        "bincode"
+    }
+    fn new(file_path: impl AsRef<Path>, file_name: impl AsRef<Path>) -> Self {
+        BincodeFile::from(with_extension(
+            file_path.as_ref().join(file_name),
+            Self::extension(),
+        ))
+    }
+}
+
+generic_filetype_impls!(BincodeFile, content);
+
+impl<T> AsMartianPrimaryType for BincodeFile<T> {
+    fn as_martian_primary_type() -> MartianPrimaryType {
+        MartianPrimaryType::FileType(Self::extension().to_string())
+    }
+    // The same Rust type for every `T`, which are all the same filetype
+    fn filetype_rust_types() -> Vec<(String, String)> {
+        vec![(
+            Self::extension().to_string(),
+            "martian::BincodeFile".to_string(),
+        )]
+    }
+    fn filetype_validators() -> Vec<FiletypeValidator> {
+        vec![FiletypeValidator::of::<Self>()]
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> FileTypeIO<T> for BincodeFile<T> {
+    fn read_from<R: Read>(reader: R) -> Result<T, Error> {
+        Ok(bincode::deserialize_from(reader)?)
+    }
+    fn write_into<W: Write>(writer: W, item: &T) -> Result<(), Error> {
+        Ok(bincode::serialize_into(writer, item)?)
+    }
+}
+
+impl<T: Serialize> BincodeFile<Vec<T>> {
+    pub fn lazy_writer(&self) -> Result<LazyBincodeWriter<T>, Error> {
+        LazyBincodeWriter::create(&self.path)
+    }
+}
+
+impl<T: DeserializeOwned> BincodeFile<Vec<T>> {
+    pub fn lazy_reader(&self) -> Result<LazyBincodeReader<T>, Error> {
+        LazyBincodeReader::open(&self.path)
+    }
+}
+
+/// Writes the items of a `Vec` to a bincode file one at a time. The file is only
+/// complete after `finish`, which reports any error, or after the writer is
+/// dropped, since bincode stores the number of items before them.
+#[derive(Debug)]
+pub struct LazyBincodeWriter<T> {
+    path: PathBuf,
+    writer: Option<BufWriter<File>>,
+    num_items: u64,
+    item_type: PhantomData<fn(&T)>,
+}
+
+impl<T: Serialize> LazyBincodeWriter<T> {
+    pub fn create(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        let create = || -> Result<_, Error> {
+            let mut writer = BufWriter::new(File::create(&path)?);
+            // Filled in by `finish`
+            bincode::serialize_into(&mut writer, &0u64)?;
+            Ok(writer)
+        };
+        let writer =
+            create().map_err(|e| format_err!("Failed to write {}: {}", path.display(), e))?;
+        Ok(LazyBincodeWriter {
+            path,
+            writer: Some(writer),
+            num_items: 0,
+            item_type: PhantomData,
+        })
+    }
+
+    pub fn write_item(&mut self, item: &T) -> Result<(), Error> {
+        bincode::serialize_into(self.writer.as_mut().unwrap(), item)
+            .map_err(|e| format_err!("Failed to write {}: {}", self.path.display(), e))?;
+        self.num_items += 1;
+        Ok(())
+    }
+
+    /// Number of items written so far
+    pub fn num_items(&self) -> u64 {
+        self.num_items
+    }
+
+    /// Write the number of items and flush the file
+    pub fn finish(mut self) -> Result<(), Error> {
+        self.close()
+            .map_err(|e| format_err!("Failed to write {}: {}", self.path.display(), e))
+    }
+}
+
+impl<T> LazyBincodeWriter<T> {
+    fn close(&mut self) -> Result<(), Error> {
+        if let Some(mut writer) = self.writer.take() {
+            writer.seek(SeekFrom::Start(0))?;
+            bincode::serialize_into(&mut writer, &self.num_items)?;
+            writer.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl<T> Drop for LazyBincodeWriter<T> {
+    fn drop(&mut self) {
+        let _ = self.close();
+    }
+}
+
+/// Iterator over the items of a `Vec` in a bincode file, which reads one item at
+/// a time
+#[derive(Debug)]
+pub struct LazyBincodeReader<T> {
+    path: PathBuf,
+    reader: BufReader<File>,
+    num_items: u64,
+    items_left: u64,
+    item_type: PhantomData<fn() -> T>,
+}
+
+impl<T: DeserializeOwned> LazyBincodeReader<T> {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        let open = || -> Result<_, Error> {
+            let mut reader = BufReader::new(File::open(&path)?);
+            let num_items: u64 = bincode::deserialize_from(&mut reader)?;
+            Ok((reader, num_items))
+        };
+        let (reader, num_items) =
+            open().map_err(|e| format_err!("Failed to read {}: {}", path.display(), e))?;
+        Ok(LazyBincodeReader {
+            path,
+            reader,
+            num_items,
+            items_left: num_items,
+            item_type: PhantomData,
+        })
+    }
+
+    /// Total number of items in the file
+    pub fn num_items(&self) -> u64 {
+        self.num_items
+    }
+}
+
+impl<T: DeserializeOwned> Iterator for LazyBincodeReader<T> {
+    type Item = Result<T, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.items_left == 0 {
+            return None;
+        }
+        let index = self.num_items - self.items_left;
+        match bincode::deserialize_from(&mut self.reader) {
+            Ok(item) => {
+                self.items_left -= 1;
+                Some(Ok(item))
+            }
+            Err(e) => {
+                // Stop after the first error
+                self.items_left = 0;
+                Some(Err(format_err!(
+                    "Failed to read {} at item {}: {}",
+                    self.path.display(),
+                    index,
+                    e
+                )))
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.items_left as usize))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Record {
+        name: String,
+        counts: Vec<i64>,
+    }
+
+    fn records(n: i64) -> Vec<Record> {
+        (0..n)
+            .map(|i| Record {
+                name: format!("r{}", i),
+                counts: vec![i, -i],
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_bincode_file() {
+        let dir = tempdir::TempDir::new("test_bincode_file").unwrap();
+        let file: BincodeFile<Vec<Record>> = BincodeFile::new(dir.path(), "records");
+        assert_eq!(file.as_ref(), dir.path().join("records.bincode"));
+        assert_eq!(
+            BincodeFile::<()>::new(dir.path(), "a.bincode").as_ref(),
+            dir.path().join("a.bincode")
+        );
+
+        file.write(&records(5)).unwrap();
+        assert_eq!(file.read().unwrap(), records(5));
+        let lazy: Vec<_> = file
+            .lazy_reader()
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(lazy, records(5));
+
+        let mut writer = file.lazy_writer().unwrap();
+        for record in records(1000) {
+            writer.write_item(&record).unwrap();
+        }
+        assert_eq!(writer.num_items(), 1000);
+        writer.finish().unwrap();
+        assert_eq!(file.read().unwrap(), records(1000));
+        assert_eq!(file.lazy_reader().unwrap().num_items(), 1000);
+
+        // Complete when dropped
+        let mut writer = file.lazy_writer().unwrap();
+        writer.write_item(&records(1)[0]).unwrap();
+        drop(writer);
+        assert_eq!(file.read().unwrap(), records(1));
+    }
+
+    #[test]
+    fn test_bincode_file_truncated() {
+        let dir = tempdir::TempDir::new("test_bincode_file_truncated").unwrap();
+        let file: BincodeFile<Vec<Record>> = BincodeFile::new(dir.path(), "records");
+        file.write(&records(3)).unwrap();
+        let len = std::fs::metadata(&file).unwrap().len();
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(&file)
+            .unwrap()
+            .set_len(len - 4)
+            .unwrap();
+
+        let mut reader = file.lazy_reader().unwrap();
+        assert!(reader.next().unwrap().is_ok());
+        assert!(reader.next().unwrap().is_ok());
+        let error = reader.next().unwrap().unwrap_err().to_string();
+        assert!(error.starts_with(&format!(
+            "Failed to read {} at item 2: ",
+            file.as_ref().display()
+        )));
+        assert!(reader.next().is_none());
+        assert!(file.read().is_err());
+    }
+
+    #[test]
+    fn test_bincode_file_json() {
+        let file: BincodeFile<Vec<u8>> = serde_json::from_str("\"/files/values.bincode\"").unwrap();
+        assert_eq!(file.as_ref(), Path::new("/files/values.bincode"));
+        assert_eq!(
+            serde_json::to_string(&file).unwrap(),
+            "\"/files/values.bincode\""
+        );
+        assert_eq!(
+            BincodeFile::<Vec<u8>>::as_martian_primary_type(),
+            MartianPrimaryType::FileType("bincode".into())
+        );
+    }
+}
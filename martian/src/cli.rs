@@ -0,0 +1,191 @@
+//! A ready made command line interface for adapters, enabled by the `cli` feature.
+//! With it, the `main` of an adapter only needs to list the stages:
+//! ```ignore
+//! fn main() -> Result<(), Error> {
+//!     martian::cli::run(martian_stages![sum_squares::SumSquares, report::Report])
+//! }
+//! ```
+//! The executable then has the subcommands
+//! - `<adapter> martian <args>...`, which mrp uses to run a stage (see `martian_main`)
+//! - `<adapter> mro [--file <FILE>] [--rewrite]` to write the mro of all the stages,
+//!   to stdout by default (see `martian_make_mro`)
+//! - `<adapter> list-stages` to list the stage names along with their keys
+//!
+//! Adapters which need other subcommands or options can keep parsing the arguments
+//! themselves, and call `martian_main` and `martian_make_mro` directly.
+
+use crate::{martian_main, martian_make_mro, RawMartianStage, StageMro};
+use clap::{Arg, ArgAction, Command};
+use failure::Error;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fmt::Write;
+
+/// The stage registry and the mro registry, as returned by `martian_stages!`
+pub type Registry = (HashMap<String, Box<dyn RawMartianStage>>, Vec<StageMro>);
+
+/// Run the subcommand in the arguments of the executable. Prints the usage and
+/// exits if the arguments are invalid.
+pub fn run(registry: Registry) -> Result<(), Error> {
+    run_with_args(std::env::args_os(), registry)
+}
+
+/// Same as `run`, with the given arguments, starting with the executable name
+pub fn run_with_args<I, T>(args: I, registry: Registry) -> Result<(), Error>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<OsString> + Clone,
+{
+    let (stage_registry, mro_registry) = registry;
+    let matches = command().get_matches_from(args);
+    match matches.subcommand() {
+        Some(("martian", sub)) => {
+            let args = sub.get_many::<String>("args").unwrap().cloned().collect();
+            martian_main(args, stage_registry)
+        }
+        Some(("mro", sub)) => martian_make_mro(
+            sub.get_one::<String>("file"),
+            sub.get_flag("rewrite"),
+            mro_registry,
+        ),
+        Some(("list-stages", _)) => {
+            print!("{}", stage_list(&mro_registry));
+            Ok(())
+        }
+        _ => unreachable!("clap requires a subcommand"),
+    }
+}
+
+fn command() -> Command {
+    Command::new("adapter")
+        .about("Martian adapter")
+        .subcommand_required(true)
+        .arg_required_else_help(true)
+        .subcommand(
+            Command::new("martian")
+                .about("Run a stage. This is how mrp calls the adapter")
+                .arg(
+                    Arg::new("args")
+                        .num_args(1..)
+                        .required(true)
+                        .trailing_var_arg(true)
+                        .allow_hyphen_values(true),
+                ),
+        )
+        .subcommand(
+            Command::new("mro")
+                .about("Write the mro of all the stages")
+                .arg(
+                    Arg::new("file")
+                        .long("file")
+                        .value_name("FILE")
+                        .help("Output file for the mro, instead of stdout"),
+                )
+                .arg(
+                    Arg::new("rewrite")
+                        .long("rewrite")
+                        .action(ArgAction::SetTrue)
+                        .help("Overwrite the file if it exists"),
+                ),
+        )
+        .subcommand(Command::new("list-stages").about("List the stages with their keys"))
+}
+
+// One line per stage with the stage name and key, aligned in two columns
+fn stage_list(mro_registry: &[StageMro]) -> String {
+    let width = mro_registry
+        .iter()
+        .map(|stage_mro| stage_mro.stage_name().len())
+        .max()
+        .unwrap_or(0);
+    let mut result = String::new();
+    for stage_mro in mro_registry {
+        writeln!(
+            &mut result,
+            "{:width$}  {}",
+            stage_mro.stage_name(),
+            stage_mro.stage_key(),
+            width = width
+        )
+        .unwrap();
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_mro;
+
+    fn mro_registry() -> Vec<StageMro> {
+        parse_mro(
+            r#"
+            stage SUM_SQUARES(
+                in  float[] values,
+                out float   sum,
+                src comp    "adapter martian sum_squares",
+            )
+
+            stage REPORT(
+                in  float sum,
+                src comp  "adapter martian write_report",
+            )
+            "#,
+        )
+        .unwrap()
+        .stages
+    }
+
+    #[test]
+    fn test_command() {
+        command().debug_assert();
+        let parse = |args: &[&str]| command().try_get_matches_from(args);
+
+        let matches =
+            parse(&["adapter", "martian", "sum_squares", "main", "/md", "/files"]).unwrap();
+        let (name, sub) = matches.subcommand().unwrap();
+        assert_eq!(name, "martian");
+        let args: Vec<_> = sub.get_many::<String>("args").unwrap().collect();
+        assert_eq!(args, vec!["sum_squares", "main", "/md", "/files"]);
+
+        let matches = parse(&["adapter", "mro", "--file", "stages.mro", "--rewrite"]).unwrap();
+        let (name, sub) = matches.subcommand().unwrap();
+        assert_eq!(name, "mro");
+        assert_eq!(sub.get_one::<String>("file").unwrap(), "stages.mro");
+        assert!(sub.get_flag("rewrite"));
+
+        assert!(parse(&["adapter"]).is_err());
+        assert!(parse(&["adapter", "martian"]).is_err());
+        assert!(parse(&["adapter", "list-stages", "extra"]).is_err());
+        assert!(parse(&["adapter", "run"]).is_err());
+    }
+
+    #[test]
+    fn test_stage_list() {
+        assert_eq!(
+            stage_list(&mro_registry()),
+            "SUM_SQUARES  sum_squares\nREPORT       write_report\n"
+        );
+        assert_eq!(stage_list(&[]), "");
+    }
+
+    #[test]
+    fn test_run_mro() {
+        let tmp_dir = tempdir::TempDir::new("test_run_mro").unwrap();
+        let mro_path = tmp_dir.path().join("stages.mro");
+        let mro_arg = mro_path.to_str().unwrap();
+        let registry = || (HashMap::new(), mro_registry());
+        run_with_args(["adapter", "mro", "--file", mro_arg], registry()).unwrap();
+        let mro = std::fs::read_to_string(&mro_path).unwrap();
+        assert!(mro.contains("stage SUM_SQUARES("));
+        assert!(mro.contains("stage REPORT("));
+
+        // The file is only overwritten with --rewrite
+        assert!(run_with_args(["adapter", "mro", "--file", mro_arg], registry()).is_err());
+        run_with_args(
+            ["adapter", "mro", "--file", mro_arg, "--rewrite"],
+            registry(),
+        )
+        .unwrap();
+    }
+}
@@ -0,0 +1,233 @@
+//! Compressed versions of any filetype, e.g `Gzip<JsonFile>` is a `.json.gz` file
+//! which is written and read as a `JsonFile`, compressing and decompressing it
+//! on the fly. `Gzip` is always available, `Lz4` and `Zstd` are enabled by the
+//! `lz4` and `zstd` features.
+
+use crate::mro::{AsMartianPrimaryType, MartianPrimaryType};
+use crate::types::{
+    generic_filetype_impls, validate_magic_bytes, FileTypeIO, FiletypeValidator, MartianFileType,
+};
+use failure::Error;
+use std::any::TypeId;
+use std::io::{Read, Write};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+// The extension `<inner extension>.<compression>` of each compressed filetype,
+// leaked once per filetype since `MartianFileType::extension` is `'static`
+static EXTENSIONS: Mutex<Vec<(TypeId, &'static str)>> = Mutex::new(Vec::new());
+
+fn compressed_extension<C: 'static>(inner: &str, compression: &str) -> &'static str {
+    let mut extensions = EXTENSIONS.lock().unwrap_or_else(|e| e.into_inner());
+    let type_id = TypeId::of::<C>();
+    match extensions.iter().find(|(id, _)| *id == type_id) {
+        Some((_, extension)) => extension,
+        None => {
+            let extension: &'static str =
+                Box::leak(format!("{}.{}", inner, compression).into_boxed_str());
+            extensions.push((type_id, extension));
+            extension
+        }
+    }
+}
+
+macro_rules! compressed_filetype {
+    (
+        $(#[$doc:meta])* $name:ident, $compression:expr, magic($magic:expr),
+        decoder($reader:ident) => $decoder:expr,
+        encoder($writer:ident) => $encoder:expr $(,)*
+    ) => {
+        $(#[$doc])*
+        pub struct $name<F> {
+            path: PathBuf,
+            inner: PhantomData<fn() -> F>,
+        }
+
+        impl<F> MartianFileType for $name<F>
+        where
+            F: MartianFileType + AsRef<Path> + 'static,
+        {
+            fn extension() -> &'static str {
+                compressed_extension::<Self>(F::extension(), $compression)
+            }
+            fn new(file_path: impl AsRef<Path>, file_name: impl AsRef<Path>) -> Self {
+                let path = file_path.as_ref().join(file_name.as_ref());
+                if path.to_string_lossy().ends_with(&format!(".{}", Self::extension())) {
+                    return $name::from(path);
+                }
+                let mut path = F::new(file_path, file_name).as_ref().as_os_str().to_owned();
+                path.push(concat!(".", $compression));
+                $name::from(PathBuf::from(path))
+            }
+            // Only the start of the file, a truncated file is caught when it is read
+            fn validate(path: &Path) -> Result<(), Error> {
+                validate_magic_bytes(path, &$magic)
+            }
+        }
+
+        generic_filetype_impls!($name, inner);
+
+        impl<F> AsMartianPrimaryType for $name<F>
+        where
+            F: MartianFileType + AsRef<Path> + 'static,
+        {
+            fn as_martian_primary_type() -> MartianPrimaryType {
+                MartianPrimaryType::FileType(Self::extension().to_string())
+            }
+            fn filetype_rust_types() -> Vec<(String, String)> {
+                vec![(
+                    Self::extension().to_string(),
+                    std::any::type_name::<Self>().to_string(),
+                )]
+            }
+            fn filetype_validators() -> Vec<FiletypeValidator> {
+                vec![FiletypeValidator::of::<Self>()]
+            }
+        }
+
+        impl<F, T> FileTypeIO<T> for $name<F>
+        where
+            F: FileTypeIO<T> + 'static,
+        {
+            fn read_from<R: Read>($reader: R) -> Result<T, Error> {
+                F::read_from($decoder)
+            }
+            fn write_into<W: Write>($writer: W, item: &T) -> Result<(), Error> {
+                let mut encoder = $encoder;
+                F::write_into(&mut encoder, item)?;
+                encoder.finish()?;
+                Ok(())
+            }
+        }
+    };
+}
+
+compressed_filetype! {
+    /// A gzip compressed `F`, with the extension `<extension of F>.gz`:
+    /// ```ignore
+    /// martian_filetype! { JsonFile, "json" }
+    /// let summary: Gzip<JsonFile> = rover.make_path("summary"); // summary.json.gz
+    /// summary.write(&metrics)?;
+    /// ```
+    Gzip, "gz", magic([0x1f, 0x8b]),
+    decoder(reader) => flate2::read::MultiGzDecoder::new(reader),
+    encoder(writer) => flate2::write::GzEncoder::new(writer, flate2::Compression::default()),
+}
+
+#[cfg(feature = "lz4")]
+compressed_filetype! {
+    /// A lz4 compressed `F` in the lz4 frame format, with the extension
+    /// `<extension of F>.lz4`. See `Gzip`.
+    Lz4, "lz4", magic([0x04, 0x22, 0x4d, 0x18]),
+    decoder(reader) => lz4_flex::frame::FrameDecoder::new(reader),
+    encoder(writer) => lz4_flex::frame::FrameEncoder::new(writer),
+}
+
+#[cfg(feature = "zstd")]
+compressed_filetype! {
+    /// A zstd compressed `F`, with the extension `<extension of F>.zst`. See `Gzip`.
+    Zstd, "zst", magic([0x28, 0xb5, 0x2f, 0xfd]),
+    decoder(reader) => zstd::stream::read::Decoder::new(reader)?,
+    encoder(writer) => zstd::stream::write::Encoder::new(writer, 0)?,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{read_json, with_extension, write_json};
+    use serde::de::DeserializeOwned;
+    use serde::{Deserialize, Serialize};
+
+    struct JsonFile(PathBuf);
+
+    impl AsRef<Path> for JsonFile {
+        fn as_ref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl MartianFileType for JsonFile {
+        fn extension() -> &'static str {
+            "json"
+        }
+        fn new(file_path: impl AsRef<Path>, file_name: impl AsRef<Path>) -> Self {
+            JsonFile(with_extension(
+                file_path.as_ref().join(file_name),
+                Self::extension(),
+            ))
+        }
+    }
+
+    impl<T: Serialize + DeserializeOwned> FileTypeIO<T> for JsonFile {
+        fn read_from<R: Read>(reader: R) -> Result<T, Error> {
+            read_json(reader)
+        }
+        fn write_into<W: Write>(writer: W, item: &T) -> Result<(), Error> {
+            write_json(writer, item)
+        }
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Metrics {
+        reads: Vec<u64>,
+    }
+
+    fn roundtrip<F: FileTypeIO<Metrics> + std::fmt::Debug>(dir: &Path) {
+        let metrics = Metrics {
+            reads: (0..1000).collect(),
+        };
+        let file = F::new(dir, "metrics");
+        file.write(&metrics).unwrap();
+        assert_eq!(file.read().unwrap(), metrics);
+        let compressed = std::fs::metadata(&file).unwrap().len();
+        let json = serde_json::to_string_pretty(&metrics).unwrap().len();
+        assert!((compressed as usize) < json / 2, "{:?}", file);
+    }
+
+    #[test]
+    fn test_gzip() {
+        let dir = tempdir::TempDir::new("test_gzip").unwrap();
+        assert_eq!(Gzip::<JsonFile>::extension(), "json.gz");
+        assert_eq!(
+            Gzip::<JsonFile>::new(dir.path(), "metrics").as_ref(),
+            dir.path().join("metrics.json.gz")
+        );
+        assert_eq!(
+            Gzip::<JsonFile>::new(dir.path(), "metrics.json.gz").as_ref(),
+            dir.path().join("metrics.json.gz")
+        );
+        assert_eq!(
+            Gzip::<Gzip<JsonFile>>::new(dir.path(), "metrics").as_ref(),
+            dir.path().join("metrics.json.gz.gz")
+        );
+        assert_eq!(
+            Gzip::<JsonFile>::as_martian_primary_type(),
+            MartianPrimaryType::FileType("json.gz".into())
+        );
+        roundtrip::<Gzip<JsonFile>>(dir.path());
+
+        let file = Gzip::<JsonFile>::new(dir.path(), "plain");
+        std::fs::write(&file, "[1, 2]").unwrap();
+        assert!(Gzip::<JsonFile>::validate(file.as_ref()).is_err());
+        assert!(Gzip::<JsonFile>::validate(&dir.path().join("metrics.json.gz")).is_ok());
+        let error = FileTypeIO::<Metrics>::read(&file).unwrap_err().to_string();
+        assert!(error.starts_with(&format!("Failed to read {}: ", file.as_ref().display())));
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn test_lz4() {
+        let dir = tempdir::TempDir::new("test_lz4").unwrap();
+        assert_eq!(Lz4::<JsonFile>::extension(), "json.lz4");
+        roundtrip::<Lz4<JsonFile>>(dir.path());
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_zstd() {
+        let dir = tempdir::TempDir::new("test_zstd").unwrap();
+        assert_eq!(Zstd::<JsonFile>::extension(), "json.zst");
+        roundtrip::<Zstd<JsonFile>>(dir.path());
+    }
+}
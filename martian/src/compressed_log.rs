@@ -0,0 +1,123 @@
+//! A gzip compressed copy of the stage log, written to a file under the files
+//! directory in addition to the martian `_log` file. Useful for very chatty
+//! stages in deployments which route the `_log` elsewhere.
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How often the compressed stream is flushed, so that the log can be inspected
+/// while the stage is running. Flushing on every line would hurt the compression.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+struct GzLog {
+    encoder: GzEncoder<File>,
+    last_flush: Instant,
+}
+
+/// Handle to the compressed log. Clones write to the same file. Anything written
+/// after `finish` is dropped.
+#[derive(Clone)]
+pub(crate) struct CompressedLog(Arc<Mutex<Option<GzLog>>>);
+
+impl CompressedLog {
+    pub(crate) fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let gz_log = GzLog {
+            encoder: GzEncoder::new(File::create(path)?, Compression::default()),
+            last_flush: Instant::now(),
+        };
+        Ok(CompressedLog(Arc::new(Mutex::new(Some(gz_log)))))
+    }
+
+    /// Write the gzip trailer and close the file. Only the first call does anything,
+    /// since the log is finished both when the stage is done and when it is
+    /// terminated, and later writes are dropped.
+    pub(crate) fn finish(&self) -> io::Result<()> {
+        // A panic while logging must not prevent finishing the log
+        let gz_log = self.0.lock().unwrap_or_else(|e| e.into_inner()).take();
+        match gz_log {
+            Some(gz_log) => gz_log.encoder.finish()?.sync_all(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Write for CompressedLog {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Keep logging after a panic while logging
+        if let Some(ref mut gz_log) = *self.0.lock().unwrap_or_else(|e| e.into_inner()) {
+            gz_log.encoder.write_all(buf)?;
+            if gz_log.last_flush.elapsed() >= FLUSH_INTERVAL {
+                gz_log.encoder.flush()?;
+                gz_log.last_flush = Instant::now();
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // Flushing is rate limited in `write`
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    #[test]
+    fn test_compressed_log() {
+        let tmp_dir = tempdir::TempDir::new("test_compressed_log").unwrap();
+        let path = tmp_dir.path().join("_log.gz");
+
+        let log = CompressedLog::create(&path).unwrap();
+        let mut writer = log.clone();
+        writeln!(writer, "[2019-01-01 00:00:00][INFO] first line").unwrap();
+        writeln!(writer, "[2019-01-01 00:00:01][WARN] second line").unwrap();
+        log.finish().unwrap();
+        writeln!(writer, "dropped after finish").unwrap();
+        log.finish().unwrap();
+
+        let mut contents = String::new();
+        GzDecoder::new(File::open(&path).unwrap())
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(
+            contents.lines().collect::<Vec<_>>(),
+            vec![
+                "[2019-01-01 00:00:00][INFO] first line",
+                "[2019-01-01 00:00:01][WARN] second line"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_finish_after_panic() {
+        let tmp_dir = tempdir::TempDir::new("test_finish_after_panic").unwrap();
+        let path = tmp_dir.path().join("_log.gz");
+
+        let log = CompressedLog::create(&path).unwrap();
+        writeln!(log.clone(), "before the panic").unwrap();
+        let poisoned = log.clone();
+        let _ = std::thread::spawn(move || {
+            let _guard = poisoned.0.lock().unwrap();
+            panic!("panic while logging");
+        })
+        .join();
+        assert!(log.0.is_poisoned());
+        writeln!(log.clone(), "after the panic").unwrap();
+        log.finish().unwrap();
+
+        let mut contents = String::new();
+        GzDecoder::new(File::open(&path).unwrap())
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "before the panic\nafter the panic\n");
+    }
+}
@@ -0,0 +1,163 @@
+//! Tabular files of records with a header line, read and written using serde,
+//! enabled by the `csv` feature. Each record is a row and each field of the
+//! record is a column named after the field.
+
+use crate::mro::{AsMartianPrimaryType, MartianPrimaryType};
+use crate::types::{
+    generic_filetype_impls, with_extension, FileTypeIO, FiletypeValidator, MartianFileType,
+};
+use failure::Error;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::io::{Read, Write};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+
+macro_rules! delimited_filetype {
+    ($(#[$doc:meta])* $name:ident, $extension:expr, $delimiter:expr) => {
+        $(#[$doc])*
+        pub struct $name<T> {
+            path: PathBuf,
+            record: PhantomData<fn() -> T>,
+        }
+
+        impl<T> MartianFileType for $name<T> {
+            fn extension() -> &'static str {
+                $extension
+            }
+            fn new(file_path: impl AsRef<Path>, file_name: impl AsRef<Path>) -> Self {
+                $name::from(with_extension(
+                    file_path.as_ref().join(file_name),
+                    Self::extension(),
+                ))
+            }
+        }
+
+        generic_filetype_impls!($name, record);
+
+        impl<T> AsMartianPrimaryType for $name<T> {
+            fn as_martian_primary_type() -> MartianPrimaryType {
+                MartianPrimaryType::FileType(Self::extension().to_string())
+            }
+            // The same Rust type for every `T`, which are all the same filetype
+            fn filetype_rust_types() -> Vec<(String, String)> {
+                vec![(
+                    Self::extension().to_string(),
+                    concat!("martian::", stringify!($name)).to_string(),
+                )]
+            }
+            fn filetype_validators() -> Vec<FiletypeValidator> {
+                vec![FiletypeValidator::of::<Self>()]
+            }
+        }
+
+        impl<T: Serialize + DeserializeOwned> FileTypeIO<Vec<T>> for $name<T> {
+            fn read_from<R: Read>(reader: R) -> Result<Vec<T>, Error> {
+                read_records(reader, $delimiter)
+            }
+            fn write_into<W: Write>(writer: W, records: &Vec<T>) -> Result<(), Error> {
+                write_records(writer, records, $delimiter)
+            }
+        }
+    };
+}
+
+delimited_filetype! {
+    /// A `.csv` file of records of type `T`, with a header line:
+    /// ```ignore
+    /// let metrics: CsvFile<Metric> = rover.make_path("metrics");
+    /// metrics.write(&vec![Metric { name: "reads".into(), value: 10.0 }])?;
+    /// let rows: Vec<Metric> = metrics.read()?;
+    /// ```
+    CsvFile, "csv", b','
+}
+
+delimited_filetype! {
+    /// A `.tsv` file of records of type `T`, with a header line. See `CsvFile`.
+    TsvFile, "tsv", b'\t'
+}
+
+fn read_records<T: DeserializeOwned, R: Read>(reader: R, delimiter: u8) -> Result<Vec<T>, Error> {
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .from_reader(reader);
+    Ok(reader.deserialize().collect::<Result<_, _>>()?)
+}
+
+fn write_records<T: Serialize, W: Write>(
+    writer: W,
+    records: &[T],
+    delimiter: u8,
+) -> Result<(), Error> {
+    let mut writer = csv::WriterBuilder::new()
+        .delimiter(delimiter)
+        .from_writer(writer);
+    for record in records {
+        writer.serialize(record)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Metric {
+        name: String,
+        value: f64,
+        sample: Option<String>,
+    }
+
+    fn metrics() -> Vec<Metric> {
+        vec![
+            Metric {
+                name: "reads".into(),
+                value: 10.0,
+                sample: Some("a, b".into()),
+            },
+            Metric {
+                name: "fraction".into(),
+                value: 0.5,
+                sample: None,
+            },
+        ]
+    }
+
+    #[test]
+    fn test_csv_file() {
+        let dir = tempdir::TempDir::new("test_csv_file").unwrap();
+        let file: CsvFile<Metric> = CsvFile::new(dir.path(), "metrics");
+        assert_eq!(file.as_ref(), dir.path().join("metrics.csv"));
+        file.write(&metrics()).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&file).unwrap(),
+            "name,value,sample\nreads,10.0,\"a, b\"\nfraction,0.5,\n"
+        );
+        assert_eq!(file.read().unwrap(), metrics());
+
+        std::fs::write(&file, "name,value,sample\nreads,many,\n").unwrap();
+        let error = file.read().unwrap_err().to_string();
+        assert!(error.starts_with(&format!("Failed to read {}: ", file.as_ref().display())));
+        assert!(error.contains("line: 2"), "{}", error);
+    }
+
+    #[test]
+    fn test_tsv_file() {
+        let dir = tempdir::TempDir::new("test_tsv_file").unwrap();
+        let file: TsvFile<Metric> = TsvFile::new(dir.path(), "metrics.tsv");
+        assert_eq!(file.as_ref(), dir.path().join("metrics.tsv"));
+        file.write(&metrics()).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&file).unwrap(),
+            "name\tvalue\tsample\nreads\t10.0\ta, b\nfraction\t0.5\t\n"
+        );
+        assert_eq!(file.read().unwrap(), metrics());
+        assert_eq!(
+            TsvFile::<Metric>::as_martian_primary_type(),
+            MartianPrimaryType::FileType("tsv".into())
+        );
+    }
+}
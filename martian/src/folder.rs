@@ -0,0 +1,235 @@
+//! Directories as stage outputs, for tools which write a whole directory, e.g a
+//! reference index or a report bundle. A folder is a `path` in the mro. The stage
+//! which writes it records a manifest of its files, so that the stage reading it,
+//! typically the join, can check that none of them went missing or got truncated.
+
+use crate::mro::{AsMartianPrimaryType, MartianPrimaryType};
+use failure::{format_err, Error};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+
+/// Name of the manifest within the folder, which is not part of the manifest
+pub const MANIFEST_NAME: &str = "_manifest.json";
+
+/// The files within a folder, with their paths relative to the folder, sorted
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FolderManifest {
+    pub files: Vec<ManifestEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub path: PathBuf,
+    pub size: u64,
+}
+
+impl FolderManifest {
+    /// The manifest of the files currently in `dir`, including the files in its
+    /// subdirectories
+    pub fn of_dir(dir: impl AsRef<Path>) -> Result<Self, Error> {
+        let dir = dir.as_ref();
+        let mut files = Vec::new();
+        list_files(dir, Path::new(""), &mut files)
+            .map_err(|e| format_err!("Failed to list the files in {}: {}", dir.display(), e))?;
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(FolderManifest { files })
+    }
+
+    pub fn total_size(&self) -> u64 {
+        self.files.iter().map(|entry| entry.size).sum()
+    }
+}
+
+fn list_files(dir: &Path, relative: &Path, files: &mut Vec<ManifestEntry>) -> Result<(), Error> {
+    for entry in std::fs::read_dir(dir.join(relative))? {
+        let path = relative.join(entry?.file_name());
+        if path == Path::new(MANIFEST_NAME) {
+            continue;
+        }
+        // Follows symlinks, which tools use to link to the files they reuse
+        let metadata = std::fs::metadata(dir.join(&path))?;
+        if metadata.is_dir() {
+            list_files(dir, &path, files)?;
+        } else {
+            files.push(ManifestEntry {
+                path,
+                size: metadata.len(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// A directory which is the output of a stage. Create it within the files
+/// directory, let the tool fill it up, and record its manifest:
+/// ```ignore
+/// let index: Folder = rover.create_folder("star_index")?;
+/// run_star_genome_generate(&index)?;
+/// index.write_manifest()?;
+/// ```
+/// The stage reading it calls `validate` before using it. Implement it for a
+/// newtype around a `PathBuf` to tell the folders of different tools apart.
+pub trait FolderType: AsRef<Path> + From<PathBuf> {
+    /// Create the directory `<files_path>/<name>`, along with its parents
+    fn create(files_path: impl AsRef<Path>, name: impl AsRef<Path>) -> Result<Self, Error>
+    where
+        Self: Sized,
+    {
+        let path = files_path.as_ref().join(name);
+        std::fs::create_dir_all(&path)
+            .map_err(|e| format_err!("Failed to create {}: {}", path.display(), e))?;
+        Ok(Self::from(path))
+    }
+
+    fn manifest_path(&self) -> PathBuf {
+        self.as_ref().join(MANIFEST_NAME)
+    }
+
+    /// Record the files which are in the folder now in its manifest
+    fn write_manifest(&self) -> Result<FolderManifest, Error> {
+        let manifest = FolderManifest::of_dir(self)?;
+        let path = self.manifest_path();
+        File::create(&path)
+            .map_err(Error::from)
+            .and_then(|file| {
+                Ok(serde_json::to_writer_pretty(
+                    BufWriter::new(file),
+                    &manifest,
+                )?)
+            })
+            .map_err(|e| format_err!("Failed to write {}: {}", path.display(), e))?;
+        Ok(manifest)
+    }
+
+    /// The manifest written by `write_manifest`
+    fn read_manifest(&self) -> Result<FolderManifest, Error> {
+        let path = self.manifest_path();
+        File::open(&path)
+            .map_err(Error::from)
+            .and_then(|file| Ok(serde_json::from_reader(BufReader::new(file))?))
+            .map_err(|e| format_err!("Failed to read {}: {}", path.display(), e))
+    }
+
+    /// Check that every file in the manifest is in the folder with the same size.
+    /// Files which are not in the manifest are allowed.
+    fn validate(&self) -> Result<FolderManifest, Error> {
+        let manifest = self.read_manifest()?;
+        for entry in &manifest.files {
+            let path = self.as_ref().join(&entry.path);
+            let size = match std::fs::metadata(&path) {
+                Ok(metadata) => metadata.len(),
+                Err(_) => {
+                    return Err(format_err!(
+                        "The folder {} does not match its manifest: {} is missing",
+                        self.as_ref().display(),
+                        entry.path.display()
+                    ))
+                }
+            };
+            if size != entry.size {
+                return Err(format_err!(
+                    "The folder {} does not match its manifest: {} has {} bytes instead of {}",
+                    self.as_ref().display(),
+                    entry.path.display(),
+                    size,
+                    entry.size
+                ));
+            }
+        }
+        Ok(manifest)
+    }
+}
+
+/// A directory of any content, which is a `path` in the mro. See `FolderType`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Folder(PathBuf);
+
+impl FolderType for Folder {}
+
+impl From<PathBuf> for Folder {
+    fn from(path: PathBuf) -> Self {
+        Folder(path)
+    }
+}
+
+impl AsRef<Path> for Folder {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsMartianPrimaryType for Folder {
+    fn as_martian_primary_type() -> MartianPrimaryType {
+        MartianPrimaryType::Path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_folder_manifest() {
+        let dir = tempdir::TempDir::new("test_folder_manifest").unwrap();
+        let folder = Folder::create(dir.path(), "index/star").unwrap();
+        assert_eq!(folder.as_ref(), dir.path().join("index/star"));
+        std::fs::create_dir(folder.as_ref().join("sub")).unwrap();
+        std::fs::write(folder.as_ref().join("SA"), b"suffix array").unwrap();
+        std::fs::write(folder.as_ref().join("sub/Genome"), b"ACGT").unwrap();
+
+        let manifest = folder.write_manifest().unwrap();
+        assert_eq!(
+            manifest.files,
+            vec![
+                ManifestEntry {
+                    path: "SA".into(),
+                    size: 12
+                },
+                ManifestEntry {
+                    path: "sub/Genome".into(),
+                    size: 4
+                },
+            ]
+        );
+        assert_eq!(manifest.total_size(), 16);
+        assert_eq!(folder.validate().unwrap(), manifest);
+        assert_eq!(folder.write_manifest().unwrap(), manifest);
+
+        std::fs::write(folder.as_ref().join("extra.log"), b"").unwrap();
+        assert!(folder.validate().is_ok());
+        std::fs::write(folder.as_ref().join("sub/Genome"), b"AC").unwrap();
+        assert_eq!(
+            folder.validate().unwrap_err().to_string(),
+            format!(
+                "The folder {} does not match its manifest: sub/Genome has 2 bytes instead of 4",
+                folder.as_ref().display()
+            )
+        );
+        std::fs::remove_file(folder.as_ref().join("SA")).unwrap();
+        assert_eq!(
+            folder.validate().unwrap_err().to_string(),
+            format!(
+                "The folder {} does not match its manifest: SA is missing",
+                folder.as_ref().display()
+            )
+        );
+
+        let other = Folder::create(dir.path(), "other").unwrap();
+        assert!(other
+            .validate()
+            .unwrap_err()
+            .to_string()
+            .starts_with("Failed to read "));
+    }
+
+    #[test]
+    fn test_folder_mro_type() {
+        assert_eq!(Folder::as_martian_primary_type(), MartianPrimaryType::Path);
+        let folder: Folder = serde_json::from_str("\"/files/index\"").unwrap();
+        assert_eq!(folder.as_ref(), Path::new("/files/index"));
+        assert_eq!(serde_json::to_string(&folder).unwrap(), "\"/files/index\"");
+    }
+}
@@ -0,0 +1,357 @@
+//! Streaming of the items of the json array in a file, one item at a time, so
+//! that stages can write and read millions of records without holding all of
+//! them in memory. `martian_filetype!` implements `LazyWrite` and `LazyRead` for
+//! the filetypes with a `json` extension.
+
+use crate::types::MartianFileType;
+use failure::{format_err, Error};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+
+/// Write the items of a json array to a file of this type one at a time:
+/// ```ignore
+/// let mut writer = JsonFile::new(rover.files_path(), "records").lazy_writer()?;
+/// for record in records {
+///     writer.write_item(&record)?;
+/// }
+/// writer.finish()?;
+/// ```
+pub trait LazyWrite<T: Serialize>: MartianFileType + AsRef<Path> {
+    fn lazy_writer(&self) -> Result<LazyJsonWriter<T>, Error> {
+        LazyJsonWriter::create(self.as_ref())
+    }
+}
+
+/// Read the items of the json array in a file of this type one at a time:
+/// ```ignore
+/// for record in JsonFile::new(rover.files_path(), "records").lazy_reader()? {
+///     let record: Record = record?;
+/// }
+/// ```
+pub trait LazyRead<T: DeserializeOwned>: MartianFileType + AsRef<Path> {
+    fn lazy_reader(&self) -> Result<LazyJsonReader<T>, Error> {
+        LazyJsonReader::open(self.as_ref())
+    }
+}
+
+/// Writes a json array to a file item by item. The array is closed by `finish`,
+/// which reports any error, or else when the writer is dropped.
+#[derive(Debug)]
+pub struct LazyJsonWriter<T> {
+    path: PathBuf,
+    writer: Option<BufWriter<File>>,
+    num_items: usize,
+    item_type: PhantomData<fn(&T)>,
+}
+
+impl<T: Serialize> LazyJsonWriter<T> {
+    pub fn create(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        let mut writer = File::create(&path)
+            .map(BufWriter::new)
+            .map_err(|e| format_err!("Failed to write {}: {}", path.display(), e))?;
+        writer.write_all(b"[")?;
+        Ok(LazyJsonWriter {
+            path,
+            writer: Some(writer),
+            num_items: 0,
+            item_type: PhantomData,
+        })
+    }
+
+    pub fn write_item(&mut self, item: &T) -> Result<(), Error> {
+        let writer = self.writer.as_mut().unwrap();
+        let separator: &[u8] = if self.num_items == 0 { b"\n" } else { b",\n" };
+        writer
+            .write_all(separator)
+            .map_err(Error::from)
+            .and_then(|_| Ok(serde_json::to_writer(&mut *writer, item)?))
+            .map_err(|e| format_err!("Failed to write {}: {}", self.path.display(), e))?;
+        self.num_items += 1;
+        Ok(())
+    }
+
+    /// Number of items written so far
+    pub fn num_items(&self) -> usize {
+        self.num_items
+    }
+
+    /// Close the array and flush the file
+    pub fn finish(mut self) -> Result<(), Error> {
+        self.close()
+            .map_err(|e| format_err!("Failed to write {}: {}", self.path.display(), e))
+    }
+
+    fn close(&mut self) -> std::io::Result<()> {
+        if let Some(mut writer) = self.writer.take() {
+            writer.write_all(b"\n]")?;
+            writer.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl<T> Drop for LazyJsonWriter<T> {
+    fn drop(&mut self) {
+        if let Some(mut writer) = self.writer.take() {
+            let _ = writer.write_all(b"\n]").and_then(|_| writer.flush());
+        }
+    }
+}
+
+/// Iterator over the items of the json array in a file, which parses one item
+/// at a time
+#[derive(Debug)]
+pub struct LazyJsonReader<T> {
+    path: PathBuf,
+    reader: BufReader<File>,
+    num_items: usize,
+    done: bool,
+    item_type: PhantomData<fn() -> T>,
+}
+
+impl<T: DeserializeOwned> LazyJsonReader<T> {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        let mut reader = LazyJsonReader {
+            reader: BufReader::new(
+                File::open(&path)
+                    .map_err(|e| format_err!("Failed to read {}: {}", path.display(), e))?,
+            ),
+            path,
+            num_items: 0,
+            done: false,
+            item_type: PhantomData,
+        };
+        match reader.next_byte()? {
+            Some(b'[') => Ok(reader),
+            _ => Err(reader.error("expected a json array")),
+        }
+    }
+
+    fn error(&self, message: impl std::fmt::Display) -> Error {
+        format_err!(
+            "Failed to read {} at item {}: {}",
+            self.path.display(),
+            self.num_items,
+            message
+        )
+    }
+
+    // The next byte which is not whitespace, without consuming it
+    fn peek_byte(&mut self) -> Result<Option<u8>, Error> {
+        loop {
+            let buf = self.reader.fill_buf()?;
+            match buf.iter().position(|b| !b.is_ascii_whitespace()) {
+                Some(i) => {
+                    let byte = buf[i];
+                    self.reader.consume(i);
+                    return Ok(Some(byte));
+                }
+                None if buf.is_empty() => return Ok(None),
+                None => {
+                    let len = buf.len();
+                    self.reader.consume(len);
+                }
+            }
+        }
+    }
+
+    fn next_byte(&mut self) -> Result<Option<u8>, Error> {
+        let byte = self.peek_byte()?;
+        if byte.is_some() {
+            self.reader.consume(1);
+        }
+        Ok(byte)
+    }
+
+    // The bytes of the next json value, which starts at a byte which is not
+    // whitespace. Only finds where the value ends, `serde_json` validates it.
+    fn read_value(&mut self) -> Result<Vec<u8>, Error> {
+        let mut value = Vec::new();
+        let (mut depth, mut in_string, mut escaped) = (0usize, false, false);
+        loop {
+            let buf = self.reader.fill_buf()?;
+            if buf.is_empty() {
+                return Err(self.error("unexpected end of file"));
+            }
+            let mut end = None;
+            for (i, &b) in buf.iter().enumerate() {
+                if in_string {
+                    match b {
+                        _ if escaped => escaped = false,
+                        b'\\' => escaped = true,
+                        b'"' => in_string = false,
+                        _ => {}
+                    }
+                    if !in_string && depth == 0 {
+                        end = Some(i + 1);
+                        break;
+                    }
+                    continue;
+                }
+                match b {
+                    b'"' => in_string = true,
+                    b'[' | b'{' => depth += 1,
+                    b']' | b'}' if depth > 0 => {
+                        depth -= 1;
+                        if depth == 0 {
+                            end = Some(i + 1);
+                            break;
+                        }
+                    }
+                    // The end of a number or a literal, which is not part of it
+                    b',' | b']' | b'}' if depth == 0 => {
+                        end = Some(i);
+                        break;
+                    }
+                    _ if b.is_ascii_whitespace() && depth == 0 => {
+                        end = Some(i);
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+            let consumed = end.unwrap_or(buf.len());
+            value.extend_from_slice(&buf[..consumed]);
+            self.reader.consume(consumed);
+            if end.is_some() {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn read_item(&mut self) -> Result<Option<T>, Error> {
+        match self.peek_byte()? {
+            Some(b']') if self.num_items == 0 => {
+                self.reader.consume(1);
+                return Ok(None);
+            }
+            _ if self.num_items == 0 => {}
+            Some(b',') => self.reader.consume(1),
+            Some(b']') => {
+                self.reader.consume(1);
+                return Ok(None);
+            }
+            _ => return Err(self.error("expected `,` or `]`")),
+        }
+        if self.peek_byte()?.is_none() {
+            return Err(self.error("unexpected end of file"));
+        }
+        let value = self.read_value()?;
+        let item = serde_json::from_slice(&value).map_err(|e| self.error(e))?;
+        self.num_items += 1;
+        Ok(Some(item))
+    }
+}
+
+impl<T: DeserializeOwned> Iterator for LazyJsonReader<T> {
+    type Item = Result<T, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let item = self.read_item();
+        // Stop after the end of the array or the first error
+        if !matches!(item, Ok(Some(_))) {
+            self.done = true;
+        }
+        item.transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Record {
+        name: String,
+        counts: Vec<i64>,
+    }
+
+    #[test]
+    fn test_lazy_json_roundtrip() {
+        let dir = tempdir::TempDir::new("test_lazy_json_roundtrip").unwrap();
+        let path = dir.path().join("records.json");
+        let records: Vec<_> = (0..100)
+            .map(|i| Record {
+                name: format!("r{} \"[]{{,\\", i),
+                counts: vec![i, -i],
+            })
+            .collect();
+
+        let mut writer = LazyJsonWriter::create(&path).unwrap();
+        for record in &records {
+            writer.write_item(record).unwrap();
+        }
+        assert_eq!(writer.num_items(), 100);
+        writer.finish().unwrap();
+        let all: Vec<Record> =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(all, records);
+
+        let read: Vec<Record> = LazyJsonReader::open(&path)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(read, records);
+
+        // Closed when dropped
+        let mut writer = LazyJsonWriter::create(&path).unwrap();
+        writer.write_item(&1.5).unwrap();
+        writer.write_item(&-2.0).unwrap();
+        drop(writer);
+        let read: Vec<f64> = LazyJsonReader::open(&path)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(read, vec![1.5, -2.0]);
+
+        drop(LazyJsonWriter::<i32>::create(&path).unwrap());
+        assert_eq!(LazyJsonReader::<i32>::open(&path).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_lazy_json_reader() {
+        let dir = tempdir::TempDir::new("test_lazy_json_reader").unwrap();
+        let path = dir.path().join("values.json");
+        let read = |json: &str| -> Result<Vec<Option<u32>>, String> {
+            std::fs::write(&path, json).unwrap();
+            LazyJsonReader::open(&path)
+                .and_then(|reader| reader.collect())
+                .map_err(|e| e.to_string().replace(&path.display().to_string(), "FILE"))
+        };
+
+        assert_eq!(
+            read(" [ 1 ,null,\n 23 ] "),
+            Ok(vec![Some(1), None, Some(23)])
+        );
+        assert_eq!(read("[]"), Ok(vec![]));
+        assert_eq!(
+            read("[1 2]"),
+            Err("Failed to read FILE at item 1: expected `,` or `]`".to_string())
+        );
+        assert_eq!(
+            read("{}"),
+            Err("Failed to read FILE at item 0: expected a json array".to_string())
+        );
+        assert_eq!(
+            read("[1, 2"),
+            Err("Failed to read FILE at item 1: unexpected end of file".to_string())
+        );
+        assert_eq!(
+            read("[1, \"a\"]"),
+            Err(
+                "Failed to read FILE at item 1: invalid type: string \"a\", expected u32 at line 1 column 3"
+                    .to_string()
+            )
+        );
+    }
+}
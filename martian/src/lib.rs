@@ -4,34 +4,38 @@ pub use failure::{format_err, Error};
 use failure_derive::Fail;
 
 use backtrace::Backtrace;
+use std::cell::Cell;
 use std::io;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
 use std::thread;
 
-use log::{error, info};
+use log::{error, info, warn};
 
 use chrono::Local;
 use std::collections::HashMap;
 use std::fmt::Write as FmtWrite;
 use std::fs::File;
 use std::io::Write as IoWrite;
-use std::os::unix::io::FromRawFd;
 use std::panic;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 mod metadata;
 pub use metadata::*;
 
+mod adapter_io;
+pub use adapter_io::AdapterIo;
+use adapter_io::IoSink;
+
 #[macro_use]
 mod macros;
 pub mod types;
-pub use types::MartianFileType;
+pub use types::{FileTypeIO, MartianFileType};
 
 mod stage;
 pub mod utils;
 pub use stage::*;
 
+pub mod monitor;
+pub use monitor::MonitorOptions;
 pub mod mro;
 pub use mro::*;
 
@@ -39,7 +43,54 @@ pub use log::LevelFilter;
 
 pub mod prelude;
 
-// Ways a stage can fail.
+mod compressed_log;
+use compressed_log::CompressedLog;
+
+mod signals;
+pub use signals::register_cleanup;
+
+mod perf;
+
+mod temp_dir;
+pub use temp_dir::ScopedTempDir;
+
+mod folder;
+pub use folder::{Folder, FolderManifest, FolderType, ManifestEntry, MANIFEST_NAME};
+
+mod lazy_json;
+pub use lazy_json::{LazyJsonReader, LazyJsonWriter, LazyRead, LazyWrite};
+
+#[cfg(feature = "bincode")]
+mod bincode_file;
+#[cfg(feature = "bincode")]
+pub use bincode_file::{BincodeFile, LazyBincodeReader, LazyBincodeWriter};
+
+#[cfg(feature = "csv")]
+mod csv_file;
+#[cfg(feature = "csv")]
+pub use csv_file::{CsvFile, TsvFile};
+
+mod compressed_file;
+pub use compressed_file::Gzip;
+#[cfg(feature = "lz4")]
+pub use compressed_file::Lz4;
+#[cfg(feature = "zstd")]
+pub use compressed_file::Zstd;
+
+#[cfg(feature = "tracing")]
+mod tracing_logger;
+
+#[cfg(feature = "cli")]
+pub mod cli;
+
+#[cfg(feature = "tokio")]
+mod async_stage;
+#[cfg(feature = "tokio")]
+pub use async_stage::{block_on, AsyncMartianMain, AsyncMartianStage};
+
+// Ways a stage can fail. Each of them has a machine readable `code`, which is
+// written to `_errors` along with the message (see `StageError::errors_message`)
+// so that pipeline tooling can categorize failures without matching the message.
 #[derive(Debug, Fail)]
 pub enum StageError {
     // Controlled shutdown for known condition in data or config
@@ -49,29 +100,121 @@ pub enum StageError {
     // Unexpected error
     #[fail(display = "{}", message)]
     PipelineError { message: String },
+
+    // The inputs of the stage are invalid, e.g a malformed input file
+    #[fail(display = "{}", message)]
+    InvalidInput { message: String },
+
+    // The stage ran out of memory, time or disk space
+    #[fail(display = "{}", message)]
+    ResourceExhausted { message: String },
+
+    // An external tool or service used by the stage failed
+    #[fail(display = "{}", message)]
+    DependencyFailure { message: String },
+
+    // A bug in the stage, e.g a broken invariant
+    #[fail(display = "{}", message)]
+    InternalBug { message: String },
+}
+
+impl StageError {
+    /// Machine readable code of the kind of failure, e.g `INVALID_INPUT`
+    pub fn code(&self) -> &'static str {
+        match self {
+            StageError::MartianExit { .. } => "USER_ASSERT",
+            StageError::PipelineError { .. } => "PIPELINE_ERROR",
+            StageError::InvalidInput { .. } => "INVALID_INPUT",
+            StageError::ResourceExhausted { .. } => "RESOURCE_EXHAUSTED",
+            StageError::DependencyFailure { .. } => "DEPENDENCY_FAILURE",
+            StageError::InternalBug { .. } => "INTERNAL_BUG",
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            StageError::MartianExit { message }
+            | StageError::PipelineError { message }
+            | StageError::InvalidInput { message }
+            | StageError::ResourceExhausted { message }
+            | StageError::DependencyFailure { message }
+            | StageError::InternalBug { message } => message,
+        }
+    }
+
+    /// Whether martian should report the failure as an assertion, which is not
+    /// worth retrying. Running out of resources or a failing dependency may be
+    /// transient, so these can be retried by mrp.
+    pub fn is_assert(&self) -> bool {
+        !matches!(
+            self,
+            StageError::ResourceExhausted { .. } | StageError::DependencyFailure { .. }
+        )
+    }
+
+    /// What is written to `_errors`: `ASSERT: [CODE] message` for assertions
+    /// and `[CODE] message` otherwise
+    pub fn errors_message(&self) -> String {
+        let prefix = if self.is_assert() { "ASSERT: " } else { "" };
+        format!("{}[{}] {}", prefix, self.code(), self.message())
+    }
 }
 
 pub fn initialize(args: Vec<String>, log_file: &File) -> Result<Metadata, Error> {
-    let mut md = Metadata::new(args, log_file);
+    initialize_metadata(Metadata::new(args, log_file))
+}
+
+fn initialize_metadata(mut md: Metadata) -> Result<Metadata, Error> {
     println!("got metadata: {:?}", md);
     md.update_jobinfo()?;
 
     Ok(md)
 }
 
+/// Exit code of `martian_run` when the stage completes successfully
+pub const EXIT_CODE_SUCCESS: i32 = 0;
+/// Exit code of `martian_run` when the stage fails with a `StageError` other than
+/// the ones below, or any other unexpected error
+pub const EXIT_CODE_PIPELINE_ERROR: i32 = 1;
+/// Exit code of `martian_run` when the stage shuts down with a `StageError::MartianExit`
+/// or a `StageError::InvalidInput`
+pub const EXIT_CODE_MARTIAN_EXIT: i32 = 2;
+
+/// Run the stage using `martian_main`, report any error to martian using
+/// `handle_stage_error` and exit the process with one of `EXIT_CODE_SUCCESS`,
+/// `EXIT_CODE_MARTIAN_EXIT` or `EXIT_CODE_PIPELINE_ERROR`. This gives every adapter
+/// binary the same exit semantics.
+pub fn martian_run(args: Vec<String>, stage_map: HashMap<String, Box<dyn RawMartianStage>>) -> ! {
+    martian_run_with_options(args, stage_map, RunOptions::new())
+}
+
+/// Same as `martian_run`, running the stage using `martian_main_with_options`
+pub fn martian_run_with_options(
+    args: Vec<String>,
+    stage_map: HashMap<String, Box<dyn RawMartianStage>>,
+    options: RunOptions,
+) -> ! {
+    let code = match martian_main_with_options(args, stage_map, options) {
+        Ok(()) => EXIT_CODE_SUCCESS,
+        Err(err) => {
+            let code = match err.downcast_ref::<StageError>() {
+                Some(StageError::MartianExit { .. }) | Some(StageError::InvalidInput { .. }) => {
+                    EXIT_CODE_MARTIAN_EXIT
+                }
+                _ => EXIT_CODE_PIPELINE_ERROR,
+            };
+            handle_stage_error(err);
+            code
+        }
+    };
+    std::process::exit(code)
+}
+
 pub fn handle_stage_error(err: Error) {
     // Try to handle know StageError cases
     match &err.downcast::<StageError>() {
         &Ok(ref e) => {
-            match e {
-                &StageError::MartianExit { message: ref m } => {
-                    let _ = write_errors(&format!("ASSERT: {}", m));
-                }
-                // No difference here at this point
-                &StageError::PipelineError { message: ref m } => {
-                    let _ = write_errors(&format!("ASSERT: {}", m));
-                }
-            }
+            let _ = write_errors(&e.errors_message());
         }
         &Err(ref e) => {
             let msg = format!("stage error:{}\n{}", e.as_fail(), e.backtrace());
@@ -81,11 +224,7 @@ pub fn handle_stage_error(err: Error) {
 }
 
 fn write_errors(msg: &str) -> Result<(), Error> {
-    unsafe {
-        let mut err_file = File::from_raw_fd(4);
-        let _ = err_file.write(msg.as_bytes())?;
-        Ok(())
-    }
+    metadata::write_stage_errors(msg)
 }
 
 /// Log a panic to the martian output machinery
@@ -101,16 +240,225 @@ pub fn log_panic(panic: &panic::PanicInfo) {
     let _ = write_errors(&msg);
 }
 
-fn setup_logging(log_file: &File, level: LevelFilter) {
+thread_local! {
+    // Index of the chunk whose main is running on this thread, if it is a chunk
+    // of a stage with a split. It is included in every log line so that log lines
+    // from different chunks can be told apart, including chunks running
+    // concurrently in the same process (see `run_stage_parallel`).
+    static LOG_CHUNK_INDEX: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+pub(crate) fn set_log_chunk_index(chunk_index: Option<usize>) {
+    LOG_CHUNK_INDEX.with(|idx| idx.set(chunk_index));
+}
+
+fn log_chunk_index() -> Option<usize> {
+    LOG_CHUNK_INDEX.with(|idx| idx.get())
+}
+
+/// Prefix for log lines, e.g `[chunk 3] ` while running the main of chunk 3.
+/// Empty outside of a chunk
+fn chunk_prefix(chunk_index: Option<usize>) -> String {
+    match chunk_index {
+        Some(idx) => format!("[chunk {}] ", idx),
+        None => String::new(),
+    }
+}
+
+/// Which logging framework is hooked up to the martian `_log` file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogBackend {
+    /// Records from the `log` crate, using `fern`
+    #[default]
+    Log,
+    /// Events and spans from the `tracing` crate, using `tracing_subscriber`.
+    /// Records from the `log` crate are forwarded as well.
+    #[cfg(feature = "tracing")]
+    Tracing,
+}
+
+/// Format of the records written to the martian `_log` file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    /// `[time][LEVEL] message`
+    #[default]
+    Text,
+    /// One JSON object per line, with the keys `timestamp`, `level`, `target`,
+    /// `stage`, `chunk` (the chunk index, `null` outside of a chunk) and `message`,
+    /// for log aggregation tools
+    Json,
+}
+
+/// Formats the log records of a stage
+#[derive(Debug, Clone)]
+pub(crate) struct LogFormatter {
+    format: LogFormat,
+    stage: String,
+}
+
+impl LogFormatter {
+    pub(crate) fn new(format: LogFormat, stage: impl ToString) -> Self {
+        LogFormatter {
+            format,
+            stage: stage.to_string(),
+        }
+    }
+
+    /// The log line for a record logged on this thread, without the trailing newline
+    pub(crate) fn format(
+        &self,
+        level: impl std::fmt::Display,
+        target: &str,
+        message: impl std::fmt::Display,
+    ) -> String {
+        self.format_chunk(log_chunk_index(), level, target, message)
+    }
+
+    // The log line for a record logged by the main of the chunk `chunk`, if any
+    fn format_chunk(
+        &self,
+        chunk: Option<usize>,
+        level: impl std::fmt::Display,
+        target: &str,
+        message: impl std::fmt::Display,
+    ) -> String {
+        match self.format {
+            LogFormat::Text => format!(
+                "[{}][{}] {}{}",
+                Local::now().format("%Y-%m-%d %H:%M:%S"),
+                level,
+                chunk_prefix(chunk),
+                message
+            ),
+            LogFormat::Json => serde_json::json!({
+                "timestamp": Local::now().to_rfc3339(),
+                "level": level.to_string(),
+                "target": target,
+                "stage": self.stage,
+                "chunk": chunk,
+                "message": message.to_string(),
+            })
+            .to_string(),
+        }
+    }
+}
+
+/// Environment variable which overrides the log level of the stages, e.g
+/// `MARTIAN_LOG_LEVEL=debug`. See `LogOptions::level`
+pub const LOG_LEVEL_ENV_VAR: &str = "MARTIAN_LOG_LEVEL";
+/// Key in `_jobinfo` which overrides the log level of the stage, unless
+/// `LOG_LEVEL_ENV_VAR` is set. See `LogOptions::level`
+pub const LOG_LEVEL_JOBINFO_KEY: &str = "log_level";
+
+/// The log level set at runtime in the environment or `_jobinfo`, if any
+fn log_level_override(
+    env_level: Option<String>,
+    jobinfo_level: Option<&serde_json::Value>,
+) -> Result<Option<LevelFilter>, Error> {
+    let (level, source) = match (env_level, jobinfo_level) {
+        (Some(level), _) => (level, LOG_LEVEL_ENV_VAR),
+        (None, Some(serde_json::Value::String(level))) => (level.clone(), LOG_LEVEL_JOBINFO_KEY),
+        (None, Some(level)) => (level.to_string(), LOG_LEVEL_JOBINFO_KEY),
+        (None, None) => return Ok(None),
+    };
+    match level.trim().parse::<LevelFilter>() {
+        Ok(level) => Ok(Some(level)),
+        Err(_) => Err(format_err!(
+            "Invalid log level {} in {}, expected one of off, error, warn, info, debug or trace",
+            level,
+            source
+        )),
+    }
+}
+
+/// The log level in the `_jobinfo` of the stage, read before the metadata is
+/// initialized so that logging can be set up first
+fn jobinfo_log_level(metadata_path: &Path) -> Option<serde_json::Value> {
+    let jobinfo = std::fs::read_to_string(metadata_path.join("_jobinfo")).ok()?;
+    let mut jobinfo: serde_json::Value = serde_json::from_str(&jobinfo).ok()?;
+    jobinfo
+        .get_mut(LOG_LEVEL_JOBINFO_KEY)
+        .map(serde_json::Value::take)
+}
+
+/// How the stage logs are set up, see `RunOptions::log`
+/// ```rust
+/// use martian::{LevelFilter, LogOptions};
+/// let options = LogOptions::new(LevelFilter::Info).compressed_log("_log.gz");
+/// ```
+#[derive(Debug, Clone)]
+pub struct LogOptions {
+    /// Log level, unless another one is set at runtime using the environment
+    /// variable `LOG_LEVEL_ENV_VAR` or the `_jobinfo` key `LOG_LEVEL_JOBINFO_KEY`,
+    /// e.g to debug a failing pipestance without rebuilding the adapter. An
+    /// invalid level set at runtime is reported in the log, and this one is used.
+    pub level: LevelFilter,
+    pub backend: LogBackend,
+    pub format: LogFormat,
+    /// Also write a gzip compressed copy of the log to this file under the
+    /// files directory of the stage
+    pub compressed_log: Option<String>,
+}
+
+impl LogOptions {
+    pub fn new(level: LevelFilter) -> Self {
+        LogOptions {
+            level,
+            backend: LogBackend::default(),
+            format: LogFormat::default(),
+            compressed_log: None,
+        }
+    }
+    pub fn backend(mut self, backend: LogBackend) -> Self {
+        self.backend = backend;
+        self
+    }
+    pub fn format(mut self, format: LogFormat) -> Self {
+        self.format = format;
+        self
+    }
+    pub fn compressed_log(mut self, file_name: impl ToString) -> Self {
+        self.compressed_log = Some(file_name.to_string());
+        self
+    }
+}
+
+fn setup_logging(
+    log_file: &IoSink,
+    level: LevelFilter,
+    backend: LogBackend,
+    formatter: LogFormatter,
+    compressed_log: Option<CompressedLog>,
+) {
+    match backend {
+        LogBackend::Log => setup_fern_logging(log_file, level, formatter, compressed_log),
+        #[cfg(feature = "tracing")]
+        LogBackend::Tracing => {
+            tracing_logger::setup_logging(log_file, level, formatter, compressed_log)
+        }
+    }
+}
+
+fn setup_fern_logging(
+    log_file: &IoSink,
+    level: LevelFilter,
+    formatter: LogFormatter,
+    compressed_log: Option<CompressedLog>,
+) {
     let base_config = fern::Dispatch::new().level(level);
 
-    let logger_config = fern::Dispatch::new()
-        .format(|out, msg, record| {
-            let time_str = Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
-            out.finish(format_args!("[{}][{}] {}", time_str, record.level(), msg))
+    let mut logger_config = fern::Dispatch::new()
+        .format(move |out, msg, record| {
+            out.finish(format_args!(
+                "{}",
+                formatter.format(record.level(), record.target(), msg)
+            ))
         })
-        .chain(log_file.try_clone().expect("couldn't open log file"))
+        .chain(Box::new(log_file.clone()) as Box<dyn IoWrite + Send>)
         .chain(io::stdout());
+    if let Some(compressed_log) = compressed_log {
+        logger_config = logger_config.chain(Box::new(compressed_log) as Box<dyn IoWrite + Send>);
+    }
 
     let cfg = base_config.chain(logger_config).apply();
 
@@ -123,7 +471,7 @@ pub fn martian_main(
     args: Vec<String>,
     stage_map: HashMap<String, Box<RawMartianStage>>,
 ) -> Result<(), Error> {
-    martian_main_with_log_level(args, stage_map, LevelFilter::Debug)
+    martian_main_with_options(args, stage_map, RunOptions::new())
 }
 
 pub fn martian_main_with_log_level(
@@ -131,28 +479,146 @@ pub fn martian_main_with_log_level(
     stage_map: HashMap<String, Box<RawMartianStage>>,
     level: LevelFilter,
 ) -> Result<(), Error> {
+    martian_main_with_options(args, stage_map, RunOptions::new().log_level(level))
+}
+
+/// Options for running a stage using `martian_main_with_options` or
+/// `martian_run_with_options`
+/// ```rust
+/// use martian::{LargeIntPolicy, LevelFilter, MonitorOptions, RunOptions};
+/// let options = RunOptions::new()
+///     .log_level(LevelFilter::Info)
+///     .large_int_policy(LargeIntPolicy::Error)
+///     .monitor(MonitorOptions::new().abort_fraction(0.98));
+/// ```
+#[derive(Debug, Clone)]
+pub struct RunOptions {
+    /// How the stage logs are set up. Logs at `LevelFilter::Debug` by default
+    pub log: LogOptions,
+    /// How integers in the stage outputs which martian cannot represent exactly
+    /// are handled. See `Metadata::set_large_int_policy`
+    pub large_int_policy: LargeIntPolicy,
+    /// Where the stage log and errors are written. See `AdapterIo`
+    pub io: AdapterIo,
+    /// How the memory usage of the stage is checked. See `MonitorOptions`
+    pub monitor: MonitorOptions,
+}
+
+impl Default for RunOptions {
+    fn default() -> Self {
+        RunOptions {
+            log: LogOptions::new(LevelFilter::Debug),
+            large_int_policy: LargeIntPolicy::default(),
+            io: AdapterIo::default(),
+            monitor: MonitorOptions::default(),
+        }
+    }
+}
+
+impl RunOptions {
+    pub fn new() -> Self {
+        RunOptions::default()
+    }
+    pub fn log(mut self, log: LogOptions) -> Self {
+        self.log = log;
+        self
+    }
+    /// Shorthand for setting `LogOptions::level` only
+    pub fn log_level(mut self, level: LevelFilter) -> Self {
+        self.log.level = level;
+        self
+    }
+    pub fn large_int_policy(mut self, large_int_policy: LargeIntPolicy) -> Self {
+        self.large_int_policy = large_int_policy;
+        self
+    }
+    pub fn io(mut self, io: AdapterIo) -> Self {
+        self.io = io;
+        self
+    }
+    pub fn monitor(mut self, monitor: MonitorOptions) -> Self {
+        self.monitor = monitor;
+        self
+    }
+}
+
+/// Same as `martian_main`, using the log, large int, io and monitor settings in
+/// `options`
+pub fn martian_main_with_options(
+    args: Vec<String>,
+    stage_map: HashMap<String, Box<dyn RawMartianStage>>,
+    options: RunOptions,
+) -> Result<(), Error> {
+    let RunOptions {
+        log: options,
+        large_int_policy,
+        io,
+        monitor: monitor_options,
+    } = options;
     info!("got args: {:?}", args);
 
-    // The log file is opened by the monitor process and should never be closed by
-    // the adapter.
-    let log_file: File = unsafe { File::from_raw_fd(3) };
+    // args[2] is the metadata directory of the stage
+    let metadata_path = args.get(2).map(PathBuf::from).unwrap_or_default();
+    let (log_file, error_sink) = io.resolve(&metadata_path);
+    // Errors from here on are written to the error sink of the stage
+    let md = Metadata::with_sinks(args.clone(), log_file.clone(), error_sink);
+    metadata::set_stage_metadata(md.clone());
 
-    // Hook rust logging up to Martian _log file
-    setup_logging(&log_file, level);
+    // args[3] is the files directory of the stage
+    let compressed_log = match (options.compressed_log, args.get(3)) {
+        (Some(file_name), Some(files_path)) => Some(CompressedLog::create(
+            Path::new(files_path).join(file_name),
+        )?),
+        _ => None,
+    };
+
+    // Hook rust logging up to Martian _log file, before anything else logs. An
+    // invalid log level set at runtime must not fail the stage, so it is only
+    // reported in the log. args[0] is the stage name
+    let (level, invalid_level) = match log_level_override(
+        std::env::var(LOG_LEVEL_ENV_VAR).ok(),
+        jobinfo_log_level(&metadata_path).as_ref(),
+    ) {
+        Ok(level) => (level.unwrap_or(options.level), None),
+        Err(e) => (options.level, Some(e)),
+    };
+    let formatter = LogFormatter::new(options.format, args.first().map_or("", |s| s.as_str()));
+    setup_logging(
+        &log_file,
+        level,
+        options.backend,
+        formatter,
+        compressed_log.clone(),
+    );
+    if let Some(e) = invalid_level {
+        warn!("{}. Logging at level {} instead.", e, options.level);
+    }
 
     // setup Martian metadata
-    let md = initialize(args, &log_file)?;
+    let mut md = initialize_metadata(md)?;
+    md.set_large_int_policy(large_int_policy);
+    metadata::set_stage_metadata(md.clone());
+
+    // Report termination by the cluster scheduler in _errors, and keep the
+    // compressed log readable
+    signals::install_handlers()?;
+    if let Some(compressed_log) = compressed_log.clone() {
+        register_cleanup(move || {
+            let _ = compressed_log.finish();
+        });
+    }
 
     // Get the stage implementation
     let stage = stage_map
         .get(&md.stage_name)
         .ok_or(failure::err_msg("couldn't find requested stage"))?;
 
-    // Setup monitor thread -- this handles heartbeat & memory checking
-    let stage_done = Arc::new(AtomicBool::new(false));
+    // Setup monitor thread -- this handles heartbeat, memory checking and timeouts
+    let monitor = monitor::Monitor::start(md.clone(), monitor_options, stage.max_runtime())?;
 
     // Setup panic hook. If a stage panics, we'll shutdown cleanly to martian
     let p = panic::take_hook();
+    let panic_log = compressed_log.clone();
     panic::set_hook(Box::new(move |info| {
         let backtrace = Backtrace::new();
 
@@ -181,21 +647,38 @@ pub fn martian_main_with_log_level(
 
         error!("{}", msg);
         let _ = write_errors(&msg);
+        // The process may not get to finish the compressed log after a panic
+        if let Some(ref compressed_log) = panic_log {
+            let _ = compressed_log.finish();
+        }
         p(info);
     }));
 
-    if md.stage_type == "split" {
-        stage.split(md)?;
-    } else if md.stage_type == "main" {
-        stage.main(md)?;
-    } else if md.stage_type == "join" {
-        stage.join(md)?;
-    } else {
-        panic!("Unrecognized stage type");
+    // Record the performance of the stage in _jobinfo, for _perf. This is done
+    // by `Metadata::complete` if the stage succeeds, and below otherwise.
+    md.start_perf_timer();
+    let mut perf_md = md.clone();
+
+    let result = match md.stage_type.parse::<StageType>() {
+        Ok(StageType::Split) => stage.split(md),
+        Ok(StageType::Main) => stage.main(md),
+        Ok(StageType::Join) => stage.join(md),
+        Err(e) => Err(e),
     };
 
-    stage_done.store(true, Ordering::Relaxed);
-    Ok(())
+    monitor.stop();
+    if result.is_err() {
+        if let Err(e) = perf_md.record_perf() {
+            error!("Failed to record the performance data in _jobinfo: {}", e);
+        }
+    }
+    // A failure to finish the compressed log must not hide the outcome of the stage
+    if let Some(compressed_log) = compressed_log {
+        if let Err(e) = compressed_log.finish() {
+            error!("Failed to finish the compressed log: {}", e);
+        }
+    }
+    result
 }
 
 const MRO_HEADER: &str = r#"
@@ -212,38 +695,1476 @@ pub fn martian_make_mro(
     rewrite: bool,
     mro_registry: Vec<StageMro>,
 ) -> Result<(), Error> {
+    martian_make_mro_with_options(file_name, rewrite, mro_registry, MakeMroOptions::new())
+}
+
+/// Options for generating the mro using `martian_make_mro_with_options`
+/// ```rust
+/// use martian::MakeMroOptions;
+/// let options = MakeMroOptions::new().src_prefix("../bin").strict(true);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct MakeMroOptions {
+    /// Path to the directory containing the adapter executable, which is used in the
+    /// `src comp` line of every stage instead of the bare adapter name.
+    /// See `StageMro::set_src_prefix`
+    pub src_prefix: Option<PathBuf>,
+    /// Fail if a stage does not have any outputs, unless it is a preflight stage.
+    /// See `StageMro::check_outputs`
+    pub strict: bool,
+    /// Pipelines written after all the stages
+    pub pipelines: Vec<PipelineMro>,
+    /// Write the stages to separate files next to the mro file, one file per
+    /// group (see `groups`) and one per remaining stage, e.g `sum_squares.mro`
+    /// for `SUM_SQUARES`. The mro file itself contains an `@include` of each
+    /// of these files, followed by the pipelines.
+    pub split_files: bool,
+    /// Stages written to a common file when splitting the mro, as the file name
+    /// without the `.mro` extension and the names of the stages in it
+    pub groups: Vec<(String, Vec<String>)>,
+    /// Declare the filetypes in this file instead, which is included by the
+    /// generated files that use any filetype. The path is relative to the
+    /// directory of the mro file, e.g `../common/filetypes.mro` to share the
+    /// declarations across crates. The file is created if needed, and the
+    /// filetypes already declared in it are kept.
+    pub shared_filetypes: Option<String>,
+    /// Write the documentation of the stage inputs and outputs (the doc comments
+    /// of the struct fields with `#[derive(MartianStruct)]`) as `#` comment lines
+    /// above each field. See `MroField::with_doc`
+    pub field_docs: bool,
+    /// Name of the adapter executable in the `src comp` line of every stage,
+    /// instead of the one each `StageMro` was created with (`adapter_name!()`
+    /// when using `martian_stages!`). See `StageMro::set_adapter_name`
+    pub adapter_name: Option<String>,
+    /// Write the stages in alphabetical order of their names instead of the
+    /// order of the registry, so that the mro does not depend on the order in
+    /// which the stages are listed. The filetypes are always sorted.
+    pub sort_stages: bool,
+}
+
+impl MakeMroOptions {
+    pub fn new() -> Self {
+        MakeMroOptions::default()
+    }
+    pub fn src_prefix(mut self, src_prefix: impl AsRef<Path>) -> Self {
+        self.src_prefix = Some(src_prefix.as_ref().to_path_buf());
+        self
+    }
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+    /// Add a pipeline to the mro. It is checked using `PipelineMro::verify`
+    pub fn pipeline(mut self, pipeline_mro: PipelineMro) -> Self {
+        self.pipelines.push(pipeline_mro);
+        self
+    }
+    pub fn split_files(mut self, split_files: bool) -> Self {
+        self.split_files = split_files;
+        self
+    }
+    /// Write the stages `stage_names` to `<name>.mro`. Implies `split_files`
+    pub fn group(mut self, name: impl ToString, stage_names: &[&str]) -> Self {
+        self.split_files = true;
+        self.groups.push((
+            name.to_string(),
+            stage_names.iter().map(|s| s.to_string()).collect(),
+        ));
+        self
+    }
+    pub fn shared_filetypes(mut self, include_path: impl ToString) -> Self {
+        self.shared_filetypes = Some(include_path.to_string());
+        self
+    }
+    pub fn field_docs(mut self, field_docs: bool) -> Self {
+        self.field_docs = field_docs;
+        self
+    }
+    pub fn adapter_name(mut self, adapter_name: impl ToString) -> Self {
+        self.adapter_name = Some(adapter_name.to_string());
+        self
+    }
+    pub fn sort_stages(mut self, sort_stages: bool) -> Self {
+        self.sort_stages = sort_stages;
+        self
+    }
+}
+
+/// Same as `martian_make_mro`, with an optional path to the directory containing
+/// the adapter executable, which is used in the `src comp` line of every stage
+/// instead of the bare adapter name. See `StageMro::set_src_prefix`
+pub fn martian_make_mro_with_src_prefix(
+    file_name: Option<impl AsRef<Path>>,
+    rewrite: bool,
+    mro_registry: Vec<StageMro>,
+    src_prefix: Option<impl AsRef<Path>>,
+) -> Result<(), Error> {
+    let mut options = MakeMroOptions::new();
+    if let Some(prefix) = src_prefix {
+        options = options.src_prefix(prefix);
+    }
+    martian_make_mro_with_options(file_name, rewrite, mro_registry, options)
+}
+
+/// Same as `martian_make_mro`, customized using `options`
+pub fn martian_make_mro_with_options(
+    file_name: Option<impl AsRef<Path>>,
+    rewrite: bool,
+    mut mro_registry: Vec<StageMro>,
+    options: MakeMroOptions,
+) -> Result<(), Error> {
+    if !options.field_docs {
+        for stage_mro in &mut mro_registry {
+            stage_mro.remove_field_docs();
+        }
+    }
+    if options.sort_stages {
+        mro_registry.sort_by(|a, b| a.stage_name().cmp(b.stage_name()));
+    }
+    if let Some(ref adapter_name) = options.adapter_name {
+        for stage_mro in &mut mro_registry {
+            stage_mro
+                .set_adapter_name(adapter_name)
+                .map_err(|e| format_err!("{}", e))?;
+        }
+    }
+    // Report all the problems in the stages together
+    let mut problems = match validate_mro_registry(&mro_registry) {
+        Ok(()) => Vec::new(),
+        Err(e) => e.problems().to_vec(),
+    };
+    if options.strict {
+        for stage_mro in &mro_registry {
+            if let Err(e) = stage_mro.check_outputs() {
+                problems.push(e);
+            }
+        }
+    }
+    MroValidationError::from_problems(problems)?;
     if let Some(ref f) = file_name {
-        let file_path = f.as_ref();
-        if file_path.is_dir() {
-            return Err(format_err!(
-                "Error! Path {} is a directory!",
-                file_path.display()
+        check_mro_path(f.as_ref(), rewrite)?;
+    }
+
+    if options.split_files || options.shared_filetypes.is_some() {
+        let file_path = match file_name {
+            Some(ref f) => f.as_ref(),
+            None => {
+                return Err(format_err!(
+                    "Writing the mro to more than one file needs the path of the mro file"
+                ))
+            }
+        };
+        let dir = file_path.parent().unwrap_or_else(|| Path::new(""));
+        let top_name = file_path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let mut shared_filetypes = match options.shared_filetypes {
+            Some(ref include) => Some(SharedFiletypes::read(dir, include)?),
+            None => None,
+        };
+        let mut files = if options.split_files {
+            make_split_mro_strings(mro_registry, &options, &top_name, shared_filetypes.as_mut())?
+        } else {
+            let contents = make_mro_string_with_includes(
+                mro_registry,
+                options.src_prefix.as_deref(),
+                &options.pipelines,
+                &[],
+                shared_filetypes.as_mut(),
+            )?;
+            vec![(top_name, contents)]
+        };
+        // Check all the files before writing any of them. The shared filetypes
+        // are merged, so they can always be rewritten.
+        for (name, _) in &files {
+            check_mro_path(&dir.join(name), rewrite)?;
+        }
+        if let Some(shared_filetypes) = shared_filetypes {
+            if files
+                .iter()
+                .any(|(name, _)| *name == shared_filetypes.include)
+            {
+                return Err(format_err!(
+                    "The shared filetypes {} would overwrite a generated mro file",
+                    shared_filetypes.include
+                ));
+            }
+            files.push((
+                shared_filetypes.include.clone(),
+                shared_filetypes.to_string(),
             ));
         }
-        if file_path.exists() && !rewrite {
+        for (name, contents) in files {
+            std::fs::write(dir.join(name), contents)?;
+        }
+        return Ok(());
+    }
+
+    let final_mro_string = make_mro_string(mro_registry, options.src_prefix, &options.pipelines)?;
+    match file_name {
+        Some(f) => {
+            let mut output = File::create(f)?;
+            output.write(final_mro_string.as_bytes())?;
+        }
+        None => {
+            println!("{}", final_mro_string);
+        }
+    }
+    Ok(())
+}
+
+fn check_mro_path(file_path: &Path, rewrite: bool) -> Result<(), Error> {
+    if file_path.is_dir() {
+        return Err(format_err!(
+            "Error! Path {} is a directory!",
+            file_path.display()
+        ));
+    }
+    if file_path.exists() && !rewrite {
+        return Err(format_err!(
+            "File {} exists. You need to explicitly mention if it is okay to rewrite.",
+            file_path.display()
+        ));
+    }
+    Ok(())
+}
+
+// The full mro for the registry and the pipelines, including the header
+fn make_mro_string(
+    mro_registry: Vec<StageMro>,
+    src_prefix: Option<impl AsRef<Path>>,
+    pipelines: &[PipelineMro],
+) -> Result<String, Error> {
+    make_mro_string_with_includes(
+        mro_registry,
+        src_prefix.as_ref().map(AsRef::as_ref),
+        pipelines,
+        &[],
+        None,
+    )
+}
+
+// The filetypes declared in a file included by the generated mro files
+// (see `MakeMroOptions::shared_filetypes`)
+struct SharedFiletypes {
+    include: String,
+    header: FiletypeHeader,
+}
+
+impl SharedFiletypes {
+    // Start from the filetypes already declared in the file, if it exists
+    fn read(dir: &Path, include: &str) -> Result<Self, Error> {
+        let file_path = dir.join(include);
+        let mut header = FiletypeHeader::default();
+        if file_path.exists() {
+            let contents = std::fs::read_to_string(&file_path)?;
+            let mro_file = parse_mro(&contents).map_err(|e| {
+                format_err!("Unable to parse mro file {}: {}", file_path.display(), e)
+            })?;
+            for filetype in mro_file.filetypes {
+                header.add_filetype(filetype);
+            }
+        }
+        Ok(SharedFiletypes {
+            include: include.to_string(),
+            header,
+        })
+    }
+}
+
+impl std::fmt::Display for SharedFiletypes {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}{}", MRO_HEADER, self.header)
+    }
+}
+
+// The mro files when the stages are split across files, as (file name, contents).
+// The top level file `top_name`, with the `@include`s and the pipelines, is last
+fn make_split_mro_strings(
+    mro_registry: Vec<StageMro>,
+    options: &MakeMroOptions,
+    top_name: &str,
+    mut shared_filetypes: Option<&mut SharedFiletypes>,
+) -> Result<Vec<(String, String)>, Error> {
+    let mut group_of = HashMap::new();
+    for (group, stage_names) in &options.groups {
+        for stage_name in stage_names {
+            if let Some(other) = group_of.insert(stage_name.as_str(), group.as_str()) {
+                return Err(format_err!(
+                    "Stage {} is in both the groups {} and {}",
+                    stage_name,
+                    other,
+                    group
+                ));
+            }
+            if !mro_registry.iter().any(|s| s.stage_name() == stage_name) {
+                return Err(format_err!(
+                    "Stage {} in the group {} is not in the registry",
+                    stage_name,
+                    group
+                ));
+            }
+        }
+    }
+
+    // Stages of each file, in the order the files first appear in the registry
+    let mut file_stages: Vec<(String, Vec<StageMro>)> = Vec::new();
+    for stage_mro in mro_registry {
+        let file_name = match group_of.get(stage_mro.stage_name()) {
+            Some(group) => format!("{}.mro", group),
+            None => format!("{}.mro", stage_mro.stage_name().to_lowercase()),
+        };
+        match file_stages.iter_mut().find(|(name, _)| *name == file_name) {
+            Some((_, stages)) => stages.push(stage_mro),
+            None => file_stages.push((file_name, vec![stage_mro])),
+        }
+    }
+
+    let mut files = Vec::new();
+    let mut includes = Vec::new();
+    for (file_name, stages) in file_stages {
+        if file_name == top_name {
             return Err(format_err!(
-                "File {} exists. You need to explicitly mention if it is okay to rewrite.",
-                file_path.display()
+                "The stages written to {} would overwrite the mro file",
+                file_name
             ));
         }
+        let contents = make_mro_string_with_includes(
+            stages,
+            options.src_prefix.as_deref(),
+            &[],
+            &[],
+            shared_filetypes.as_deref_mut(),
+        )?;
+        includes.push(file_name.clone());
+        files.push((file_name, contents));
     }
+    let top = make_mro_string_with_includes(
+        Vec::new(),
+        options.src_prefix.as_deref(),
+        &options.pipelines,
+        &includes,
+        shared_filetypes,
+    )?;
+    files.push((top_name.to_string(), top));
+    Ok(files)
+}
 
+fn make_mro_string_with_includes(
+    mro_registry: Vec<StageMro>,
+    src_prefix: Option<&Path>,
+    pipelines: &[PipelineMro],
+    includes: &[String],
+    shared_filetypes: Option<&mut SharedFiletypes>,
+) -> Result<String, Error> {
     let mut filetype_header = FiletypeHeader::default();
+    let mut struct_header = StructHeader::default();
     let mut mro_string = String::new();
-    for stage_mro in mro_registry {
+    for mut stage_mro in mro_registry {
+        if let Some(ref prefix) = src_prefix {
+            stage_mro
+                .set_src_prefix(prefix)
+                .map_err(|e| format_err!("{}", e))?;
+        }
         filetype_header.add_stage(&stage_mro);
+        struct_header
+            .add_stage(&stage_mro)
+            .map_err(|e| format_err!("{}", e))?;
         writeln!(&mut mro_string, "{}", stage_mro)?;
     }
+    for pipeline_mro in pipelines {
+        pipeline_mro.verify().map_err(|e| format_err!("{}", e))?;
+        filetype_header.add_pipeline(pipeline_mro);
+        struct_header
+            .add_pipeline(pipeline_mro)
+            .map_err(|e| format_err!("{}", e))?;
+        writeln!(&mut mro_string, "{}", pipeline_mro)?;
+    }
 
-    let final_mro_string = format!("{}{}{}", MRO_HEADER, filetype_header, mro_string);
+    let mut include_lines = String::new();
+    if let Some(shared) = shared_filetypes {
+        if !filetype_header.is_empty() {
+            for ext in filetype_header.extensions() {
+                shared.header.add_filetype(ext);
+            }
+            writeln!(&mut include_lines, "@include \"{}\"", shared.include)?;
+            filetype_header = FiletypeHeader::default();
+        }
+    }
+    for include in includes {
+        writeln!(&mut include_lines, "@include \"{}\"", include)?;
+    }
+    if !include_lines.is_empty() {
+        include_lines.push('\n');
+    }
+    Ok(format!(
+        "{}{}{}{}{}",
+        MRO_HEADER, include_lines, filetype_header, struct_header, mro_string
+    ))
+}
+
+/// Check that the mro file `file_name` matches the mro that `martian_make_mro`
+/// would generate for the registry, for example in CI to catch a checked in mro
+/// which has drifted from the code. The comment banner at the top of the file
+/// and differences only in whitespace are ignored. If the file differs, the
+/// error contains a unified diff from the file to the generated mro.
+pub fn martian_check_mro(
+    file_name: impl AsRef<Path>,
+    mro_registry: Vec<StageMro>,
+) -> Result<(), Error> {
+    let file_path = file_name.as_ref();
+    let on_disk = std::fs::read_to_string(file_path)
+        .map_err(|e| format_err!("Unable to read mro file {}: {}", file_path.display(), e))?;
+    let generated = make_mro_string(mro_registry, None::<&Path>, &[])?;
+
+    let on_disk = normalize_mro(&on_disk);
+    let generated = normalize_mro(&generated);
+    if on_disk == generated {
+        return Ok(());
+    }
+    Err(format_err!(
+        "The mro file {} does not match the mro generated from the code:\n{}",
+        file_path.display(),
+        utils::unified_diff(
+            &on_disk,
+            &generated,
+            &file_path.display().to_string(),
+            "generated"
+        )
+    ))
+}
+
+/// Compare the stages defined in the mro file `file_name` with the stages in
+/// the registry, without touching the file. Unlike `martian_check_mro`, which
+/// compares the text, this returns the stages added, removed or changed in the
+/// registry relative to the file, and the fields which changed in each stage.
+/// An empty diff means that the file is up to date.
+pub fn martian_diff_mro(
+    file_name: impl AsRef<Path>,
+    mro_registry: Vec<StageMro>,
+) -> Result<MroDiff, Error> {
+    let file_path = file_name.as_ref();
+    let on_disk = std::fs::read_to_string(file_path)
+        .map_err(|e| format_err!("Unable to read mro file {}: {}", file_path.display(), e))?;
+    let mro_file = parse_mro(&on_disk)
+        .map_err(|e| format_err!("Unable to parse mro file {}: {}", file_path.display(), e))?;
+    Ok(MroDiff::between(&mro_file.stages, &mro_registry))
+}
+
+// Lines of the mro without the leading comment banner, with whitespace
+// collapsed and blank lines dropped
+fn normalize_mro(mro: &str) -> Vec<String> {
+    mro.lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+        .skip_while(|line| line.is_empty() || line.starts_with('#'))
+        .filter(|line| !line.is_empty())
+        .collect()
+}
+
+/// A machine readable catalog of all the stages in the registry, listing the stage
+/// name, adapter, key, stage and chunk inputs/outputs with their martian types and the
+/// `using` attributes of each stage, along with all the filetypes used. Object keys
+/// are sorted, so the output is stable.
+pub fn martian_make_manifest(mro_registry: &[StageMro]) -> serde_json::Value {
+    let mut filetype_header = FiletypeHeader::default();
+    let mut stages = Vec::new();
+    for stage_mro in mro_registry {
+        filetype_header.add_stage(stage_mro);
+        stages.push(stage_mro.manifest_entry());
+    }
+    serde_json::json!({
+        "stages": stages,
+        "filetypes": filetype_header.extensions(),
+    })
+}
+
+/// Write the manifest from `martian_make_manifest` to a file, or print it to stdout
+/// if `file_name` is None.
+pub fn martian_make_manifest_file(
+    file_name: Option<impl AsRef<Path>>,
+    rewrite: bool,
+    mro_registry: &[StageMro],
+) -> Result<(), Error> {
+    let manifest = serde_json::to_string_pretty(&martian_make_manifest(mro_registry))?;
     match file_name {
         Some(f) => {
-            let mut output = File::create(f)?;
-            output.write(final_mro_string.as_bytes())?;
+            let file_path = f.as_ref();
+            if file_path.exists() && !rewrite {
+                return Err(format_err!(
+                    "File {} exists. You need to explicitly mention if it is okay to rewrite.",
+                    file_path.display()
+                ));
+            }
+            let mut output = File::create(file_path)?;
+            output.write_all(manifest.as_bytes())?;
         }
         None => {
-            println!("{}", final_mro_string);
+            println!("{}", manifest);
         }
     }
     Ok(())
 }
+
+/// Write the mro registry to a JSON cache file. The mro can later be generated from
+/// the cache using `read_mro_cache` and `martian_make_mro` without recompiling the stages.
+pub fn write_mro_cache(
+    cache_file: impl AsRef<Path>,
+    mro_registry: &[StageMro],
+) -> Result<(), Error> {
+    let file = File::create(cache_file)?;
+    serde_json::to_writer_pretty(file, mro_registry)?;
+    Ok(())
+}
+
+/// Read the mro registry from a cache file created using `write_mro_cache`
+pub fn read_mro_cache(cache_file: impl AsRef<Path>) -> Result<Vec<StageMro>, Error> {
+    let file = File::open(cache_file)?;
+    let mro_registry: Vec<StageMro> = serde_json::from_reader(io::BufReader::new(file))?;
+    for stage_mro in &mro_registry {
+        stage_mro.check_field_names()?;
+    }
+    Ok(mro_registry)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::MartianVoid;
+    use serde::{Deserialize, Serialize};
+
+    // Records the log prefix of each chunk
+    #[derive(Default)]
+    struct TwoChunks {
+        chunk_prefixes: std::sync::Mutex<Vec<String>>,
+    }
+
+    impl MroMaker for TwoChunks {
+        fn stage_name() -> String {
+            "TWO_CHUNKS".into()
+        }
+        fn stage_in_and_out() -> InAndOut {
+            InAndOut::default()
+        }
+        fn chunk_in_and_out() -> Option<InAndOut> {
+            Some(InAndOut::default())
+        }
+        fn using_attributes() -> MroUsing {
+            MroUsing::default()
+        }
+    }
+
+    impl MartianStage for TwoChunks {
+        type StageInputs = MartianVoid;
+        type StageOutputs = MartianVoid;
+        type ChunkInputs = MartianVoid;
+        type ChunkOutputs = MartianVoid;
+
+        fn split(&self, _: MartianVoid, _: MartianRover) -> Result<StageDef<MartianVoid>, Error> {
+            info!("splitting TWO_CHUNKS");
+            let mut stage_def = StageDef::new();
+            stage_def.add_chunk(MartianVoid::new());
+            stage_def.add_chunk(MartianVoid::new());
+            Ok(stage_def)
+        }
+
+        fn main(
+            &self,
+            _: MartianVoid,
+            _: MartianVoid,
+            _: MartianRover,
+        ) -> Result<MartianVoid, Error> {
+            info!("running TWO_CHUNKS");
+            self.chunk_prefixes
+                .lock()
+                .unwrap()
+                .push(chunk_prefix(log_chunk_index()));
+            Ok(MartianVoid::new())
+        }
+
+        fn join(
+            &self,
+            _: MartianVoid,
+            _: Vec<MartianVoid>,
+            _: Vec<MartianVoid>,
+            _: MartianRover,
+        ) -> Result<MartianVoid, Error> {
+            info!("joining TWO_CHUNKS");
+            Ok(MartianVoid::new())
+        }
+    }
+
+    // `test_martian_run_exit_codes` runs itself in a subprocess with this variable
+    // set to the metadata directory of the stage
+    const MARTIAN_RUN_DIR_VAR: &str = "MARTIAN_RUN_TEST_DIR";
+
+    struct ExitWith;
+
+    #[derive(Serialize, Deserialize)]
+    struct ExitArgs {
+        mode: String,
+    }
+
+    impl MartianStruct for ExitArgs {
+        fn mro_fields() -> Vec<MroField> {
+            Vec::new()
+        }
+    }
+
+    impl MroMaker for ExitWith {
+        fn stage_name() -> String {
+            "EXIT_WITH".into()
+        }
+        fn stage_in_and_out() -> InAndOut {
+            InAndOut::default()
+        }
+        fn chunk_in_and_out() -> Option<InAndOut> {
+            None
+        }
+        fn using_attributes() -> MroUsing {
+            MroUsing::default()
+        }
+    }
+
+    impl MartianMain for ExitWith {
+        type StageInputs = ExitArgs;
+        type StageOutputs = MartianVoid;
+
+        fn main(&self, args: ExitArgs, rover: MartianRover) -> Result<MartianVoid, Error> {
+            let message = format!("mode {}", args.mode);
+            match args.mode.as_str() {
+                "exit" => martian_exit!("{}", message),
+                "error" => Err(StageError::PipelineError { message }.into()),
+                "input" => Err(StageError::InvalidInput { message }.into()),
+                "tool" => Err(StageError::DependencyFailure { message }.into()),
+                "sleep" => {
+                    let cleanup_file = rover.files_path().join("cleanup");
+                    register_cleanup(move || std::fs::write(cleanup_file, "done").unwrap());
+                    std::fs::write(rover.files_path().join("sleeping"), "").unwrap();
+                    thread::sleep(std::time::Duration::from_secs(60));
+                    Ok(MartianVoid::new())
+                }
+                _ => Ok(MartianVoid::new()),
+            }
+        }
+    }
+
+    /// `ExitWith` in the `sleep` mode, which runs for too long
+    struct Hang;
+
+    impl MroMaker for Hang {
+        fn stage_name() -> String {
+            "HANG".into()
+        }
+        fn stage_in_and_out() -> InAndOut {
+            InAndOut::default()
+        }
+        fn chunk_in_and_out() -> Option<InAndOut> {
+            None
+        }
+        fn using_attributes() -> MroUsing {
+            MroUsing::default()
+        }
+    }
+
+    impl MartianMain for Hang {
+        type StageInputs = ExitArgs;
+        type StageOutputs = MartianVoid;
+
+        fn main(&self, args: ExitArgs, rover: MartianRover) -> Result<MartianVoid, Error> {
+            MartianMain::main(&ExitWith, args, rover)
+        }
+
+        fn max_runtime(&self) -> Option<std::time::Duration> {
+            Some(std::time::Duration::from_millis(200))
+        }
+    }
+
+    fn martian_run_child(dir: &Path) -> ! {
+        let path = |p: &Path| p.to_str().unwrap().to_string();
+        // The stage name is in the _stage file next to the _args
+        let stage_name = std::fs::read_to_string(dir.join("_stage")).unwrap();
+        let args = vec![
+            stage_name,
+            "main".to_string(),
+            path(dir),
+            path(&dir.join("files")),
+            path(&dir.join("_run")),
+        ];
+        let mut stage_map: HashMap<String, Box<dyn RawMartianStage>> = HashMap::new();
+        stage_map.insert("EXIT_WITH".into(), Box::new(ExitWith));
+        stage_map.insert("HANG".into(), Box::new(Hang));
+        martian_run(args, stage_map)
+    }
+
+    /// Run the test `test_name` in a subprocess, which runs the stage `stage_name`
+    /// with `mode` in `dir` using `martian_run`
+    fn spawn_martian_run(
+        dir: &Path,
+        test_name: &str,
+        stage_name: &str,
+        mode: &str,
+    ) -> std::process::Child {
+        use std::os::unix::io::AsRawFd;
+        use std::os::unix::process::CommandExt;
+        use std::process::{Command, Stdio};
+
+        std::fs::create_dir(dir.join("files")).unwrap();
+        std::fs::write(dir.join("_stage"), stage_name).unwrap();
+        let files = vec![("args", serde_json::json!({ "mode": mode }))];
+        stage::write_metadata_files(dir, files, Resource::new()).unwrap();
+
+        // Martian passes the log file as fd 3 and the errors file as fd 4
+        let log_fd = File::create(dir.join("_log")).unwrap();
+        let errors_fd = File::create(dir.join("_errors")).unwrap();
+        let (log_raw, errors_raw) = (log_fd.as_raw_fd(), errors_fd.as_raw_fd());
+        let mut cmd = Command::new(std::env::current_exe().unwrap());
+        cmd.args(["--exact", test_name])
+            .env(MARTIAN_RUN_DIR_VAR, dir)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+        unsafe {
+            cmd.pre_exec(move || {
+                for &(src, dst) in &[(log_raw, 3), (errors_raw, 4)] {
+                    // dup2 onto itself is a no-op which keeps close-on-exec set
+                    let ret = if src == dst {
+                        libc::fcntl(dst, libc::F_SETFD, 0)
+                    } else {
+                        libc::dup2(src, dst)
+                    };
+                    if ret < 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
+        cmd.spawn().unwrap()
+    }
+
+    #[test]
+    fn test_stage_error_codes() {
+        let message = "bad input".to_string();
+        let err = StageError::InvalidInput { message };
+        assert_eq!(err.code(), "INVALID_INPUT");
+        assert_eq!(err.to_string(), "bad input");
+        assert_eq!(err.errors_message(), "ASSERT: [INVALID_INPUT] bad input");
+
+        let message = "out of disk".to_string();
+        let err = StageError::ResourceExhausted { message };
+        assert!(!err.is_assert());
+        assert_eq!(err.errors_message(), "[RESOURCE_EXHAUSTED] out of disk");
+    }
+
+    #[test]
+    fn test_martian_exit_macros() {
+        fn check(num_reads: usize, max_reads: usize) -> Result<usize, Error> {
+            martian_assert!(max_reads > 0);
+            martian_assert!(
+                num_reads <= max_reads,
+                "{} reads, at most {}",
+                num_reads,
+                max_reads
+            );
+            if num_reads == 0 {
+                martian_exit!("No reads");
+            }
+            Ok(num_reads)
+        }
+        let message = |result: Result<usize, Error>| match result.unwrap_err().downcast() {
+            Ok(err @ StageError::MartianExit { .. }) => err.to_string(),
+            other => panic!("Expected a MartianExit, found {:?}", other),
+        };
+
+        assert_eq!(check(5, 10).unwrap(), 5);
+        assert_eq!(message(check(5, 0)), "Assertion failed: max_reads > 0");
+        assert_eq!(message(check(20, 10)), "20 reads, at most 10");
+        assert_eq!(message(check(0, 10)), "No reads");
+    }
+
+    #[test]
+    fn test_martian_run_exit_codes() {
+        if let Some(dir) = std::env::var_os(MARTIAN_RUN_DIR_VAR) {
+            martian_run_child(Path::new(&dir));
+        }
+
+        let (exit, error) = (EXIT_CODE_MARTIAN_EXIT, EXIT_CODE_PIPELINE_ERROR);
+        let cases = [
+            ("ok", EXIT_CODE_SUCCESS, ""),
+            ("exit", exit, "ASSERT: [USER_ASSERT] mode exit"),
+            ("error", error, "ASSERT: [PIPELINE_ERROR] mode error"),
+            ("input", exit, "ASSERT: [INVALID_INPUT] mode input"),
+            ("tool", error, "[DEPENDENCY_FAILURE] mode tool"),
+        ];
+        for &(mode, expected_code, expected_errors) in &cases {
+            let tmp_dir = tempdir::TempDir::new("test_martian_run_exit_codes").unwrap();
+            let dir = tmp_dir.path();
+            let mut child =
+                spawn_martian_run(dir, "tests::test_martian_run_exit_codes", "EXIT_WITH", mode);
+            let status = child.wait().unwrap();
+            assert_eq!(status.code(), Some(expected_code), "mode {}", mode);
+
+            let errors = std::fs::read_to_string(dir.join("_errors")).unwrap();
+            assert_eq!(errors, expected_errors);
+            assert_eq!(dir.join("_outs").exists(), mode == "ok");
+
+            // The performance data is recorded whether the stage fails or not
+            let jobinfo = std::fs::read_to_string(dir.join("_jobinfo")).unwrap();
+            let jobinfo: serde_json::Value = serde_json::from_str(&jobinfo).unwrap();
+            assert!(jobinfo["wallclock"]["duration_seconds"].is_number());
+            assert!(jobinfo["rusage"]["self"]["ru_utime"].is_number());
+        }
+    }
+
+    #[test]
+    fn test_martian_run_sigterm() {
+        use std::os::unix::process::ExitStatusExt;
+
+        if let Some(dir) = std::env::var_os(MARTIAN_RUN_DIR_VAR) {
+            martian_run_child(Path::new(&dir));
+        }
+
+        let tmp_dir = tempdir::TempDir::new("test_martian_run_sigterm").unwrap();
+        let dir = tmp_dir.path();
+        let mut child =
+            spawn_martian_run(dir, "tests::test_martian_run_sigterm", "EXIT_WITH", "sleep");
+        let start = std::time::Instant::now();
+        while !dir.join("files/sleeping").exists() {
+            assert!(start.elapsed().as_secs() < 30, "the stage did not start");
+            thread::sleep(std::time::Duration::from_millis(10));
+        }
+        unsafe {
+            libc::kill(child.id() as libc::pid_t, libc::SIGTERM);
+        }
+        let status = child.wait().unwrap();
+        assert_eq!(status.signal(), Some(libc::SIGTERM));
+
+        let errors = std::fs::read_to_string(dir.join("_errors")).unwrap();
+        assert_eq!(errors, "stage terminated by signal SIGTERM");
+        assert_eq!(
+            std::fs::read_to_string(dir.join("files/cleanup")).unwrap(),
+            "done"
+        );
+        assert!(!dir.join("_outs").exists());
+    }
+
+    #[test]
+    fn test_martian_run_timeout() {
+        if let Some(dir) = std::env::var_os(MARTIAN_RUN_DIR_VAR) {
+            martian_run_child(Path::new(&dir));
+        }
+
+        let tmp_dir = tempdir::TempDir::new("test_martian_run_timeout").unwrap();
+        let dir = tmp_dir.path();
+        let mut child = spawn_martian_run(dir, "tests::test_martian_run_timeout", "HANG", "sleep");
+        let status = child.wait().unwrap();
+        assert_eq!(status.code(), Some(EXIT_CODE_PIPELINE_ERROR));
+
+        let errors = std::fs::read_to_string(dir.join("_errors")).unwrap();
+        assert_eq!(
+            errors,
+            "[RESOURCE_EXHAUSTED] stage timed out: the main of HANG did not finish within its \
+             maximum runtime of 200ms"
+        );
+        // The cleanup callbacks run as well
+        assert!(dir.join("files/cleanup").exists());
+        assert!(!dir.join("_outs").exists());
+    }
+
+    struct SumSquares;
+
+    impl MroMaker for SumSquares {
+        fn stage_name() -> String {
+            "SUM_SQUARES".into()
+        }
+        fn stage_in_and_out() -> InAndOut {
+            InAndOut {
+                inputs: vec![MroField::new(
+                    "values",
+                    MartianBlanketType::Array(MartianPrimaryType::Float),
+                )],
+                outputs: vec![MroField::new(
+                    "sum",
+                    MartianBlanketType::Primary(MartianPrimaryType::Float),
+                )],
+            }
+        }
+        fn chunk_in_and_out() -> Option<InAndOut> {
+            None
+        }
+        fn using_attributes() -> MroUsing {
+            MroUsing::default()
+        }
+    }
+
+    fn check_mro_registry() -> Vec<StageMro> {
+        vec![SumSquares::stage_mro("adapter", "sum_squares")]
+    }
+
+    #[test]
+    fn test_martian_check_mro() {
+        let tmp_dir = tempdir::TempDir::new("test_martian_check_mro").unwrap();
+        let mro_path = tmp_dir.path().join("stages.mro");
+        let generated = make_mro_string(check_mro_registry(), None::<&Path>, &[]).unwrap();
+
+        // Matching file
+        std::fs::write(&mro_path, &generated).unwrap();
+        martian_check_mro(&mro_path, check_mro_registry()).unwrap();
+
+        // A different banner and whitespace only changes
+        let reformatted = generated
+            .replace("DO NOT MODIFY THIS FILE DIRECTLY", "Regenerated in CI")
+            .replace("float[] values", "float[]    values")
+            .replace("\n)", "\n\n)");
+        std::fs::write(&mro_path, reformatted).unwrap();
+        martian_check_mro(&mro_path, check_mro_registry()).unwrap();
+
+        // Semantic difference
+        std::fs::write(&mro_path, generated.replace("float   sum", "int     sum")).unwrap();
+        let err = martian_check_mro(&mro_path, check_mro_registry()).unwrap_err();
+        let message = err.to_string();
+        assert!(message.contains("does not match the mro generated from the code"));
+        assert!(message.contains("\n-out int sum,\n+out float sum,\n"));
+
+        // Missing file
+        assert!(
+            martian_check_mro(tmp_dir.path().join("missing.mro"), check_mro_registry()).is_err()
+        );
+    }
+
+    #[test]
+    fn test_martian_diff_mro() {
+        let tmp_dir = tempdir::TempDir::new("test_martian_diff_mro").unwrap();
+        let mro_path = tmp_dir.path().join("stages.mro");
+        let generated = make_mro_string(check_mro_registry(), None::<&Path>, &[]).unwrap();
+
+        std::fs::write(&mro_path, &generated).unwrap();
+        assert!(martian_diff_mro(&mro_path, check_mro_registry())
+            .unwrap()
+            .is_empty());
+
+        let stale = generated
+            .replace("float   sum", "int     sum")
+            .replace("float[] values,", "float[] values,\n    in  int     count,")
+            + "\nstage OLD_STAGE(\n    src comp \"adapter martian old_stage\",\n)\n";
+        std::fs::write(&mro_path, stale).unwrap();
+        let mut registry = check_mro_registry();
+        registry.push(CheckInputs::stage_mro("adapter", "check_inputs"));
+        let diff = martian_diff_mro(&mro_path, registry).unwrap();
+        assert_eq!(diff.added_stages, vec!["CHECK_INPUTS".to_string()]);
+        assert_eq!(diff.removed_stages, vec!["OLD_STAGE".to_string()]);
+        assert_eq!(diff.changed_stages.len(), 1);
+        assert_eq!(
+            diff.to_string(),
+            "+ stage CHECK_INPUTS\n\
+             - stage OLD_STAGE\n\
+             ~ stage SUM_SQUARES\n    \
+             - in int count\n    \
+             ~ out int sum -> out float sum\n"
+        );
+    }
+
+    struct CheckInputs;
+
+    impl MroMaker for CheckInputs {
+        fn stage_name() -> String {
+            "CHECK_INPUTS".into()
+        }
+        fn stage_in_and_out() -> InAndOut {
+            InAndOut::default()
+        }
+        fn chunk_in_and_out() -> Option<InAndOut> {
+            None
+        }
+        fn using_attributes() -> MroUsing {
+            MroUsing::default()
+        }
+        fn preflight() -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn test_make_mro_strict() {
+        let tmp_dir = tempdir::TempDir::new("test_make_mro_strict").unwrap();
+        let mro_path = tmp_dir.path().join("stages.mro");
+        let make_mro = |registry, strict| {
+            martian_make_mro_with_options(
+                Some(&mro_path),
+                true,
+                registry,
+                MakeMroOptions::new().strict(strict),
+            )
+        };
+
+        // ExitWith has no outputs
+        let registry = || {
+            vec![
+                SumSquares::stage_mro("adapter", "sum_squares"),
+                ExitWith::stage_mro("adapter", "exit_with"),
+            ]
+        };
+        make_mro(registry(), false).unwrap();
+        let err = make_mro(registry(), true).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Stage EXIT_WITH does not have any outputs."));
+
+        // Preflight stages are exempt
+        let registry = vec![
+            SumSquares::stage_mro("adapter", "sum_squares"),
+            CheckInputs::stage_mro("adapter", "check_inputs"),
+        ];
+        make_mro(registry, true).unwrap();
+    }
+
+    #[test]
+    fn test_make_mro_pipelines() {
+        let tmp_dir = tempdir::TempDir::new("test_make_mro_pipelines").unwrap();
+        let mro_path = tmp_dir.path().join("pipeline.mro");
+        let sum_squares = SumSquares::stage_mro("adapter", "sum_squares");
+        let pipeline = PipelineMro::passthrough("SUM_SQUARES_PIPELINE", &sum_squares);
+        let options = MakeMroOptions::new().pipeline(pipeline.clone());
+        martian_make_mro_with_options(Some(&mro_path), false, vec![sum_squares], options).unwrap();
+        let mro = std::fs::read_to_string(&mro_path).unwrap();
+        assert!(mro.ends_with(&format!(")\n\n{}\n", pipeline)));
+
+        // Invalid pipelines are rejected
+        let sum_squares = SumSquares::stage_mro("adapter", "sum_squares");
+        let broken = PipelineMro::new("BROKEN").call(CallMro::new(&sum_squares));
+        let err = martian_make_mro_with_options(
+            Some(&mro_path),
+            true,
+            vec![SumSquares::stage_mro("adapter", "sum_squares")],
+            MakeMroOptions::new().pipeline(broken),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Input values of the call to SUM_SQUARES in pipeline BROKEN is not bound"
+        );
+    }
+
+    struct InvalidStage;
+
+    impl MroMaker for InvalidStage {
+        fn stage_name() -> String {
+            "INVALID_STAGE".into()
+        }
+        fn stage_in_and_out() -> InAndOut {
+            InAndOut {
+                inputs: vec![
+                    MroField::new("values", MartianBlanketType::Array(MartianPrimaryType::Int)),
+                    MroField::new("values", MartianBlanketType::Array(MartianPrimaryType::Int)),
+                ],
+                outputs: Vec::new(),
+            }
+        }
+        fn chunk_in_and_out() -> Option<InAndOut> {
+            None
+        }
+        fn using_attributes() -> MroUsing {
+            MroUsing {
+                mem_gb: Some(8),
+                vmem_gb: Some(4),
+                ..Default::default()
+            }
+        }
+    }
+
+    #[test]
+    fn test_make_mro_validation() {
+        // The problems in all the stages are reported together
+        let err = martian_make_mro_with_options(
+            None::<&Path>,
+            false,
+            vec![
+                InvalidStage::stage_mro("adapter", "invalid_stage"),
+                SumSquares::stage_mro("adapter", "sum_squares"),
+                TwoChunks::stage_mro("adapter", "two_chunks"),
+            ],
+            MakeMroOptions::new().strict(true),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Found 4 problems in the mro:
+  - Found duplicate field values in the StageInputs of stage INVALID_STAGE
+  - Expected vmem_gb to be at least mem_gb in the using attributes, found vmem_gb = 4 and mem_gb = 8 (stage INVALID_STAGE)
+  - Stage INVALID_STAGE does not have any outputs. If this is intended, mark it as a preflight stage.
+  - Stage TWO_CHUNKS does not have any outputs. If this is intended, mark it as a preflight stage."
+        );
+    }
+
+    #[test]
+    fn test_make_mro_sort_stages() {
+        let registry = || {
+            vec![
+                SumSquares::stage_mro("adapter", "sum_squares"),
+                CheckInputs::stage_mro("adapter", "check_inputs"),
+            ]
+        };
+        let make_mro = |options: MakeMroOptions| {
+            let tmp_dir = tempdir::TempDir::new("test_make_mro_sort_stages").unwrap();
+            let mro_path = tmp_dir.path().join("stages.mro");
+            martian_make_mro_with_options(Some(&mro_path), false, registry(), options).unwrap();
+            std::fs::read_to_string(&mro_path).unwrap()
+        };
+        let position = |mro: &str, stage: &str| mro.find(&format!("stage {}(", stage)).unwrap();
+
+        let mro = make_mro(MakeMroOptions::new());
+        assert!(position(&mro, "SUM_SQUARES") < position(&mro, "CHECK_INPUTS"));
+
+        let sorted = make_mro(MakeMroOptions::new().sort_stages(true));
+        assert!(position(&sorted, "CHECK_INPUTS") < position(&sorted, "SUM_SQUARES"));
+        // The same stages in any order give the same mro
+        let mut reversed = registry();
+        reversed.reverse();
+        let tmp_dir = tempdir::TempDir::new("test_make_mro_sort_stages").unwrap();
+        let mro_path = tmp_dir.path().join("stages.mro");
+        let options = MakeMroOptions::new().sort_stages(true);
+        martian_make_mro_with_options(Some(&mro_path), false, reversed, options).unwrap();
+        assert_eq!(std::fs::read_to_string(&mro_path).unwrap(), sorted);
+    }
+
+    #[test]
+    fn test_make_mro_adapter_name() {
+        let tmp_dir = tempdir::TempDir::new("test_make_mro_adapter_name").unwrap();
+        let mro_path = tmp_dir.path().join("stages.mro");
+        let options = MakeMroOptions::new().adapter_name("my_adapter");
+        martian_make_mro_with_options(
+            Some(&mro_path),
+            false,
+            vec![SumSquares::stage_mro(adapter_name!(), "sum_squares")],
+            options,
+        )
+        .unwrap();
+        let mro = std::fs::read_to_string(&mro_path).unwrap();
+        assert!(mro.contains(r#""my_adapter martian sum_squares""#));
+
+        let err = martian_make_mro_with_options(
+            Some(&mro_path),
+            true,
+            vec![SumSquares::stage_mro(adapter_name!(), "sum_squares")],
+            MakeMroOptions::new().adapter_name("my adapter"),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Adapter name `my adapter` contains the character ' ', which cannot be used in the mro src"
+        );
+    }
+
+    #[test]
+    fn test_make_mro_split_files() {
+        let tmp_dir = tempdir::TempDir::new("test_make_mro_split_files").unwrap();
+        let mro_path = tmp_dir.path().join("pipeline.mro");
+        let registry = || {
+            vec![
+                SumSquares::stage_mro("adapter", "sum_squares"),
+                CheckInputs::stage_mro("adapter", "check_inputs"),
+                SumPoints::stage_mro("adapter", "sum_points"),
+            ]
+        };
+        let sum_squares = SumSquares::stage_mro("adapter", "sum_squares");
+        let pipeline = PipelineMro::passthrough("SUM_SQUARES_PIPELINE", &sum_squares);
+        let options = || {
+            MakeMroOptions::new()
+                .group("sums", &["SUM_SQUARES", "SUM_POINTS"])
+                .pipeline(pipeline.clone())
+        };
+        martian_make_mro_with_options(Some(&mro_path), false, registry(), options()).unwrap();
+
+        let read = |name: &str| std::fs::read_to_string(tmp_dir.path().join(name)).unwrap();
+        assert_eq!(
+            read("pipeline.mro"),
+            format!(
+                "{}@include \"sums.mro\"\n@include \"check_inputs.mro\"\n\n{}\n",
+                MRO_HEADER, pipeline
+            )
+        );
+        assert_eq!(
+            read("sums.mro"),
+            make_mro_string(
+                vec![
+                    SumSquares::stage_mro("adapter", "sum_squares"),
+                    SumPoints::stage_mro("adapter", "sum_points"),
+                ],
+                None::<&Path>,
+                &[]
+            )
+            .unwrap()
+        );
+        assert_eq!(
+            read("check_inputs.mro"),
+            make_mro_string(
+                vec![CheckInputs::stage_mro("adapter", "check_inputs")],
+                None::<&Path>,
+                &[]
+            )
+            .unwrap()
+        );
+
+        // None of the files are rewritten unless asked to
+        std::fs::remove_file(&mro_path).unwrap();
+        assert!(
+            martian_make_mro_with_options(Some(&mro_path), false, registry(), options()).is_err()
+        );
+        assert!(!mro_path.exists());
+        martian_make_mro_with_options(Some(&mro_path), true, registry(), options()).unwrap();
+
+        let err = martian_make_mro_with_options(
+            Some(&mro_path),
+            true,
+            registry(),
+            MakeMroOptions::new().group("sums", &["SUM_SQUARES", "SUM_CUBES"]),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Stage SUM_CUBES in the group sums is not in the registry"
+        );
+        assert!(martian_make_mro_with_options(
+            None::<&Path>,
+            true,
+            registry(),
+            MakeMroOptions::new().split_files(true)
+        )
+        .is_err());
+    }
+
+    struct CountReads;
+
+    impl MroMaker for CountReads {
+        fn stage_name() -> String {
+            "COUNT_READS".into()
+        }
+        fn stage_in_and_out() -> InAndOut {
+            InAndOut {
+                inputs: vec![MroField::new(
+                    "reads",
+                    MartianBlanketType::Primary(MartianPrimaryType::FileType("bam".into())),
+                )
+                .with_doc("Reads to count")],
+                outputs: vec![MroField::new(
+                    "summary",
+                    MartianBlanketType::Primary(MartianPrimaryType::FileType("json".into())),
+                )],
+            }
+        }
+        fn chunk_in_and_out() -> Option<InAndOut> {
+            None
+        }
+        fn using_attributes() -> MroUsing {
+            MroUsing::default()
+        }
+    }
+
+    #[test]
+    fn test_make_mro_shared_filetypes() {
+        let tmp_dir = tempdir::TempDir::new("test_make_mro_shared_filetypes").unwrap();
+        let crate_dir = tmp_dir.path().join("my_crate");
+        std::fs::create_dir_all(&crate_dir).unwrap();
+        let shared_path = tmp_dir.path().join("filetypes.mro");
+        std::fs::write(&shared_path, "filetype txt;\nfiletype json;\n").unwrap();
+
+        let mro_path = crate_dir.join("stages.mro");
+        let registry = || {
+            vec![
+                CountReads::stage_mro("adapter", "count_reads"),
+                SumSquares::stage_mro("adapter", "sum_squares"),
+            ]
+        };
+        let options = MakeMroOptions::new().shared_filetypes("../filetypes.mro");
+        martian_make_mro_with_options(Some(&mro_path), false, registry(), options.clone()).unwrap();
+        let mro = std::fs::read_to_string(&mro_path).unwrap();
+        assert!(mro.starts_with(&format!("{}@include \"../filetypes.mro\"\n\n", MRO_HEADER)));
+        assert!(!mro.contains("filetype "));
+        assert_eq!(
+            parse_mro(&std::fs::read_to_string(&shared_path).unwrap())
+                .unwrap()
+                .filetypes,
+            vec!["bam", "json", "txt"]
+        );
+
+        // Only the split files using a filetype include the shared filetypes
+        martian_make_mro_with_options(Some(&mro_path), true, registry(), options.split_files(true))
+            .unwrap();
+        let read = |name: &str| std::fs::read_to_string(crate_dir.join(name)).unwrap();
+        assert!(read("count_reads.mro").contains("@include \"../filetypes.mro\"\n"));
+        assert!(!read("sum_squares.mro").contains("@include"));
+        assert_eq!(
+            read("stages.mro"),
+            format!(
+                "{}@include \"count_reads.mro\"\n@include \"sum_squares.mro\"\n\n",
+                MRO_HEADER
+            )
+        );
+    }
+
+    #[test]
+    fn test_make_mro_field_docs() {
+        let tmp_dir = tempdir::TempDir::new("test_make_mro_field_docs").unwrap();
+        let mro_path = tmp_dir.path().join("stages.mro");
+        let make_mro = |options: MakeMroOptions| {
+            let registry = vec![CountReads::stage_mro("adapter", "count_reads")];
+            martian_make_mro_with_options(Some(&mro_path), true, registry, options).unwrap();
+            std::fs::read_to_string(&mro_path).unwrap()
+        };
+        assert!(!make_mro(MakeMroOptions::new()).contains("Reads to count"));
+        assert!(make_mro(MakeMroOptions::new().field_docs(true))
+            .contains("(\n    # Reads to count\n    in  bam  reads,\n"));
+    }
+
+    struct SumPoints;
+
+    impl MroMaker for SumPoints {
+        fn stage_name() -> String {
+            "SUM_POINTS".into()
+        }
+        fn stage_in_and_out() -> InAndOut {
+            let point = StructDef::new(
+                "Point",
+                vec![
+                    MroField::new("x", MartianBlanketType::Primary(MartianPrimaryType::Float)),
+                    MroField::new("y", MartianBlanketType::Primary(MartianPrimaryType::Float)),
+                ],
+            );
+            InAndOut {
+                inputs: vec![MroField::new(
+                    "points",
+                    MartianBlanketType::Array(MartianPrimaryType::Struct(point.clone())),
+                )],
+                outputs: vec![MroField::new(
+                    "sum",
+                    MartianBlanketType::Primary(MartianPrimaryType::Struct(point)),
+                )],
+            }
+        }
+        fn chunk_in_and_out() -> Option<InAndOut> {
+            None
+        }
+        fn using_attributes() -> MroUsing {
+            MroUsing::default()
+        }
+    }
+
+    #[test]
+    fn test_make_mro_structs() {
+        let registry = vec![
+            SumPoints::stage_mro("adapter", "sum_points"),
+            SumSquares::stage_mro("adapter", "sum_squares"),
+            SumPoints::stage_mro("adapter", "sum_points_again"),
+        ];
+        let mro = make_mro_string(registry, None::<&Path>, &[]).unwrap();
+        // The struct is declared once, in the header before the stages
+        assert_eq!(mro.matches("struct Point(").count(), 1);
+        assert!(mro.starts_with(&format!(
+            "{}struct Point(\n    float x,\n    float y,\n)\n\nstage SUM_POINTS(\n",
+            MRO_HEADER
+        )));
+        assert!(mro.contains("    in  Point[] points,\n"));
+    }
+
+    #[test]
+    fn test_log_chunk_prefix() {
+        let formatter = LogFormatter::new(LogFormat::Text, "TWO_CHUNKS");
+        let line = formatter.format_chunk(Some(3), log::Level::Info, "two_chunks", "running");
+        assert!(line.ends_with("][INFO] [chunk 3] running"));
+        let line = formatter.format_chunk(None, log::Level::Info, "two_chunks", "joining");
+        assert!(line.ends_with("][INFO] joining"));
+
+        // The chunk index is per thread
+        set_log_chunk_index(Some(1));
+        assert!(formatter
+            .format(log::Level::Info, "two_chunks", "running")
+            .ends_with("][INFO] [chunk 1] running"));
+        thread::spawn(|| assert_eq!(chunk_prefix(log_chunk_index()), ""))
+            .join()
+            .unwrap();
+        set_log_chunk_index(None);
+        assert_eq!(chunk_prefix(log_chunk_index()), "");
+
+        // Set for each chunk by the in-process runners, also when the chunks
+        // run concurrently
+        let stage = TwoChunks::default();
+        stage.test_run_tmpdir(MartianVoid::new()).unwrap();
+        run_stage_parallel(&stage, MartianVoid::new(), 2).unwrap();
+        let mut prefixes = stage.chunk_prefixes.into_inner().unwrap();
+        prefixes.sort();
+        assert_eq!(
+            prefixes,
+            vec!["[chunk 0] ", "[chunk 0] ", "[chunk 1] ", "[chunk 1] "]
+        );
+        assert_eq!(chunk_prefix(log_chunk_index()), "");
+    }
+
+    #[test]
+    fn test_log_formatter_json() {
+        let formatter = LogFormatter::new(LogFormat::Json, "SUM_SQUARES");
+        let line = formatter.format_chunk(Some(2), log::Level::Info, "sum_sq::stage", "sum = 14\n");
+        assert!(!line.contains('\n'));
+        let record: serde_json::Value = serde_json::from_str(&line).unwrap();
+        let keys: Vec<_> = record.as_object().unwrap().keys().collect();
+        assert_eq!(
+            keys,
+            vec!["chunk", "level", "message", "stage", "target", "timestamp"]
+        );
+        assert_eq!(record["level"], "INFO");
+        assert_eq!(record["target"], "sum_sq::stage");
+        assert_eq!(record["stage"], "SUM_SQUARES");
+        assert_eq!(record["message"], "sum = 14\n");
+        assert_eq!(record["chunk"], 2);
+        let timestamp = record["timestamp"].as_str().unwrap();
+        assert!(chrono::DateTime::parse_from_rfc3339(timestamp).is_ok());
+
+        let formatter = LogFormatter::new(LogFormat::Text, "SUM_SQUARES");
+        let line = formatter.format(log::Level::Info, "sum_sq::stage", "sum = 14");
+        assert!(line.starts_with('['));
+        assert!(line.ends_with("][INFO] sum = 14"));
+    }
+
+    #[test]
+    fn test_log_level_override() {
+        use serde_json::json;
+        assert_eq!(log_level_override(None, None).unwrap(), None);
+        assert_eq!(
+            log_level_override(Some("debug".to_string()), None).unwrap(),
+            Some(LevelFilter::Debug)
+        );
+        assert_eq!(
+            log_level_override(None, Some(&json!("TRACE"))).unwrap(),
+            Some(LevelFilter::Trace)
+        );
+        // The environment takes precedence over the jobinfo
+        assert_eq!(
+            log_level_override(Some("off".to_string()), Some(&json!("trace"))).unwrap(),
+            Some(LevelFilter::Off)
+        );
+        assert_eq!(
+            log_level_override(Some("verbose".to_string()), None)
+                .unwrap_err()
+                .to_string(),
+            "Invalid log level verbose in MARTIAN_LOG_LEVEL, expected one of off, error, \
+             warn, info, debug or trace"
+        );
+        assert!(log_level_override(None, Some(&json!(3))).is_err());
+    }
+
+    #[test]
+    fn test_jobinfo_log_level() {
+        use serde_json::json;
+        let dir = tempdir::TempDir::new("test_jobinfo_log_level").unwrap();
+        assert_eq!(jobinfo_log_level(dir.path()), None);
+        std::fs::write(dir.path().join("_jobinfo"), "{\"threads\": 1").unwrap();
+        assert_eq!(jobinfo_log_level(dir.path()), None);
+        std::fs::write(dir.path().join("_jobinfo"), r#"{"threads": 1}"#).unwrap();
+        assert_eq!(jobinfo_log_level(dir.path()), None);
+        std::fs::write(
+            dir.path().join("_jobinfo"),
+            r#"{"threads": 1, "log_level": "debug"}"#,
+        )
+        .unwrap();
+        assert_eq!(jobinfo_log_level(dir.path()), Some(json!("debug")));
+    }
+}
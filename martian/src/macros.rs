@@ -1,19 +1,112 @@
+/// Name of the adapter executable, known at compile time: the name of the
+/// binary target being compiled (`CARGO_BIN_NAME`), or the name of the package
+/// (`CARGO_PKG_NAME`) outside of a binary target, e.g in a library or a test.
+/// It is used by `martian_stages!` in the `src comp` line of every stage, and
+/// can be overridden using `MakeMroOptions::adapter_name`.
+#[macro_export]
+macro_rules! adapter_name {
+    () => {
+        match option_env!("CARGO_BIN_NAME") {
+            Some(name) => name,
+            None => env!("CARGO_PKG_NAME"),
+        }
+    };
+}
+
+/// Version of the adapter executable, known at compile time: the environment
+/// variable `MARTIAN_ADAPTER_VERSION` when the adapter is built, e.g the git
+/// commit set by the build script of the adapter, or else the version of the
+/// package (`CARGO_PKG_VERSION`). `martian_stages!` uses it for `set_adapter_version`.
+#[macro_export]
+macro_rules! adapter_version {
+    () => {
+        match option_env!("MARTIAN_ADAPTER_VERSION") {
+            Some(version) => version,
+            None => env!("CARGO_PKG_VERSION"),
+        }
+    };
+}
+
+/// Raise an alarm for the running stage, with the arguments of `format!`, e.g
+/// `alarm!("{} reads were discarded", discarded)?`. See `martian::alarm`.
+#[macro_export]
+macro_rules! alarm {
+    ($($arg:tt)*) => {
+        $crate::alarm(&format!($($arg)*))
+    };
+}
+
+/// Return early from a stage with a `StageError::MartianExit`, the controlled
+/// shutdown for a known problem in the data or the configuration. Takes the
+/// arguments of `format!`:
+/// ```rust
+/// # use martian::{martian_exit, Error};
+/// fn check_reads(num_reads: usize) -> Result<(), Error> {
+///     if num_reads == 0 {
+///         martian_exit!("No reads in the input. Check the FASTQ files");
+///     }
+///     Ok(())
+/// }
+/// assert!(check_reads(0).is_err());
+/// ```
+#[macro_export]
+macro_rules! martian_exit {
+    ($($arg:tt)*) => {
+        return Err($crate::StageError::MartianExit {
+            message: format!($($arg)*),
+        }
+        .into())
+    };
+}
+
+/// Return early from a stage with a `StageError::MartianExit` unless the
+/// condition holds, e.g `martian_assert!(chemistry.is_some(), "Unknown chemistry {}", name)`.
+/// Without a message, the message is the condition.
+#[macro_export]
+macro_rules! martian_assert {
+    ($cond:expr $(,)?) => {
+        if !$cond {
+            $crate::martian_exit!("Assertion failed: {}", stringify!($cond));
+        }
+    };
+    ($cond:expr, $($arg:tt)+) => {
+        if !$cond {
+            $crate::martian_exit!($($arg)+);
+        }
+    };
+}
+
+/// Build the stage registry of an adapter from a list of stages, for example
+/// `martian_stages![sum_squares::SumSquares, report::Report]`. It returns
+/// - the map from stage key to stage, for `martian_main`
+/// - the `StageMro` of every stage, for `martian_make_mro`
+///
+/// The key of a stage is the snake case name of its struct (`sum_squares`),
+/// unless it is set using `#[make_mro(stage_key = ..)]`. It is also the key in
+/// the `src` line of the generated mro, so that the two never drift apart. The
+/// stages need to implement `MroMaker`, which is derived using `#[make_mro]`.
+///
+/// The adapter name in the `src` line is `adapter_name!()`. The version of the
+/// adapter in `MartianRover::versions` is set to `adapter_version!()`.
 #[macro_export]
 macro_rules! martian_stages {
     ( $( $x:path ),* ) => {
         {
-            let mut stage_registry: ::std::collections::HashMap<String, Box<::martian::RawMartianStage>> = ::std::collections::HashMap::default();
+            $crate::set_adapter_version($crate::adapter_version!());
+            let mut stage_registry: ::std::collections::HashMap<String, Box<dyn ::martian::RawMartianStage>> = ::std::collections::HashMap::default();
+            #[allow(unused_mut)]
+            let mut mro_registry = Vec::new();
             $(
-                stage_registry.insert(::martian::utils::to_exec_name(stringify!($x)), Box::new($x));
+                let stage_key = <$x as ::martian::MroMaker>::stage_key()
+                    .unwrap_or_else(|| ::martian::utils::to_exec_name(stringify!($x)));
+                mro_registry.push(<$x as ::martian::MroMaker>::stage_mro(
+                    $crate::adapter_name!(),
+                    &stage_key,
+                ));
+                stage_registry.insert(stage_key, Box::new($x));
             )*
-            let mut mro_registry = vec![
-            	$(<$x as ::martian::MroMaker>::stage_mro(
-            		::martian::utils::current_executable(),
-            		::martian::utils::to_exec_name(stringify!($x)),
-            	)),*
-            ];
             (stage_registry, mro_registry)
         }
     };
-    ( $( $x: path, )*) => ( martian_stages![$($x),*]);
+    ( $( $x: path, )*) => ( $crate::martian_stages![$($x),*]);
 }
@@ -1,14 +1,25 @@
 use std;
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 use std::env;
 use std::fs::{rename, File, OpenOptions};
-use std::io::{Read, Write};
-use std::os::unix::io::FromRawFd;
-use std::path::PathBuf;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
 
-use crate::write_errors;
+use crate::adapter_io::IoSink;
+use crate::perf::PerfTimer;
+use crate::signals;
+use crate::temp_dir::ScopedTempDir;
+use crate::types::MartianMakePath;
+use crate::utils::canonical_json_encode;
 use chrono::*;
-use failure::Error;
+use failure::{format_err, Error};
+use log::{error, warn};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
 use serde_json::map::Map;
 use serde_json::{self, json, Value};
 
@@ -17,6 +28,106 @@ pub type Json = Value;
 type Result<T> = std::result::Result<T, Error>;
 
 const METADATA_PREFIX: &'static str = "_";
+const WARNING_PREFIX: &str = "WARNING:";
+
+/// The phase of a stage martian asks the adapter to run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StageType {
+    Split,
+    Main,
+    Join,
+}
+
+impl FromStr for StageType {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "split" => Ok(StageType::Split),
+            "main" => Ok(StageType::Main),
+            "join" => Ok(StageType::Join),
+            _ => Err(format_err!(
+                "Unrecognized stage type {}. Expected one of split, main or join",
+                s
+            )),
+        }
+    }
+}
+
+/// Largest integer which is represented exactly by the float64 numbers that
+/// martian (and python) use for JSON numbers, i.e 2^53 - 1
+pub const MAX_SAFE_JSON_INT: u64 = (1 << 53) - 1;
+
+/// What to do with integers larger than `MAX_SAFE_JSON_INT` in magnitude when
+/// writing the outputs (and chunk definitions) of a stage. A `u64` or `i64` field
+/// maps to an mro `int`, but such values silently lose precision once they are
+/// read by martian.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LargeIntPolicy {
+    /// Write the integer as is
+    #[default]
+    Allow,
+    /// Write the integer as is, and warn about the fields which lose precision
+    Warn,
+    /// Fail the stage
+    Error,
+    /// Write the integer as a string, e.g `"9007199254740993"`. The field
+    /// should be declared as a string in the mro.
+    Stringify,
+}
+
+impl LargeIntPolicy {
+    /// Apply the policy to the integers within `value`, which is the `name` file
+    /// (e.g `outs`) of a stage. Returns the (JSON) paths of the large integers.
+    pub fn apply(self, name: &str, value: &mut Json) -> Result<Vec<String>> {
+        let mut paths = Vec::new();
+        if self != LargeIntPolicy::Allow {
+            replace_large_ints(value, name.to_string(), self, &mut paths);
+        }
+        match self {
+            LargeIntPolicy::Error if !paths.is_empty() => Err(format_err!(
+                "Integers larger than 2^53 cannot be represented exactly by martian: {}",
+                paths.join(", ")
+            )),
+            _ => Ok(paths),
+        }
+    }
+}
+
+fn is_large_int(number: &serde_json::Number) -> bool {
+    match (number.as_u64(), number.as_i64()) {
+        (Some(n), _) => n > MAX_SAFE_JSON_INT,
+        (None, Some(n)) => n.unsigned_abs() > MAX_SAFE_JSON_INT,
+        (None, None) => false,
+    }
+}
+
+// Collect the paths of the large integers, stringifying them if the policy says so
+fn replace_large_ints(
+    value: &mut Json,
+    path: String,
+    policy: LargeIntPolicy,
+    paths: &mut Vec<String>,
+) {
+    match value {
+        Value::Number(number) if is_large_int(number) => {
+            if policy == LargeIntPolicy::Stringify {
+                *value = Value::String(number.to_string());
+            }
+            paths.push(path);
+        }
+        Value::Array(values) => {
+            for (i, v) in values.iter_mut().enumerate() {
+                replace_large_ints(v, format!("{}[{}]", path, i), policy, paths);
+            }
+        }
+        Value::Object(obj) => {
+            for (key, v) in obj.iter_mut() {
+                replace_large_ints(v, format!("{}.{}", path, key), policy, paths);
+            }
+        }
+        _ => {}
+    }
+}
 
 /// Tracking the metadata for one Martian chunk invocation
 #[derive(Debug, Clone)]
@@ -27,8 +138,108 @@ pub struct Metadata<'a> {
     pub files_path: String,
     run_file: String,
     jobinfo: JsonDict,
+    job_info: JobInfo,
     cache: HashSet<String>,
-    log_file: &'a File,
+    log_file: LogFile<'a>,
+    error_sink: IoSink,
+    large_int_policy: LargeIntPolicy,
+    last_progress: Option<Instant>,
+    perf_timer: Option<PerfTimer>,
+}
+
+/// Where `Metadata::log` writes
+#[derive(Debug, Clone)]
+enum LogFile<'a> {
+    File(&'a File),
+    Sink(IoSink),
+}
+
+impl<'a> LogFile<'a> {
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        match self {
+            LogFile::File(file) => file.write_all(buf).and(file.flush()),
+            LogFile::Sink(sink) => sink.write_all(buf).and(sink.flush()),
+        }
+    }
+}
+
+/// The contents of `_jobinfo` which are useful to a stage, as written by mrp.
+/// The keys which are missing are `None`. See `Metadata::jobinfo`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct JobInfo {
+    /// Number of threads allocated to the job, which may be fractional
+    pub threads: Option<f64>,
+    /// Memory allocated to the job, in GB
+    #[serde(rename = "memGB")]
+    pub mem_gb: Option<f64>,
+    /// Virtual memory allocated to the job, in GB
+    #[serde(rename = "vmemGB")]
+    pub vmem_gb: Option<f64>,
+    /// Profiling mode of the pipestance, e.g `disable` or `cpu`
+    pub profile_mode: Option<String>,
+    pub version: Option<JobVersion>,
+    pub invocation: Option<JobInvocation>,
+}
+
+/// Versions of martian and of the pipelines running the job
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct JobVersion {
+    pub martian: Option<String>,
+    pub pipelines: Option<String>,
+}
+
+/// The pipeline invocation which started the pipestance
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct JobInvocation {
+    /// Name of the pipeline or stage which was called
+    pub call: Option<String>,
+    /// Arguments of the call
+    #[serde(default)]
+    pub args: JsonDict,
+}
+
+/// A single `_jobinfo` entry that differs between two reads.
+/// `None` means the key was absent in that read.
+#[derive(Debug, Clone, PartialEq)]
+pub struct JobInfoChange {
+    pub old: Option<Json>,
+    pub new: Option<Json>,
+}
+
+/// What changed in `_jobinfo` between two reads, keyed by the jobinfo key
+/// (e.g. `threads`, `memGB`). Empty if nothing changed.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct JobInfoDiff(BTreeMap<String, JobInfoChange>);
+
+impl JobInfoDiff {
+    fn between(old: &JsonDict, new: &JsonDict) -> Self {
+        let mut diff = BTreeMap::new();
+        for key in old.keys().chain(new.keys()) {
+            let (old_val, new_val) = (old.get(key), new.get(key));
+            if old_val != new_val {
+                diff.insert(
+                    key.clone(),
+                    JobInfoChange {
+                        old: old_val.cloned(),
+                        new: new_val.cloned(),
+                    },
+                );
+            }
+        }
+        JobInfoDiff(diff)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn get(&self, key: &str) -> Option<&JobInfoChange> {
+        self.0.get(key)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &JobInfoChange)> {
+        self.0.iter()
+    }
 }
 
 pub fn make_timestamp(datetime: DateTime<Local>) -> String {
@@ -40,7 +251,23 @@ pub fn make_timestamp_now() -> String {
 }
 
 impl<'a> Metadata<'a> {
+    /// Metadata logging to `log_file`, which writes the errors to the `_errors`
+    /// file in the metadata directory (`args[2]`)
     pub fn new(args: Vec<String>, log_file: &'a File) -> Metadata {
+        let error_sink = IoSink::lazy_file(Path::new(&args[2]).join("_errors"));
+        Metadata::with_log_file(args, LogFile::File(log_file), error_sink)
+    }
+
+    /// Same as `new`, writing to the log and error sinks resolved by `AdapterIo`
+    pub(crate) fn with_sinks(
+        args: Vec<String>,
+        log_sink: IoSink,
+        error_sink: IoSink,
+    ) -> Metadata<'a> {
+        Metadata::with_log_file(args, LogFile::Sink(log_sink), error_sink)
+    }
+
+    fn with_log_file(args: Vec<String>, log_file: LogFile<'a>, error_sink: IoSink) -> Metadata<'a> {
         // # Take options from command line.
         // shell_cmd, stagecode_path, metadata_path, files_path, run_file = argv
         let md = Metadata {
@@ -51,21 +278,139 @@ impl<'a> Metadata<'a> {
             run_file: args[4].clone(),
             cache: HashSet::new(),
             jobinfo: Map::new(),
+            job_info: JobInfo::default(),
             log_file: log_file,
+            error_sink,
+            large_int_policy: LargeIntPolicy::default(),
+            last_progress: None,
+            perf_timer: None,
         };
 
         md
     }
 
-    /// Path within chunk
+    /// Metadata rooted at an on-disk pipestance directory, for debugging a stage
+    /// against the recorded outputs of a previous run without running the whole
+    /// pipeline. Use `read_stage_outs` to load the outputs of a stage within it.
+    pub fn from_pipestance_path(
+        path: impl AsRef<Path>,
+        log_file: &'a File,
+    ) -> Result<Metadata<'a>> {
+        let path = path.as_ref();
+        if !path.is_dir() {
+            return Err(format_err!(
+                "Pipestance directory {} does not exist",
+                path.display()
+            ));
+        }
+        let path_str = |p: PathBuf| {
+            p.to_str()
+                .map(String::from)
+                .ok_or_else(|| format_err!("Invalid pipestance path {}", p.display()))
+        };
+        let stage_name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or_default()
+            .to_string();
+        Ok(Metadata {
+            stage_name,
+            stage_type: "main".to_string(),
+            metadata_path: path_str(path.to_path_buf())?,
+            files_path: path_str(path.join("files"))?,
+            run_file: path_str(path.join("_run"))?,
+            cache: HashSet::new(),
+            jobinfo: Map::new(),
+            job_info: JobInfo::default(),
+            log_file: LogFile::File(log_file),
+            // Not a stage run by mrp, whose _errors should be left alone
+            error_sink: IoSink::new(io::stderr()),
+            large_int_policy: LargeIntPolicy::default(),
+            last_progress: None,
+            perf_timer: None,
+        })
+    }
+
+    /// Read the `_outs` of a stage, given its path relative to the pipestance,
+    /// e.g. `SUM_SQUARES` or `SUM_SQUARES/fork1`. If the path does not name a
+    /// fork, the outputs of `fork0` are read.
+    pub fn read_stage_outs<T: DeserializeOwned>(
+        &self,
+        stage_rel_path: impl AsRef<Path>,
+    ) -> Result<T> {
+        let stage_path = Path::new(&self.metadata_path).join(stage_rel_path);
+        if !stage_path.is_dir() {
+            return Err(format_err!(
+                "Stage directory {} does not exist",
+                stage_path.display()
+            ));
+        }
+        let outs_path = [
+            stage_path.join("_outs"),
+            stage_path.join("fork0").join("_outs"),
+        ]
+        .iter()
+        .find(|p| p.is_file())
+        .cloned()
+        .ok_or_else(|| {
+            format_err!(
+                "No _outs found for stage {}. Has the stage completed?",
+                stage_path.display()
+            )
+        })?;
+        let mut buf = String::new();
+        File::open(&outs_path)?.read_to_string(&mut buf)?;
+        serde_json::from_str(&buf).map_err(|e| {
+            format_err!(
+                "Unable to parse the stage outputs in {}: {}",
+                outs_path.display(),
+                e
+            )
+        })
+    }
+
+    /// Index of the chunk, if this is the main of a chunk. Martian runs
+    /// chunk `N` of a stage within a `chnkN` directory.
+    pub fn chunk_index(&self) -> Option<usize> {
+        let dir_name = PathBuf::from(&self.metadata_path)
+            .file_name()?
+            .to_str()?
+            .to_string();
+        if self.stage_type != "main" || !dir_name.starts_with("chnk") {
+            return None;
+        }
+        dir_name["chnk".len()..].parse().ok()
+    }
+
+    /// Path of the metadata file `_<name>` of the chunk, e.g `_outs`. Output
+    /// files of the stage go in the files directory, see `make_files_path`.
     pub fn make_path(&self, name: &str) -> PathBuf {
         let mut pb = PathBuf::from(self.metadata_path.clone());
         pb.push(METADATA_PREFIX.to_string() + name);
         pb
     }
 
+    /// Path of the file `filename` in the files directory of the chunk. For a
+    /// type implementing `MartianFileType`, the extension of the filetype is
+    /// added unless `filename` already has it. Same as `MartianRover::make_path`.
+    pub fn make_files_path<T>(&self, filename: impl AsRef<Path>) -> T
+    where
+        T: MartianMakePath,
+    {
+        <T as MartianMakePath>::make_path(&self.files_path, filename)
+    }
+
+    /// A new temporary directory within the files directory of the chunk, which
+    /// is removed when it is dropped. Prefer this over `/tmp`, which is small on
+    /// most cluster nodes.
+    pub fn temp_dir(&self) -> Result<ScopedTempDir> {
+        Ok(ScopedTempDir::new_in(Path::new(&self.files_path))?)
+    }
+
     /// Write to a file inside the chunk
     pub fn write_raw(&mut self, name: &str, text: String) -> Result<()> {
+        // The process must not exit with a partially written file, e.g `_outs`
+        let _guard = signals::outputs_guard();
         let mut f = File::create(self.make_path(name))?;
         f.write(text.as_bytes())?;
         self.update_journal(name)?;
@@ -80,25 +425,31 @@ impl<'a> Metadata<'a> {
             name.to_string()
         };
 
-        if !self.cache.contains(name) || force {
+        if !self.cache.contains(&journal_name) || force {
             let run_file = format!("{}.{}", self.run_file, journal_name);
-            let tmp_run_file = run_file.clone() + ".tmp";
-
-            {
-                let mut f = File::create(&tmp_run_file)?;
-                f.write(make_timestamp_now().as_bytes())?;
-            };
-            rename(&tmp_run_file, &run_file)?;
+            with_retries(|| write_journal_file(&run_file)).map_err(|e| {
+                format_err!("Failed to update the journal file {}: {}", run_file, e)
+            })?;
             self.cache.insert(journal_name);
         }
 
         Ok(())
     }
 
-    fn update_journal(&mut self, name: &str) -> Result<()> {
+    /// Tell mrp that the metadata file `_<name>` of the chunk was updated, by
+    /// writing the journal file which mrp watches. The journal is only written
+    /// the first time a name is updated. The journal is often on NFS, so failed
+    /// writes are retried a few times before giving up.
+    pub fn update_journal(&mut self, name: &str) -> Result<()> {
         self.update_journal_main(name, false)
     }
 
+    /// Tell mrp that the stage is still alive. `martian_main` does this every
+    /// `monitor::HEARTBEAT_INTERVAL` while the stage runs.
+    pub fn heartbeat(&mut self) -> Result<()> {
+        self.update_journal_main("heartbeat", true)
+    }
+
     /*
     fn write_json(&mut self, name: &str, object: &Json) {
         // Serialize using `json::encode`
@@ -116,6 +467,39 @@ impl<'a> Metadata<'a> {
         Ok(())
     }
 
+    /// Write the stage outputs to `_outs`. With `canonical`, the keys of every
+    /// object are sorted (see `utils::canonical_json_encode`), so that the
+    /// outputs are reproducible byte for byte. The adapter writes the outputs of
+    /// a stage with this, canonical if `MartianStage::canonical_outs` is set.
+    /// Large integers are handled as set using `set_large_int_policy`.
+    pub fn write_outs<T: Serialize>(&mut self, outs: &T, canonical: bool) -> Result<()> {
+        let mut outs = serde_json::to_value(outs)?;
+        self.check_large_ints("outs", &mut outs)?;
+        let encoded = if canonical {
+            canonical_json_encode(&outs)?
+        } else {
+            serde_json::to_string_pretty(&outs)?
+        };
+        self.write_raw("outs", encoded)
+    }
+
+    /// How integers larger than `MAX_SAFE_JSON_INT` are written to the outputs
+    /// and chunk definitions. Defaults to `LargeIntPolicy::Allow`
+    pub fn set_large_int_policy(&mut self, policy: LargeIntPolicy) {
+        self.large_int_policy = policy;
+    }
+
+    fn check_large_ints(&self, name: &str, value: &mut Json) -> Result<()> {
+        let paths = self.large_int_policy.apply(name, value)?;
+        if self.large_int_policy == LargeIntPolicy::Warn && !paths.is_empty() {
+            self.warn(&format!(
+                "Integers larger than 2^53 lose precision when read by martian: {}",
+                paths.join(", ")
+            ));
+        }
+        Ok(())
+    }
+
     pub(crate) fn read_json(&self, name: &str) -> Result<Json> {
         let mut f = File::open(self.make_path(name))?;
         let mut buf = String::new();
@@ -130,14 +514,42 @@ impl<'a> Metadata<'a> {
         Ok(r.as_object().unwrap().clone())
     }
 
-    pub(crate) fn read_json_obj_array(&self, name: &str) -> Result<Vec<JsonDict>> {
-        let json = self.read_json(name)?;
-        let arr = json.as_array().unwrap();
-        let r: Vec<JsonDict> = arr
-            .into_iter()
-            .map(|o| o.as_object().unwrap().clone())
-            .collect();
-        Ok(r)
+    /// Deserialize `_args`. If a field has the wrong type, the error names it,
+    /// e.g `Invalid _args at values[2]: invalid type: string "x", expected f64`
+    pub fn read_args<T: DeserializeOwned>(&self) -> Result<T> {
+        decode_json("args", self.read_json("args")?)
+    }
+
+    /// Deserialize `_chunk_defs`, the inputs of every chunk, in the join
+    pub fn read_chunk_defs<T: DeserializeOwned>(&self) -> Result<Vec<T>> {
+        decode_json("chunk_defs", self.read_json("chunk_defs")?)
+    }
+
+    /// Deserialize `_chunk_outs`, the outputs of every chunk, in the join
+    pub fn read_chunk_outs<T: DeserializeOwned>(&self) -> Result<Vec<T>> {
+        decode_json("chunk_outs", self.read_json("chunk_outs")?)
+    }
+
+    /// Write the chunk definitions returned by the split to `_stage_defs`.
+    /// Large integers are handled as set using `set_large_int_policy`.
+    pub fn write_stage_defs<T: Serialize>(&mut self, stage_defs: &T) -> Result<()> {
+        let mut value = serde_json::to_value(stage_defs)?;
+        self.check_large_ints("stage_defs", &mut value)?;
+        self.write_raw("stage_defs", serde_json::to_string_pretty(&value)?)
+    }
+
+    /// Read `_args` on top of `defaults` and deserialize the result. The args are
+    /// deep merged with the defaults, with the args winning:
+    /// - Objects are merged recursively, keys missing in the args are taken
+    ///   from the defaults
+    /// - A `null` in the args keeps the default. Martian writes every input of
+    ///   the stage to `_args`, with `null` for those which are not set.
+    /// - Any other value in the args (scalars and arrays) replaces the default as
+    ///   a whole. Arrays are not merged element wise.
+    pub fn read_args_with_defaults<T: DeserializeOwned>(&self, defaults: &Json) -> Result<T> {
+        let mut merged = defaults.clone();
+        merge_json(&mut merged, self.read_json("args")?);
+        decode_json("args", merged)
     }
 
     fn _append(&mut self, name: &str, message: &str) -> Result<()> {
@@ -154,11 +566,8 @@ impl<'a> Metadata<'a> {
 
     /// Write to _log
     pub fn log(&mut self, level: &str, message: &str) -> Result<()> {
-        let _ = self
-            .log_file
-            .write(&format!("{} [{}] {}", make_timestamp_now(), level, message).as_bytes())
-            .and(self.log_file.flush())?;
-
+        self.log_file
+            .write_all(format!("{} [{}] {}", make_timestamp_now(), level, message).as_bytes())?;
         Ok(())
     }
 
@@ -166,49 +575,880 @@ impl<'a> Metadata<'a> {
         self.log("time", message)
     }
 
+    /// Report a problem which does not fail the stage, but which the user should
+    /// know about, e.g a low quality input. Alarms are appended to `_alarm` and
+    /// shown by mrp, unlike warnings which only end up in the log.
     pub fn alarm(&mut self, message: &str) -> Result<()> {
+        warn!("ALARM: {}", message);
         self._append("alarm", &format!("{} {}", make_timestamp_now(), message))
     }
 
+    /// Report that `done` out of `total` units of work are done, e.g reads or
+    /// records, in `_progress` for mrp to display. It can be called as often as
+    /// needed: `_progress` is written at most every `PROGRESS_INTERVAL`, and when
+    /// all the work is done.
+    pub fn report_progress(&mut self, done: u64, total: u64) -> Result<()> {
+        let now = Instant::now();
+        let due = match self.last_progress {
+            Some(last) => now.duration_since(last) >= PROGRESS_INTERVAL,
+            None => true,
+        };
+        if !due && done < total {
+            return Ok(());
+        }
+        self.last_progress = Some(now);
+        let percent = if total == 0 {
+            100.0
+        } else {
+            100.0 * done as f64 / total as f64
+        };
+        let progress = format!("{}/{} ({:.1}%)", done, total, percent);
+        File::create(self.make_path("progress"))?.write_all(progress.as_bytes())?;
+        // Unlike the other files, the progress is updated many times
+        self.update_journal_main("progress", true)
+    }
+
+    /// Report a warning which does not fail the stage. It is written to stderr,
+    /// which martian captures into `_stderr`, with every line prefixed with
+    /// `WARNING:`, and logged at the `warn` level.
+    pub fn warn(&self, message: &str) {
+        warn!("{}", message);
+        let _ = write_warning(&mut io::stderr(), message);
+    }
+
     pub fn assert(&mut self, message: &str) -> Result<()> {
-        write_errors(&format!("ASSERT:{} {}", make_timestamp_now(), message))
+        self.write_errors(&format!("ASSERT:{} {}", make_timestamp_now(), message))
     }
 
-    /// Write finalized _jobinfo data
-    pub fn update_jobinfo(&mut self) -> Result<()> {
+    /// Write to `_errors`, which fails the stage
+    pub(crate) fn write_errors(&self, message: &str) -> Result<()> {
+        let mut sink = self.error_sink.clone();
+        sink.write_all(message.as_bytes())?;
+        sink.flush()?;
+        Ok(())
+    }
+
+    fn read_jobinfo(&self) -> Result<JsonDict> {
         let mut jobinfo = self.read_json_obj("jobinfo")?;
 
         let exe = env::current_exe()?.to_str().unwrap().to_string();
         jobinfo.insert("rust_exe".to_string(), Value::String(exe));
         // jobinfo.insert("rust_version", sys.version);
+        Ok(jobinfo)
+    }
 
-        self.write_json_obj("jobinfo", &jobinfo)?;
+    /// Keep the raw `jobinfo` along with its typed contents
+    fn set_jobinfo(&mut self, jobinfo: JsonDict) -> Result<()> {
+        self.job_info = serde_json::from_value(Value::Object(jobinfo.clone()))
+            .map_err(|e| format_err!("Invalid _jobinfo: {}", e))?;
         self.jobinfo = jobinfo;
         Ok(())
     }
 
+    /// Write finalized _jobinfo data
+    pub fn update_jobinfo(&mut self) -> Result<()> {
+        let jobinfo = self.read_jobinfo()?;
+        self.write_json_obj("jobinfo", &jobinfo)?;
+        self.set_jobinfo(jobinfo)
+    }
+
+    /// The contents of `_jobinfo` as of the last `update_jobinfo` or `refresh_jobinfo`
+    pub fn jobinfo(&self) -> &JobInfo {
+        &self.job_info
+    }
+
+    /// Add entries to `_jobinfo`, e.g the performance data of the stage. The file
+    /// is replaced atomically, so that mrp never reads a partial `_jobinfo`.
+    pub(crate) fn extend_jobinfo(&mut self, entries: JsonDict) -> Result<()> {
+        let mut jobinfo = self.read_json_obj("jobinfo")?;
+        jobinfo.extend(entries);
+        let path = self.make_path("jobinfo");
+        let tmp_path = self.make_path("jobinfo.tmp");
+        std::fs::write(&tmp_path, serde_json::to_string_pretty(&jobinfo)?)?;
+        rename(&tmp_path, &path)?;
+        self.update_journal("jobinfo")?;
+        self.set_jobinfo(jobinfo)
+    }
+
+    /// Start measuring the performance of the stage, which is recorded in
+    /// `_jobinfo` by `complete` or `record_perf`
+    pub(crate) fn start_perf_timer(&mut self) {
+        self.perf_timer = Some(PerfTimer::start());
+    }
+
+    /// Record the performance of the stage since `start_perf_timer` in `_jobinfo`,
+    /// for `_perf`. Does nothing if it is not measured, or was already recorded
+    /// by this copy of the metadata.
+    pub(crate) fn record_perf(&mut self) -> Result<()> {
+        match self.perf_timer.take() {
+            Some(timer) => self.extend_jobinfo(timer.finish()),
+            None => Ok(()),
+        }
+    }
+
+    /// Re-read _jobinfo, for long running stages whose resources may be
+    /// reallocated by the runtime. Returns what changed since the last read,
+    /// which is empty if nothing changed.
+    pub fn refresh_jobinfo(&mut self) -> Result<JobInfoDiff> {
+        let jobinfo = self.read_jobinfo()?;
+        let diff = JobInfoDiff::between(&self.jobinfo, &jobinfo);
+        self.set_jobinfo(jobinfo)?;
+        Ok(diff)
+    }
+
     /// Completed successfully
     pub fn complete(&mut self) {
-        unsafe {
-            File::from_raw_fd(4); // Close the error file descriptor.
+        // mrp may collect the stage as soon as it is complete
+        if let Err(e) = self.record_perf() {
+            error!("Failed to record the performance data in _jobinfo: {}", e);
         }
+        self.error_sink.close();
     }
 
     /// Get the amount of memory in GB allocated to this job by the runtime.
     pub fn get_memory_allocation(&self) -> usize {
-        self.jobinfo.get("memGB").and_then(|x| x.as_u64()).unwrap() as usize
+        self.allocation(self.job_info.mem_gb, "__mem_gb") as usize
     }
 
-    /// Get the number of threads allocated to this job by the runtime.
+    /// Get the number of threads allocated to this job by the runtime. A fractional
+    /// allocation is rounded up, since a job always gets at least one thread.
     pub fn get_threads_allocation(&self) -> usize {
-        self.jobinfo
-            .get("threads")
-            .and_then(|x| x.as_u64())
-            .unwrap() as usize
+        self.allocation(self.job_info.threads, "__threads").ceil() as usize
     }
 
     /// Get the amount of virtual memory in GB allocated to this job by the runtime.
     pub fn get_virtual_memory_allocation(&self) -> usize {
-        self.jobinfo.get("vmemGB").and_then(|x| x.as_u64()).unwrap() as usize
+        self.allocation(self.job_info.vmem_gb, "__vmem_gb") as usize
+    }
+
+    // The allocation in `_jobinfo`, which is what the job actually got, or else
+    // the request of the chunk in `_args`
+    fn allocation(&self, jobinfo: Option<f64>, args_key: &str) -> f64 {
+        jobinfo
+            .or_else(|| {
+                let args = self.read_json("args").ok()?;
+                args.get(args_key)?.as_f64()
+            })
+            .unwrap_or_else(|| {
+                panic!(
+                    "No {} allocation in _jobinfo or _args of {}",
+                    args_key.trim_start_matches('_'),
+                    self.metadata_path
+                )
+            })
+    }
+}
+
+/// Deserialize the contents of the metadata file `_<name>`, with an error which
+/// names the field that failed
+pub(crate) fn decode_json<T: DeserializeOwned>(name: &str, value: Json) -> Result<T> {
+    serde_path_to_error::deserialize(value).map_err(|e| {
+        let path = e.path().to_string();
+        if path == "." {
+            format_err!("Invalid _{}: {}", name, e.inner())
+        } else {
+            format_err!("Invalid _{} at {}: {}", name, path, e.inner())
+        }
+    })
+}
+
+/// Minimum time between two writes of `_progress`
+pub const PROGRESS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Number of attempts to write a journal file
+const JOURNAL_ATTEMPTS: u32 = 4;
+/// Wait before the second attempt, doubled after each failed attempt
+const JOURNAL_BACKOFF: Duration = Duration::from_millis(50);
+
+/// Write the current time to the journal file `run_file`. The file is written
+/// in full and synced before it is renamed, so that mrp never sees it partially
+/// written.
+fn write_journal_file(run_file: &str) -> io::Result<()> {
+    let tmp_run_file = format!("{}.tmp", run_file);
+    {
+        let mut f = File::create(&tmp_run_file)?;
+        f.write_all(make_timestamp_now().as_bytes())?;
+        f.sync_all()?;
+    }
+    rename(&tmp_run_file, run_file)
+}
+
+/// Run `f` until it succeeds, up to `JOURNAL_ATTEMPTS` times with an exponential
+/// backoff, to ride out the transient errors of network filesystems
+fn with_retries<T>(mut f: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    let mut backoff = JOURNAL_BACKOFF;
+    for _ in 1..JOURNAL_ATTEMPTS {
+        match f() {
+            Ok(value) => return Ok(value),
+            Err(e) => {
+                warn!("Retrying after journal update error: {}", e);
+                thread::sleep(backoff);
+                backoff *= 2;
+            }
+        }
+    }
+    f()
+}
+
+// The metadata of the stage run by `martian_main`, for `alarm`, `report_progress`
+// and reporting errors outside of the stage code
+static STAGE_METADATA: Mutex<Option<Metadata<'static>>> = Mutex::new(None);
+
+pub(crate) fn set_stage_metadata(md: Metadata<'static>) {
+    *STAGE_METADATA.lock().unwrap_or_else(|e| e.into_inner()) = Some(md);
+}
+
+/// Raise an alarm for the stage run by `martian_main`, see `Metadata::alarm`.
+/// This is what the `alarm!` macro calls. Outside of `martian_main`, e.g in a
+/// unit test of the stage, the alarm is only logged.
+pub fn alarm(message: &str) -> Result<()> {
+    match *STAGE_METADATA.lock().unwrap_or_else(|e| e.into_inner()) {
+        Some(ref mut md) => md.alarm(message),
+        None => {
+            warn!("ALARM: {}", message);
+            Ok(())
+        }
+    }
+}
+
+/// Write to the `_errors` of the stage run by `martian_main`, e.g the error which
+/// failed it or a panic. Outside of `martian_main`, this writes to stderr.
+pub(crate) fn write_stage_errors(message: &str) -> Result<()> {
+    match *STAGE_METADATA.lock().unwrap_or_else(|e| e.into_inner()) {
+        Some(ref md) => md.write_errors(message),
+        None => {
+            let mut stderr = io::stderr();
+            stderr.write_all(message.as_bytes())?;
+            stderr.flush()?;
+            Ok(())
+        }
+    }
+}
+
+/// Report the progress of the stage run by `martian_main`, see
+/// `Metadata::report_progress`. Outside of `martian_main`, this does nothing.
+pub fn report_progress(done: u64, total: u64) -> Result<()> {
+    match *STAGE_METADATA.lock().unwrap_or_else(|e| e.into_inner()) {
+        Some(ref mut md) => md.report_progress(done, total),
+        None => Ok(()),
+    }
+}
+
+fn write_warning(out: &mut impl Write, message: &str) -> io::Result<()> {
+    for line in message.lines() {
+        writeln!(out, "{} {}", WARNING_PREFIX, line)?;
+    }
+    out.flush()
+}
+
+/// Deep merge `value` into `base`. See `Metadata::read_args_with_defaults`
+fn merge_json(base: &mut Json, value: Json) {
+    match (base, value) {
+        (Value::Object(base), Value::Object(value)) => {
+            for (key, v) in value {
+                match base.get_mut(&key) {
+                    Some(b) => merge_json(b, v),
+                    None => {
+                        base.insert(key, v);
+                    }
+                }
+            }
+        }
+        // An unset input, which keeps the default
+        (_, Value::Null) => {}
+        (base, value) => *base = value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_jobinfo(dir: &std::path::Path, jobinfo: Json) {
+        let mut f = File::create(dir.join("_jobinfo")).unwrap();
+        f.write_all(jobinfo.to_string().as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn test_stage_type_parse() {
+        assert_eq!("split".parse::<StageType>().unwrap(), StageType::Split);
+        assert_eq!("main".parse::<StageType>().unwrap(), StageType::Main);
+        assert_eq!("join".parse::<StageType>().unwrap(), StageType::Join);
+        let err = "chunk".parse::<StageType>().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Unrecognized stage type chunk. Expected one of split, main or join"
+        );
+        assert!("Main".parse::<StageType>().is_err());
+    }
+
+    #[test]
+    fn test_large_int_policy() {
+        let outs = json!({
+            "safe": MAX_SAFE_JSON_INT,
+            "total": MAX_SAFE_JSON_INT + 2,
+            "offsets": [-1, -(MAX_SAFE_JSON_INT as i64) - 1],
+            "nested": {"max": u64::MAX},
+        });
+        let large = vec![
+            "outs.nested.max".to_string(),
+            "outs.offsets[1]".to_string(),
+            "outs.total".to_string(),
+        ];
+
+        let mut value = outs.clone();
+        assert_eq!(
+            LargeIntPolicy::Allow.apply("outs", &mut value).unwrap(),
+            vec![] as Vec<String>
+        );
+        assert_eq!(
+            LargeIntPolicy::Warn.apply("outs", &mut value).unwrap(),
+            large
+        );
+        assert_eq!(value, outs);
+
+        let err = LargeIntPolicy::Error.apply("outs", &mut value).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Integers larger than 2^53 cannot be represented exactly by martian: \
+             outs.nested.max, outs.offsets[1], outs.total"
+        );
+
+        assert_eq!(
+            LargeIntPolicy::Stringify.apply("outs", &mut value).unwrap(),
+            large
+        );
+        assert_eq!(
+            value,
+            json!({
+                "safe": MAX_SAFE_JSON_INT,
+                "total": "9007199254740993",
+                "offsets": [-1, "-9007199254740992"],
+                "nested": {"max": "18446744073709551615"},
+            })
+        );
+    }
+
+    #[test]
+    fn test_chunk_index() {
+        let tmp_dir = tempdir::TempDir::new("test_chunk_index").unwrap();
+        let log_file = File::create(tmp_dir.path().join("_log")).unwrap();
+        let make_args = |stage_type: &str, path: &str| {
+            vec![
+                "stage".to_string(),
+                stage_type.to_string(),
+                path.to_string(),
+                format!("{}/files", path),
+                format!("{}/_run", path),
+            ]
+        };
+        let md = Metadata::new(make_args("main", "/STAGE/fork0/chnk3"), &log_file);
+        assert_eq!(md.chunk_index(), Some(3));
+        let md = Metadata::new(make_args("join", "/STAGE/fork0/join"), &log_file);
+        assert_eq!(md.chunk_index(), None);
+        let md = Metadata::new(make_args("split", "/STAGE/fork0/split"), &log_file);
+        assert_eq!(md.chunk_index(), None);
+    }
+
+    #[test]
+    fn test_read_args() {
+        #[derive(Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+        struct ChunkDef {
+            values: Vec<f64>,
+        }
+
+        let tmp_dir = tempdir::TempDir::new("test_read_args").unwrap();
+        let dir = tmp_dir.path();
+        let log_file = File::create(dir.join("_log")).unwrap();
+        let path = dir.to_str().unwrap().to_string();
+        let args = vec![
+            "stage".to_string(),
+            "join".to_string(),
+            path.clone(),
+            path.clone(),
+            dir.join("_run").to_str().unwrap().to_string(),
+        ];
+        let mut md = Metadata::new(args, &log_file);
+        let write = |name: &str, value: Json| {
+            std::fs::write(dir.join(name), value.to_string()).unwrap();
+        };
+
+        write("_args", json!({"values": [1.0, 2.0]}));
+        let args: ChunkDef = md.read_args().unwrap();
+        assert_eq!(args.values, vec![1.0, 2.0]);
+        write("_args", json!({"values": [1.0, "x"]}));
+        let err = md.read_args::<ChunkDef>().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid _args at values[1]: invalid type: string \"x\", expected f64"
+        );
+        write("_args", json!({}));
+        let err = md.read_args::<ChunkDef>().unwrap_err();
+        assert_eq!(err.to_string(), "Invalid _args: missing field `values`");
+
+        write(
+            "_chunk_defs",
+            json!([{"values": [1.0]}, {"values": [2.0, 3.0]}]),
+        );
+        let chunk_defs: Vec<ChunkDef> = md.read_chunk_defs().unwrap();
+        assert_eq!(chunk_defs[1].values, vec![2.0, 3.0]);
+        write("_chunk_outs", json!([{"values": []}, {"values": null}]));
+        let err = md.read_chunk_outs::<ChunkDef>().unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Invalid _chunk_outs at [1].values: invalid type: null"));
+
+        md.write_stage_defs(&json!({"chunks": chunk_defs})).unwrap();
+        let stage_defs = md.read_json("stage_defs").unwrap();
+        assert_eq!(stage_defs["chunks"][0]["values"], json!([1.0]));
+        assert!(dir.join("_run.join_stage_defs").exists());
+    }
+
+    #[test]
+    fn test_make_files_path() {
+        let tmp_dir = tempdir::TempDir::new("test_make_files_path").unwrap();
+        let log_file = File::create(tmp_dir.path().join("_log")).unwrap();
+        let args = vec![
+            "stage".to_string(),
+            "main".to_string(),
+            "/STAGE/fork0/chnk0".to_string(),
+            "/STAGE/fork0/chnk0/files".to_string(),
+            "/STAGE/fork0/chnk0/_run".to_string(),
+        ];
+        let md = Metadata::new(args, &log_file);
+        assert_eq!(md.make_path("outs"), Path::new("/STAGE/fork0/chnk0/_outs"));
+        let path: PathBuf = md.make_files_path("reads.fastq");
+        assert_eq!(path, Path::new("/STAGE/fork0/chnk0/files/reads.fastq"));
+        let path: String = md.make_files_path("summary.json");
+        assert_eq!(path, "/STAGE/fork0/chnk0/files/summary.json");
+    }
+
+    #[test]
+    fn test_read_stage_outs() {
+        #[derive(Debug, PartialEq, serde::Deserialize)]
+        struct SumOuts {
+            sum: f64,
+        }
+
+        let tmp_dir = tempdir::TempDir::new("test_read_stage_outs").unwrap();
+        let pipestance = tmp_dir.path().join("PIPESTANCE");
+        let log_file = File::create(tmp_dir.path().join("_log")).unwrap();
+        assert!(Metadata::from_pipestance_path(&pipestance, &log_file).is_err());
+
+        for (fork, sum) in &[("fork0", 5.0), ("fork1", 14.0)] {
+            let fork_path = pipestance.join("SUM_SQUARES").join(fork);
+            std::fs::create_dir_all(&fork_path).unwrap();
+            let mut f = File::create(fork_path.join("_outs")).unwrap();
+            f.write_all(json!({ "sum": sum }).to_string().as_bytes())
+                .unwrap();
+        }
+        std::fs::create_dir_all(pipestance.join("REPORT/fork0")).unwrap();
+
+        let md = Metadata::from_pipestance_path(&pipestance, &log_file).unwrap();
+        assert_eq!(md.stage_name, "PIPESTANCE");
+        assert_eq!(
+            md.read_stage_outs::<SumOuts>("SUM_SQUARES").unwrap(),
+            SumOuts { sum: 5.0 }
+        );
+        assert_eq!(
+            md.read_stage_outs::<SumOuts>("SUM_SQUARES/fork1").unwrap(),
+            SumOuts { sum: 14.0 }
+        );
+        let err = md.read_stage_outs::<SumOuts>("REPORT").unwrap_err();
+        assert!(err.to_string().starts_with("No _outs found for stage"));
+        let err = md.read_stage_outs::<SumOuts>("MISSING").unwrap_err();
+        assert!(err.to_string().starts_with("Stage directory"));
+    }
+
+    #[test]
+    fn test_read_args_with_defaults() {
+        #[derive(Debug, PartialEq, serde::Deserialize)]
+        struct Filter {
+            min_len: u32,
+            max_len: u32,
+        }
+        #[derive(Debug, PartialEq, serde::Deserialize)]
+        struct Args {
+            threshold: f64,
+            name: String,
+            filter: Filter,
+            samples: Vec<String>,
+            note: Option<String>,
+        }
+
+        let tmp_dir = tempdir::TempDir::new("test_read_args_with_defaults").unwrap();
+        let dir = tmp_dir.path();
+        let log_file = File::create(dir.join("_log")).unwrap();
+        let path = dir.to_str().unwrap().to_string();
+        let args = vec![
+            "stage".to_string(),
+            "main".to_string(),
+            path.clone(),
+            path.clone(),
+            dir.join("_run").to_str().unwrap().to_string(),
+        ];
+        let md = Metadata::new(args, &log_file);
+        let mut f = File::create(dir.join("_args")).unwrap();
+        let args = json!({
+            "threshold": 0.5,
+            "filter": { "max_len": 100 },
+            "samples": ["c"],
+        });
+        f.write_all(args.to_string().as_bytes()).unwrap();
+
+        let defaults = json!({
+            "threshold": 0.1,
+            "name": "default",
+            "filter": { "min_len": 10, "max_len": 50 },
+            "samples": ["a", "b"],
+        });
+        assert_eq!(
+            md.read_args_with_defaults::<Args>(&defaults).unwrap(),
+            Args {
+                // Scalar override
+                threshold: 0.5,
+                name: "default".into(),
+                // Nested object merge
+                filter: Filter {
+                    min_len: 10,
+                    max_len: 100
+                },
+                // Array overwrite
+                samples: vec!["c".into()],
+                note: None,
+            }
+        );
+
+        // Martian writes null for the inputs which are not set, which keep the default
+        let args = json!({
+            "threshold": null,
+            "name": null,
+            "filter": { "min_len": null, "max_len": 100 },
+            "samples": null,
+            "note": null,
+        });
+        std::fs::write(dir.join("_args"), args.to_string()).unwrap();
+        assert_eq!(
+            md.read_args_with_defaults::<Args>(&defaults).unwrap(),
+            Args {
+                threshold: 0.1,
+                name: "default".into(),
+                filter: Filter {
+                    min_len: 10,
+                    max_len: 100
+                },
+                samples: vec!["a".into(), "b".into()],
+                note: None,
+            }
+        );
+
+        // Missing required fields are still an error
+        assert!(md.read_args_with_defaults::<Args>(&json!({})).is_err());
+    }
+
+    #[test]
+    fn test_write_warning() {
+        let mut stderr = Vec::new();
+        write_warning(&mut stderr, "low coverage").unwrap();
+        write_warning(&mut stderr, "few cells\nin sample 2").unwrap();
+        assert_eq!(
+            String::from_utf8(stderr).unwrap(),
+            "WARNING: low coverage\nWARNING: few cells\nWARNING: in sample 2\n"
+        );
+    }
+
+    #[test]
+    fn test_perf_recorded_before_complete() {
+        // Records whether `_jobinfo` had the performance data when it was closed
+        struct ErrorsFd {
+            jobinfo: PathBuf,
+            perf_when_closed: std::sync::Arc<Mutex<Option<bool>>>,
+        }
+        impl Write for ErrorsFd {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        impl Drop for ErrorsFd {
+            fn drop(&mut self) {
+                let jobinfo = std::fs::read_to_string(&self.jobinfo).unwrap();
+                *self.perf_when_closed.lock().unwrap() = Some(jobinfo.contains("wallclock"));
+            }
+        }
+
+        let tmp_dir = tempdir::TempDir::new("test_perf_recorded_before_complete").unwrap();
+        let dir = tmp_dir.path();
+        let path = dir.to_str().unwrap().to_string();
+        let args = vec![
+            "stage".to_string(),
+            "main".to_string(),
+            path.clone(),
+            path.clone(),
+            dir.join("_run").to_str().unwrap().to_string(),
+        ];
+        std::fs::write(dir.join("_jobinfo"), r#"{"threads": 1}"#).unwrap();
+        let perf_when_closed = std::sync::Arc::new(Mutex::new(None));
+        let errors = ErrorsFd {
+            jobinfo: dir.join("_jobinfo"),
+            perf_when_closed: perf_when_closed.clone(),
+        };
+        let mut md = Metadata::with_sinks(args, IoSink::new(io::sink()), IoSink::new(errors));
+        md.update_jobinfo().unwrap();
+        md.start_perf_timer();
+        md.complete();
+
+        assert_eq!(*perf_when_closed.lock().unwrap(), Some(true));
+        assert!(md.jobinfo.contains_key("rusage"));
+        assert!(!dir.join("_jobinfo.tmp").exists());
+        assert!(dir.join("_run.jobinfo").exists());
+    }
+
+    #[test]
+    fn test_update_journal() {
+        let tmp_dir = tempdir::TempDir::new("test_update_journal").unwrap();
+        let dir = tmp_dir.path();
+        let log_file = File::create(dir.join("_log")).unwrap();
+        let path = dir.to_str().unwrap().to_string();
+        let make_args = |stage_type: &str, run_file: &Path| {
+            vec![
+                "stage".to_string(),
+                stage_type.to_string(),
+                path.clone(),
+                path.clone(),
+                run_file.to_str().unwrap().to_string(),
+            ]
+        };
+        let mut md = Metadata::new(make_args("split", &dir.join("_run")), &log_file);
+        md.update_journal("stage_defs").unwrap();
+        let journal = dir.join("_run.split_stage_defs");
+        assert!(journal.exists());
+        assert!(!dir.join("_run.split_stage_defs.tmp").exists());
+
+        // The journal is only written once per name
+        std::fs::remove_file(&journal).unwrap();
+        md.update_journal("stage_defs").unwrap();
+        assert!(!journal.exists());
+
+        let mut md = Metadata::new(make_args("main", &dir.join("missing/_run")), &log_file);
+        let err = md.update_journal("outs").unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Failed to update the journal file "));
+    }
+
+    #[test]
+    fn test_with_retries() {
+        let mut attempts = 0;
+        let result = with_retries(|| {
+            attempts += 1;
+            if attempts < 3 {
+                Err(io::Error::other("stale file handle"))
+            } else {
+                Ok(attempts)
+            }
+        });
+        assert_eq!(result.unwrap(), 3);
+
+        let mut attempts = 0;
+        let result: io::Result<()> = with_retries(|| {
+            attempts += 1;
+            Err(io::Error::other("stale file handle"))
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, JOURNAL_ATTEMPTS);
+    }
+
+    #[test]
+    fn test_alarm() {
+        let tmp_dir = tempdir::TempDir::new("test_alarm").unwrap();
+        let dir = tmp_dir.path();
+        let log_file = File::create(dir.join("_log")).unwrap();
+        let path = dir.to_str().unwrap().to_string();
+        let args = vec![
+            "stage".to_string(),
+            "main".to_string(),
+            path.clone(),
+            path.clone(),
+            dir.join("_run").to_str().unwrap().to_string(),
+        ];
+        let mut md = Metadata::new(args.clone(), &log_file);
+        md.alarm("low quality reads").unwrap();
+        alarm("no effect without martian_main").unwrap();
+        // The metadata needs to outlive the alarm sink
+        let log_file: &'static File = Box::leak(Box::new(log_file));
+        set_stage_metadata(Metadata::new(args, log_file));
+        crate::alarm!("{} reads were discarded", 12).unwrap();
+
+        let alarms = std::fs::read_to_string(dir.join("_alarm")).unwrap();
+        let lines: Vec<_> = alarms.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with(" low quality reads"));
+        assert!(lines[1].ends_with(" 12 reads were discarded"));
+        assert!(dir.join("_run.alarm").exists());
+    }
+
+    #[test]
+    fn test_report_progress() {
+        let tmp_dir = tempdir::TempDir::new("test_report_progress").unwrap();
+        let dir = tmp_dir.path();
+        let log_file = File::create(dir.join("_log")).unwrap();
+        let path = dir.to_str().unwrap().to_string();
+        let args = vec![
+            "stage".to_string(),
+            "main".to_string(),
+            path.clone(),
+            path.clone(),
+            dir.join("_run").to_str().unwrap().to_string(),
+        ];
+        let mut md = Metadata::new(args, &log_file);
+        let progress = || std::fs::read_to_string(dir.join("_progress")).unwrap();
+
+        md.report_progress(1, 8).unwrap();
+        assert_eq!(progress(), "1/8 (12.5%)");
+        assert!(dir.join("_run.progress").exists());
+        // Too soon after the previous report
+        md.report_progress(2, 8).unwrap();
+        assert_eq!(progress(), "1/8 (12.5%)");
+        // The end is always reported
+        md.report_progress(8, 8).unwrap();
+        assert_eq!(progress(), "8/8 (100.0%)");
+    }
+
+    #[test]
+    fn test_typed_jobinfo() {
+        let tmp_dir = tempdir::TempDir::new("test_typed_jobinfo").unwrap();
+        let dir = tmp_dir.path();
+        let log_file = File::create(dir.join("_log")).unwrap();
+        let path = dir.to_str().unwrap().to_string();
+        let args = vec![
+            "stage".to_string(),
+            "main".to_string(),
+            path.clone(),
+            path.clone(),
+            dir.join("_run").to_str().unwrap().to_string(),
+        ];
+        let mut md = Metadata::new(args, &log_file);
+
+        write_jobinfo(
+            dir,
+            json!({
+                "threads": 0.5,
+                "memGB": 4,
+                "vmemGB": 8,
+                "profile_mode": "disable",
+                "version": {"martian": "v4.0.0", "pipelines": "1.2.3"},
+                "invocation": {"call": "SUM_SQUARES_PIPE", "args": {"values": [1.0, 2.0]}},
+                "monitor_flag": "disable",
+            }),
+        );
+        md.update_jobinfo().unwrap();
+        let jobinfo = md.jobinfo();
+        assert_eq!(jobinfo.threads, Some(0.5));
+        assert_eq!(jobinfo.mem_gb, Some(4.0));
+        assert_eq!(jobinfo.vmem_gb, Some(8.0));
+        assert_eq!(jobinfo.profile_mode.as_deref(), Some("disable"));
+        let version = jobinfo.version.as_ref().unwrap();
+        assert_eq!(version.martian.as_deref(), Some("v4.0.0"));
+        assert_eq!(version.pipelines.as_deref(), Some("1.2.3"));
+        let invocation = jobinfo.invocation.as_ref().unwrap();
+        assert_eq!(invocation.call.as_deref(), Some("SUM_SQUARES_PIPE"));
+        assert_eq!(invocation.args["values"], json!([1.0, 2.0]));
+        assert_eq!(md.get_threads_allocation(), 1);
+        assert_eq!(md.get_memory_allocation(), 4);
+
+        // Missing keys are None, wrong types are an error
+        write_jobinfo(dir, json!({"threads": 2}));
+        md.refresh_jobinfo().unwrap();
+        assert_eq!(md.jobinfo().mem_gb, None);
+        assert_eq!(md.jobinfo().version, None);
+        write_jobinfo(dir, json!({"threads": "two"}));
+        let err = md.refresh_jobinfo().unwrap_err();
+        assert!(err.to_string().starts_with("Invalid _jobinfo: "));
+    }
+
+    #[test]
+    fn test_allocation_from_args() {
+        let tmp_dir = tempdir::TempDir::new("test_allocation_from_args").unwrap();
+        let dir = tmp_dir.path();
+        let log_file = File::create(dir.join("_log")).unwrap();
+        let path = dir.to_str().unwrap().to_string();
+        let args = vec![
+            "stage".to_string(),
+            "main".to_string(),
+            path.clone(),
+            path.clone(),
+            dir.join("_run").to_str().unwrap().to_string(),
+        ];
+        let mut md = Metadata::new(args, &log_file);
+        write_jobinfo(dir, json!({"threads": 3, "memGB": 6}));
+        md.update_jobinfo().unwrap();
+        std::fs::write(
+            dir.join("_args"),
+            json!({"__threads": 1, "__mem_gb": 2, "__vmem_gb": 10}).to_string(),
+        )
+        .unwrap();
+        // The allocation in _jobinfo wins over the request in _args
+        assert_eq!(md.get_threads_allocation(), 3);
+        assert_eq!(md.get_memory_allocation(), 6);
+        assert_eq!(md.get_virtual_memory_allocation(), 10);
+        let rover = crate::MartianRover::from(&md);
+        assert_eq!(rover.get_threads(), 3);
+        assert_eq!(rover.get_mem_gb(), 6);
+        assert_eq!(rover.get_vmem_gb(), 10);
+    }
+
+    #[test]
+    fn test_refresh_jobinfo() {
+        let tmp_dir = tempdir::TempDir::new("test_refresh_jobinfo").unwrap();
+        let dir = tmp_dir.path();
+        let log_file = File::create(dir.join("_log")).unwrap();
+        let path = dir.to_str().unwrap().to_string();
+        let args = vec![
+            "stage".to_string(),
+            "main".to_string(),
+            path.clone(),
+            path.clone(),
+            dir.join("_run").to_str().unwrap().to_string(),
+        ];
+        let mut md = Metadata::new(args, &log_file);
+
+        write_jobinfo(dir, json!({"threads": 1, "memGB": 4, "vmemGB": 8}));
+        md.update_jobinfo().unwrap();
+        assert_eq!(md.get_threads_allocation(), 1);
+
+        // Nothing changed
+        assert!(md.refresh_jobinfo().unwrap().is_empty());
+
+        write_jobinfo(dir, json!({"threads": 4, "memGB": 4, "monitor": true}));
+        let diff = md.refresh_jobinfo().unwrap();
+        assert_eq!(diff.iter().count(), 3);
+        assert_eq!(
+            diff.get("threads"),
+            Some(&JobInfoChange {
+                old: Some(json!(1)),
+                new: Some(json!(4)),
+            })
+        );
+        assert_eq!(
+            diff.get("vmemGB"),
+            Some(&JobInfoChange {
+                old: Some(json!(8)),
+                new: None,
+            })
+        );
+        assert_eq!(
+            diff.get("monitor"),
+            Some(&JobInfoChange {
+                old: None,
+                new: Some(json!(true)),
+            })
+        );
+        assert!(diff.get("memGB").is_none());
+        assert_eq!(md.get_threads_allocation(), 4);
+
+        assert!(md.refresh_jobinfo().unwrap().is_empty());
     }
 }
@@ -0,0 +1,328 @@
+//! Monitoring of the running stage: the heartbeat which tells mrp that the stage
+//! is alive, and the resource usage of the stage process. The platform specific
+//! readers are behind `peak_rss`, so that stage code and tests work on both
+//! Linux and macOS.
+
+use crate::{signals, Metadata, StageError, EXIT_CODE_PIPELINE_ERROR};
+use log::{info, warn};
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// How often the `heartbeat` journal file is updated while the stage runs, the
+/// same as the python adapter. mrp declares a job dead if it stops updating it.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(120);
+
+/// How often the memory usage of the stage is checked
+pub const MEMORY_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+const BYTES_PER_GB: f64 = (1u64 << 30) as f64;
+
+/// What the monitor does when the stage gets close to the memory allotted to
+/// it (`memGB` in `_jobinfo`), see `RunOptions::monitor`
+/// ```rust
+/// use martian::MonitorOptions;
+/// let options = MonitorOptions::new().abort_fraction(0.98);
+/// ```
+#[derive(Debug, Clone)]
+pub struct MonitorOptions {
+    /// Warn (see `Metadata::warn`) once the peak memory of the stage reaches this
+    /// fraction of its allotted memory. 0.9 by default.
+    pub warn_fraction: Option<f64>,
+    /// Fail the stage once its peak memory reaches this fraction of its allotted
+    /// memory, with a message in `_errors`, rather than being killed by the
+    /// kernel OOM killer without any explanation. Disabled by default.
+    pub abort_fraction: Option<f64>,
+    heartbeat_interval: Duration,
+    memory_check_interval: Duration,
+}
+
+impl Default for MonitorOptions {
+    fn default() -> Self {
+        MonitorOptions {
+            warn_fraction: Some(0.9),
+            abort_fraction: None,
+            heartbeat_interval: HEARTBEAT_INTERVAL,
+            memory_check_interval: MEMORY_CHECK_INTERVAL,
+        }
+    }
+}
+
+impl MonitorOptions {
+    pub fn new() -> Self {
+        MonitorOptions::default()
+    }
+    pub fn warn_fraction(mut self, fraction: impl Into<Option<f64>>) -> Self {
+        self.warn_fraction = fraction.into();
+        self
+    }
+    pub fn abort_fraction(mut self, fraction: impl Into<Option<f64>>) -> Self {
+        self.abort_fraction = fraction.into();
+        self
+    }
+}
+
+/// Result of a memory check, see `check_memory`
+#[derive(Debug, PartialEq)]
+enum MemoryCheck {
+    Ok,
+    Warn(String),
+    Abort(String),
+}
+
+/// Compare the peak memory of the stage in bytes with its allotted memory
+fn check_memory(peak_rss: u64, mem_gb: f64, options: &MonitorOptions, warned: bool) -> MemoryCheck {
+    let used_gb = peak_rss as f64 / BYTES_PER_GB;
+    let fraction = used_gb / mem_gb;
+    match (options.abort_fraction, options.warn_fraction) {
+        (Some(abort), _) if fraction >= abort => MemoryCheck::Abort(format!(
+            "stage aborted after using {:.2} GB of memory, {:.0}% of the {} GB allotted to it",
+            used_gb,
+            100.0 * fraction,
+            mem_gb
+        )),
+        (_, Some(warn)) if fraction >= warn && !warned => MemoryCheck::Warn(format!(
+            "The stage is using {:.2} GB of memory, {:.0}% of the {} GB allotted to it",
+            used_gb,
+            100.0 * fraction,
+            mem_gb
+        )),
+        _ => MemoryCheck::Ok,
+    }
+}
+
+/// Background thread which updates the `heartbeat` journal file of the stage,
+/// checks its memory usage and enforces its maximum runtime until it is stopped
+pub(crate) struct Monitor {
+    stage_done: Arc<AtomicBool>,
+    handle: JoinHandle<()>,
+}
+
+impl Monitor {
+    /// Start monitoring the stage, which fails if it runs for longer than `max_runtime`
+    pub(crate) fn start(
+        mut md: Metadata<'static>,
+        options: MonitorOptions,
+        max_runtime: Option<Duration>,
+    ) -> io::Result<Self> {
+        let stage_done = Arc::new(AtomicBool::new(false));
+        let done = stage_done.clone();
+        let mem_gb = md.jobinfo().mem_gb.filter(|&mem_gb| mem_gb > 0.0);
+        let start = Instant::now();
+        let handle = thread::Builder::new()
+            .name("martian-monitor".to_string())
+            .spawn(move || {
+                let mut next_heartbeat = Instant::now();
+                let mut warned = false;
+                while !done.load(Ordering::Relaxed) {
+                    if Instant::now() >= next_heartbeat {
+                        if let Err(e) = md.heartbeat() {
+                            warn!("Failed to update the heartbeat: {}", e);
+                        }
+                        next_heartbeat = Instant::now() + options.heartbeat_interval;
+                    }
+                    if let (Some(mem_gb), Some(rss)) = (mem_gb, peak_rss()) {
+                        match check_memory(rss, mem_gb, &options, warned) {
+                            MemoryCheck::Ok => {}
+                            MemoryCheck::Warn(msg) => {
+                                md.warn(&msg);
+                                warned = true;
+                            }
+                            MemoryCheck::Abort(message) => {
+                                let err = StageError::ResourceExhausted { message };
+                                signals::abort(&err.errors_message(), EXIT_CODE_PIPELINE_ERROR);
+                            }
+                        }
+                    }
+                    if let Some(max_runtime) = max_runtime {
+                        if start.elapsed() >= max_runtime && !done.load(Ordering::Relaxed) {
+                            let message = format!(
+                                "stage timed out: the {} of {} did not finish within its maximum \
+                                 runtime of {:?}",
+                                md.stage_type, md.stage_name, max_runtime
+                            );
+                            let err = StageError::ResourceExhausted { message };
+                            signals::abort(&err.errors_message(), EXIT_CODE_PIPELINE_ERROR);
+                        }
+                    }
+                    // Woken up early by `stop`
+                    let mut wake_up = std::cmp::min(
+                        next_heartbeat,
+                        Instant::now() + options.memory_check_interval,
+                    );
+                    if let Some(max_runtime) = max_runtime {
+                        wake_up = std::cmp::min(wake_up, start + max_runtime);
+                    }
+                    while !done.load(Ordering::Relaxed) && Instant::now() < wake_up {
+                        thread::park_timeout(wake_up.saturating_duration_since(Instant::now()));
+                    }
+                }
+                if let Some(rss) = peak_rss() {
+                    info!("Peak memory usage: {:.2} GB", rss as f64 / BYTES_PER_GB);
+                }
+            })?;
+        Ok(Monitor { stage_done, handle })
+    }
+
+    /// Stop the thread once the stage is done
+    pub(crate) fn stop(self) {
+        self.stage_done.store(true, Ordering::Relaxed);
+        self.handle.thread().unpark();
+        let _ = self.handle.join();
+    }
+}
+
+/// Peak resident set size of this process in bytes, or `None` if it is not
+/// available on this platform, in which case memory monitoring is disabled.
+///
+/// On Linux, this is the largest RSS read from `/proc/self/statm` across all the
+/// calls to `peak_rss` so far. On macOS, this is the peak RSS reported by `getrusage`.
+pub fn peak_rss() -> Option<u64> {
+    imp::peak_rss()
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use std::fs;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    static PEAK_RSS: AtomicU64 = AtomicU64::new(0);
+
+    // The second field of statm is the number of resident pages
+    fn current_rss() -> Option<u64> {
+        let statm = fs::read_to_string("/proc/self/statm").ok()?;
+        let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+        if page_size <= 0 {
+            return None;
+        }
+        Some(pages * page_size as u64)
+    }
+
+    pub(super) fn peak_rss() -> Option<u64> {
+        let rss = current_rss()?;
+        Some(std::cmp::max(
+            PEAK_RSS.fetch_max(rss, Ordering::Relaxed),
+            rss,
+        ))
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod imp {
+    pub(super) fn peak_rss() -> Option<u64> {
+        let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+        if unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) } != 0 {
+            return None;
+        }
+        // ru_maxrss is in bytes on macOS (and in kilobytes on Linux)
+        Some(usage.ru_maxrss as u64)
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+mod imp {
+    pub(super) fn peak_rss() -> Option<u64> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adapter_io::IoSink;
+
+    #[test]
+    fn test_monitor_heartbeat() {
+        let tmp_dir = tempdir::TempDir::new("test_monitor_heartbeat").unwrap();
+        let dir = tmp_dir.path();
+        let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+        let args = vec![
+            "SUM_SQUARES".to_string(),
+            "main".to_string(),
+            path(""),
+            path("files"),
+            path("_run"),
+        ];
+        let md = Metadata::with_sinks(args, IoSink::new(io::sink()), IoSink::new(io::sink()));
+        let heartbeat = dir.join("_run.heartbeat");
+
+        let options = MonitorOptions {
+            heartbeat_interval: Duration::from_millis(10),
+            ..MonitorOptions::default()
+        };
+        let monitor = Monitor::start(md.clone(), options, None).unwrap();
+        let start = Instant::now();
+        while !heartbeat.exists() {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(5));
+        }
+        std::fs::remove_file(&heartbeat).unwrap();
+        // The heartbeat is updated periodically, not just once
+        while !heartbeat.exists() {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        monitor.stop();
+
+        // Stopping doesn't wait for the end of the interval
+        let monitor = Monitor::start(md, MonitorOptions::default(), None).unwrap();
+        let stop = Instant::now();
+        monitor.stop();
+        assert!(stop.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_check_memory() {
+        let gb = 1u64 << 30;
+        let options = MonitorOptions::new();
+        assert_eq!(check_memory(gb, 4.0, &options, false), MemoryCheck::Ok);
+        assert_eq!(
+            check_memory(3 * gb + 3 * gb / 4, 4.0, &options, false),
+            MemoryCheck::Warn(
+                "The stage is using 3.75 GB of memory, 94% of the 4 GB allotted to it".to_string()
+            )
+        );
+        assert_eq!(
+            check_memory(3 * gb + 3 * gb / 4, 4.0, &options, true),
+            MemoryCheck::Ok
+        );
+        // Aborting is disabled by default
+        assert_eq!(check_memory(5 * gb, 4.0, &options, true), MemoryCheck::Ok);
+
+        let options = MonitorOptions::new()
+            .warn_fraction(None)
+            .abort_fraction(0.95);
+        assert_eq!(
+            check_memory(3 * gb + 3 * gb / 4, 4.0, &options, false),
+            MemoryCheck::Ok
+        );
+        assert_eq!(
+            check_memory(4 * gb, 4.0, &options, true),
+            MemoryCheck::Abort(
+                "stage aborted after using 4.00 GB of memory, 100% of the 4 GB allotted to it"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    fn test_peak_rss() {
+        let rss = peak_rss().unwrap();
+        // More than a page and less than 1TB
+        assert!(rss > 4096);
+        assert!(rss < 1 << 40);
+
+        // Touch 64MB so that it is resident, the peak never goes down
+        let buf = vec![1u8; 64 << 20];
+        let peak = peak_rss().unwrap();
+        assert!(peak >= rss);
+        assert!(peak >= buf.len() as u64);
+        drop(buf);
+        assert!(peak_rss().unwrap() >= peak);
+    }
+}
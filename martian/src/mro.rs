@@ -16,19 +16,48 @@
 //! TODO
 //! - Simplify MroDisplay trait?
 
-use crate::types::MartianVoid;
+use crate::types::{
+    FiletypeValidator, MartianFileType, MartianVoid, NonEmptyVec, TypedMap, TypedPath,
+};
+use failure::{format_err, Error};
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::convert::TryFrom;
 use std::fmt::{Display, Write};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::string::ToString;
 
+mod diff;
+mod parser;
+pub use diff::{FieldDiff, MroDiff, MroSection, StageDiff};
+pub use parser::{parse_mro, MroFile};
+
 pub const MARTIAN_TOKENS: &[&str] = &[
     "in", "out", "stage", "volatile", "strict", "true", "split", "filetype", "src", "py", "comp",
     "retain", "mro", "using", "int", "float", "string", "map", "bool", "path", "__null__",
 ];
 
+/// Check that `name` can be used as a field name in the mro. It cannot be one of
+/// the `MARTIAN_TOKENS` or start with `__`, which martian reserves. A martian token
+/// can be escaped with a trailing underscore, e.g `in_` instead of `in`.
+pub fn check_field_name(name: &str) -> Result<(), String> {
+    if MARTIAN_TOKENS.contains(&name) {
+        return Err(format!(
+            "Martian token {0} cannot be used as field name, use {0}_ instead",
+            name
+        ));
+    }
+    if name.starts_with("__") {
+        return Err(format!(
+            "Field name {} cannot start with __, which is reserved by martian",
+            name
+        ));
+    }
+    Ok(())
+}
+
 /// Defines how an entity that denotes some part of the mro is displayed
 pub trait MroDisplay {
     fn mro_string(&self, field_width: Option<usize>) -> String {
@@ -91,48 +120,194 @@ pub enum MartianPrimaryType {
     Map,
     Path,
     FileType(String),
+    MapOf(Box<MartianBlanketType>), // Typed map e.g `map<int>`, supported since martian 4.0
+    Struct(StructDef),              // Struct declared in the mro header, since martian 4.0
 }
 
 impl MroDisplay for MartianPrimaryType {
     usize_field_len! {}
     fn mro_string_no_width(&self) -> String {
-        let value = match *self {
-            MartianPrimaryType::Int => "int",
-            MartianPrimaryType::Float => "float",
-            MartianPrimaryType::Str => "string",
-            MartianPrimaryType::Bool => "bool",
-            MartianPrimaryType::Map => "map",
-            MartianPrimaryType::Path => "path",
-            MartianPrimaryType::FileType(ref ext) => ext,
-        };
-        value.to_string()
+        match *self {
+            MartianPrimaryType::Int => "int".to_string(),
+            MartianPrimaryType::Float => "float".to_string(),
+            MartianPrimaryType::Str => "string".to_string(),
+            MartianPrimaryType::Bool => "bool".to_string(),
+            MartianPrimaryType::Map => "map".to_string(),
+            MartianPrimaryType::Path => "path".to_string(),
+            MartianPrimaryType::FileType(ref ext) => ext.clone(),
+            MartianPrimaryType::MapOf(ref value) => {
+                format!("map<{}>", value.mro_string_no_width())
+            }
+            MartianPrimaryType::Struct(ref def) => def.name.clone(),
+        }
     }
 }
 
 mro_display_to_display! {MartianPrimaryType}
 
+/// Parses the builtin martian types. Filetypes are not parsed since any
+/// identifier is potentially a filetype.
+impl FromStr for MartianPrimaryType {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(value) = s.strip_prefix("map<").and_then(|s| s.strip_suffix('>')) {
+            return Ok(MartianPrimaryType::MapOf(Box::new(value.parse()?)));
+        }
+        let value = match s {
+            "int" => MartianPrimaryType::Int,
+            "float" => MartianPrimaryType::Float,
+            "string" => MartianPrimaryType::Str,
+            "bool" => MartianPrimaryType::Bool,
+            "map" => MartianPrimaryType::Map,
+            "path" => MartianPrimaryType::Path,
+            _ => {
+                return Err(format!(
+                    "Expected one of [int, float, string, bool, map, path], Found {}",
+                    s
+                ))
+            }
+        };
+        Ok(value)
+    }
+}
+
 /// Primary Data type + Arrays (which are derived from primary types)
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
 pub enum MartianBlanketType {
     Primary(MartianPrimaryType),
     Array(MartianPrimaryType),
+    /// An array with more than one dimension, e.g `int[][]` is `NestedArray(Int, 2)`.
+    /// Use `array_of` to get the right variant for any number of dimensions.
+    NestedArray(MartianPrimaryType, usize),
 }
 
 impl MroDisplay for MartianBlanketType {
     usize_field_len! {}
     fn mro_string_no_width(&self) -> String {
+        format!("{}{}", self.primary(), "[]".repeat(self.dimensions()))
+    }
+}
+mro_display_to_display! {MartianBlanketType}
+
+/// Parses a builtin martian type, optionally followed by `[]` for an array,
+/// `[][]` for a two dimensional array and so on
+impl FromStr for MartianBlanketType {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_suffix("[]") {
+            Some(inner) => Ok(inner.parse::<MartianBlanketType>()?.array_of()),
+            None => Ok(MartianBlanketType::Primary(s.parse()?)),
+        }
+    }
+}
+
+impl MartianBlanketType {
+    /// An array of this type, e.g `int[][]` for `int[]`
+    pub fn array_of(self) -> MartianBlanketType {
+        match self {
+            MartianBlanketType::Primary(primary) => MartianBlanketType::Array(primary),
+            MartianBlanketType::Array(primary) => MartianBlanketType::NestedArray(primary, 2),
+            MartianBlanketType::NestedArray(primary, dims) => {
+                MartianBlanketType::NestedArray(primary, dims + 1)
+            }
+        }
+    }
+
+    /// Number of array dimensions, 0 for a primary type
+    pub fn dimensions(&self) -> usize {
+        match *self {
+            MartianBlanketType::Primary(_) => 0,
+            MartianBlanketType::Array(_) => 1,
+            MartianBlanketType::NestedArray(_, dims) => dims,
+        }
+    }
+
+    pub(crate) fn primary(&self) -> &MartianPrimaryType {
         match *self {
-            MartianBlanketType::Primary(ref primary) => primary.to_string(),
-            MartianBlanketType::Array(ref primary) => format!("{}[]", primary.to_string()),
+            MartianBlanketType::Primary(ref primary)
+            | MartianBlanketType::Array(ref primary)
+            | MartianBlanketType::NestedArray(ref primary, _) => primary,
         }
     }
 }
-mro_display_to_display! {MartianBlanketType}
+
+/// A martian struct type (martian 4.0), which is declared in the mro header
+/// ```mro
+/// struct ReadPair(
+///     fastq r1,
+///     fastq r2,
+/// )
+/// ```
+/// and used like any other type within the stage definitions. You can
+/// get it for a Rust struct using `#[derive(MartianStruct)]` along with
+/// `#[mro_struct]`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
+pub struct StructDef {
+    name: String,
+    fields: Vec<MroField>,
+}
+
+impl StructDef {
+    pub fn new(name: impl ToString, fields: Vec<MroField>) -> Self {
+        StructDef {
+            name: name.to_string(),
+            fields,
+        }
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn fields(&self) -> &[MroField] {
+        &self.fields
+    }
+}
+
+impl MroDisplay for StructDef {
+    fn min_width(&self) -> usize {
+        0
+    }
+
+    fn mro_string_with_width(&self, field_width: usize) -> String {
+        let mut result = String::new();
+        // Determing the field width for the type field
+        let ty_width = self.fields.iter().map(|f| f.min_width()).max().unwrap_or(0);
+        let indent = format!("{blank:indent$}", blank = "", indent = field_width);
+        writeln!(&mut result, "struct {}(", self.name).unwrap();
+        for field in &self.fields {
+            writeln!(
+                &mut result,
+                "{}{},",
+                indent,
+                field.mro_string_with_width(ty_width)
+            )
+            .unwrap();
+        }
+        writeln!(&mut result, ")").unwrap();
+        result
+    }
+
+    fn mro_string_no_width(&self) -> String {
+        self.mro_string_with_width(self.min_width())
+    }
+}
+
+mro_display_to_display! {StructDef, TAB_WIDTH_FOR_MRO}
 
 /// A trait that tells you how to convert a Rust data type to a
 /// basic Martian type.
 pub trait AsMartianPrimaryType {
     fn as_martian_primary_type() -> MartianPrimaryType;
+    /// The filetypes of this type as `(extension, Rust type name)` pairs, which are
+    /// used to detect two Rust types declaring the same filetype. Implemented by
+    /// `martian_filetype!` and `TypedPath`, and forwarded by the containers.
+    fn filetype_rust_types() -> Vec<(String, String)> {
+        Vec::new()
+    }
+    /// The `MartianFileType::validate` of the filetypes of this type, which the adapter
+    /// runs on the files in the outputs. Implemented along with `filetype_rust_types`.
+    fn filetype_validators() -> Vec<FiletypeValidator> {
+        Vec::new()
+    }
 }
 
 /// A trait that defines how to convert this Rust type into an `MartianBlanketType`.
@@ -146,6 +321,14 @@ pub trait AsMartianPrimaryType {
 /// use the `AsMartianPrimaryType` trait.
 pub trait AsMartianBlanketType {
     fn as_martian_blanket_type() -> MartianBlanketType;
+    /// See `AsMartianPrimaryType::filetype_rust_types`
+    fn filetype_rust_types() -> Vec<(String, String)> {
+        Vec::new()
+    }
+    /// See `AsMartianPrimaryType::filetype_validators`
+    fn filetype_validators() -> Vec<FiletypeValidator> {
+        Vec::new()
+    }
 }
 
 /// Macro for implementing `AsMartianPrimaryType` trait
@@ -184,6 +367,12 @@ impl<T: AsMartianPrimaryType> AsMartianBlanketType for T {
     fn as_martian_blanket_type() -> MartianBlanketType {
         MartianBlanketType::Primary(T::as_martian_primary_type())
     }
+    fn filetype_rust_types() -> Vec<(String, String)> {
+        <T as AsMartianPrimaryType>::filetype_rust_types()
+    }
+    fn filetype_validators() -> Vec<FiletypeValidator> {
+        <T as AsMartianPrimaryType>::filetype_validators()
+    }
 }
 
 impl<T: AsMartianBlanketType> AsMartianBlanketType for Option<T> {
@@ -191,11 +380,36 @@ impl<T: AsMartianBlanketType> AsMartianBlanketType for Option<T> {
         // Any variable can be `null` in Martian
         T::as_martian_blanket_type()
     }
+    fn filetype_rust_types() -> Vec<(String, String)> {
+        T::filetype_rust_types()
+    }
+    fn filetype_validators() -> Vec<FiletypeValidator> {
+        T::filetype_validators()
+    }
+}
+
+// Nested vectors are multidimensional arrays, e.g `int[][]` for `Vec<Vec<i32>>`
+impl<T: AsMartianBlanketType> AsMartianBlanketType for Vec<T> {
+    fn as_martian_blanket_type() -> MartianBlanketType {
+        T::as_martian_blanket_type().array_of()
+    }
+    fn filetype_rust_types() -> Vec<(String, String)> {
+        T::filetype_rust_types()
+    }
+    fn filetype_validators() -> Vec<FiletypeValidator> {
+        T::filetype_validators()
+    }
 }
 
-impl<T: AsMartianPrimaryType> AsMartianBlanketType for Vec<T> {
+impl<T: AsMartianBlanketType> AsMartianBlanketType for NonEmptyVec<T> {
     fn as_martian_blanket_type() -> MartianBlanketType {
-        MartianBlanketType::Array(T::as_martian_primary_type())
+        T::as_martian_blanket_type().array_of()
+    }
+    fn filetype_rust_types() -> Vec<(String, String)> {
+        T::filetype_rust_types()
+    }
+    fn filetype_validators() -> Vec<FiletypeValidator> {
+        T::filetype_validators()
     }
 }
 
@@ -203,14 +417,98 @@ impl<K: AsMartianPrimaryType, H> AsMartianBlanketType for HashSet<K, H> {
     fn as_martian_blanket_type() -> MartianBlanketType {
         MartianBlanketType::Array(K::as_martian_primary_type())
     }
+    fn filetype_rust_types() -> Vec<(String, String)> {
+        K::filetype_rust_types()
+    }
+    fn filetype_validators() -> Vec<FiletypeValidator> {
+        K::filetype_validators()
+    }
 }
 
-impl<K, V, H> AsMartianPrimaryType for HashMap<K, V, H> {
-    fn as_martian_primary_type() -> MartianPrimaryType {
+/// The mro type of a map with values of this type. Values which have a martian
+/// type give a typed map (`map<T>`), while heterogeneous values (`serde_json::Value`)
+/// fall back to an untyped `map`.
+pub trait AsMartianMapValueType {
+    fn as_martian_map_type() -> MartianPrimaryType;
+    /// See `AsMartianPrimaryType::filetype_rust_types`
+    fn filetype_rust_types() -> Vec<(String, String)> {
+        Vec::new()
+    }
+    /// See `AsMartianPrimaryType::filetype_validators`
+    fn filetype_validators() -> Vec<FiletypeValidator> {
+        Vec::new()
+    }
+}
+
+impl<V: AsMartianBlanketType> AsMartianMapValueType for V {
+    fn as_martian_map_type() -> MartianPrimaryType {
+        MartianPrimaryType::MapOf(Box::new(V::as_martian_blanket_type()))
+    }
+    fn filetype_rust_types() -> Vec<(String, String)> {
+        <V as AsMartianBlanketType>::filetype_rust_types()
+    }
+    fn filetype_validators() -> Vec<FiletypeValidator> {
+        <V as AsMartianBlanketType>::filetype_validators()
+    }
+}
+
+impl AsMartianMapValueType for Value {
+    fn as_martian_map_type() -> MartianPrimaryType {
         MartianPrimaryType::Map
     }
 }
 
+/// A map with values of a martian type is a typed `map<T>`. Values without a
+/// martian type need `#[mro_type = "map"]` on the field for an untyped `map`.
+impl<K, V: AsMartianMapValueType, H> AsMartianPrimaryType for HashMap<K, V, H> {
+    fn as_martian_primary_type() -> MartianPrimaryType {
+        V::as_martian_map_type()
+    }
+    fn filetype_rust_types() -> Vec<(String, String)> {
+        <V as AsMartianMapValueType>::filetype_rust_types()
+    }
+    fn filetype_validators() -> Vec<FiletypeValidator> {
+        <V as AsMartianMapValueType>::filetype_validators()
+    }
+}
+
+impl<V: AsMartianMapValueType> AsMartianPrimaryType for TypedMap<V> {
+    fn as_martian_primary_type() -> MartianPrimaryType {
+        V::as_martian_map_type()
+    }
+    fn filetype_rust_types() -> Vec<(String, String)> {
+        <V as AsMartianMapValueType>::filetype_rust_types()
+    }
+    fn filetype_validators() -> Vec<FiletypeValidator> {
+        <V as AsMartianMapValueType>::filetype_validators()
+    }
+}
+
+impl<F: MartianFileType> AsMartianPrimaryType for TypedPath<F> {
+    fn as_martian_primary_type() -> MartianPrimaryType {
+        MartianPrimaryType::FileType(F::extension().to_string())
+    }
+    fn filetype_rust_types() -> Vec<(String, String)> {
+        vec![(
+            F::extension().to_string(),
+            std::any::type_name::<F>().to_string(),
+        )]
+    }
+    fn filetype_validators() -> Vec<FiletypeValidator> {
+        vec![FiletypeValidator::of::<F>()]
+    }
+}
+
+/// A fieldless enum which is a `string` in the mro, whose values are the names
+/// of the variants. Parsing or deserializing any other string is an error
+/// listing the variants. Derive it using `#[derive(MartianStringEnum)]`, which
+/// also implements `AsMartianPrimaryType`, `FromStr`, `Display`, `Serialize`
+/// and `Deserialize`.
+pub trait MartianStringEnum: AsMartianPrimaryType + FromStr + Display {
+    /// Names of the variants, in the order they are declared
+    const VARIANTS: &'static [&'static str];
+}
+
 /// Each variable that is listed in the mro along with it's type form
 /// a `MroField`. For example, the following stage:
 /// ```mro
@@ -225,42 +523,124 @@ impl<K, V, H> AsMartianPrimaryType for HashMap<K, V, H> {
 /// - MroField { name: unsorted, ty: MartianBlanketType::Array(MartianPrimaryType::Int)}
 /// - MroField { name: reverse, ty: MartianBlanketType::Primary(MartianPrimaryType::Bool)}
 /// - MroField { name: sorted, ty: MartianBlanketType::Array(MartianPrimaryType::Int)}
-#[derive(Debug, Serialize, Clone, Deserialize, PartialEq, Eq)]
+///
+/// An input can have a default value, e.g `in int count = 5`
+#[derive(Debug, Serialize, Clone, Deserialize, PartialEq, Eq, Hash)]
 pub struct MroField {
     name: String,
     ty: MartianBlanketType,
     retain: bool,
+    strict: bool,
+    #[serde(default)]
+    default: Option<String>, // The default value as an mro (json) literal
+    #[serde(default)]
+    doc: Option<String>, // Written as comment lines above the field
+    #[serde(default, skip_serializing_if = "FiletypeRustTypes::is_empty")]
+    filetype_rust_types: FiletypeRustTypes,
+    #[serde(skip)]
+    filetype_validators: FiletypeValidators,
+}
+
+/// The Rust types of the filetypes in a field, as `(extension, type name)` pairs,
+/// which are used to detect conflicts (see `validate_mro_registry`). They are not
+/// part of the mro, so they never make two fields different.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+struct FiletypeRustTypes(Vec<(String, String)>);
+
+impl FiletypeRustTypes {
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl PartialEq for FiletypeRustTypes {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for FiletypeRustTypes {}
+
+impl std::hash::Hash for FiletypeRustTypes {
+    fn hash<H: std::hash::Hasher>(&self, _: &mut H) {}
+}
+
+/// The validators of the filetypes in a field, see `validate_files`. Same as
+/// `FiletypeRustTypes`, they never make two fields different.
+#[derive(Debug, Clone, Default)]
+struct FiletypeValidators(Vec<FiletypeValidator>);
+
+impl PartialEq for FiletypeValidators {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for FiletypeValidators {}
+
+impl std::hash::Hash for FiletypeValidators {
+    fn hash<H: std::hash::Hasher>(&self, _: &mut H) {}
 }
 
 /// `field_width` will decide the length of the type column
 impl MroDisplay for MroField {
     fn mro_string_no_width(&self) -> String {
-        format!("{ty} {name}", ty = self.ty.to_string(), name = &self.name)
+        self.mro_string_with_width(self.min_width())
     }
     fn min_width(&self) -> usize {
         self.ty.min_width()
     }
 
     fn mro_string_with_width(&self, field_width: usize) -> String {
-        format!(
+        let mut result = format!(
             "{ty} {name}",
             ty = self.ty.mro_string_with_width(field_width),
             name = &self.name
-        )
+        );
+        if let Some(ref default) = self.default {
+            write!(&mut result, " = {}", default).unwrap();
+        }
+        result
     }
 }
 
 mro_display_to_display! {MroField}
 
 impl MroField {
+    /// Panics if the name cannot be used in the mro. See `check_field_name`
     pub fn new(name: impl ToString, ty: MartianBlanketType) -> Self {
-        let field = MroField {
-            name: name.to_string(),
+        Self::try_new(name, ty).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Same as `new`, returning an error if the name cannot be used in the mro
+    pub fn try_new(name: impl ToString, ty: MartianBlanketType) -> Result<Self, String> {
+        let name = name.to_string();
+        check_field_name(&name)?;
+        Ok(MroField {
+            name,
             ty,
             retain: false,
-        };
-        field.verify(); // No use case to resultify this so far
-        field
+            strict: false,
+            default: None,
+            doc: None,
+            filetype_rust_types: FiletypeRustTypes::default(),
+            filetype_validators: FiletypeValidators::default(),
+        })
+    }
+
+    /// The Rust types of the filetypes in the field, see
+    /// `AsMartianBlanketType::filetype_rust_types`. `#[derive(MartianStruct)]` sets them.
+    pub fn with_filetype_rust_types(mut self, rust_types: Vec<(String, String)>) -> Self {
+        self.filetype_rust_types = FiletypeRustTypes(rust_types);
+        self
+    }
+
+    /// The validators of the filetypes in the field, see
+    /// `AsMartianBlanketType::filetype_validators`. `#[derive(MartianStruct)]` sets them.
+    pub fn with_filetype_validators(mut self, validators: Vec<FiletypeValidator>) -> Self {
+        self.filetype_validators = FiletypeValidators(validators);
+        self
     }
 
     pub fn retained(name: impl ToString, ty: MartianBlanketType) -> Self {
@@ -268,16 +648,82 @@ impl MroField {
         field.retain = true;
         field
     }
-    // Check that name does not match any martian token.
-    fn verify(&self) {
-        for &token in MARTIAN_TOKENS.iter() {
-            assert!(
-                self.name != token,
-                "Martian token {} cannot be used as field name",
-                token
-            );
+
+    /// A retained output which is marked `@strict` in the retain block.
+    /// Only stage outputs can be strict retained.
+    pub fn strict_retained(name: impl ToString, ty: MartianBlanketType) -> Self {
+        let mut field = Self::retained(name, ty);
+        field.strict = true;
+        field
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn ty(&self) -> &MartianBlanketType {
+        &self.ty
+    }
+
+    /// Set the default value of the field, e.g `in int count = 5`. Only stage
+    /// inputs can have a default value.
+    /// ```rust
+    /// use martian::{MartianBlanketType, MartianPrimaryType, MroField};
+    /// let field = MroField::new("count", MartianBlanketType::Primary(MartianPrimaryType::Int))
+    ///     .with_default(5);
+    /// assert_eq!(field.to_string(), "int count = 5");
+    /// ```
+    pub fn with_default(mut self, value: impl Serialize) -> Self {
+        self.default =
+            Some(serde_json::to_string(&value).expect("Unable to serialize the default value"));
+        self
+    }
+
+    /// The default value as an mro literal, if any
+    pub fn default_value(&self) -> Option<&str> {
+        self.default.as_deref()
+    }
+
+    /// Set the documentation of the field, which is written as `#` comment lines
+    /// above the field in the mro, one per line of `doc`. `#[derive(MartianStruct)]`
+    /// sets it from the doc comment of the field. It is only written if enabled
+    /// using `MakeMroOptions::field_docs`.
+    pub fn with_doc(mut self, doc: impl ToString) -> Self {
+        let doc = doc.to_string();
+        self.doc = if doc.trim().is_empty() {
+            None
+        } else {
+            Some(doc)
+        };
+        self
+    }
+
+    /// The documentation of the field, if any
+    pub fn doc(&self) -> Option<&str> {
+        self.doc.as_deref()
+    }
+
+    /// Description of the field in the stage manifest
+    fn manifest_entry(&self) -> Value {
+        let mut entry = json!({
+            "name": self.name,
+            "type": self.ty.to_string(),
+            "retain": self.retain,
+            "strict": self.strict,
+        });
+        if let Some(ref default) = self.default {
+            entry["default"] = serde_json::from_str(default).unwrap();
+        }
+        entry
+    }
+
+    /// Entry in the `retain` block
+    fn retain_string(&self) -> String {
+        if self.strict {
+            format!("@strict {}", self.name)
+        } else {
+            self.name.clone()
         }
-        assert!(!self.name.starts_with("__"));
     }
 }
 
@@ -287,7 +733,9 @@ impl MroField {
 /// this trait, then we can readily generate all the mro variables with the appropriate
 /// type and put them at the right place (withing stage def or chunk def).
 ///
-/// TODO : Auto derive for structs with named fields if all the fields implement `AsMartianBlanketType`
+/// Derive it using `#[derive(MartianStruct)]` for a struct with named fields, all of
+/// which implement `AsMartianBlanketType`. A field which does not is a compile error
+/// at the type of that field.
 pub trait MartianStruct {
     /// How to convert this struct into a list of `MroField`s
     fn mro_fields() -> Vec<MroField>;
@@ -299,7 +747,68 @@ impl MartianStruct for MartianVoid {
     }
 }
 
+/// Run the validators of the filetypes (see `MroField::with_filetype_validators`) on
+/// the files in `outs`, which are the serialized outputs with these `fields`. The
+/// paths without the extension of any filetype of their field are skipped, and so
+/// are the files which do not exist.
+pub(crate) fn validate_files(
+    fields: &[MroField],
+    outs: &serde_json::Map<String, Value>,
+) -> Result<(), Error> {
+    for field in fields {
+        if let Some(value) = outs.get(&field.name) {
+            validate_field_files(field, &field.ty, value)?;
+        }
+    }
+    Ok(())
+}
+
+fn validate_field_files(
+    field: &MroField,
+    ty: &MartianBlanketType,
+    value: &Value,
+) -> Result<(), Error> {
+    match (ty.primary(), value) {
+        (_, Value::Array(values)) => {
+            for value in values {
+                validate_field_files(field, ty, value)?;
+            }
+        }
+        (MartianPrimaryType::Struct(def), Value::Object(obj)) => validate_files(&def.fields, obj)?,
+        (MartianPrimaryType::MapOf(value_ty), Value::Object(obj)) => {
+            for value in obj.values() {
+                validate_field_files(field, value_ty, value)?;
+            }
+        }
+        (_, Value::String(path)) => {
+            let path = Path::new(path);
+            let validator = field
+                .filetype_validators
+                .0
+                .iter()
+                .filter(|validator| validator.matches(path))
+                .max_by_key(|validator| validator.extension().len());
+            if let Some(validator) = validator {
+                if path.exists() {
+                    validator.validate(path).map_err(|e| {
+                        format_err!(
+                            "The output {} {} is not a valid {} file: {}",
+                            field.name,
+                            path.display(),
+                            validator.extension(),
+                            e
+                        )
+                    })?;
+                }
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
 pub enum Volatile {
     Strict,
 }
@@ -326,6 +835,50 @@ impl MroDisplay for Volatile {
 
 mro_display_to_display! {Volatile}
 
+/// Number of threads requested in the `using` section of a stage. Martian
+/// interprets -1 as a request for all the cores available, which is what
+/// `AllCores` renders to. Other negative values are rejected.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(try_from = "i16", into = "i16")]
+pub enum Threads {
+    Fixed(i16),
+    AllCores,
+}
+
+const ALL_CORES_THREADS: i16 = -1;
+
+impl TryFrom<i16> for Threads {
+    type Error = String;
+    fn try_from(threads: i16) -> Result<Self, Self::Error> {
+        match threads {
+            ALL_CORES_THREADS => Ok(Threads::AllCores),
+            n if n >= 0 => Ok(Threads::Fixed(n)),
+            n => Err(format!(
+                "Invalid number of threads {}. Use {} to request all the cores available.",
+                n, ALL_CORES_THREADS
+            )),
+        }
+    }
+}
+
+impl From<Threads> for i16 {
+    fn from(threads: Threads) -> Self {
+        match threads {
+            Threads::Fixed(n) => n,
+            Threads::AllCores => ALL_CORES_THREADS,
+        }
+    }
+}
+
+impl MroDisplay for Threads {
+    usize_field_len! {}
+    fn mro_string_no_width(&self) -> String {
+        i16::from(*self).to_string()
+    }
+}
+
+mro_display_to_display! {Threads}
+
 const TAB_WIDTH_FOR_MRO: usize = 4;
 macro_rules! mro_using {
     ($($property:ident: $type:ty),*) => {
@@ -337,15 +890,127 @@ macro_rules! mro_using {
         ///     threads = 16,
         /// )
         /// ```
-        #[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+        /// Attributes which are not modelled here yet can be added to `extra`,
+        /// using `MroUsing::with_extra`.
+        #[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
         pub struct MroUsing {
             $(pub $property: Option<$type>,)*
+            /// Any other attributes, rendered as `key = value,` after the ones above
+            #[serde(flatten)]
+            pub extra: BTreeMap<String, String>,
         }
 
         impl MroUsing {
             /// If all fields as None, return False
             pub fn need_using(&self) -> bool {
-                !($(self.$property.is_none())&&*)
+                !($(self.$property.is_none())&&*) || !self.extra.is_empty()
+            }
+
+            /// Add an attribute which is not one of the fields of `MroUsing`, for
+            /// example one supported by a newer version of martian. The value is
+            /// rendered verbatim.
+            pub fn with_extra(mut self, key: impl ToString, value: impl ToString) -> Self {
+                self.extra.insert(key.to_string(), value.to_string());
+                self
+            }
+
+            /// Start building the attributes, which are checked using `validate`
+            /// when they are built
+            /// ```rust
+            /// use martian::{MroUsing, Threads};
+            /// let using = MroUsing::builder()
+            ///     .mem_gb(4)
+            ///     .vmem_gb(8)
+            ///     .threads(Threads::Fixed(2))
+            ///     .build()
+            ///     .unwrap();
+            /// assert_eq!(using.mem_gb, Some(4));
+            /// assert!(MroUsing::builder().mem_gb(4).vmem_gb(2).build().is_err());
+            /// ```
+            pub fn builder() -> MroUsingBuilder {
+                MroUsingBuilder::default()
+            }
+
+            /// The attributes set in `overrides` take precedence over the ones in
+            /// `self`, e.g to override defaults shared by many stages in one of them.
+            /// The extra attributes are merged by key.
+            pub fn merge(mut self, overrides: MroUsing) -> MroUsing {
+                $(if overrides.$property.is_some() {
+                    self.$property = overrides.$property;
+                })*
+                self.extra.extend(overrides.extra);
+                self
+            }
+
+            /// Check that the attributes are values mrp accepts
+            pub fn validate(&self) -> Result<(), String> {
+                for (key, value) in &[("mem_gb", self.mem_gb), ("vmem_gb", self.vmem_gb)] {
+                    if let Some(gb) = value {
+                        if *gb < 0 {
+                            return Err(format!(
+                                "Expected a non-negative {} in the using attributes, found {}",
+                                key, gb
+                            ));
+                        }
+                    }
+                }
+                if let (Some(mem_gb), Some(vmem_gb)) = (self.mem_gb, self.vmem_gb) {
+                    if vmem_gb < mem_gb {
+                        return Err(format!(
+                            "Expected vmem_gb to be at least mem_gb in the using attributes, found vmem_gb = {} and mem_gb = {}",
+                            vmem_gb, mem_gb
+                        ));
+                    }
+                }
+                if let Some(Threads::Fixed(n)) = self.threads {
+                    if n < 1 {
+                        return Err(format!(
+                            "Expected at least 1 thread in the using attributes, found {}. Use Threads::AllCores to request all the cores available.",
+                            n
+                        ));
+                    }
+                }
+                for key in self.extra.keys() {
+                    if $(key == stringify!($property))||* {
+                        return Err(format!(
+                            "Extra using attribute {} needs to be set using the field of MroUsing",
+                            key
+                        ));
+                    }
+                    if MARTIAN_TOKENS.contains(&key.as_str()) {
+                        return Err(format!(
+                            "Martian token {} cannot be used as an extra using attribute",
+                            key
+                        ));
+                    }
+                    let is_identifier = key.starts_with(|c: char| c.is_ascii_alphabetic())
+                        && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+                    if !is_identifier {
+                        return Err(format!("Invalid extra using attribute {}", key));
+                    }
+                }
+                Ok(())
+            }
+        }
+
+        /// Builder for `MroUsing`, see `MroUsing::builder`
+        #[derive(Debug, Default, Clone)]
+        pub struct MroUsingBuilder(MroUsing);
+
+        impl MroUsingBuilder {
+            $(pub fn $property(mut self, $property: $type) -> Self {
+                self.0.$property = Some($property);
+                self
+            })*
+            /// See `MroUsing::with_extra`
+            pub fn extra(mut self, key: impl ToString, value: impl ToString) -> Self {
+                self.0 = self.0.with_extra(key, value);
+                self
+            }
+            /// The attributes, if they pass `MroUsing::validate`
+            pub fn build(self) -> Result<MroUsing, String> {
+                self.0.validate()?;
+                Ok(self.0)
             }
         }
 
@@ -359,6 +1024,9 @@ macro_rules! mro_using {
                 $(if self.$property.is_some() {
                     w = std::cmp::max(w, stringify!($property).len());
                 })*
+                for key in self.extra.keys() {
+                    w = std::cmp::max(w, key.len());
+                }
                 w
             }
 
@@ -383,6 +1051,15 @@ macro_rules! mro_using {
                         ).unwrap()
                     }
                 )*
+                for (key, value) in &self.extra {
+                    writeln!(
+                        &mut result,
+                        "{key:<width$} = {value},",
+                        key=key,
+                        width=field_width,
+                        value=value
+                    ).unwrap()
+                }
                 result
             }
         }
@@ -390,23 +1067,42 @@ macro_rules! mro_using {
     };
 }
 
-mro_using! {mem_gb: i16, vmem_gb: i16, threads: i16, volatile: Volatile}
+mro_using! {mem_gb: i16, vmem_gb: i16, threads: Threads, volatile: Volatile}
 
 /// Input and outputs together
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
 pub struct InAndOut {
     pub inputs: Vec<MroField>,
     pub outputs: Vec<MroField>,
 }
 
 impl InAndOut {
+    fn remove_docs(&mut self) {
+        for field in self.inputs.iter_mut().chain(self.outputs.iter_mut()) {
+            field.doc = None;
+        }
+    }
+
+    fn manifest_entry(&self) -> Value {
+        json!({
+            "inputs": self.inputs.iter().map(MroField::manifest_entry).collect::<Vec<_>>(),
+            "outputs": self.outputs.iter().map(MroField::manifest_entry).collect::<Vec<_>>(),
+        })
+    }
+
     fn retain_field_names(&self) -> Vec<String> {
         self.outputs
             .iter()
             .filter(|field| field.retain)
-            .map(|field| field.name.clone())
+            .map(|field| field.retain_string())
             .collect()
     }
+
+    fn is_retained(&self, name: &str) -> bool {
+        self.outputs
+            .iter()
+            .any(|field| field.retain && field.name == name)
+    }
 }
 
 impl MroDisplay for InAndOut {
@@ -433,6 +1129,15 @@ impl MroDisplay for InAndOut {
         let mut result = String::new();
         for (key, fields) in &[("in", &self.inputs), ("out", &self.outputs)] {
             for field in *fields {
+                if let Some(ref doc) = field.doc {
+                    for line in doc.lines().map(str::trim_end) {
+                        if line.is_empty() {
+                            writeln!(&mut result, "#").unwrap();
+                        } else {
+                            writeln!(&mut result, "# {}", line).unwrap();
+                        }
+                    }
+                }
                 writeln!(
                     &mut result,
                     "{key:3} {f},",
@@ -448,19 +1153,27 @@ impl MroDisplay for InAndOut {
 mro_display_to_display! {InAndOut}
 
 /// The list of filetypes we list at the top of the mro
-/// A simple wrapper around a HashSet of all file extensions.
+/// A simple wrapper around the set of all file extensions, which are always
+/// declared in alphabetical order, so that the mro does not change between runs.
 #[derive(Debug, PartialEq, Default)]
-pub struct FiletypeHeader(HashSet<String>);
+pub struct FiletypeHeader(BTreeSet<String>);
 
 impl From<&MroField> for FiletypeHeader {
     fn from(field: &MroField) -> FiletypeHeader {
-        let mut result = HashSet::new();
-        match field.ty {
-            MartianBlanketType::Primary(MartianPrimaryType::FileType(ref ext)) => {
+        let mut result = BTreeSet::new();
+        // The filetype could be the value type of a (nested) typed map or
+        // a field of a struct
+        match field.ty.primary() {
+            MartianPrimaryType::FileType(ext) => {
                 result.insert(ext.to_string());
             }
-            MartianBlanketType::Array(MartianPrimaryType::FileType(ref ext)) => {
-                result.insert(ext.to_string());
+            MartianPrimaryType::MapOf(value) => {
+                result.extend(FiletypeHeader::from(&MroField::new("", (**value).clone())).0);
+            }
+            MartianPrimaryType::Struct(def) => {
+                for struct_field in &def.fields {
+                    result.extend(FiletypeHeader::from(struct_field).0);
+                }
             }
             _ => {}
         }
@@ -470,7 +1183,7 @@ impl From<&MroField> for FiletypeHeader {
 
 impl From<&InAndOut> for FiletypeHeader {
     fn from(in_out: &InAndOut) -> FiletypeHeader {
-        let mut result = HashSet::new();
+        let mut result = BTreeSet::new();
         for field in in_out.inputs.iter().chain(in_out.outputs.iter()) {
             result.extend(FiletypeHeader::from(field).0);
         }
@@ -492,6 +1205,21 @@ impl FiletypeHeader {
     pub fn add_stage(&mut self, stage_mro: &StageMro) {
         self.0.extend(FiletypeHeader::from(stage_mro).0);
     }
+    pub fn add_pipeline(&mut self, pipeline_mro: &PipelineMro) {
+        self.0
+            .extend(FiletypeHeader::from(&pipeline_mro.pipeline_in_out).0);
+    }
+    /// Declare the file extension `ext`, e.g `bam`
+    pub fn add_filetype(&mut self, ext: impl ToString) {
+        self.0.insert(ext.to_string());
+    }
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+    /// All the file extensions, sorted
+    pub fn extensions(&self) -> Vec<&str> {
+        self.0.iter().map(String::as_str).collect()
+    }
 }
 
 // Just need display here
@@ -505,10 +1233,8 @@ impl MroDisplay for FiletypeHeader {
         if self.0.is_empty() {
             return result;
         }
-        let mut extensions: Vec<_> = self.0.iter().collect();
         writeln!(&mut result, "").unwrap();
-        extensions.sort();
-        for ext in extensions {
+        for ext in self.extensions() {
             writeln!(&mut result, "filetype {};", ext).unwrap();
         }
         writeln!(&mut result, "").unwrap();
@@ -521,36 +1247,138 @@ impl MroDisplay for FiletypeHeader {
 
 mro_display_to_display! { FiletypeHeader }
 
+/// The struct declarations we list at the top of the mro, after the filetypes.
+/// A struct is listed after all the structs it refers to.
+#[derive(Debug, PartialEq, Default)]
+pub struct StructHeader(Vec<StructDef>);
+
+impl StructHeader {
+    pub fn add_stage(&mut self, stage_mro: &StageMro) -> Result<(), String> {
+        self.add_in_out(&stage_mro.stage_in_out)?;
+        if let Some(ref chunk_in_out) = stage_mro.chunk_in_out {
+            self.add_in_out(chunk_in_out)?;
+        }
+        Ok(())
+    }
+
+    pub fn add_pipeline(&mut self, pipeline_mro: &PipelineMro) -> Result<(), String> {
+        self.add_in_out(&pipeline_mro.pipeline_in_out)
+    }
+
+    fn add_in_out(&mut self, in_out: &InAndOut) -> Result<(), String> {
+        for field in in_out.inputs.iter().chain(in_out.outputs.iter()) {
+            self.add_type(&field.ty)?;
+        }
+        Ok(())
+    }
+
+    fn add_type(&mut self, ty: &MartianBlanketType) -> Result<(), String> {
+        match ty.primary() {
+            MartianPrimaryType::MapOf(value) => self.add_type(value),
+            MartianPrimaryType::Struct(def) => {
+                for field in &def.fields {
+                    self.add_type(&field.ty)?;
+                }
+                match self.0.iter().find(|existing| existing.name == def.name) {
+                    Some(existing) if existing != def => Err(format!(
+                        "Found two different definitions of the struct {}:\n{}\n{}",
+                        def.name, existing, def
+                    )),
+                    Some(_) => Ok(()),
+                    None => {
+                        self.0.push(def.clone());
+                        Ok(())
+                    }
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn structs(&self) -> &[StructDef] {
+        &self.0
+    }
+}
+
+impl MroDisplay for StructHeader {
+    fn min_width(&self) -> usize {
+        // No configuration here
+        0
+    }
+    fn mro_string_no_width(&self) -> String {
+        let mut result = String::new();
+        for def in &self.0 {
+            writeln!(&mut result, "{}", def).unwrap();
+        }
+        result
+    }
+    fn mro_string_with_width(&self, _: usize) -> String {
+        self.mro_string_no_width()
+    }
+}
+
+mro_display_to_display! { StructHeader }
+
 /// Can be auto generated using proc macro attribute
 /// #[make_mro] on MartianMain or MartianStage
 /// implementations if the associated types implement `MartianStruct`
 pub trait MroMaker {
+    /// The stage definition, which is not validated here, so that `martian_make_mro`
+    /// can report the problems in all the stages together. See `StageMro::validate`
     fn stage_mro(adapter_name: impl ToString, stage_key: impl ToString) -> StageMro {
-        let result = StageMro {
+        StageMro {
             stage_name: Self::stage_name(),
             adapter_name: adapter_name.to_string(),
             stage_key: stage_key.to_string(),
             stage_in_out: Self::stage_in_and_out(),
             chunk_in_out: Self::chunk_in_and_out(),
             using_attrs: Self::using_attributes(),
-        };
-        result.verify();
-        result
+            src_prefix: None,
+            preflight: Self::preflight(),
+            description: Self::description().map(String::from),
+        }
     }
-    fn mro(adapter_name: impl ToString, stage_key: impl ToString) -> String {
+    /// The mro of the stage, with the filetypes and structs it uses, or every
+    /// problem found in the stage definition (see `StageMro::validate`)
+    fn mro(
+        adapter_name: impl ToString,
+        stage_key: impl ToString,
+    ) -> Result<String, MroValidationError> {
         let stage_mro = Self::stage_mro(adapter_name, stage_key);
+        stage_mro.validate()?;
         let filetype = FiletypeHeader::from(&stage_mro);
-        format!("{}{}", filetype.to_string(), stage_mro.to_string())
+        let mut structs = StructHeader::default();
+        if let Err(e) = structs.add_stage(&stage_mro) {
+            MroValidationError::from_problems(vec![e])?;
+        }
+        Ok(format!("{}{}{}", filetype, structs, stage_mro))
     }
     fn stage_name() -> String;
+    /// Key of the stage in the adapter, if it is different from the snake case
+    /// name of the stage struct. Set using `#[make_mro(stage_key = my_stage)]`
+    fn stage_key() -> Option<String> {
+        None
+    }
     fn stage_in_and_out() -> InAndOut;
     fn chunk_in_and_out() -> Option<InAndOut>;
     fn using_attributes() -> MroUsing;
+    /// Preflight stages only check the inputs of a pipeline, so they are allowed
+    /// to have no outputs when generating the mro in strict mode.
+    fn preflight() -> bool {
+        false
+    }
+    /// One line description of what the stage does, written as a comment above
+    /// the stage in the mro and included in the stage manifest. `#[make_mro]`
+    /// uses the first line of the doc comment on the trait implementation.
+    fn description() -> Option<&'static str> {
+        None
+    }
 }
 
 /// All the data needed to create a stage definition mro.
-/// TODO: Retain
-#[derive(Debug)]
+/// It can be serialized, so that a registry of stages can be cached
+/// (see `write_mro_cache` and `read_mro_cache`)
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
 pub struct StageMro {
     stage_name: String,     // e.g CORRECT_BARCODES in `stage CORRECT_BARCODES(..)`
     adapter_name: String, // Martian adapter e.g `cr_slfe` in `src comp "cr_slfe martian correct_barcodes"
@@ -558,6 +1386,12 @@ pub struct StageMro {
     stage_in_out: InAndOut, // Inputs and outputs of the stage
     chunk_in_out: Option<InAndOut>, // Inputs and outputs of the chunk. None indicates a stage with only a main
     using_attrs: MroUsing,          // Things coming under using
+    #[serde(default)]
+    src_prefix: Option<PathBuf>, // Directory prefixed to the adapter in `src comp`. None means the bare adapter name
+    #[serde(default)]
+    preflight: bool, // Preflight stages are allowed to have no outputs
+    #[serde(default)]
+    description: Option<String>, // Written as a comment above the stage
 }
 
 impl MroDisplay for StageMro {
@@ -570,15 +1404,23 @@ impl MroDisplay for StageMro {
 
     fn mro_string_with_width(&self, field_width: usize) -> String {
         let mut result = String::new();
-        // Determing the field width for the type field
-        let ty_width = std::cmp::max(
+        // Determing the field width for the type field. The `comp` in the src
+        // line shares the column with the types, so it is at least that wide
+        let ty_width = *[
             self.stage_in_out.min_width(),
             self.chunk_in_out
                 .as_ref()
                 .map(|chunk| chunk.min_width())
                 .unwrap_or(0),
-        );
+            "comp".len(),
+        ]
+        .iter()
+        .max()
+        .unwrap();
         let indent = format!("{blank:indent$}", blank = "", indent = field_width);
+        if let Some(ref description) = self.description {
+            writeln!(&mut result, "# {}", description).unwrap();
+        }
         writeln!(&mut result, "stage {}(", self.stage_name).unwrap();
 
         for line in self.stage_in_out.mro_string(Some(ty_width)).lines() {
@@ -590,7 +1432,7 @@ impl MroDisplay for StageMro {
             space = indent,
             comp = "comp",
             ty_width = ty_width,
-            adapter = self.adapter_name,
+            adapter = self.src_adapter(),
             stage_key = self.stage_key,
         )
         .unwrap();
@@ -608,7 +1450,16 @@ impl MroDisplay for StageMro {
                 writeln!(&mut result, "{}{}", indent, line).unwrap();
             }
         }
-        let retain_names = self.stage_in_out.retain_field_names();
+        // A stage has a single retain block, which also lists the retained chunk
+        // outputs, after the retained stage outputs
+        let mut retain_names = self.stage_in_out.retain_field_names();
+        if let Some(ref chunk_in_out) = self.chunk_in_out {
+            for name in chunk_in_out.retain_field_names() {
+                if !self.stage_in_out.is_retained(&name) {
+                    retain_names.push(name);
+                }
+            }
+        }
         if !retain_names.is_empty() {
             writeln!(&mut result, ") retain (").unwrap();
             for line in retain_names {
@@ -623,397 +1474,2488 @@ impl MroDisplay for StageMro {
 
 mro_display_to_display! {StageMro, TAB_WIDTH_FOR_MRO}
 
-impl StageMro {
-    fn verify(&self) {
-        // By design, all the field names are guaranteed to be not
-        // any of the martian tokens. It raises a compile error when
-        // deriving MartianStruct and is checked when creating a
-        // MaroField wusing new() which is the only public entry point.
-        // So we don't have anything to check for a MainOnly stage
-        if self.chunk_in_out.is_none() {
-            return;
-        }
-
-        let chunk_in_out = self.chunk_in_out.as_ref().unwrap();
-        // Do not allow the same field name in stage and chunk inputs
-        // O(mn) is good enough
-        for f_chunk in chunk_in_out.inputs.iter() {
-            for f_stage in self.stage_in_out.inputs.iter() {
-                assert!(
-                    !(f_chunk.name == f_stage.name),
-                    "ERROR: Found identical field {} in stage and chunk inputs",
-                    f_chunk.name
-                )
+/// The value bound to an input of a `call` or to an output of a pipeline
+#[derive(Debug, Clone, PartialEq)]
+pub enum MroBinding {
+    /// `self.name`, an input of the pipeline
+    PipelineInput(String),
+    /// `STAGE.name`, an output of a stage called in the pipeline
+    StageOutput { stage: String, output: String },
+    /// A constant, written as json e.g `true`, `1.5`, `"GRCh38"` or `null`
+    Value(Value),
+}
+
+impl MroBinding {
+    pub fn pipeline_input(name: impl ToString) -> Self {
+        MroBinding::PipelineInput(name.to_string())
+    }
+    pub fn stage_output(stage: impl ToString, output: impl ToString) -> Self {
+        MroBinding::StageOutput {
+            stage: stage.to_string(),
+            output: output.to_string(),
+        }
+    }
+}
+
+impl Display for MroBinding {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            MroBinding::PipelineInput(name) => write!(f, "self.{}", name),
+            MroBinding::StageOutput { stage, output } => write!(f, "{}.{}", stage, output),
+            MroBinding::Value(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+// `name = binding,` for every binding, aligned on the `=`
+fn binding_lines(bindings: &[(String, MroBinding)]) -> Vec<String> {
+    let name_width = bindings.iter().map(|(n, _)| n.len()).max().unwrap_or(0);
+    bindings
+        .iter()
+        .map(|(name, binding)| {
+            format!(
+                "{name:<width$} = {binding},",
+                name = name,
+                width = name_width,
+                binding = binding
+            )
+        })
+        .collect()
+}
+
+/// A `call` of a stage within a pipeline, binding every input of the stage.
+/// The `local`, `preflight` and `disabled` modifiers are written in the `using`
+/// section of the call.
+/// ```rust
+/// # use martian::{CallMro, MroBinding, StageMro};
+/// # fn call(sum_squares: &StageMro) -> CallMro {
+/// CallMro::new(sum_squares)
+///     .bind("values", MroBinding::pipeline_input("values"))
+///     .bind("scale", MroBinding::Value(2.into()))
+///     .disabled(MroBinding::pipeline_input("skip_sum"))
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct CallMro {
+    stage_name: String,
+    stage_in_out: InAndOut, // Used to check the bindings
+    bindings: Vec<(String, MroBinding)>,
+    local: bool,
+    preflight: bool,
+    disabled: Option<MroBinding>,
+}
+
+impl CallMro {
+    /// A call of the stage, which is a preflight call if the stage is a
+    /// preflight stage
+    pub fn new(stage_mro: &StageMro) -> Self {
+        CallMro {
+            stage_name: stage_mro.stage_name.clone(),
+            stage_in_out: stage_mro.stage_in_out.clone(),
+            bindings: Vec::new(),
+            local: false,
+            preflight: stage_mro.preflight,
+            disabled: None,
+        }
+    }
+    /// Bind the stage input `input` to `binding`
+    pub fn bind(mut self, input: impl ToString, binding: MroBinding) -> Self {
+        self.bindings.push((input.to_string(), binding));
+        self
+    }
+    /// Run the stage on the machine running mrp instead of the cluster (`local = true`)
+    pub fn local(mut self, local: bool) -> Self {
+        self.local = local;
+        self
+    }
+    /// Run the stage before the rest of the pipeline, to check the inputs (`preflight = true`)
+    pub fn preflight(mut self, preflight: bool) -> Self {
+        self.preflight = preflight;
+        self
+    }
+    /// Skip the stage if `binding` is true, e.g `disabled = self.skip_report`.
+    /// The outputs of a disabled stage are `null`.
+    pub fn disabled(mut self, binding: MroBinding) -> Self {
+        self.disabled = Some(binding);
+        self
+    }
+    // The modifiers in the `using` section of the call
+    fn modifiers(&self) -> Vec<(String, MroBinding)> {
+        let mut modifiers = Vec::new();
+        if self.local {
+            modifiers.push(("local".to_string(), MroBinding::Value(json!(true))));
+        }
+        if self.preflight {
+            modifiers.push(("preflight".to_string(), MroBinding::Value(json!(true))));
+        }
+        if let Some(ref disabled) = self.disabled {
+            modifiers.push(("disabled".to_string(), disabled.clone()));
+        }
+        modifiers
+    }
+}
+
+/// A pipeline, which calls a number of stages, binding the stage inputs to the
+/// pipeline inputs or the outputs of other stages, and returns the pipeline outputs.
+/// For example:
+/// ```mro
+/// pipeline SUM_SQUARES_PIPELINE(
+///     in  float[] values,
+///     out float   sum,
+/// )
+/// {
+///     call SUM_SQUARES(
+///         values = self.values,
+///     )
+///
+///     return (
+///         sum = SUM_SQUARES.sum,
+///     )
+/// }
+/// ```
+/// can be built using
+/// ```rust
+/// # use martian::{CallMro, MroBinding, PipelineMro, StageMro};
+/// # use martian::{MartianBlanketType::*, MartianPrimaryType::*};
+/// # fn pipeline(sum_squares: &StageMro) -> PipelineMro {
+/// PipelineMro::new("SUM_SQUARES_PIPELINE")
+///     .input("values", Array(Float))
+///     .call(CallMro::new(sum_squares).bind("values", MroBinding::pipeline_input("values")))
+///     .output("sum", Primary(Float), MroBinding::stage_output("SUM_SQUARES", "sum"))
+/// # }
+/// ```
+/// Pipelines are written after the stages by `martian_make_mro_with_options`.
+/// See `MakeMroOptions::pipeline`
+#[derive(Debug, Clone)]
+pub struct PipelineMro {
+    pipeline_name: String,
+    pipeline_in_out: InAndOut,
+    calls: Vec<CallMro>,
+    returns: Vec<(String, MroBinding)>,
+}
+
+impl PipelineMro {
+    pub fn new(pipeline_name: impl ToString) -> Self {
+        PipelineMro {
+            pipeline_name: pipeline_name.to_string(),
+            pipeline_in_out: InAndOut::default(),
+            calls: Vec::new(),
+            returns: Vec::new(),
+        }
+    }
+
+    /// Add an input to the pipeline
+    pub fn input(mut self, name: impl ToString, ty: MartianBlanketType) -> Self {
+        self.pipeline_in_out.inputs.push(MroField::new(name, ty));
+        self
+    }
+
+    /// Add an output to the pipeline, which is bound to `binding` in the `return`
+    pub fn output(
+        mut self,
+        name: impl ToString,
+        ty: MartianBlanketType,
+        binding: MroBinding,
+    ) -> Self {
+        let name = name.to_string();
+        self.pipeline_in_out.outputs.push(MroField::new(&name, ty));
+        self.returns.push((name, binding));
+        self
+    }
+
+    /// Call a stage in the pipeline. The calls are listed in this order.
+    pub fn call(mut self, call: CallMro) -> Self {
+        self.calls.push(call);
+        self
+    }
+
+    /// A pipeline wrapping a single stage, which passes all the pipeline inputs
+    /// to the stage and returns all the stage outputs. This is useful to run a
+    /// stage in isolation, for example in tests.
+    pub fn passthrough(pipeline_name: impl ToString, stage_mro: &StageMro) -> Self {
+        let mut pipeline = PipelineMro::new(pipeline_name);
+        let mut call = CallMro::new(stage_mro);
+        for field in &stage_mro.stage_in_out.inputs {
+            pipeline = pipeline.input(&field.name, field.ty.clone());
+            call = call.bind(&field.name, MroBinding::pipeline_input(&field.name));
+        }
+        for field in &stage_mro.stage_in_out.outputs {
+            pipeline = pipeline.output(
+                &field.name,
+                field.ty.clone(),
+                MroBinding::stage_output(&stage_mro.stage_name, &field.name),
+            );
+        }
+        pipeline.call(call)
+    }
+
+    /// Check that
+    /// - Every input of every stage that is called is bound exactly once, and nothing else is bound
+    /// - A stage is called at most once
+    /// - `self.x` refers to an input of the pipeline and `STAGE.y` refers to an output of
+    ///   a stage called in the pipeline
+    /// - A preflight call is of a stage without outputs, and none of its inputs are bound
+    ///   to the output of another stage
+    pub fn verify(&self) -> Result<(), String> {
+        let mut field_names = HashSet::new();
+        for field in self
+            .pipeline_in_out
+            .inputs
+            .iter()
+            .chain(&self.pipeline_in_out.outputs)
+        {
+            if !field_names.insert(&field.name) {
+                return Err(format!(
+                    "Pipeline {} has more than one parameter named {}",
+                    self.pipeline_name, field.name
+                ));
             }
         }
 
-        // Do not allow the same field name in stage and chunk outputs
-        // O(mn) is good enough
-        for f_chunk in chunk_in_out.outputs.iter() {
-            for f_stage in self.stage_in_out.outputs.iter() {
-                assert!(
-                    !(f_chunk.name == f_stage.name),
-                    "ERROR: Found identical field {} in stage and chunk outputs",
-                    f_chunk.name
-                )
+        let mut called = HashSet::new();
+        for call in &self.calls {
+            if !called.insert(&call.stage_name) {
+                return Err(format!(
+                    "Pipeline {} calls the stage {} more than once",
+                    self.pipeline_name, call.stage_name
+                ));
+            }
+        }
+
+        for call in &self.calls {
+            if call.preflight && !call.stage_in_out.outputs.is_empty() {
+                return Err(format!(
+                    "The preflight call to {} in pipeline {} is of a stage with outputs",
+                    call.stage_name, self.pipeline_name
+                ));
+            }
+            let mut bound = HashSet::new();
+            for (name, binding) in &call.bindings {
+                let context = format!("Input {} of the call to {}", name, call.stage_name);
+                if let (true, MroBinding::StageOutput { .. }) = (call.preflight, binding) {
+                    return Err(format!(
+                        "{} in pipeline {} is bound to {}, but a preflight call cannot be bound to the outputs of other stages",
+                        context, self.pipeline_name, binding
+                    ));
+                }
+                if !call.stage_in_out.inputs.iter().any(|f| f.name == *name) {
+                    return Err(format!(
+                        "{} in pipeline {} is not an input of the stage",
+                        context, self.pipeline_name
+                    ));
+                }
+                if !bound.insert(name) {
+                    return Err(format!(
+                        "{} in pipeline {} is bound more than once",
+                        context, self.pipeline_name
+                    ));
+                }
+                if let MroBinding::StageOutput { ref stage, .. } = binding {
+                    if *stage == call.stage_name {
+                        return Err(format!(
+                            "{} in pipeline {} is bound to an output of the same stage",
+                            context, self.pipeline_name
+                        ));
+                    }
+                }
+                self.check_binding(binding, &context)?;
+            }
+            if let Some(ref disabled) = call.disabled {
+                let context = format!("The disabled modifier of the call to {}", call.stage_name);
+                if let MroBinding::StageOutput { ref stage, .. } = disabled {
+                    if *stage == call.stage_name {
+                        return Err(format!(
+                            "{} in pipeline {} is bound to an output of the same stage",
+                            context, self.pipeline_name
+                        ));
+                    }
+                }
+                self.check_binding(disabled, &context)?;
+            }
+            for field in &call.stage_in_out.inputs {
+                if !bound.contains(&field.name) {
+                    return Err(format!(
+                        "Input {} of the call to {} in pipeline {} is not bound",
+                        field.name, call.stage_name, self.pipeline_name
+                    ));
+                }
+            }
+        }
+
+        for (name, binding) in &self.returns {
+            self.check_binding(binding, &format!("Output {}", name))?;
+        }
+        Ok(())
+    }
+
+    // Check that the binding refers to an input of the pipeline or to an output
+    // of a stage called in the pipeline. `context` describes what is bound.
+    fn check_binding(&self, binding: &MroBinding, context: &str) -> Result<(), String> {
+        let error = |reason: String| {
+            Err(format!(
+                "{} in pipeline {} is bound to {}, {}",
+                context, self.pipeline_name, binding, reason
+            ))
+        };
+        match binding {
+            MroBinding::PipelineInput(name) => {
+                if !self.pipeline_in_out.inputs.iter().any(|f| f.name == *name) {
+                    return error("which is not an input of the pipeline".to_string());
+                }
+            }
+            MroBinding::StageOutput { stage, output } => {
+                match self.calls.iter().find(|call| call.stage_name == *stage) {
+                    Some(call) => {
+                        if !call.stage_in_out.outputs.iter().any(|f| f.name == *output) {
+                            return error(format!("which is not an output of the stage {}", stage));
+                        }
+                    }
+                    None => {
+                        return error(format!("but {} is not called in the pipeline", stage));
+                    }
+                }
             }
+            MroBinding::Value(_) => {}
         }
+        Ok(())
     }
 }
 
-// impl std::fmt::Display for Stage {
-//     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-//         write!(f, "{}", self.to_mro_string())
-//     }
-// }
+impl MroDisplay for PipelineMro {
+    fn min_width(&self) -> usize {
+        0
+    }
+    fn mro_string_no_width(&self) -> String {
+        self.mro_string_with_width(self.min_width())
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use indoc::indoc;
-    use MartianBlanketType::*;
-    use MartianPrimaryType::*;
+    fn mro_string_with_width(&self, field_width: usize) -> String {
+        let mut result = String::new();
+        let indent = format!("{blank:indent$}", blank = "", indent = field_width);
+        writeln!(&mut result, "pipeline {}(", self.pipeline_name).unwrap();
+        for line in self.pipeline_in_out.mro_string_no_width().lines() {
+            writeln!(&mut result, "{}{}", indent, line).unwrap();
+        }
+        writeln!(&mut result, ")\n{{").unwrap();
 
-    #[test]
-    fn test_martian_primary_type_display() {
-        assert_eq!(Int.mro_string_no_width(), "int");
-        assert_eq!(Int.mro_string(Some(4)), "int ");
-        assert_eq!(FileType("txt".into()).mro_string_with_width(5), "txt  ");
-        assert_eq!(FileType("fastq.lz4".into()).mro_string(None), "fastq.lz4");
+        for call in &self.calls {
+            writeln!(&mut result, "{}call {}(", indent, call.stage_name).unwrap();
+            for line in binding_lines(&call.bindings) {
+                writeln!(&mut result, "{}{}{}", indent, indent, line).unwrap();
+            }
+            let modifiers = call.modifiers();
+            if !modifiers.is_empty() {
+                writeln!(&mut result, "{}) using (", indent).unwrap();
+                for line in binding_lines(&modifiers) {
+                    writeln!(&mut result, "{}{}{}", indent, indent, line).unwrap();
+                }
+            }
+            writeln!(&mut result, "{})\n", indent).unwrap();
+        }
+
+        writeln!(&mut result, "{}return (", indent).unwrap();
+        for line in binding_lines(&self.returns) {
+            writeln!(&mut result, "{}{}{}", indent, indent, line).unwrap();
+        }
+        writeln!(&mut result, "{})\n}}", indent).unwrap();
+
+        result
     }
+}
 
-    #[test]
-    fn test_martian_type_display() {
-        assert_eq!(Primary(Int).mro_string_no_width(), "int");
-        assert_eq!(Array(Int).mro_string(Some(7)), "int[]  ");
-        assert_eq!(
-            Array(FileType("txt".into())).mro_string_with_width(5),
-            "txt[]"
-        );
-        assert_eq!(
-            Primary(FileType("fastq.lz4".into())).mro_string(None),
-            "fastq.lz4"
-        );
+mro_display_to_display! {PipelineMro, TAB_WIDTH_FOR_MRO}
+
+impl StageMro {
+    /// Name of the stage, e.g `CORRECT_BARCODES` in `stage CORRECT_BARCODES(..)`
+    pub fn stage_name(&self) -> &str {
+        &self.stage_name
     }
 
-    #[test]
-    fn test_volatile_parse() {
-        assert_eq!("strict".parse::<Volatile>(), Ok(Volatile::Strict));
-        assert!("foo".parse::<Volatile>().is_err());
+    /// Key of the stage in the adapter, as in `src comp "<adapter> martian <stage_key>"`
+    pub fn stage_key(&self) -> &str {
+        &self.stage_key
+    }
+
+    /// Drop the documentation of all the fields, so that it is not written
+    /// to the mro. See `MroField::with_doc`
+    pub fn remove_field_docs(&mut self) {
+        self.stage_in_out.remove_docs();
+        if let Some(ref mut chunk_in_out) = self.chunk_in_out {
+            chunk_in_out.remove_docs();
+        }
+    }
+
+    pub fn adapter_name(&self) -> &str {
+        &self.adapter_name
+    }
+
+    /// Use `adapter_name` as the name of the adapter executable in the `src comp`
+    /// line. The name cannot be empty or contain whitespace, quotes, backslashes
+    /// or path separators.
+    pub fn set_adapter_name(&mut self, adapter_name: impl ToString) -> Result<(), String> {
+        let adapter_name = adapter_name.to_string();
+        if adapter_name.is_empty() {
+            return Err("The adapter name cannot be empty".to_string());
+        }
+        if let Some(c) = adapter_name
+            .chars()
+            .find(|c| c.is_whitespace() || c.is_control() || ['"', '\\', '/'].contains(c))
+        {
+            return Err(format!(
+                "Adapter name `{}` contains the character {:?}, which cannot be used in the mro src",
+                adapter_name, c
+            ));
+        }
+        self.adapter_name = adapter_name;
+        Ok(())
+    }
+
+    /// Reference the adapter executable in the `src comp` line using a path
+    /// (relative to the mro file or absolute) instead of the bare adapter name,
+    /// for deployments where the adapter is not in `PATH`. The path cannot
+    /// contain whitespace, quotes or backslashes since it needs to be quoted
+    /// in the mro.
+    pub fn set_src_prefix(&mut self, src_prefix: impl AsRef<Path>) -> Result<(), String> {
+        let prefix = src_prefix.as_ref();
+        let prefix_str = prefix
+            .to_str()
+            .ok_or_else(|| format!("src prefix {} is not valid UTF-8", prefix.display()))?;
+        if let Some(c) = prefix_str
+            .chars()
+            .find(|c| c.is_whitespace() || c.is_control() || *c == '"' || *c == '\\')
+        {
+            return Err(format!(
+                "src prefix `{}` contains the character {:?}, which cannot be used in the mro src",
+                prefix_str, c
+            ));
+        }
+        self.src_prefix = Some(prefix.to_path_buf());
+        Ok(())
+    }
+
+    /// Description of the stage in the stage manifest. See `martian_make_manifest`
+    pub(crate) fn manifest_entry(&self) -> Value {
+        let mut using = serde_json::to_value(&self.using_attrs).unwrap();
+        if let Value::Object(ref mut map) = using {
+            map.retain(|_, value| !value.is_null());
+        }
+        let mut entry = json!({
+            "name": self.stage_name,
+            "adapter": self.src_adapter(),
+            "key": self.stage_key,
+            "stage": self.stage_in_out.manifest_entry(),
+            "chunk": self.chunk_in_out.as_ref().map(InAndOut::manifest_entry),
+            "using": using,
+        });
+        if let Some(ref description) = self.description {
+            entry["description"] = json!(description);
+        }
+        entry
+    }
+
+    /// A stage without any outputs is almost always a mistake, unless it is a
+    /// preflight stage. Checked when generating the mro in strict mode.
+    pub fn check_outputs(&self) -> Result<(), String> {
+        if self.stage_in_out.outputs.is_empty() && !self.preflight {
+            return Err(format!(
+                "Stage {} does not have any outputs. If this is intended, mark it as a preflight stage.",
+                self.stage_name
+            ));
+        }
+        Ok(())
+    }
+
+    // The adapter as written in the `src comp` line
+    fn src_adapter(&self) -> String {
+        match self.src_prefix {
+            Some(ref prefix) => prefix.join(&self.adapter_name).display().to_string(),
+            None => self.adapter_name.clone(),
+        }
+    }
+
+    /// Check that none of the four field lists (stage inputs/outputs and chunk
+    /// inputs/outputs) contains the same field name twice, which martian would reject.
+    pub fn check_duplicate_fields(&self) -> Result<(), MroValidationError> {
+        MroValidationError::from_problems(self.duplicate_fields())
+    }
+
+    /// Check the names of all the fields using `check_field_name`, listing every
+    /// invalid name. The fields created using `MroField::new` are always valid,
+    /// unlike the ones deserialized, e.g using `read_mro_cache`.
+    pub fn check_field_names(&self) -> Result<(), MroValidationError> {
+        MroValidationError::from_problems(self.invalid_field_names())
+    }
+
+    /// Check everything martian would reject in the stage definition, collecting
+    /// all the problems instead of stopping at the first one:
+    /// - invalid or duplicate field names
+    /// - the same field in the stage and chunk inputs, or in the stage and chunk outputs
+    /// - retained chunk inputs, and strict retained fields other than the stage outputs
+    /// - default values for fields other than the stage inputs
+    /// - invalid using attributes (see `MroUsing::validate`)
+    pub fn validate(&self) -> Result<(), MroValidationError> {
+        let mut problems = self.invalid_field_names();
+        problems.extend(self.duplicate_fields());
+        if let Err(e) = self.using_attrs.validate() {
+            problems.push(format!("{} (stage {})", e, self.stage_name));
+        }
+
+        // Strict retain only makes sense for the stage outputs
+        for field in self.stage_in_out.inputs.iter().chain(
+            self.chunk_in_out
+                .iter()
+                .flat_map(|chunk| chunk.inputs.iter().chain(chunk.outputs.iter())),
+        ) {
+            if field.strict {
+                problems.push(format!(
+                    "Field {} of stage {} is strict retained, which is only allowed for stage outputs",
+                    field.name, self.stage_name
+                ));
+            }
+        }
+
+        // Default values only make sense for the stage inputs
+        for field in self.stage_in_out.outputs.iter().chain(
+            self.chunk_in_out
+                .iter()
+                .flat_map(|chunk| chunk.inputs.iter().chain(chunk.outputs.iter())),
+        ) {
+            if field.default.is_some() {
+                problems.push(format!(
+                    "Field {} of stage {} has a default value, which is only allowed for stage inputs",
+                    field.name, self.stage_name
+                ));
+            }
+        }
+
+        if let Some(ref chunk_in_out) = self.chunk_in_out {
+            // Chunk retains need to refer to chunk outputs
+            for field in chunk_in_out.inputs.iter().filter(|field| field.retain) {
+                problems.push(format!(
+                    "Chunk input {} of stage {} is retained, only chunk outputs can be retained",
+                    field.name, self.stage_name
+                ));
+            }
+
+            // Do not allow the same field name in stage and chunk inputs (or outputs)
+            // O(mn) is good enough
+            let lists = [
+                ("inputs", &self.stage_in_out.inputs, &chunk_in_out.inputs),
+                ("outputs", &self.stage_in_out.outputs, &chunk_in_out.outputs),
+            ];
+            for (kind, stage_fields, chunk_fields) in lists.iter() {
+                for f_chunk in chunk_fields.iter() {
+                    if stage_fields
+                        .iter()
+                        .any(|f_stage| f_stage.name == f_chunk.name)
+                    {
+                        problems.push(format!(
+                            "Found identical field {} in stage and chunk {} of stage {}",
+                            f_chunk.name, kind, self.stage_name
+                        ));
+                    }
+                }
+            }
+        }
+        MroValidationError::from_problems(problems)
+    }
+
+    // The four field lists: stage inputs/outputs and chunk inputs/outputs
+    fn field_lists(&self) -> Vec<(&'static str, &Vec<MroField>)> {
+        let mut field_lists = vec![
+            ("StageInputs", &self.stage_in_out.inputs),
+            ("StageOutputs", &self.stage_in_out.outputs),
+        ];
+        if let Some(ref chunk_in_out) = self.chunk_in_out {
+            field_lists.push(("ChunkInputs", &chunk_in_out.inputs));
+            field_lists.push(("ChunkOutputs", &chunk_in_out.outputs));
+        }
+        field_lists
+    }
+
+    fn invalid_field_names(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        for (list_name, fields) in self.field_lists() {
+            for field in fields {
+                if let Err(e) = check_field_name(&field.name) {
+                    problems.push(format!(
+                        "{} in the {} of stage {}",
+                        e, list_name, self.stage_name
+                    ));
+                }
+            }
+        }
+        problems
+    }
+
+    fn duplicate_fields(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        for (list_name, fields) in self.field_lists() {
+            let mut seen = HashSet::new();
+            for field in fields {
+                if !seen.insert(&field.name) {
+                    problems.push(format!(
+                        "Found duplicate field {} in the {} of stage {}",
+                        field.name, list_name, self.stage_name
+                    ));
+                }
+            }
+        }
+        problems
+    }
+}
+
+/// Every problem found when validating the stages of an mro, reported together
+/// so that they can all be fixed at once. See `StageMro::validate` and
+/// `validate_mro_registry`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MroValidationError {
+    problems: Vec<String>,
+}
+
+impl MroValidationError {
+    pub(crate) fn from_problems(problems: Vec<String>) -> Result<(), Self> {
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(MroValidationError { problems })
+        }
+    }
+    pub fn problems(&self) -> &[String] {
+        &self.problems
+    }
+}
+
+impl Display for MroValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.problems.as_slice() {
+            [problem] => write!(f, "{}", problem),
+            problems => {
+                write!(f, "Found {} problems in the mro:", problems.len())?;
+                for problem in problems {
+                    write!(f, "\n  - {}", problem)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for MroValidationError {}
+
+/// Validate every stage in the registry (see `StageMro::validate`), and check
+/// that the structs used by the stages do not conflict with each other.
+/// `martian_make_mro` reports all the problems found here together.
+pub fn validate_mro_registry(mro_registry: &[StageMro]) -> Result<(), MroValidationError> {
+    let mut problems = Vec::new();
+    let mut struct_header = StructHeader::default();
+    for stage_mro in mro_registry {
+        if let Err(e) = stage_mro.validate() {
+            problems.extend(e.problems);
+        }
+        if let Err(e) = struct_header.add_stage(stage_mro) {
+            problems.push(format!("{} (stage {})", e, stage_mro.stage_name));
+        }
+    }
+    problems.extend(filetype_conflicts(mro_registry, &struct_header));
+    MroValidationError::from_problems(problems)
+}
+
+// The filetypes declared by more than one Rust type, and the filetypes with the
+// same name as a struct, which the mro cannot tell apart
+fn filetype_conflicts(mro_registry: &[StageMro], struct_header: &StructHeader) -> Vec<String> {
+    fn collect(field: &MroField, rust_types: &mut Vec<(String, String)>) {
+        rust_types.extend(field.filetype_rust_types.0.iter().cloned());
+        collect_type(&field.ty, rust_types);
+    }
+    fn collect_type(ty: &MartianBlanketType, rust_types: &mut Vec<(String, String)>) {
+        match ty.primary() {
+            MartianPrimaryType::MapOf(value) => collect_type(value, rust_types),
+            MartianPrimaryType::Struct(def) => {
+                for field in &def.fields {
+                    collect(field, rust_types);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let mut problems = Vec::new();
+    // The first Rust type and stage seen for each extension
+    let mut declared_by: BTreeMap<String, (String, &str)> = BTreeMap::new();
+    let mut reported = HashSet::new();
+    for stage_mro in mro_registry {
+        let mut rust_types = Vec::new();
+        for (_, fields) in stage_mro.field_lists() {
+            for field in fields {
+                collect(field, &mut rust_types);
+            }
+        }
+        for (ext, rust_type) in rust_types {
+            match declared_by.get(&ext) {
+                Some((other_type, other_stage)) if *other_type != rust_type => {
+                    if reported.insert((ext.clone(), rust_type.clone())) {
+                        problems.push(format!(
+                            "Filetype {} is declared by both the Rust types {} (stage {}) and {} (stage {})",
+                            ext, other_type, other_stage, rust_type, stage_mro.stage_name
+                        ));
+                    }
+                }
+                Some(_) => {}
+                None => {
+                    declared_by.insert(ext, (rust_type, &stage_mro.stage_name));
+                }
+            }
+        }
+    }
+
+    for stage_mro in mro_registry {
+        for ext in FiletypeHeader::from(stage_mro).extensions() {
+            if struct_header.structs().iter().any(|def| def.name == ext)
+                && reported.insert((ext.to_string(), String::new()))
+            {
+                problems.push(format!(
+                    "Filetype {} of stage {} has the same name as a struct",
+                    ext, stage_mro.stage_name
+                ));
+            }
+        }
+    }
+    problems
+}
+
+// impl std::fmt::Display for Stage {
+//     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+//         write!(f, "{}", self.to_mro_string())
+//     }
+// }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indoc::indoc;
+    use MartianBlanketType::*;
+    use MartianPrimaryType::*;
+
+    impl StageMro {
+        // A stage of `my_adapter` without a split, `using` or any of the options
+        fn new(
+            stage_name: impl ToString,
+            stage_key: impl ToString,
+            stage_in_out: InAndOut,
+        ) -> Self {
+            StageMro {
+                stage_name: stage_name.to_string(),
+                adapter_name: "my_adapter".into(),
+                stage_key: stage_key.to_string(),
+                stage_in_out,
+                chunk_in_out: None,
+                using_attrs: MroUsing::default(),
+                src_prefix: None,
+                preflight: false,
+                description: None,
+            }
+        }
+    }
+
+    #[test]
+    fn test_martian_primary_type_display() {
+        assert_eq!(Int.mro_string_no_width(), "int");
+        assert_eq!(Int.mro_string(Some(4)), "int ");
+        assert_eq!(FileType("txt".into()).mro_string_with_width(5), "txt  ");
+        assert_eq!(FileType("fastq.lz4".into()).mro_string(None), "fastq.lz4");
+    }
+
+    #[test]
+    fn test_martian_type_display() {
+        assert_eq!(Primary(Int).mro_string_no_width(), "int");
+        assert_eq!(Array(Int).mro_string(Some(7)), "int[]  ");
+        assert_eq!(
+            Array(FileType("txt".into())).mro_string_with_width(5),
+            "txt[]"
+        );
+        assert_eq!(
+            Primary(FileType("fastq.lz4".into())).mro_string(None),
+            "fastq.lz4"
+        );
+    }
+
+    #[test]
+    fn test_martian_type_parse() {
+        assert_eq!("int".parse::<MartianPrimaryType>(), Ok(Int));
+        assert_eq!("string".parse::<MartianPrimaryType>(), Ok(Str));
+        assert!("str".parse::<MartianPrimaryType>().is_err());
+        assert!("txt".parse::<MartianPrimaryType>().is_err());
+        assert_eq!("float".parse::<MartianBlanketType>(), Ok(Primary(Float)));
+        assert_eq!("path[]".parse::<MartianBlanketType>(), Ok(Array(Path)));
+        assert_eq!(
+            "int[][]".parse::<MartianBlanketType>(),
+            Ok(NestedArray(Int, 2))
+        );
+        assert!("int[]]".parse::<MartianBlanketType>().is_err());
+        assert!("[]".parse::<MartianBlanketType>().is_err());
+        assert_eq!(
+            "map<int>".parse::<MartianPrimaryType>(),
+            Ok(MapOf(Box::new(Primary(Int))))
+        );
+        assert_eq!(
+            "map<float[]>[]".parse::<MartianBlanketType>(),
+            Ok(Array(MapOf(Box::new(Array(Float)))))
+        );
+        assert!("map<>".parse::<MartianPrimaryType>().is_err());
+        assert!("map<int".parse::<MartianPrimaryType>().is_err());
+    }
+
+    #[test]
+    fn test_non_empty_vec_type() {
+        assert_eq!(NonEmptyVec::<u32>::as_martian_blanket_type(), Array(Int));
+    }
+
+    #[test]
+    fn test_nested_array_type() {
+        assert_eq!(
+            Vec::<Vec<u32>>::as_martian_blanket_type(),
+            NestedArray(Int, 2)
+        );
+        assert_eq!(
+            Vec::<Vec<Option<Vec<String>>>>::as_martian_blanket_type(),
+            NestedArray(Str, 3)
+        );
+        assert_eq!(
+            NonEmptyVec::<Vec<f64>>::as_martian_blanket_type().to_string(),
+            "float[][]"
+        );
+        assert_eq!(
+            Vec::<HashMap<String, Vec<Vec<i32>>>>::as_martian_blanket_type().to_string(),
+            "map<int[][]>[]"
+        );
+        assert_eq!(NestedArray(Int, 2).mro_string(Some(9)), "int[][]  ");
+        assert_eq!(Primary(Bool).array_of().array_of().dimensions(), 2);
+    }
+
+    #[test]
+    fn test_typed_map_type() {
+        assert_eq!(
+            TypedMap::<Vec<f64>>::as_martian_primary_type(),
+            MapOf(Box::new(Array(Float)))
+        );
+        assert_eq!(
+            Vec::<TypedMap<u32>>::as_martian_blanket_type(),
+            Array(MapOf(Box::new(Primary(Int))))
+        );
+        assert_eq!(TypedMap::<Value>::as_martian_primary_type(), Map);
+    }
+
+    #[test]
+    fn test_hash_map_type() {
+        assert_eq!(
+            HashMap::<String, i32>::as_martian_blanket_type().to_string(),
+            "map<int>"
+        );
+        assert_eq!(
+            HashMap::<String, Vec<String>>::as_martian_blanket_type().to_string(),
+            "map<string[]>"
+        );
+        assert_eq!(
+            Vec::<HashMap<String, Option<f64>>>::as_martian_blanket_type().to_string(),
+            "map<float>[]"
+        );
+        assert_eq!(
+            HashMap::<String, HashMap<String, bool>>::as_martian_blanket_type().to_string(),
+            "map<map<bool>>"
+        );
+        // Heterogeneous values
+        assert_eq!(
+            HashMap::<String, Value>::as_martian_blanket_type(),
+            Primary(Map)
+        );
+
+        let field = MroField::new(
+            "reads",
+            Primary(MapOf(Box::new(Array(FileType("fastq".into()))))),
+        );
+        assert_eq!(field.to_string(), "map<fastq[]> reads");
+        assert_eq!(
+            FiletypeHeader::from(&field),
+            FiletypeHeader(vec!["fastq".to_string()].into_iter().collect())
+        );
+    }
+
+    #[test]
+    fn test_volatile_parse() {
+        assert_eq!("strict".parse::<Volatile>(), Ok(Volatile::Strict));
+        assert!("foo".parse::<Volatile>().is_err());
+    }
+
+    #[test]
+    fn test_volatile_display() {
+        let vol = Volatile::Strict;
+        assert_eq!(vol.mro_string(None), "strict");
+        assert_eq!(vol.mro_string_no_width(), "strict");
+        assert_eq!(vol.min_width(), 6);
+        assert_eq!(vol.mro_string(Some(10)), "strict    ");
+    }
+
+    #[test]
+    fn test_mro_using_display() {
+        assert_eq!(
+            MroUsing {
+                mem_gb: Some(1),
+                ..Default::default()
+            }
+            .to_string(),
+            indoc!(
+                "
+                mem_gb = 1,
+            "
+            )
+        );
+
+        assert_eq!(
+            MroUsing {
+                mem_gb: Some(1),
+                vmem_gb: Some(4),
+                volatile: Some(Volatile::Strict),
+                ..Default::default()
+            }
+            .mro_string_no_width(),
+            indoc!(
+                "
+                mem_gb   = 1,
+                vmem_gb  = 4,
+                volatile = strict,
+            "
+            )
+        );
+
+        assert_eq!(
+            MroUsing {
+                threads: Some(Threads::Fixed(2)),
+                ..Default::default()
+            }
+            .mro_string_with_width(10),
+            indoc!(
+                "
+                threads    = 2,
+            "
+            )
+        );
+    }
+
+    #[test]
+    fn test_threads() {
+        assert_eq!(Threads::Fixed(8).to_string(), "8");
+        assert_eq!(Threads::AllCores.to_string(), "-1");
+        assert_eq!(Threads::try_from(8), Ok(Threads::Fixed(8)));
+        assert_eq!(Threads::try_from(-1), Ok(Threads::AllCores));
+        assert_eq!(
+            Threads::try_from(-4),
+            Err("Invalid number of threads -4. Use -1 to request all the cores available.".into())
+        );
+        assert!(serde_json::from_str::<Threads>("-2").is_err());
+        assert_eq!(serde_json::to_string(&Threads::AllCores).unwrap(), "-1");
+        assert_eq!(
+            serde_json::from_str::<Threads>("8").unwrap(),
+            Threads::Fixed(8)
+        );
+
+        for &threads in &[Threads::Fixed(8), Threads::AllCores] {
+            let using = MroUsing {
+                threads: Some(threads),
+                ..Default::default()
+            };
+            assert!(using.validate().is_ok());
+            assert_eq!(using.to_string(), format!("threads = {},\n", threads));
+        }
+        assert!(MroUsing {
+            threads: Some(Threads::Fixed(0)),
+            ..Default::default()
+        }
+        .validate()
+        .is_err());
+    }
+
+    #[test]
+    fn test_mro_using_builder() {
+        let using = MroUsing::builder()
+            .mem_gb(4)
+            .vmem_gb(4)
+            .threads(Threads::AllCores)
+            .volatile(Volatile::Strict)
+            .extra("gpus", 1)
+            .build()
+            .unwrap();
+        assert_eq!(
+            using,
+            MroUsing {
+                mem_gb: Some(4),
+                vmem_gb: Some(4),
+                threads: Some(Threads::AllCores),
+                volatile: Some(Volatile::Strict),
+                ..Default::default()
+            }
+            .with_extra("gpus", 1)
+        );
+        assert_eq!(MroUsing::builder().build().unwrap(), MroUsing::default());
+
+        let errors = vec![
+            (
+                MroUsing::builder().mem_gb(-1),
+                "Expected a non-negative mem_gb in the using attributes, found -1",
+            ),
+            (
+                MroUsing::builder().vmem_gb(-2),
+                "Expected a non-negative vmem_gb in the using attributes, found -2",
+            ),
+            (
+                MroUsing::builder().mem_gb(8).vmem_gb(4),
+                "Expected vmem_gb to be at least mem_gb in the using attributes, found vmem_gb = 4 and mem_gb = 8",
+            ),
+            (
+                MroUsing::builder().threads(Threads::Fixed(0)),
+                "Expected at least 1 thread in the using attributes, found 0. Use Threads::AllCores to request all the cores available.",
+            ),
+        ];
+        for (builder, error) in errors {
+            assert_eq!(builder.build().unwrap_err(), error);
+        }
+    }
+
+    #[test]
+    fn test_mro_using_merge() {
+        let defaults = MroUsing::builder()
+            .mem_gb(2)
+            .threads(Threads::Fixed(1))
+            .extra("gpus", 0)
+            .build()
+            .unwrap();
+        let overrides = MroUsing::builder()
+            .threads(Threads::Fixed(4))
+            .volatile(Volatile::Strict)
+            .extra("gpus", 1)
+            .build()
+            .unwrap();
+        assert_eq!(
+            defaults.clone().merge(overrides),
+            MroUsing::builder()
+                .mem_gb(2)
+                .threads(Threads::Fixed(4))
+                .volatile(Volatile::Strict)
+                .extra("gpus", 1)
+                .build()
+                .unwrap()
+        );
+        assert_eq!(defaults.clone().merge(MroUsing::default()), defaults);
+        assert_eq!(MroUsing::default().merge(defaults.clone()), defaults);
+    }
+
+    #[test]
+    fn test_mro_using_extra() {
+        let using = MroUsing {
+            mem_gb: Some(4),
+            ..Default::default()
+        }
+        .with_extra("disabled_if", "self.skip")
+        .with_extra("gpus", 1);
+        assert!(using.need_using());
+        assert!(using.validate().is_ok());
+        assert_eq!(
+            using.to_string(),
+            indoc!(
+                "
+                mem_gb      = 4,
+                disabled_if = self.skip,
+                gpus        = 1,
+            "
+            )
+        );
+        assert!(MroUsing::default().with_extra("gpus", 1).need_using());
+
+        let json = serde_json::to_value(&using).unwrap();
+        assert_eq!(json["gpus"], "1");
+        let round_trip: MroUsing = serde_json::from_value(json).unwrap();
+        assert_eq!(round_trip.to_string(), using.to_string());
+
+        for key in &["threads", "volatile", "split", "2gpus", "gpu count"] {
+            assert!(
+                MroUsing::default().with_extra(key, 1).validate().is_err(),
+                "{}",
+                key
+            );
+        }
+    }
+
+    #[test]
+    fn test_mro_using_need_using() {
+        assert_eq!(MroUsing::default().need_using(), false);
+        assert_eq!(
+            MroUsing {
+                mem_gb: Some(1),
+                ..Default::default()
+            }
+            .need_using(),
+            true
+        );
+        assert_eq!(
+            MroUsing {
+                mem_gb: Some(1),
+                threads: Some(Threads::Fixed(3)),
+                ..Default::default()
+            }
+            .need_using(),
+            true
+        );
+    }
+
+    #[test]
+    fn test_in_and_out_display() {
+        let in_out = InAndOut {
+            inputs: vec![
+                MroField::new("unsorted", Array(Float)),
+                MroField::new("reverse", Primary(Bool)),
+            ],
+            outputs: vec![
+                MroField::new("sorted", Array(Float)),
+                MroField::new("sum", Primary(Float)),
+            ],
+        };
+        let expected = indoc!(
+            "
+            in  float[] unsorted,
+            in  bool    reverse,
+            out float[] sorted,
+            out float   sum,
+        "
+        );
+        assert_eq!(in_out.mro_string(None), expected);
+        assert_eq!(in_out.to_string(), expected);
+    }
+
+    #[test]
+    fn test_stage_mro_display_1() {
+        let expected_mro = indoc!(
+            r#"
+            stage SUM_SQUARES(
+                in  float[] values,
+                out float   sum,
+                src comp    "my_adapter martian sum_squares",
+            ) split (
+                in  float   value,
+                out float   value,
+            )
+            "#
+        );
+
+        let stage_mro = StageMro {
+            chunk_in_out: Some(InAndOut {
+                inputs: vec![MroField::new("value", Primary(Float))],
+                outputs: vec![MroField::new("value", Primary(Float))],
+            }),
+            ..StageMro::new(
+                "SUM_SQUARES",
+                "sum_squares",
+                InAndOut {
+                    inputs: vec![MroField::new("values", Array(Float))],
+                    outputs: vec![MroField::new("sum", Primary(Float))],
+                },
+            )
+        };
+
+        assert_eq!(stage_mro.to_string(), expected_mro);
+    }
+
+    #[test]
+    fn test_stage_mro_display_2() {
+        let expected_mro = indoc!(
+            r#"
+            stage SUM_SQUARES(
+                in  float[] values,
+                out float   sum,
+                src comp    "my_adapter martian sum_squares",
+            ) split (
+            )
+            "#
+        );
+
+        let stage_mro = StageMro {
+            chunk_in_out: Some(InAndOut::default()),
+            ..StageMro::new(
+                "SUM_SQUARES",
+                "sum_squares",
+                InAndOut {
+                    inputs: vec![MroField::new("values", Array(Float))],
+                    outputs: vec![MroField::new("sum", Primary(Float))],
+                },
+            )
+        };
+
+        assert_eq!(stage_mro.to_string(), expected_mro);
+    }
+
+    #[test]
+    fn test_stage_mro_display_3() {
+        let expected_mro = indoc!(
+            r#"
+            stage SUM_SQUARES(
+                in  float[] values,
+                out float   sum,
+                src comp    "my_adapter martian sum_squares",
+            )
+            "#
+        );
+
+        let stage_mro = StageMro::new(
+            "SUM_SQUARES",
+            "sum_squares",
+            InAndOut {
+                inputs: vec![MroField::new("values", Array(Float))],
+                outputs: vec![MroField::new("sum", Primary(Float))],
+            },
+        );
+
+        assert_eq!(stage_mro.to_string(), expected_mro);
+    }
+
+    #[test]
+    fn test_stage_mro_display_4() {
+        let expected_mro = indoc!(
+            r#"
+            stage SUM_SQUARES(
+                in  float[] values,
+                out float   sum,
+                src comp    "my_adapter martian sum_squares",
+            ) using (
+                mem_gb  = 1,
+                threads = 2,
+            )
+            "#
+        );
+
+        let stage_mro = StageMro {
+            using_attrs: MroUsing {
+                mem_gb: Some(1),
+                threads: Some(Threads::Fixed(2)),
+                ..Default::default()
+            },
+            ..StageMro::new(
+                "SUM_SQUARES",
+                "sum_squares",
+                InAndOut {
+                    inputs: vec![MroField::new("values", Array(Float))],
+                    outputs: vec![MroField::new("sum", Primary(Float))],
+                },
+            )
+        };
+
+        assert_eq!(stage_mro.to_string(), expected_mro);
+    }
+
+    #[test]
+    fn test_stage_mro_display_5() {
+        let expected_mro = indoc!(
+            r#"
+            stage SUM_SQUARES(
+                in  float[] values,
+                out float   sum,
+                src comp    "my_adapter martian sum_squares",
+            ) using (
+                mem_gb  = 1,
+                threads = 2,
+            ) retain (
+                sum,
+            )
+            "#
+        );
+
+        let stage_mro = StageMro {
+            using_attrs: MroUsing {
+                mem_gb: Some(1),
+                threads: Some(Threads::Fixed(2)),
+                ..Default::default()
+            },
+            ..StageMro::new(
+                "SUM_SQUARES",
+                "sum_squares",
+                InAndOut {
+                    inputs: vec![MroField::new("values", Array(Float))],
+                    outputs: vec![MroField::retained("sum", Primary(Float))],
+                },
+            )
+        };
+
+        assert_eq!(stage_mro.to_string(), expected_mro);
+    }
+
+    #[test]
+    fn test_stage_mro_display_strict_retain() {
+        let expected_mro = indoc!(
+            r#"
+            stage SUM_SQUARES(
+                in  float[] values,
+                out float   sum,
+                out float[] squares,
+                src comp    "my_adapter martian sum_squares",
+            ) retain (
+                sum,
+                @strict squares,
+            )
+            "#
+        );
+
+        let stage_mro = StageMro::new(
+            "SUM_SQUARES",
+            "sum_squares",
+            InAndOut {
+                inputs: vec![MroField::new("values", Array(Float))],
+                outputs: vec![
+                    MroField::retained("sum", Primary(Float)),
+                    MroField::strict_retained("squares", Array(Float)),
+                ],
+            },
+        );
+        stage_mro.validate().unwrap();
+
+        assert_eq!(stage_mro.to_string(), expected_mro);
+    }
+
+    #[test]
+    fn test_stage_mro_display_chunk_retain() {
+        let expected_mro = indoc!(
+            r#"
+            stage SUM_SQUARES(
+                in  float[] values,
+                out float   sum,
+                src comp    "my_adapter martian sum_squares",
+            ) split (
+                in  float   value,
+                out float   square,
+                out txt     log,
+            ) using (
+                mem_gb = 1,
+            ) retain (
+                sum,
+                log,
+            )
+            "#
+        );
+
+        let stage_mro = StageMro {
+            chunk_in_out: Some(InAndOut {
+                inputs: vec![MroField::new("value", Primary(Float))],
+                outputs: vec![
+                    MroField::new("square", Primary(Float)),
+                    MroField::retained("log", Primary(FileType("txt".into()))),
+                ],
+            }),
+            using_attrs: MroUsing {
+                mem_gb: Some(1),
+                ..Default::default()
+            },
+            ..StageMro::new(
+                "SUM_SQUARES",
+                "sum_squares",
+                InAndOut {
+                    inputs: vec![MroField::new("values", Array(Float))],
+                    outputs: vec![MroField::retained("sum", Primary(Float))],
+                },
+            )
+        };
+        stage_mro.validate().unwrap();
+
+        assert_eq!(stage_mro.to_string(), expected_mro);
+    }
+
+    #[test]
+    fn test_stage_mro_chunk_retain_input() {
+        let stage_mro = StageMro {
+            chunk_in_out: Some(InAndOut {
+                inputs: vec![MroField::retained("value", Primary(Float))],
+                outputs: vec![MroField::new("square", Primary(Float))],
+            }),
+            ..StageMro::new(
+                "SUM_SQUARES",
+                "sum_squares",
+                InAndOut {
+                    inputs: vec![MroField::new("values", Array(Float))],
+                    outputs: vec![MroField::new("sum", Primary(Float))],
+                },
+            )
+        };
+        let err = stage_mro.validate().unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("only chunk outputs can be retained"));
+    }
+
+    #[test]
+    fn test_stage_mro_strict_retain_input() {
+        let stage_mro = StageMro::new(
+            "SUM_SQUARES",
+            "sum_squares",
+            InAndOut {
+                inputs: vec![MroField::strict_retained("values", Array(Float))],
+                outputs: vec![MroField::new("sum", Primary(Float))],
+            },
+        );
+        let err = stage_mro.validate().unwrap_err();
+        assert!(err.to_string().contains("only allowed for stage outputs"));
+    }
+
+    #[test]
+    fn test_stage_mro_src_prefix() {
+        let mut stage_mro = StageMro::new(
+            "SUM_SQUARES",
+            "sum_squares",
+            InAndOut {
+                inputs: vec![MroField::new("values", Array(Float))],
+                outputs: vec![MroField::new("sum", Primary(Float))],
+            },
+        );
+        assert!(stage_mro
+            .to_string()
+            .contains(r#"src comp    "my_adapter martian sum_squares","#));
+
+        stage_mro.set_src_prefix("../../bin").unwrap();
+        assert!(stage_mro
+            .to_string()
+            .contains(r#"src comp    "../../bin/my_adapter martian sum_squares","#));
+
+        stage_mro.set_src_prefix("/opt/pipeline/bin").unwrap();
+        assert!(stage_mro
+            .to_string()
+            .contains(r#"src comp    "/opt/pipeline/bin/my_adapter martian sum_squares","#));
+
+        assert!(stage_mro.set_src_prefix("my bin").is_err());
+        assert!(stage_mro.set_src_prefix("bin\"").is_err());
+        // Unchanged after an error
+        assert!(stage_mro
+            .to_string()
+            .contains("/opt/pipeline/bin/my_adapter"));
+    }
+
+    #[test]
+    fn test_stage_mro_duplicate_fields() {
+        let stage_mro = StageMro {
+            chunk_in_out: Some(InAndOut {
+                inputs: vec![MroField::new("value", Primary(Float))],
+                outputs: vec![
+                    MroField::new("square", Primary(Float)),
+                    MroField::new("square", Primary(Int)),
+                ],
+            }),
+            ..StageMro::new(
+                "SUM_SQUARES",
+                "sum_squares",
+                InAndOut {
+                    inputs: vec![MroField::new("values", Array(Float))],
+                    outputs: vec![MroField::new("sum", Primary(Float))],
+                },
+            )
+        };
+        assert_eq!(
+            stage_mro.check_duplicate_fields().unwrap_err().problems(),
+            ["Found duplicate field square in the ChunkOutputs of stage SUM_SQUARES"]
+        );
+    }
+
+    #[test]
+    fn test_validate_files() {
+        // Json files which have to be a json array
+        struct ArrayFile;
+        impl MartianFileType for ArrayFile {
+            fn extension() -> &'static str {
+                "array.json"
+            }
+            fn new(_: impl AsRef<std::path::Path>, _: impl AsRef<std::path::Path>) -> Self {
+                ArrayFile
+            }
+            fn validate(path: &std::path::Path) -> Result<(), Error> {
+                let _: Vec<Value> = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+                Ok(())
+            }
+        }
+        let validators = vec![FiletypeValidator::of::<ArrayFile>()];
+        let sample = StructDef::new(
+            "Sample",
+            vec![
+                MroField::new("counts", Primary(FileType("array.json".into())))
+                    .with_filetype_validators(validators.clone()),
+            ],
+        );
+        let fields = vec![
+            MroField::new("counts", Array(FileType("array.json".into())))
+                .with_filetype_validators(validators.clone()),
+            MroField::new("samples", Primary(MapOf(Box::new(Primary(Struct(sample)))))),
+            MroField::new("log", Primary(Path)),
+        ];
+
+        let dir = tempdir::TempDir::new("test_validate_files").unwrap();
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+        std::fs::write(path("a.array.json"), "[1, 2]").unwrap();
+        std::fs::write(path("b.array.json"), "[3, 4]").unwrap();
+        std::fs::write(path("truncated.array.json"), "[5, ").unwrap();
+        std::fs::write(path("stage.log"), "[5, ").unwrap();
+        let validate = |outs: Value| validate_files(&fields, outs.as_object().unwrap());
+
+        assert!(validate(json!({
+            "counts": [path("a.array.json"), null, path("missing.array.json")],
+            "samples": {"s1": {"counts": path("b.array.json")}, "s2": null},
+            "log": path("stage.log"),
+        }))
+        .is_ok());
+        assert_eq!(
+            validate(json!({"counts": [path("a.array.json"), path("truncated.array.json")]}))
+                .unwrap_err()
+                .to_string(),
+            format!(
+                "The output counts {} is not a valid array.json file: EOF while parsing a value at line 1 column 4",
+                path("truncated.array.json")
+            )
+        );
+        assert!(validate(json!({
+            "samples": {"s1": {"counts": path("truncated.array.json")}},
+        }))
+        .is_err());
+    }
+
+    #[test]
+    fn test_filetype_conflicts() {
+        let stage_mro = |name: &str, field: MroField| {
+            StageMro::new(
+                name,
+                name.to_lowercase(),
+                InAndOut {
+                    inputs: Vec::new(),
+                    outputs: vec![field],
+                },
+            )
+        };
+        let txt_field = |rust_type: &str| {
+            MroField::new("notes", Primary(FileType("txt".into())))
+                .with_filetype_rust_types(vec![("txt".into(), rust_type.into())])
+        };
+        let registry = vec![
+            stage_mro("WRITE_NOTES", txt_field("notes::TxtFile")),
+            stage_mro("COPY_NOTES", txt_field("notes::TxtFile")),
+            stage_mro("READ_NOTES", txt_field("reader::TextFile")),
+            stage_mro("EDIT_NOTES", txt_field("reader::TextFile")),
+        ];
+        assert_eq!(
+            validate_mro_registry(&registry).unwrap_err().problems(),
+            &["Filetype txt is declared by both the Rust types notes::TxtFile (stage WRITE_NOTES) and reader::TextFile (stage READ_NOTES)"]
+        );
+        assert!(validate_mro_registry(&registry[..2]).is_ok());
+
+        // The Rust types are not part of the mro
+        assert_eq!(txt_field("notes::TxtFile"), txt_field("reader::TextFile"));
+
+        // A filetype cannot have the same name as a struct
+        let reads = StructDef::new("reads", vec![MroField::new("r1", Primary(Path))]);
+        let registry = vec![
+            stage_mro("ALIGN", MroField::new("reads", Primary(Struct(reads)))),
+            stage_mro(
+                "SORT",
+                MroField::new("sorted", Primary(FileType("reads".into()))),
+            ),
+        ];
+        assert_eq!(
+            validate_mro_registry(&registry).unwrap_err().problems(),
+            &["Filetype reads of stage SORT has the same name as a struct"]
+        );
+    }
+
+    #[test]
+    fn test_stage_mro_validate() {
+        let stage_mro = StageMro {
+            chunk_in_out: Some(InAndOut {
+                inputs: vec![
+                    MroField::retained("value", Primary(Float)),
+                    MroField::new("values", Array(Float)),
+                ],
+                outputs: vec![
+                    MroField::new("square", Primary(Float)),
+                    MroField::new("square", Primary(Float)),
+                ],
+            }),
+            using_attrs: MroUsing {
+                mem_gb: Some(-1),
+                ..Default::default()
+            },
+            ..StageMro::new(
+                "SUM_SQUARES",
+                "sum_squares",
+                InAndOut {
+                    inputs: vec![
+                        MroField::new("values", Array(Float)),
+                        MroField::strict_retained("scale", Primary(Float)),
+                    ],
+                    outputs: vec![MroField::new("sum", Primary(Float)).with_default(0)],
+                },
+            )
+        };
+        assert_eq!(
+            stage_mro.validate().unwrap_err().problems(),
+            &[
+                "Found duplicate field square in the ChunkOutputs of stage SUM_SQUARES",
+                "Expected a non-negative mem_gb in the using attributes, found -1 (stage SUM_SQUARES)",
+                "Field scale of stage SUM_SQUARES is strict retained, which is only allowed for stage outputs",
+                "Field sum of stage SUM_SQUARES has a default value, which is only allowed for stage inputs",
+                "Chunk input value of stage SUM_SQUARES is retained, only chunk outputs can be retained",
+                "Found identical field values in stage and chunk inputs of stage SUM_SQUARES",
+            ]
+        );
+
+        // A single problem is reported as is
+        let error = MroValidationError {
+            problems: vec!["Found duplicate field square".into()],
+        };
+        assert_eq!(error.to_string(), "Found duplicate field square");
+    }
+
+    #[test]
+    fn test_check_field_names() {
+        assert!(check_field_name("values").is_ok());
+        assert_eq!(
+            check_field_name("in").unwrap_err(),
+            "Martian token in cannot be used as field name, use in_ instead"
+        );
+        assert!(MroField::try_new("map", Primary(Int)).is_err());
+        assert!(MroField::try_new("map_", Primary(Int)).is_ok());
+        assert!(MroField::try_new("__reads", Primary(Int)).is_err());
+
+        // Fields which bypass `MroField::new`, e.g when deserialized, are all reported
+        let invalid = |name: &str| {
+            let mut field = MroField::new("valid", Primary(Float));
+            field.name = name.to_string();
+            field
+        };
+        let stage_mro = StageMro {
+            chunk_in_out: Some(InAndOut {
+                inputs: Vec::new(),
+                outputs: vec![invalid("__square")],
+            }),
+            ..StageMro::new(
+                "SUM_SQUARES",
+                "sum_squares",
+                InAndOut {
+                    inputs: vec![invalid("in"), MroField::new("values", Array(Float))],
+                    outputs: vec![MroField::new("sum", Primary(Float))],
+                },
+            )
+        };
+        assert_eq!(
+            stage_mro.check_field_names().unwrap_err().to_string(),
+            indoc!(
+                "
+                Found 2 problems in the mro:
+                  - Martian token in cannot be used as field name, use in_ instead in the StageInputs of stage SUM_SQUARES
+                  - Field name __square cannot start with __, which is reserved by martian in the ChunkOutputs of stage SUM_SQUARES"
+            )
+        );
+    }
+
+    #[test]
+    fn test_stage_mro_verify_duplicate_fields() {
+        let stage_mro = StageMro::new(
+            "SUM_SQUARES",
+            "sum_squares",
+            InAndOut {
+                inputs: vec![
+                    MroField::new("values", Array(Float)),
+                    MroField::new("values", Array(Int)),
+                ],
+                outputs: vec![MroField::new("sum", Primary(Float))],
+            },
+        );
+        let err = stage_mro.validate().unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("Found duplicate field values in the StageInputs"));
+    }
+
+    #[test]
+    fn test_stage_mro_display_duplicate_inputs() {
+        let stage_mro = StageMro {
+            chunk_in_out: Some(InAndOut {
+                inputs: vec![MroField::new("values", Array(Float))],
+                outputs: Vec::new(),
+            }),
+            using_attrs: MroUsing {
+                mem_gb: Some(1),
+                threads: Some(Threads::Fixed(2)),
+                ..Default::default()
+            },
+            ..StageMro::new(
+                "SUM_SQUARES",
+                "sum_squares",
+                InAndOut {
+                    inputs: vec![MroField::new("values", Array(Float))],
+                    outputs: vec![MroField::new("sum", Primary(Float))],
+                },
+            )
+        };
+        let err = stage_mro.validate().unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("Found identical field values in stage and chunk inputs"));
+    }
+
+    #[test]
+    fn test_stage_mro_display_duplicate_outputs() {
+        let stage_mro = StageMro {
+            chunk_in_out: Some(InAndOut {
+                inputs: Vec::new(),
+                outputs: vec![MroField::new("sum", Primary(Int))],
+            }),
+            using_attrs: MroUsing {
+                mem_gb: Some(1),
+                threads: Some(Threads::Fixed(2)),
+                ..Default::default()
+            },
+            ..StageMro::new(
+                "SUM_SQUARES",
+                "sum_squares",
+                InAndOut {
+                    inputs: vec![MroField::new("values", Array(Float))],
+                    outputs: vec![MroField::new("sum", Primary(Float))],
+                },
+            )
+        };
+        let err = stage_mro.validate().unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("Found identical field sum in stage and chunk outputs"));
+    }
+
+    #[test]
+    fn test_mro_cache_round_trip() {
+        let registry = vec![
+            StageMro {
+                chunk_in_out: Some(InAndOut {
+                    inputs: vec![MroField::new("value", Primary(Float))],
+                    outputs: vec![MroField::new("square", Primary(FileType("txt".into())))],
+                }),
+                using_attrs: MroUsing {
+                    mem_gb: Some(1),
+                    volatile: Some(Volatile::Strict),
+                    ..Default::default()
+                },
+                ..StageMro::new(
+                    "SUM_SQUARES",
+                    "sum_squares",
+                    InAndOut {
+                        inputs: vec![MroField::new("values", Array(Float))],
+                        outputs: vec![MroField::retained("sum", Primary(Float))],
+                    },
+                )
+            },
+            StageMro {
+                src_prefix: Some("../bin".into()),
+                preflight: true,
+                ..StageMro::new(
+                    "REPORT",
+                    "report",
+                    InAndOut {
+                        inputs: vec![MroField::new("sum", Primary(Float))],
+                        outputs: vec![MroField::new("summary", Primary(FileType("json".into())))],
+                    },
+                )
+            },
+        ];
+
+        let tmp_dir = tempdir::TempDir::new("test_mro_cache").unwrap();
+        let cache_file = tmp_dir.path().join("mro_cache.json");
+        crate::write_mro_cache(&cache_file, &registry).unwrap();
+        let cached = crate::read_mro_cache(&cache_file).unwrap();
+
+        assert_eq!(cached.len(), registry.len());
+        for (expected, actual) in registry.iter().zip(cached.iter()) {
+            assert_eq!(actual.to_string(), expected.to_string());
+            assert_eq!(FiletypeHeader::from(actual), FiletypeHeader::from(expected));
+        }
+    }
+
+    #[test]
+    fn test_passthrough_pipeline() {
+        let stage_mro = StageMro {
+            chunk_in_out: Some(InAndOut {
+                inputs: vec![MroField::new("value", Primary(Float))],
+                outputs: vec![MroField::new("square", Primary(Float))],
+            }),
+            ..StageMro::new(
+                "SUM_SQUARES",
+                "sum_squares",
+                InAndOut {
+                    inputs: vec![
+                        MroField::new("values", Array(Float)),
+                        MroField::new("scale", Primary(Int)),
+                    ],
+                    outputs: vec![
+                        MroField::retained("sum", Primary(Float)),
+                        MroField::new("summary", Primary(FileType("json".into()))),
+                    ],
+                },
+            )
+        };
+        let pipeline = PipelineMro::passthrough("SUM_SQUARES_PIPELINE", &stage_mro);
+        assert_eq!(
+            pipeline.to_string(),
+            indoc!(
+                r#"
+                pipeline SUM_SQUARES_PIPELINE(
+                    in  float[] values,
+                    in  int     scale,
+                    out float   sum,
+                    out json    summary,
+                )
+                {
+                    call SUM_SQUARES(
+                        values = self.values,
+                        scale  = self.scale,
+                    )
+
+                    return (
+                        sum     = SUM_SQUARES.sum,
+                        summary = SUM_SQUARES.summary,
+                    )
+                }
+            "#
+            )
+        );
+    }
+
+    fn pipeline_stages() -> (StageMro, StageMro) {
+        let stage = |name: &str, inputs, outputs| {
+            StageMro::new(name, name.to_lowercase(), InAndOut { inputs, outputs })
+        };
+        let sum_squares = stage(
+            "SUM_SQUARES",
+            vec![
+                MroField::new("values", Array(Float)),
+                MroField::new("scale", Primary(Int)),
+            ],
+            vec![MroField::new("sum", Primary(Float))],
+        );
+        let report = stage(
+            "REPORT",
+            vec![
+                MroField::new("sum", Primary(Float)),
+                MroField::new("sample_id", Primary(Str)),
+            ],
+            vec![MroField::new("summary", Primary(FileType("json".into())))],
+        );
+        (sum_squares, report)
     }
 
     #[test]
-    fn test_volatile_display() {
-        let vol = Volatile::Strict;
-        assert_eq!(vol.mro_string(None), "strict");
-        assert_eq!(vol.mro_string_no_width(), "strict");
-        assert_eq!(vol.min_width(), 6);
-        assert_eq!(vol.mro_string(Some(10)), "strict    ");
+    fn test_pipeline_builder() {
+        let (sum_squares, report) = pipeline_stages();
+        let pipeline = PipelineMro::new("SUM_AND_REPORT")
+            .input("values", Array(Float))
+            .input("sample_id", Primary(Str))
+            .call(
+                CallMro::new(&sum_squares)
+                    .bind("values", MroBinding::pipeline_input("values"))
+                    .bind("scale", MroBinding::Value(json!(2))),
+            )
+            .call(
+                CallMro::new(&report)
+                    .bind("sum", MroBinding::stage_output("SUM_SQUARES", "sum"))
+                    .bind("sample_id", MroBinding::pipeline_input("sample_id")),
+            )
+            .output(
+                "summary",
+                Primary(FileType("json".into())),
+                MroBinding::stage_output("REPORT", "summary"),
+            )
+            .output("version", Primary(Str), MroBinding::Value(json!("1.0")));
+        pipeline.verify().unwrap();
+        assert_eq!(
+            pipeline.to_string(),
+            indoc!(
+                r#"
+                pipeline SUM_AND_REPORT(
+                    in  float[] values,
+                    in  string  sample_id,
+                    out json    summary,
+                    out string  version,
+                )
+                {
+                    call SUM_SQUARES(
+                        values = self.values,
+                        scale  = 2,
+                    )
+
+                    call REPORT(
+                        sum       = SUM_SQUARES.sum,
+                        sample_id = self.sample_id,
+                    )
+
+                    return (
+                        summary = REPORT.summary,
+                        version = "1.0",
+                    )
+                }
+            "#
+            )
+        );
     }
 
     #[test]
-    fn test_mro_using_display() {
+    fn test_pipeline_call_modifiers() {
+        let (sum_squares, report) = pipeline_stages();
+        let check_inputs = StageMro {
+            preflight: true,
+            ..StageMro::new(
+                "CHECK_INPUTS",
+                "check_inputs",
+                InAndOut {
+                    inputs: vec![
+                        MroField::new("values", Array(Float)),
+                        MroField::new("sample_id", Primary(Str)),
+                    ],
+                    outputs: Vec::new(),
+                },
+            )
+        };
+        let pipeline = PipelineMro::new("SUM_AND_REPORT")
+            .input("values", Array(Float))
+            .input("skip_sum", Primary(Bool))
+            .call(
+                CallMro::new(&check_inputs)
+                    .bind("values", MroBinding::pipeline_input("values"))
+                    .bind("sample_id", MroBinding::Value(json!("s1"))),
+            )
+            .call(
+                CallMro::new(&sum_squares)
+                    .bind("values", MroBinding::pipeline_input("values"))
+                    .bind("scale", MroBinding::Value(json!(2)))
+                    .local(true)
+                    .disabled(MroBinding::pipeline_input("skip_sum")),
+            )
+            .call(
+                CallMro::new(&report)
+                    .bind("sum", MroBinding::stage_output("SUM_SQUARES", "sum"))
+                    .bind("sample_id", MroBinding::Value(json!("s1"))),
+            );
+        pipeline.verify().unwrap();
         assert_eq!(
-            MroUsing {
-                mem_gb: Some(1),
-                ..Default::default()
-            }
-            .to_string(),
+            pipeline.to_string(),
             indoc!(
-                "
-                mem_gb = 1,
-            "
+                r#"
+                pipeline SUM_AND_REPORT(
+                    in  float[] values,
+                    in  bool    skip_sum,
+                )
+                {
+                    call CHECK_INPUTS(
+                        values    = self.values,
+                        sample_id = "s1",
+                    ) using (
+                        preflight = true,
+                    )
+
+                    call SUM_SQUARES(
+                        values = self.values,
+                        scale  = 2,
+                    ) using (
+                        local    = true,
+                        disabled = self.skip_sum,
+                    )
+
+                    call REPORT(
+                        sum       = SUM_SQUARES.sum,
+                        sample_id = "s1",
+                    )
+
+                    return (
+                    )
+                }
+            "#
             )
         );
 
+        // A preflight call can neither be of a stage with outputs, nor be bound to
+        // the outputs of other stages
+        let preflight = |call: CallMro| {
+            PipelineMro::new("PIPE")
+                .call(
+                    CallMro::new(&sum_squares)
+                        .bind("values", MroBinding::Value(json!([])))
+                        .bind("scale", MroBinding::Value(json!(1))),
+                )
+                .call(call)
+                .verify()
+        };
         assert_eq!(
-            MroUsing {
-                mem_gb: Some(1),
-                vmem_gb: Some(4),
-                volatile: Some(Volatile::Strict),
-                ..Default::default()
-            }
-            .mro_string_no_width(),
-            indoc!(
-                "
-                mem_gb   = 1,
-                vmem_gb  = 4,
-                volatile = strict,
-            "
+            preflight(
+                CallMro::new(&report)
+                    .bind("sum", MroBinding::Value(json!(1.0)))
+                    .bind("sample_id", MroBinding::Value(json!("s1")))
+                    .preflight(true)
             )
+            .unwrap_err(),
+            "The preflight call to REPORT in pipeline PIPE is of a stage with outputs"
         );
-
         assert_eq!(
-            MroUsing {
-                threads: Some(2),
-                ..Default::default()
-            }
-            .mro_string_with_width(10),
-            indoc!(
-                "
-                threads    = 2,
-            "
+            preflight(
+                CallMro::new(&check_inputs)
+                    .bind("values", MroBinding::stage_output("SUM_SQUARES", "sum"))
+                    .bind("sample_id", MroBinding::Value(json!("s1")))
             )
+            .unwrap_err(),
+            "Input values of the call to CHECK_INPUTS in pipeline PIPE is bound to SUM_SQUARES.sum, but a preflight call cannot be bound to the outputs of other stages"
+        );
+
+        // A preflight stage can be called as a regular stage
+        assert!(!CallMro::new(&check_inputs)
+            .preflight(false)
+            .modifiers()
+            .iter()
+            .any(|(name, _)| name == "preflight"));
+
+        let disabled = |binding| {
+            PipelineMro::new("PIPE")
+                .call(
+                    CallMro::new(&sum_squares)
+                        .bind("values", MroBinding::Value(json!([])))
+                        .bind("scale", MroBinding::Value(json!(1)))
+                        .disabled(binding),
+                )
+                .verify()
+        };
+        assert!(disabled(MroBinding::Value(json!(false))).is_ok());
+        assert_eq!(
+            disabled(MroBinding::pipeline_input("skip")).unwrap_err(),
+            "The disabled modifier of the call to SUM_SQUARES in pipeline PIPE is bound to self.skip, which is not an input of the pipeline"
+        );
+        assert_eq!(
+            disabled(MroBinding::stage_output("SUM_SQUARES", "sum")).unwrap_err(),
+            "The disabled modifier of the call to SUM_SQUARES in pipeline PIPE is bound to an output of the same stage"
         );
     }
 
     #[test]
-    fn test_mro_using_need_using() {
-        assert_eq!(MroUsing::default().need_using(), false);
+    fn test_pipeline_verify() {
+        let (sum_squares, report) = pipeline_stages();
+        let call_sum_squares = || {
+            CallMro::new(&sum_squares)
+                .bind("values", MroBinding::pipeline_input("values"))
+                .bind("scale", MroBinding::Value(json!(1)))
+        };
+        let pipeline = || PipelineMro::new("PIPE").input("values", Array(Float));
+        let verify_err = |pipeline: PipelineMro| pipeline.verify().unwrap_err();
+
         assert_eq!(
-            MroUsing {
-                mem_gb: Some(1),
-                ..Default::default()
-            }
-            .need_using(),
-            true
+            verify_err(pipeline().call(
+                CallMro::new(&sum_squares).bind("values", MroBinding::pipeline_input("values"))
+            )),
+            "Input scale of the call to SUM_SQUARES in pipeline PIPE is not bound"
         );
         assert_eq!(
-            MroUsing {
-                mem_gb: Some(1),
-                threads: Some(3),
-                ..Default::default()
-            }
-            .need_using(),
-            true
+            verify_err(
+                pipeline().call(call_sum_squares().bind("offset", MroBinding::Value(json!(0))))
+            ),
+            "Input offset of the call to SUM_SQUARES in pipeline PIPE is not an input of the stage"
+        );
+        assert_eq!(
+            verify_err(
+                pipeline().call(call_sum_squares().bind("scale", MroBinding::Value(json!(0))))
+            ),
+            "Input scale of the call to SUM_SQUARES in pipeline PIPE is bound more than once"
+        );
+        assert_eq!(
+            verify_err(pipeline().call(call_sum_squares()).call(call_sum_squares())),
+            "Pipeline PIPE calls the stage SUM_SQUARES more than once"
+        );
+        assert_eq!(
+            verify_err(pipeline().input("values", Primary(Int))),
+            "Pipeline PIPE has more than one parameter named values"
+        );
+        assert_eq!(
+            verify_err(pipeline().call(
+                CallMro::new(&report)
+                    .bind("sum", MroBinding::stage_output("SUM_SQUARES", "sum"))
+                    .bind("sample_id", MroBinding::pipeline_input("sample_id"))
+            )),
+            "Input sum of the call to REPORT in pipeline PIPE is bound to SUM_SQUARES.sum, but SUM_SQUARES is not called in the pipeline"
+        );
+        assert_eq!(
+            verify_err(pipeline().call(call_sum_squares()).output(
+                "sum",
+                Primary(Float),
+                MroBinding::stage_output("SUM_SQUARES", "total")
+            )),
+            "Output sum in pipeline PIPE is bound to SUM_SQUARES.total, which is not an output of the stage SUM_SQUARES"
+        );
+        assert_eq!(
+            verify_err(pipeline().output("x", Primary(Int), MroBinding::pipeline_input("y"))),
+            "Output x in pipeline PIPE is bound to self.y, which is not an input of the pipeline"
         );
     }
 
     #[test]
-    fn test_in_and_out_display() {
-        let in_out = InAndOut {
-            inputs: vec![
-                MroField::new("unsorted", Array(Float)),
-                MroField::new("reverse", Primary(Bool)),
-            ],
-            outputs: vec![
-                MroField::new("sorted", Array(Float)),
-                MroField::new("sum", Primary(Float)),
-            ],
-        };
-        let expected = indoc!(
-            "
-            in  float[] unsorted,
-            in  bool    reverse,
-            out float[] sorted,
-            out float   sum,
-        "
+    fn test_manifest() {
+        let registry = vec![
+            StageMro {
+                chunk_in_out: Some(InAndOut {
+                    inputs: vec![MroField::new("value", Primary(Float))],
+                    outputs: vec![MroField::new("square", Primary(Float))],
+                }),
+                using_attrs: MroUsing {
+                    mem_gb: Some(2),
+                    volatile: Some(Volatile::Strict),
+                    ..Default::default()
+                },
+                ..StageMro::new(
+                    "SUM_SQUARES",
+                    "sum_squares",
+                    InAndOut {
+                        inputs: vec![MroField::new("values", Array(Float))],
+                        outputs: vec![MroField::retained("sum", Primary(Float))],
+                    },
+                )
+            },
+            StageMro::new(
+                "REPORT",
+                "report",
+                InAndOut {
+                    inputs: vec![MroField::new("sum", Primary(Float))],
+                    outputs: vec![MroField::new("summary", Primary(FileType("json".into())))],
+                },
+            ),
+        ];
+        let manifest = crate::martian_make_manifest(&registry);
+        let field = |name: &str, ty: &str, retain: bool| json!({"name": name, "type": ty, "retain": retain, "strict": false});
+        assert_eq!(
+            manifest,
+            json!({
+                "stages": [
+                    {
+                        "name": "SUM_SQUARES",
+                        "adapter": "my_adapter",
+                        "key": "sum_squares",
+                        "stage": {
+                            "inputs": [field("values", "float[]", false)],
+                            "outputs": [field("sum", "float", true)],
+                        },
+                        "chunk": {
+                            "inputs": [field("value", "float", false)],
+                            "outputs": [field("square", "float", false)],
+                        },
+                        "using": {"mem_gb": 2, "volatile": "strict"},
+                    },
+                    {
+                        "name": "REPORT",
+                        "adapter": "my_adapter",
+                        "key": "report",
+                        "stage": {
+                            "inputs": [field("sum", "float", false)],
+                            "outputs": [field("summary", "json", false)],
+                        },
+                        "chunk": null,
+                        "using": {},
+                    },
+                ],
+                "filetypes": ["json"],
+            })
         );
-        assert_eq!(in_out.mro_string(None), expected);
-        assert_eq!(in_out.to_string(), expected);
+        // Keys are sorted
+        let manifest_str = serde_json::to_string(&manifest).unwrap();
+        assert!(manifest_str.starts_with(r#"{"filetypes":["json"],"stages":[{"adapter""#));
     }
 
     #[test]
-    fn test_stage_mro_display_1() {
+    fn test_stage_description() {
         let expected_mro = indoc!(
             r#"
+            # Sum of the squares of the values
             stage SUM_SQUARES(
                 in  float[] values,
                 out float   sum,
                 src comp    "my_adapter martian sum_squares",
-            ) split (
-                in  float   value,
-                out float   value,
             )
             "#
         );
-
         let stage_mro = StageMro {
-            stage_name: "SUM_SQUARES".into(),
-            adapter_name: "my_adapter".into(),
-            stage_key: "sum_squares".into(),
-            stage_in_out: InAndOut {
-                inputs: vec![MroField::new("values", Array(Float))],
-                outputs: vec![MroField::new("sum", Primary(Float))],
-            },
-            chunk_in_out: Some(InAndOut {
-                inputs: vec![MroField::new("value", Primary(Float))],
-                outputs: vec![MroField::new("value", Primary(Float))],
-            }),
-            using_attrs: MroUsing::default(),
+            description: Some("Sum of the squares of the values".into()),
+            ..StageMro::new(
+                "SUM_SQUARES",
+                "sum_squares",
+                InAndOut {
+                    inputs: vec![MroField::new("values", Array(Float))],
+                    outputs: vec![MroField::new("sum", Primary(Float))],
+                },
+            )
         };
-
         assert_eq!(stage_mro.to_string(), expected_mro);
+        assert_eq!(
+            stage_mro.manifest_entry()["description"],
+            json!("Sum of the squares of the values")
+        );
     }
 
     #[test]
-    fn test_stage_mro_display_2() {
+    fn test_stage_mro_display_all_sections() {
         let expected_mro = indoc!(
             r#"
+            # Chunked sum of squares
             stage SUM_SQUARES(
-                in  float[] values,
-                out float   sum,
-                src comp    "my_adapter martian sum_squares",
+                in  float[]  values,
+                in  int      chunk_size = 100,
+                out float    sum,
+                out json     summary,
+                src comp     "my_adapter martian sum_squares",
             ) split (
+                in  float[]  chunk_values,
+                out float    chunk_sum,
+                out map<int> counts,
+            ) using (
+                mem_gb   = 4,
+                threads  = 2,
+                volatile = strict,
+                disabled = false,
+            ) retain (
+                @strict summary,
+                counts,
             )
             "#
         );
-
         let stage_mro = StageMro {
-            stage_name: "SUM_SQUARES".into(),
-            adapter_name: "my_adapter".into(),
-            stage_key: "sum_squares".into(),
-            stage_in_out: InAndOut {
-                inputs: vec![MroField::new("values", Array(Float))],
-                outputs: vec![MroField::new("sum", Primary(Float))],
-            },
-            chunk_in_out: Some(InAndOut::default()),
-            using_attrs: MroUsing::default(),
+            chunk_in_out: Some(InAndOut {
+                inputs: vec![MroField::new("chunk_values", Array(Float))],
+                outputs: vec![
+                    MroField::new("chunk_sum", Primary(Float)),
+                    MroField::retained("counts", Primary(MapOf(Box::new(Primary(Int))))),
+                ],
+            }),
+            using_attrs: MroUsing {
+                mem_gb: Some(4),
+                threads: Some(Threads::Fixed(2)),
+                volatile: Some(Volatile::Strict),
+                ..Default::default()
+            }
+            .with_extra("disabled", "false"),
+            description: Some("Chunked sum of squares".into()),
+            ..StageMro::new(
+                "SUM_SQUARES",
+                "sum_squares",
+                InAndOut {
+                    inputs: vec![
+                        MroField::new("values", Array(Float)),
+                        MroField::new("chunk_size", Primary(Int)).with_default(100),
+                    ],
+                    outputs: vec![
+                        MroField::new("sum", Primary(Float)),
+                        MroField::strict_retained("summary", Primary(FileType("json".into()))),
+                    ],
+                },
+            )
         };
-
+        stage_mro.validate().unwrap();
         assert_eq!(stage_mro.to_string(), expected_mro);
     }
 
     #[test]
-    fn test_stage_mro_display_3() {
+    fn test_stage_mro_display_narrow_types() {
+        // The type column is at least as wide as `comp`
         let expected_mro = indoc!(
             r#"
-            stage SUM_SQUARES(
-                in  float[] values,
-                out float   sum,
-                src comp    "my_adapter martian sum_squares",
+            stage COUNT(
+                in  int  n,
+                out int  count,
+                src comp "my_adapter martian count",
+            ) split (
+                in  bool flag,
             )
             "#
         );
-
         let stage_mro = StageMro {
-            stage_name: "SUM_SQUARES".into(),
-            adapter_name: "my_adapter".into(),
-            stage_key: "sum_squares".into(),
-            stage_in_out: InAndOut {
-                inputs: vec![MroField::new("values", Array(Float))],
-                outputs: vec![MroField::new("sum", Primary(Float))],
-            },
-            chunk_in_out: None,
-            using_attrs: MroUsing::default(),
+            chunk_in_out: Some(InAndOut {
+                inputs: vec![MroField::new("flag", Primary(Bool))],
+                outputs: vec![],
+            }),
+            ..StageMro::new(
+                "COUNT",
+                "count",
+                InAndOut {
+                    inputs: vec![MroField::new("n", Primary(Int))],
+                    outputs: vec![MroField::new("count", Primary(Int))],
+                },
+            )
         };
-
         assert_eq!(stage_mro.to_string(), expected_mro);
     }
 
     #[test]
-    fn test_stage_mro_display_4() {
+    fn test_stage_mro_display_field_docs() {
         let expected_mro = indoc!(
             r#"
             stage SUM_SQUARES(
+                # The values to square
                 in  float[] values,
+                # Sum of the squares.
+                #
+                # Retained for the report.
                 out float   sum,
                 src comp    "my_adapter martian sum_squares",
-            ) using (
-                mem_gb  = 1,
-                threads = 2,
             )
             "#
         );
-
-        let stage_mro = StageMro {
-            stage_name: "SUM_SQUARES".into(),
-            adapter_name: "my_adapter".into(),
-            stage_key: "sum_squares".into(),
-            stage_in_out: InAndOut {
-                inputs: vec![MroField::new("values", Array(Float))],
-                outputs: vec![MroField::new("sum", Primary(Float))],
-            },
-            chunk_in_out: None,
-            using_attrs: MroUsing {
-                mem_gb: Some(1),
-                threads: Some(2),
-                ..Default::default()
+        let mut stage_mro = StageMro::new(
+            "SUM_SQUARES",
+            "sum_squares",
+            InAndOut {
+                inputs: vec![MroField::new("values", Array(Float)).with_doc("The values to square")],
+                outputs: vec![MroField::new("sum", Primary(Float))
+                    .with_doc("Sum of the squares.\n\nRetained for the report.")],
             },
-        };
-
+        );
         assert_eq!(stage_mro.to_string(), expected_mro);
+
+        stage_mro.remove_field_docs();
+        assert_eq!(stage_mro.stage_in_out.outputs[0].doc(), None);
+        assert!(!stage_mro.to_string().contains('#'));
+        assert_eq!(
+            MroField::new("sum", Primary(Float)).with_doc("  ").doc(),
+            None
+        );
     }
 
     #[test]
-    fn test_stage_mro_display_5() {
+    fn test_stage_default_values() {
         let expected_mro = indoc!(
             r#"
             stage SUM_SQUARES(
                 in  float[] values,
+                in  float   scale = 2.5,
+                in  int[]   skip = [1,2],
                 out float   sum,
                 src comp    "my_adapter martian sum_squares",
-            ) using (
-                mem_gb  = 1,
-                threads = 2,
-            ) retain (
-                sum,
             )
             "#
         );
-
-        let stage_mro = StageMro {
-            stage_name: "SUM_SQUARES".into(),
-            adapter_name: "my_adapter".into(),
-            stage_key: "sum_squares".into(),
-            stage_in_out: InAndOut {
-                inputs: vec![MroField::new("values", Array(Float))],
-                outputs: vec![MroField::retained("sum", Primary(Float))],
-            },
-            chunk_in_out: None,
-            using_attrs: MroUsing {
-                mem_gb: Some(1),
-                threads: Some(2),
-                ..Default::default()
+        let stage_mro = StageMro::new(
+            "SUM_SQUARES",
+            "sum_squares",
+            InAndOut {
+                inputs: vec![
+                    MroField::new("values", Array(Float)),
+                    MroField::new("scale", Primary(Float)).with_default(2.5),
+                    MroField::new("skip", Array(Int)).with_default(vec![1, 2]),
+                ],
+                outputs: vec![MroField::new("sum", Primary(Float))],
             },
-        };
-
+        );
+        stage_mro.validate().unwrap();
         assert_eq!(stage_mro.to_string(), expected_mro);
+        let inputs = &stage_mro.manifest_entry()["stage"]["inputs"];
+        assert_eq!(inputs[0].get("default"), None);
+        assert_eq!(inputs[1]["default"], json!(2.5));
+        assert_eq!(inputs[2]["default"], json!([1, 2]));
     }
 
     #[test]
-    #[should_panic]
-    fn test_stage_mro_display_duplicate_inputs() {
-        let stage_mro = StageMro {
-            stage_name: "SUM_SQUARES".into(),
-            adapter_name: "my_adapter".into(),
-            stage_key: "sum_squares".into(),
-            stage_in_out: InAndOut {
-                inputs: vec![MroField::new("values", Array(Float))],
-                outputs: vec![MroField::new("sum", Primary(Float))],
-            },
-            chunk_in_out: Some(InAndOut {
+    fn test_stage_default_value_output() {
+        let stage_mro = StageMro::new(
+            "SUM_SQUARES",
+            "sum_squares",
+            InAndOut {
                 inputs: vec![MroField::new("values", Array(Float))],
-                outputs: Vec::new(),
-            }),
-            using_attrs: MroUsing {
-                mem_gb: Some(1),
-                threads: Some(2),
-                ..Default::default()
+                outputs: vec![MroField::new("sum", Primary(Float)).with_default(0.0)],
             },
-        };
-        stage_mro.verify();
+        );
+        let err = stage_mro.validate().unwrap_err();
+        assert!(err.to_string().contains("only allowed for stage inputs"));
     }
 
     #[test]
-    #[should_panic]
-    fn test_stage_mro_display_duplicate_outputs() {
+    fn test_struct_header() {
+        let read_pair = StructDef::new(
+            "ReadPair",
+            vec![
+                MroField::new("r1", Primary(FileType("fastq".into()))),
+                MroField::new("r2", Primary(FileType("fastq".into()))),
+            ],
+        );
+        let sample = StructDef::new(
+            "Sample",
+            vec![
+                MroField::new("name", Primary(Str)),
+                MroField::new("reads", Array(Struct(read_pair.clone()))),
+            ],
+        );
         let stage_mro = StageMro {
-            stage_name: "SUM_SQUARES".into(),
-            adapter_name: "my_adapter".into(),
-            stage_key: "sum_squares".into(),
-            stage_in_out: InAndOut {
-                inputs: vec![MroField::new("values", Array(Float))],
-                outputs: vec![MroField::new("sum", Primary(Float))],
-            },
             chunk_in_out: Some(InAndOut {
-                inputs: Vec::new(),
-                outputs: vec![MroField::new("sum", Primary(Int))],
+                inputs: vec![MroField::new("pair", Primary(Struct(read_pair.clone())))],
+                outputs: vec![],
             }),
-            using_attrs: MroUsing {
-                mem_gb: Some(1),
-                threads: Some(2),
-                ..Default::default()
+            ..StageMro::new(
+                "ALIGN",
+                "align",
+                InAndOut {
+                    inputs: vec![
+                        MroField::new("sample", Primary(Struct(sample.clone()))),
+                        MroField::new(
+                            "extra",
+                            Primary(MapOf(Box::new(Primary(Struct(read_pair.clone()))))),
+                        ),
+                    ],
+                    outputs: vec![MroField::new("bam", Primary(FileType("bam".into())))],
+                },
+            )
+        };
+
+        let mut header = StructHeader::default();
+        header.add_stage(&stage_mro).unwrap();
+        // Referenced structs come first, and each struct is listed once
+        assert_eq!(header.structs(), &[read_pair.clone(), sample]);
+        assert_eq!(
+            header.to_string(),
+            indoc!(
+                r#"
+                struct ReadPair(
+                    fastq r1,
+                    fastq r2,
+                )
+
+                struct Sample(
+                    string     name,
+                    ReadPair[] reads,
+                )
+
+                "#
+            )
+        );
+        assert_eq!(
+            FiletypeHeader::from(&stage_mro).extensions(),
+            vec!["bam", "fastq"]
+        );
+        assert!(stage_mro
+            .to_string()
+            .contains("    in  Sample        sample,\n"));
+
+        // A different struct with the same name is an error
+        let other = StructDef::new("ReadPair", vec![MroField::new("r1", Primary(Path))]);
+        let other_stage = StageMro {
+            stage_in_out: InAndOut {
+                inputs: vec![MroField::new("pair", Primary(Struct(other)))],
+                outputs: vec![],
             },
+            chunk_in_out: None,
+            ..stage_mro
         };
-        stage_mro.verify();
+        assert!(header
+            .add_stage(&other_stage)
+            .unwrap_err()
+            .starts_with("Found two different definitions of the struct ReadPair"));
     }
 
     #[test]
     fn test_filetype_header_from_mro_field() {
         assert_eq!(
             FiletypeHeader::from(&MroField::new("foo", Array(Float))),
-            FiletypeHeader(HashSet::new())
+            FiletypeHeader(BTreeSet::new())
         );
         assert_eq!(
             FiletypeHeader::from(&MroField::new("foo", Array(FileType("txt".into())))),
@@ -1044,7 +3986,7 @@ mod tests {
 
     #[test]
     fn test_filetype_header_display() {
-        assert_eq!(FiletypeHeader(HashSet::new()).to_string(), "");
+        assert_eq!(FiletypeHeader(BTreeSet::new()).to_string(), "");
         assert_eq!(
             FiletypeHeader(vec!["txt"].into_iter().map(|x| x.to_string()).collect()).to_string(),
             "\nfiletype txt;\n\n"
@@ -1068,5 +4010,4 @@ mod tests {
             ]
         );
     }
-
 }
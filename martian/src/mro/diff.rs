@@ -0,0 +1,335 @@
+//! Differences between two sets of stage definitions, for example the stages
+//! in a checked in mro file (read using `parse_mro`) and the stages in the
+//! registry of an adapter. See `martian_diff_mro`
+
+use super::*;
+
+/// The part of a stage definition a field belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MroSection {
+    StageInputs,
+    StageOutputs,
+    ChunkInputs,
+    ChunkOutputs,
+}
+
+impl Display for MroSection {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            MroSection::StageInputs => "in",
+            MroSection::StageOutputs => "out",
+            MroSection::ChunkInputs => "chunk in",
+            MroSection::ChunkOutputs => "chunk out",
+        })
+    }
+}
+
+/// A field which was added, removed or changed (in type or retain)
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldDiff {
+    Added {
+        section: MroSection,
+        field: MroField,
+    },
+    Removed {
+        section: MroSection,
+        field: MroField,
+    },
+    Changed {
+        section: MroSection,
+        old: MroField,
+        new: MroField,
+    },
+}
+
+// e.g `out float sum (retained)`
+fn field_string(section: MroSection, field: &MroField) -> String {
+    let retain = match (field.retain, field.strict) {
+        (true, true) => " (strict retained)",
+        (true, false) => " (retained)",
+        _ => "",
+    };
+    format!("{} {}{}", section, field, retain)
+}
+
+impl Display for FieldDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            FieldDiff::Added { section, field } => {
+                write!(f, "+ {}", field_string(*section, field))
+            }
+            FieldDiff::Removed { section, field } => {
+                write!(f, "- {}", field_string(*section, field))
+            }
+            FieldDiff::Changed { section, old, new } => write!(
+                f,
+                "~ {} -> {}",
+                field_string(*section, old),
+                field_string(*section, new)
+            ),
+        }
+    }
+}
+
+/// How a stage differs between the old and the new definition
+#[derive(Debug, Clone, PartialEq)]
+pub struct StageDiff {
+    pub stage_name: String,
+    pub fields: Vec<FieldDiff>,
+    /// Other differences (src, split, using attributes and description), described in words
+    pub other: Vec<String>,
+}
+
+impl StageDiff {
+    /// Differences from `old` to `new`, or `None` if they only differ in whether
+    /// they are preflight stages, which is not part of the mro.
+    pub fn between(old: &StageMro, new: &StageMro) -> Option<StageDiff> {
+        let mut fields = Vec::new();
+        let empty = InAndOut::default();
+        let old_chunk = old.chunk_in_out.as_ref().unwrap_or(&empty);
+        let new_chunk = new.chunk_in_out.as_ref().unwrap_or(&empty);
+        let sections = [
+            (
+                MroSection::StageInputs,
+                &old.stage_in_out.inputs,
+                &new.stage_in_out.inputs,
+            ),
+            (
+                MroSection::StageOutputs,
+                &old.stage_in_out.outputs,
+                &new.stage_in_out.outputs,
+            ),
+            (
+                MroSection::ChunkInputs,
+                &old_chunk.inputs,
+                &new_chunk.inputs,
+            ),
+            (
+                MroSection::ChunkOutputs,
+                &old_chunk.outputs,
+                &new_chunk.outputs,
+            ),
+        ];
+        for (section, old_fields, new_fields) in sections.iter() {
+            diff_fields(*section, old_fields, new_fields, &mut fields);
+        }
+
+        let mut other = Vec::new();
+        let src = |stage_mro: &StageMro| {
+            format!(
+                "{} martian {}",
+                stage_mro.src_adapter(),
+                stage_mro.stage_key
+            )
+        };
+        if src(old) != src(new) {
+            other.push(format!(
+                "src changed from \"{}\" to \"{}\"",
+                src(old),
+                src(new)
+            ));
+        }
+        match (&old.chunk_in_out, &new.chunk_in_out) {
+            (None, Some(_)) => other.push("split added".to_string()),
+            (Some(_), None) => other.push("split removed".to_string()),
+            _ => {}
+        }
+        if old.using_attrs != new.using_attrs {
+            let using = |stage_mro: &StageMro| {
+                let attrs = stage_mro.using_attrs.mro_string_no_width();
+                format!("({})", attrs.lines().collect::<Vec<_>>().join(" "))
+            };
+            other.push(format!(
+                "using changed from {} to {}",
+                using(old),
+                using(new)
+            ));
+        }
+        if old.description != new.description {
+            other.push("description changed".to_string());
+        }
+
+        if fields.is_empty() && other.is_empty() {
+            return None;
+        }
+        Some(StageDiff {
+            stage_name: new.stage_name.clone(),
+            fields,
+            other,
+        })
+    }
+}
+
+// Fields of `new` which are added or changed relative to `old`, followed by the
+// fields of `old` which are removed
+fn diff_fields(
+    section: MroSection,
+    old: &[MroField],
+    new: &[MroField],
+    result: &mut Vec<FieldDiff>,
+) {
+    // The docs are comments, which do not change the stage
+    let without_doc = |field: &MroField| MroField {
+        doc: None,
+        ..field.clone()
+    };
+    for field in new {
+        match old.iter().find(|f| f.name == field.name) {
+            None => result.push(FieldDiff::Added {
+                section,
+                field: field.clone(),
+            }),
+            Some(old_field) if without_doc(old_field) != without_doc(field) => {
+                result.push(FieldDiff::Changed {
+                    section,
+                    old: old_field.clone(),
+                    new: field.clone(),
+                })
+            }
+            Some(_) => {}
+        }
+    }
+    for field in old {
+        if !new.iter().any(|f| f.name == field.name) {
+            result.push(FieldDiff::Removed {
+                section,
+                field: field.clone(),
+            });
+        }
+    }
+}
+
+/// Differences between two sets of stage definitions, matched by stage name
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct MroDiff {
+    /// Stages which are only in the new definitions
+    pub added_stages: Vec<String>,
+    /// Stages which are only in the old definitions
+    pub removed_stages: Vec<String>,
+    pub changed_stages: Vec<StageDiff>,
+}
+
+impl MroDiff {
+    pub fn between(old: &[StageMro], new: &[StageMro]) -> MroDiff {
+        let mut diff = MroDiff::default();
+        for new_stage in new {
+            match old.iter().find(|s| s.stage_name == new_stage.stage_name) {
+                Some(old_stage) => diff
+                    .changed_stages
+                    .extend(StageDiff::between(old_stage, new_stage)),
+                None => diff.added_stages.push(new_stage.stage_name.clone()),
+            }
+        }
+        for old_stage in old {
+            if !new.iter().any(|s| s.stage_name == old_stage.stage_name) {
+                diff.removed_stages.push(old_stage.stage_name.clone());
+            }
+        }
+        diff
+    }
+
+    /// True if there are no differences
+    pub fn is_empty(&self) -> bool {
+        self.added_stages.is_empty()
+            && self.removed_stages.is_empty()
+            && self.changed_stages.is_empty()
+    }
+}
+
+/// One line per difference, for example
+/// ```text
+/// + stage REPORT
+/// ~ stage SUM_SQUARES
+///     + in float scale
+///     ~ out float sum -> out int sum
+///     using changed from (mem_gb = 1,) to (mem_gb = 2,)
+/// ```
+impl Display for MroDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for name in &self.added_stages {
+            writeln!(f, "+ stage {}", name)?;
+        }
+        for name in &self.removed_stages {
+            writeln!(f, "- stage {}", name)?;
+        }
+        for stage_diff in &self.changed_stages {
+            writeln!(f, "~ stage {}", stage_diff.stage_name)?;
+            for field_diff in &stage_diff.fields {
+                writeln!(f, "    {}", field_diff)?;
+            }
+            for other in &stage_diff.other {
+                writeln!(f, "    {}", other)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use MartianBlanketType::*;
+    use MartianPrimaryType::*;
+
+    fn sum_squares(chunk_in_out: Option<InAndOut>, using_attrs: MroUsing) -> StageMro {
+        StageMro {
+            stage_name: "SUM_SQUARES".into(),
+            adapter_name: "my_adapter".into(),
+            stage_key: "sum_squares".into(),
+            stage_in_out: InAndOut {
+                inputs: vec![MroField::new("values", Array(Float))],
+                outputs: vec![MroField::new("sum", Primary(Float))],
+            },
+            chunk_in_out,
+            using_attrs,
+            src_prefix: None,
+            preflight: false,
+            description: None,
+        }
+    }
+
+    #[test]
+    fn test_stage_diff() {
+        let old = sum_squares(None, MroUsing::default());
+        assert_eq!(StageDiff::between(&old, &old), None);
+        let mut documented = sum_squares(None, MroUsing::default());
+        documented.stage_in_out.outputs[0] = MroField::new("sum", Primary(Float)).with_doc("Sum");
+        assert_eq!(StageDiff::between(&old, &documented), None);
+
+        let new = sum_squares(
+            Some(InAndOut {
+                inputs: vec![MroField::new("value", Primary(Float))],
+                outputs: vec![MroField::retained("square", Primary(Float))],
+            }),
+            MroUsing {
+                mem_gb: Some(2),
+                ..Default::default()
+            },
+        );
+        let diff = StageDiff::between(&old, &new).unwrap();
+        assert_eq!(
+            diff.fields,
+            vec![
+                FieldDiff::Added {
+                    section: MroSection::ChunkInputs,
+                    field: MroField::new("value", Primary(Float)),
+                },
+                FieldDiff::Added {
+                    section: MroSection::ChunkOutputs,
+                    field: MroField::retained("square", Primary(Float)),
+                },
+            ]
+        );
+        assert_eq!(
+            diff.other,
+            vec![
+                "split added".to_string(),
+                "using changed from () to (mem_gb = 2,)".to_string(),
+            ]
+        );
+        assert_eq!(
+            diff.fields[1].to_string(),
+            "+ chunk out float square (retained)"
+        );
+    }
+}
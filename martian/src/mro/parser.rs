@@ -0,0 +1,792 @@
+//! A parser for the subset of the mro language that describes stages, so that
+//! existing mro files can be read into `StageMro`s, for example to compare the
+//! stage definitions in a hand written mro against the Rust stages.
+//!
+//! Pipelines and `@include`s are skipped. Types which are not builtin and not
+//! declared as a `struct` in the file are taken to be filetypes, since they could
+//! be declared in an included file.
+
+use super::*;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String), // Identifiers and keywords, including `@strict` and filetypes like `bam.bai`
+    Str(String),   // The contents of a double quoted string
+    Num(String),
+    Punct(char),
+}
+
+impl Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Token::Ident(s) | Token::Num(s) => write!(f, "`{}`", s),
+            Token::Str(s) => write!(f, "\"{}\"", s),
+            Token::Punct(c) => write!(f, "`{}`", c),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Lexed {
+    token: Token,
+    line: usize,
+}
+
+// Split the text into tokens, dropping the comments. Comments which are the only
+// thing on a line are returned separately, keyed by line number.
+fn tokenize(text: &str) -> Result<(Vec<Lexed>, HashMap<usize, String>), String> {
+    let mut tokens = Vec::new();
+    let mut comments = HashMap::new();
+    for (index, line_text) in text.lines().enumerate() {
+        let line = index + 1;
+        let mut chars = line_text.char_indices().peekable();
+        while let Some((start, c)) = chars.next() {
+            let token = match c {
+                c if c.is_whitespace() => continue,
+                '#' => {
+                    if line_text[..start].trim().is_empty() {
+                        comments.insert(line, line_text[start + 1..].trim().to_string());
+                    }
+                    break;
+                }
+                '"' => {
+                    let mut value = String::new();
+                    loop {
+                        match chars.next() {
+                            Some((_, '"')) => break,
+                            Some((_, '\\')) => match chars.next() {
+                                Some((_, escaped)) => value.push(escaped),
+                                None => return Err(format!("line {}: unterminated string", line)),
+                            },
+                            Some((_, c)) => value.push(c),
+                            None => return Err(format!("line {}: unterminated string", line)),
+                        }
+                    }
+                    Token::Str(value)
+                }
+                c if c.is_ascii_alphabetic() || c == '_' || c == '@' => {
+                    let mut value = c.to_string();
+                    while let Some(&(_, c)) = chars.peek() {
+                        if !(c.is_ascii_alphanumeric() || c == '_' || c == '.') {
+                            break;
+                        }
+                        value.push(c);
+                        chars.next();
+                    }
+                    Token::Ident(value)
+                }
+                c if c.is_ascii_digit() || c == '-' => {
+                    let mut value = c.to_string();
+                    while let Some(&(_, c)) = chars.peek() {
+                        if !(c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '+') {
+                            break;
+                        }
+                        value.push(c);
+                        chars.next();
+                    }
+                    Token::Num(value)
+                }
+                '(' | ')' | '{' | '}' | '[' | ']' | '<' | '>' | ',' | ';' | '=' | ':' => {
+                    Token::Punct(c)
+                }
+                _ => return Err(format!("line {}: unexpected character `{}`", line, c)),
+            };
+            tokens.push(Lexed { token, line });
+        }
+    }
+    Ok((tokens, comments))
+}
+
+/// The contents of an mro file, read using `parse_mro`
+#[derive(Debug, Default)]
+pub struct MroFile {
+    /// Declared filetypes, in the order they are declared
+    pub filetypes: Vec<String>,
+    pub structs: Vec<StructDef>,
+    pub stages: Vec<StageMro>,
+}
+
+impl MroFile {
+    /// The stage named `stage_name`, if it is defined in the file
+    pub fn stage(&self, stage_name: &str) -> Option<&StageMro> {
+        self.stages.iter().find(|s| s.stage_name == stage_name)
+    }
+}
+
+/// Read the stages, structs and filetypes defined in the text of an mro file.
+/// Stages need to be `src comp` stages using a martian adapter, i.e.
+/// `src comp "adapter martian stage_key"`. The comment directly above a stage, if
+/// any, is taken to be its description. Since it is not part of the mro, no stage
+/// is marked as a preflight stage.
+pub fn parse_mro(text: &str) -> Result<MroFile, String> {
+    let (tokens, comments) = tokenize(text)?;
+    let mut parser = Parser {
+        tokens,
+        comments,
+        pos: 0,
+        file: MroFile::default(),
+    };
+    parser.parse_file()?;
+    Ok(parser.file)
+}
+
+struct Parser {
+    tokens: Vec<Lexed>,
+    comments: HashMap<usize, String>,
+    pos: usize,
+    file: MroFile,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|t| &t.token)
+    }
+
+    fn line(&self) -> usize {
+        match self.tokens.get(self.pos) {
+            Some(lexed) => lexed.line,
+            None => self.tokens.last().map(|t| t.line).unwrap_or(0),
+        }
+    }
+
+    fn error<T>(&self, message: impl Display) -> Result<T, String> {
+        Err(format!("line {}: {}", self.line(), message))
+    }
+
+    fn unexpected<T>(&self, expected: &str) -> Result<T, String> {
+        match self.peek() {
+            Some(token) => self.error(format!("expected {}, found {}", expected, token)),
+            None => self.error(format!("expected {}, found the end of the file", expected)),
+        }
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).map(|t| t.token.clone());
+        self.pos += 1;
+        token
+    }
+
+    fn eat_punct(&mut self, c: char) -> bool {
+        if self.peek() == Some(&Token::Punct(c)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn eat_ident(&mut self, ident: &str) -> bool {
+        match self.peek() {
+            Some(Token::Ident(s)) if s == ident => {
+                self.pos += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn expect_punct(&mut self, c: char) -> Result<(), String> {
+        if self.eat_punct(c) {
+            Ok(())
+        } else {
+            self.unexpected(&format!("`{}`", c))
+        }
+    }
+
+    fn expect_ident(&mut self, what: &str) -> Result<String, String> {
+        match self.peek() {
+            Some(Token::Ident(s)) => {
+                let s = s.clone();
+                self.pos += 1;
+                Ok(s)
+            }
+            _ => self.unexpected(what),
+        }
+    }
+
+    fn expect_str(&mut self, what: &str) -> Result<String, String> {
+        match self.peek() {
+            Some(Token::Str(s)) => {
+                let s = s.clone();
+                self.pos += 1;
+                Ok(s)
+            }
+            _ => self.unexpected(what),
+        }
+    }
+
+    fn parse_file(&mut self) -> Result<(), String> {
+        while let Some(token) = self.peek().cloned() {
+            match token {
+                Token::Ident(ref keyword) if keyword == "filetype" => {
+                    self.pos += 1;
+                    let filetype = self.expect_ident("a filetype")?;
+                    self.expect_punct(';')?;
+                    self.file.filetypes.push(filetype);
+                }
+                Token::Ident(ref keyword) if keyword == "struct" => {
+                    self.pos += 1;
+                    let def = self.parse_struct()?;
+                    self.file.structs.push(def);
+                }
+                Token::Ident(ref keyword) if keyword == "stage" => {
+                    let description = self.comments.get(&(self.line() - 1)).cloned();
+                    self.pos += 1;
+                    let mut stage_mro = self.parse_stage()?;
+                    stage_mro.description = description.filter(|d| !d.is_empty());
+                    self.file.stages.push(stage_mro);
+                }
+                Token::Ident(ref keyword) if keyword == "pipeline" => {
+                    self.pos += 1;
+                    self.skip_pipeline()?;
+                }
+                Token::Ident(ref keyword) if keyword == "@include" => {
+                    self.pos += 1;
+                    self.expect_str("the included file")?;
+                }
+                _ => {
+                    return self.unexpected("one of filetype, struct, stage, pipeline or @include")
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn parse_type(&mut self) -> Result<MartianBlanketType, String> {
+        let name = self.expect_ident("a type")?;
+        let primary = if name == "map" && self.eat_punct('<') {
+            let value = self.parse_type()?;
+            self.expect_punct('>')?;
+            MartianPrimaryType::MapOf(Box::new(value))
+        } else if let Ok(builtin) = name.parse::<MartianPrimaryType>() {
+            builtin
+        } else if let Some(def) = self.file.structs.iter().find(|s| s.name == name) {
+            MartianPrimaryType::Struct(def.clone())
+        } else {
+            MartianPrimaryType::FileType(name)
+        };
+        let mut ty = MartianBlanketType::Primary(primary);
+        while self.eat_punct('[') {
+            self.expect_punct(']')?;
+            ty = ty.array_of();
+        }
+        Ok(ty)
+    }
+
+    // `TYPE name` optionally followed by a default value, a help string and an
+    // output file name, and a comma unless it is the last parameter
+    fn parse_field(&mut self) -> Result<MroField, String> {
+        // The comment lines directly above the field are its documentation
+        let mut doc_lines = Vec::new();
+        let mut line = self.line();
+        while let Some(comment) = line.checked_sub(1).and_then(|l| self.comments.get(&l)) {
+            doc_lines.push(comment.clone());
+            line -= 1;
+        }
+        doc_lines.reverse();
+        let doc = doc_lines.join("\n");
+        let ty = self.parse_type()?;
+        let name_line = self.line();
+        let name = self.expect_ident("a parameter name")?;
+        let mut field = MroField::try_new(name, ty)
+            .map_err(|e| format!("line {}: {}", name_line, e))?
+            .with_doc(doc.trim());
+        if self.eat_punct('=') {
+            field = field.with_default(self.parse_value()?);
+        }
+        while let Some(Token::Str(_)) = self.peek() {
+            self.pos += 1;
+        }
+        if !self.eat_punct(',') && self.peek() != Some(&Token::Punct(')')) {
+            return self.unexpected("`,`");
+        }
+        Ok(field)
+    }
+
+    // A literal value, e.g `5`, `"text"`, `null`, `[1, 2]` or `{"key": true}`
+    fn parse_value(&mut self) -> Result<Value, String> {
+        let line = self.line();
+        match self.next() {
+            Some(Token::Str(s)) => Ok(Value::String(s)),
+            Some(Token::Num(s)) => {
+                serde_json::from_str(&s).map_err(|_| format!("line {}: invalid number {}", line, s))
+            }
+            Some(Token::Ident(ref s)) if s == "true" => Ok(Value::Bool(true)),
+            Some(Token::Ident(ref s)) if s == "false" => Ok(Value::Bool(false)),
+            Some(Token::Ident(ref s)) if s == "null" => Ok(Value::Null),
+            Some(Token::Punct('[')) => {
+                let mut values = Vec::new();
+                while !self.eat_punct(']') {
+                    values.push(self.parse_value()?);
+                    if !self.eat_punct(',') && self.peek() != Some(&Token::Punct(']')) {
+                        return self.unexpected("`,`");
+                    }
+                }
+                Ok(Value::Array(values))
+            }
+            Some(Token::Punct('{')) => {
+                let mut map = serde_json::Map::new();
+                while !self.eat_punct('}') {
+                    let key = self.expect_str("a map key")?;
+                    self.expect_punct(':')?;
+                    map.insert(key, self.parse_value()?);
+                    if !self.eat_punct(',') && self.peek() != Some(&Token::Punct('}')) {
+                        return self.unexpected("`,`");
+                    }
+                }
+                Ok(Value::Object(map))
+            }
+            _ => {
+                self.pos -= 1;
+                self.unexpected("a value")
+            }
+        }
+    }
+
+    fn parse_struct(&mut self) -> Result<StructDef, String> {
+        let name = self.expect_ident("a struct name")?;
+        self.expect_punct('(')?;
+        let mut fields = Vec::new();
+        while !self.eat_punct(')') {
+            fields.push(self.parse_field()?);
+        }
+        Ok(StructDef::new(name, fields))
+    }
+
+    // The `in` and `out` parameters up to the closing parenthesis. `src` is only
+    // allowed for the stage parameters, where it is stored in `src`.
+    fn parse_params(&mut self, mut src: Option<&mut Option<String>>) -> Result<InAndOut, String> {
+        let mut in_out = InAndOut::default();
+        while !self.eat_punct(')') {
+            let kind = self.expect_ident("in, out or src")?;
+            match kind.as_str() {
+                "in" => in_out.inputs.push(self.parse_field()?),
+                "out" => in_out.outputs.push(self.parse_field()?),
+                "src" if matches!(src, Some(None)) => {
+                    let lang = self.expect_ident("the stage language")?;
+                    if lang != "comp" {
+                        return self.error(format!(
+                            "only `src comp` stages are supported, found `src {}`",
+                            lang
+                        ));
+                    }
+                    let value = self.expect_str("the stage source")?;
+                    **src.as_mut().unwrap() = Some(value);
+                    if !self.eat_punct(',') && self.peek() != Some(&Token::Punct(')')) {
+                        return self.unexpected("`,`");
+                    }
+                }
+                _ => {
+                    self.pos -= 1;
+                    return self.unexpected("in, out or src");
+                }
+            }
+        }
+        Ok(in_out)
+    }
+
+    fn parse_using(&mut self) -> Result<MroUsing, String> {
+        let mut using = MroUsing::default();
+        while !self.eat_punct(')') {
+            let key = self.expect_ident("a using attribute")?;
+            self.expect_punct('=')?;
+            let line = self.line();
+            let value = match self.next() {
+                Some(Token::Ident(s)) | Some(Token::Num(s)) => s,
+                Some(Token::Str(s)) => format!("\"{}\"", s),
+                _ => {
+                    self.pos -= 1;
+                    return self.unexpected("the value of the using attribute");
+                }
+            };
+            let invalid = || format!("line {}: invalid value {} for {}", line, value, key);
+            match key.as_str() {
+                "mem_gb" => using.mem_gb = Some(value.parse().map_err(|_| invalid())?),
+                "vmem_gb" => using.vmem_gb = Some(value.parse().map_err(|_| invalid())?),
+                "threads" => {
+                    let threads = value.parse::<i16>().map_err(|_| invalid())?;
+                    using.threads = Some(
+                        Threads::try_from(threads).map_err(|e| format!("line {}: {}", line, e))?,
+                    )
+                }
+                "volatile" => using.volatile = Some(value.parse().map_err(|_| invalid())?),
+                _ => {
+                    using.extra.insert(key.clone(), value.clone());
+                }
+            }
+            if !self.eat_punct(',') && self.peek() != Some(&Token::Punct(')')) {
+                return self.unexpected("`,`");
+            }
+        }
+        Ok(using)
+    }
+
+    // Mark the retained outputs of the stage, or else of the chunks, which are
+    // listed in the same block
+    fn parse_retain(
+        &mut self,
+        stage_in_out: &mut InAndOut,
+        mut chunk_in_out: Option<&mut InAndOut>,
+    ) -> Result<(), String> {
+        while !self.eat_punct(')') {
+            let strict = self.eat_ident("@strict");
+            let name = self.expect_ident("the name of a retained output")?;
+            let field = match stage_in_out.outputs.iter_mut().find(|f| f.name == name) {
+                Some(field) => Some(field),
+                None => chunk_in_out
+                    .as_mut()
+                    .and_then(|chunk| chunk.outputs.iter_mut().find(|f| f.name == name)),
+            };
+            match field {
+                Some(field) => {
+                    field.retain = true;
+                    field.strict = strict;
+                }
+                None => return self.error(format!("retained {} is not an output", name)),
+            }
+            if !self.eat_punct(',') && self.peek() != Some(&Token::Punct(')')) {
+                return self.unexpected("`,`");
+            }
+        }
+        Ok(())
+    }
+
+    fn parse_stage(&mut self) -> Result<StageMro, String> {
+        let stage_name = self.expect_ident("a stage name")?;
+        self.expect_punct('(')?;
+        let mut src = None;
+        let mut stage_in_out = self.parse_params(Some(&mut src))?;
+        let src = match src {
+            Some(src) => src,
+            None => return self.error(format!("stage {} does not have a src", stage_name)),
+        };
+        let (adapter, stage_key) = match src.split_whitespace().collect::<Vec<_>>().as_slice() {
+            [adapter, "martian", stage_key] => (adapter.to_string(), stage_key.to_string()),
+            _ => {
+                return self.error(format!(
+                    "expected the src of stage {} to be \"<adapter> martian <stage_key>\", found \"{}\"",
+                    stage_name, src
+                ))
+            }
+        };
+
+        // The sections following the stage parameters, e.g `) split (`
+        let mut chunk_in_out = None;
+        let mut using_attrs = MroUsing::default();
+        loop {
+            if self.eat_ident("split") {
+                // Older mro files use `split using (`
+                self.eat_ident("using");
+                self.expect_punct('(')?;
+                chunk_in_out = Some(self.parse_params(None)?);
+            } else if self.eat_ident("using") {
+                self.expect_punct('(')?;
+                using_attrs = self.parse_using()?;
+            } else if self.eat_ident("retain") {
+                self.expect_punct('(')?;
+                self.parse_retain(&mut stage_in_out, chunk_in_out.as_mut())?;
+            } else {
+                break;
+            }
+        }
+
+        let mut stage_mro = StageMro {
+            stage_name,
+            adapter_name: adapter.clone(),
+            stage_key,
+            stage_in_out,
+            chunk_in_out,
+            using_attrs,
+            src_prefix: None,
+            preflight: false,
+            description: None,
+        };
+        let adapter_path = Path::new(&adapter);
+        if let (Some(prefix), Some(name)) = (adapter_path.parent(), adapter_path.file_name()) {
+            if !prefix.as_os_str().is_empty() {
+                stage_mro.adapter_name = name.to_string_lossy().to_string();
+                stage_mro.src_prefix = Some(prefix.to_path_buf());
+            }
+        }
+        Ok(stage_mro)
+    }
+
+    // Skip the parameters and the body of a pipeline
+    fn skip_pipeline(&mut self) -> Result<(), String> {
+        self.expect_ident("a pipeline name")?;
+        let mut depth = 0;
+        loop {
+            match self.next() {
+                Some(Token::Punct('{')) => depth += 1,
+                Some(Token::Punct('}')) => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(());
+                    }
+                }
+                Some(_) => {}
+                None => return self.error("unterminated pipeline"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indoc::indoc;
+    use MartianBlanketType::*;
+    use MartianPrimaryType::*;
+
+    #[test]
+    fn test_parse_round_trip() {
+        let read_pair = StructDef::new(
+            "ReadPair",
+            vec![
+                MroField::new("r1", Primary(FileType("fastq".into()))),
+                MroField::new("r2", Primary(FileType("fastq".into()))),
+            ],
+        );
+        let mut sum_squares = StageMro {
+            stage_name: "SUM_SQUARES".into(),
+            adapter_name: "my_adapter".into(),
+            stage_key: "sum_squares".into(),
+            stage_in_out: InAndOut {
+                inputs: vec![
+                    MroField::new("values", Array(Float)),
+                    MroField::new("reads", Array(Struct(read_pair))),
+                    MroField::new("counts", Primary(MapOf(Box::new(Array(Int))))),
+                    MroField::new("groups", NestedArray(Str, 2)),
+                ],
+                outputs: vec![
+                    MroField::retained("sum", Primary(Float)),
+                    MroField::strict_retained("index", Primary(FileType("bam.bai".into()))),
+                ],
+            },
+            chunk_in_out: Some(InAndOut {
+                inputs: vec![MroField::new("value", Primary(Float))],
+                outputs: vec![MroField::retained("log", Primary(FileType("txt".into())))],
+            }),
+            using_attrs: MroUsing {
+                mem_gb: Some(4),
+                threads: Some(Threads::AllCores),
+                volatile: Some(Volatile::Strict),
+                ..Default::default()
+            }
+            .with_extra("special", "\"large\""),
+            src_prefix: None,
+            preflight: false,
+            description: Some("Sum of the squares".into()),
+        };
+        sum_squares.set_src_prefix("../bin").unwrap();
+        let report = StageMro {
+            stage_name: "REPORT".into(),
+            adapter_name: "my_adapter".into(),
+            stage_key: "report".into(),
+            stage_in_out: InAndOut {
+                inputs: vec![MroField::new("sum", Primary(Float))],
+                outputs: vec![],
+            },
+            chunk_in_out: None,
+            using_attrs: MroUsing::default(),
+            src_prefix: None,
+            preflight: false,
+            description: None,
+        };
+
+        let mut filetypes = FiletypeHeader::default();
+        let mut structs = StructHeader::default();
+        let mut mro = String::new();
+        for stage_mro in &[&sum_squares, &report] {
+            filetypes.add_stage(stage_mro);
+            structs.add_stage(stage_mro).unwrap();
+            writeln!(&mut mro, "{}", stage_mro).unwrap();
+        }
+        let mro = format!("#\n# Banner\n#\n\n{}{}{}", filetypes, structs, mro);
+
+        let file = parse_mro(&mro).unwrap();
+        assert_eq!(file.filetypes, vec!["bam.bai", "fastq", "txt"]);
+        assert_eq!(file.structs, structs.structs());
+        assert_eq!(file.stages, vec![sum_squares, report]);
+        assert_eq!(file.stage("REPORT").unwrap().stage_key, "report");
+        assert!(file.stage("MISSING").is_none());
+    }
+
+    #[test]
+    fn test_parse_hand_written() {
+        let mro = indoc!(
+            r#"
+            @include "other.mro"
+
+            filetype json;
+
+            # Not a description, since there is a blank line
+
+            stage CHUNK_READS(
+                in  bam      reads    "The input reads",
+                in  int      per_chunk,
+                out json     summary  "Summary"  "summary.json",
+                src comp     "bin/my_adapter martian chunk_reads",
+            ) split using (
+                in  string[] ids,
+                out int      count
+            )
+
+            pipeline CHUNK(
+                in  bam  reads,
+                out json summary,
+            )
+            {
+                call CHUNK_READS(
+                    reads     = self.reads,
+                    per_chunk = 1000,
+                )
+
+                return (
+                    summary = CHUNK_READS.summary,
+                )
+            }
+            "#
+        );
+        let file = parse_mro(mro).unwrap();
+        assert_eq!(file.filetypes, vec!["json"]);
+        assert_eq!(file.stages.len(), 1);
+        let stage_mro = &file.stages[0];
+        assert_eq!(stage_mro.description, None);
+        assert_eq!(stage_mro.adapter_name, "my_adapter");
+        assert_eq!(stage_mro.src_prefix, Some(PathBuf::from("bin")));
+        assert_eq!(
+            stage_mro.stage_in_out,
+            InAndOut {
+                inputs: vec![
+                    MroField::new("reads", Primary(FileType("bam".into()))),
+                    MroField::new("per_chunk", Primary(Int)),
+                ],
+                outputs: vec![MroField::new("summary", Primary(FileType("json".into())))],
+            }
+        );
+        assert_eq!(
+            stage_mro.chunk_in_out,
+            Some(InAndOut {
+                inputs: vec![MroField::new("ids", Array(Str))],
+                outputs: vec![MroField::new("count", Primary(Int))],
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_field_docs() {
+        let mro = indoc!(
+            r#"
+            # Sum of squares
+            stage SUM_SQUARES(
+                # The values to square
+                in  float[] values,
+                in  float   scale,  # Not a doc comment
+                # Sum of the squares.
+                #
+                # Retained for the report.
+                out float   sum,
+                src comp    "my_adapter martian sum_squares",
+            )
+            "#
+        );
+        let file = parse_mro(mro).unwrap();
+        let stage = &file.stages[0];
+        let docs: Vec<_> = stage.stage_in_out.inputs.iter().map(|f| f.doc()).collect();
+        assert_eq!(docs, vec![Some("The values to square"), None]);
+        assert_eq!(
+            stage.stage_in_out.outputs[0].doc(),
+            Some("Sum of the squares.\n\nRetained for the report.")
+        );
+        assert_eq!(stage.description.as_deref(), Some("Sum of squares"));
+        assert_eq!(parse_mro(&stage.to_string()).unwrap().stages, file.stages);
+    }
+
+    #[test]
+    fn test_parse_default_values() {
+        let mro = indoc!(
+            r#"
+            stage SUM_SQUARES(
+                in  float[]  values  = [1, -2.5e3],
+                in  string   mode    = "sum"  "How to combine the squares",
+                in  map<int> limits  = {"low": 0, "high": null},
+                in  bool     verbose = false,
+                out float    sum,
+                src comp     "my_adapter martian sum_squares",
+            )
+            "#
+        );
+        let file = parse_mro(mro).unwrap();
+        let inputs = &file.stages[0].stage_in_out.inputs;
+        let defaults: Vec<_> = inputs.iter().map(|f| f.default_value()).collect();
+        assert_eq!(
+            defaults,
+            vec![
+                Some("[1,-2500.0]"),
+                Some(r#""sum""#),
+                Some(r#"{"high":null,"low":0}"#),
+                Some("false"),
+            ]
+        );
+        assert_eq!(
+            parse_mro(&file.stages[0].to_string()).unwrap().stages,
+            file.stages
+        );
+        assert_eq!(
+            parse_mro("stage FOO(\n    in int x = ,\n)").unwrap_err(),
+            "line 2: expected a value, found `,`"
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let parse_err = |mro: &str| parse_mro(mro).unwrap_err();
+        assert_eq!(
+            parse_err("stage FOO(\n    in int x,\n    src py \"stages/foo\",\n)"),
+            "line 3: only `src comp` stages are supported, found `src py`"
+        );
+        assert_eq!(
+            parse_err("stage FOO(\n    in int x,\n)"),
+            "line 3: stage FOO does not have a src"
+        );
+        assert_eq!(
+            parse_err("stage FOO(\n    in int[3] x,\n)"),
+            "line 2: expected `]`, found `3`"
+        );
+        assert_eq!(
+            parse_err("stage FOO(\n    in int x\n    out int y,\n)"),
+            "line 3: expected `,`, found `out`"
+        );
+        assert_eq!(
+            parse_err("stage FOO(\n    src comp \"foo\",\n)"),
+            "line 3: expected the src of stage FOO to be \"<adapter> martian <stage_key>\", found \"foo\""
+        );
+        assert_eq!(
+            parse_err(
+                "stage FOO(\n    out int y,\n    src comp \"a martian foo\",\n) retain (\n    x,\n)"
+            ),
+            "line 5: retained x is not an output"
+        );
+        assert_eq!(
+            parse_err(
+                "stage FOO(\n    src comp \"a martian foo\",\n) using (\n    mem_gb = lots,\n)"
+            ),
+            "line 4: invalid value lots for mem_gb"
+        );
+        assert_eq!(
+            parse_err(
+                "stage FOO(\n    src comp \"a martian foo\",\n) using (\n    threads = -2,\n)"
+            ),
+            "line 4: Invalid number of threads -2. Use -1 to request all the cores available."
+        );
+        assert_eq!(
+            parse_err("stage FOO(\n    in int __x,\n)"),
+            "line 2: Field name __x cannot start with __, which is reserved by martian"
+        );
+        assert_eq!(
+            parse_err("call FOO()"),
+            "line 1: expected one of filetype, struct, stage, pipeline or @include, found `call`"
+        );
+    }
+}
@@ -0,0 +1,113 @@
+//! Performance data of a stage, recorded in `_jobinfo` under the same keys as the
+//! python adapter (`wallclock`, `rusage` and `io`), from which mrp builds the
+//! `_perf` summary of the pipestance. This way, the performance dashboards work
+//! for Rust stages as they do for python ones.
+
+use crate::metadata::{make_timestamp, JsonDict};
+use chrono::{DateTime, Local};
+use serde_json::{json, Value};
+use std::time::Instant;
+
+/// Measures a stage from `start` to `finish`
+#[derive(Debug, Clone)]
+pub(crate) struct PerfTimer {
+    start: DateTime<Local>,
+    instant: Instant,
+}
+
+impl PerfTimer {
+    pub(crate) fn start() -> Self {
+        PerfTimer {
+            start: Local::now(),
+            instant: Instant::now(),
+        }
+    }
+
+    /// The `_jobinfo` entries for the time since `start`
+    pub(crate) fn finish(self) -> JsonDict {
+        let duration = self.instant.elapsed();
+        let mut perf = JsonDict::new();
+        perf.insert(
+            "wallclock".to_string(),
+            json!({
+                "start": make_timestamp(self.start),
+                "end": make_timestamp(Local::now()),
+                "duration_seconds": duration.as_secs_f64(),
+            }),
+        );
+        perf.insert(
+            "rusage".to_string(),
+            json!({
+                "self": rusage(libc::RUSAGE_SELF),
+                "children": rusage(libc::RUSAGE_CHILDREN),
+            }),
+        );
+        if let Some(io) = io_counters() {
+            perf.insert("io".to_string(), io);
+        }
+        perf
+    }
+}
+
+/// Resource usage as returned by `getrusage`, with the same keys as the python
+/// `resource` module. `ru_maxrss` is in kilobytes on Linux and bytes on macOS.
+fn rusage(who: libc::c_int) -> Value {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    if unsafe { libc::getrusage(who, &mut usage) } != 0 {
+        return Value::Null;
+    }
+    let seconds = |t: libc::timeval| t.tv_sec as f64 + t.tv_usec as f64 / 1e6;
+    json!({
+        "ru_utime": seconds(usage.ru_utime),
+        "ru_stime": seconds(usage.ru_stime),
+        "ru_maxrss": usage.ru_maxrss,
+        "ru_minflt": usage.ru_minflt,
+        "ru_majflt": usage.ru_majflt,
+        "ru_inblock": usage.ru_inblock,
+        "ru_oublock": usage.ru_oublock,
+        "ru_nvcsw": usage.ru_nvcsw,
+        "ru_nivcsw": usage.ru_nivcsw,
+    })
+}
+
+/// I/O counters of this process from `/proc/self/io`, e.g `read_bytes`,
+/// `write_bytes`, `syscr`, or `None` if they are not available
+fn io_counters() -> Option<Value> {
+    let io = std::fs::read_to_string("/proc/self/io").ok()?;
+    let counters: JsonDict = io
+        .lines()
+        .filter_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            let value: u64 = value.trim().parse().ok()?;
+            Some((key.trim().to_string(), Value::from(value)))
+        })
+        .collect();
+    Some(Value::Object(counters))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_perf_timer() {
+        let timer = PerfTimer::start();
+        // Burn some CPU
+        let sum: u64 = (0..1_000_000u64).map(|i| i % 7).sum();
+        assert!(sum > 0);
+        let perf = timer.finish();
+
+        let wallclock = &perf["wallclock"];
+        assert!(wallclock["duration_seconds"].as_f64().unwrap() >= 0.0);
+        assert!(wallclock["start"].as_str().unwrap() <= wallclock["end"].as_str().unwrap());
+
+        let rusage = &perf["rusage"]["self"];
+        assert!(rusage["ru_utime"].as_f64().unwrap() > 0.0);
+        assert!(rusage["ru_maxrss"].as_i64().unwrap() > 0);
+        assert!(perf["rusage"]["children"].is_object());
+
+        if cfg!(target_os = "linux") {
+            assert!(perf["io"]["rchar"].as_u64().is_some());
+        }
+    }
+}
@@ -1,8 +1,18 @@
+pub use crate::alarm;
+pub use crate::folder::{Folder, FolderType};
 pub use crate::stage::{
     MartianMain, MartianRover, MartianStage, RawMartianStage, Resource, StageDef,
 };
-pub use crate::types::{MartianFileType, MartianMakePath, MartianVoid};
-pub use crate::{martian_main, martian_main_with_log_level, martian_make_mro};
+pub use crate::types::{
+    FileTypeIO, MartianFileType, MartianMakePath, MartianVoid, NonEmptyVec, TypedMap,
+};
+pub use crate::{martian_assert, martian_exit};
+pub use crate::{
+    martian_check_mro, martian_main, martian_main_with_log_level, martian_main_with_options,
+    martian_make_mro, martian_make_mro_with_options, martian_make_mro_with_src_prefix, martian_run,
+    martian_run_with_options, read_mro_cache, write_mro_cache, LogBackend, LogFormat, LogOptions,
+    MakeMroOptions, RunOptions,
+};
 pub use failure::Error;
 pub use log::LevelFilter;
 pub use martian_stages;
@@ -0,0 +1,139 @@
+//! Clean shutdown when the stage process is terminated by a signal, e.g when the
+//! cluster scheduler kills a chunk. Instead of dying silently, the adapter writes
+//! the signal to `_errors`, runs the cleanup callbacks registered by the stage
+//! and flushes the log before terminating. The monitor does the same when it
+//! aborts a stage which uses too much memory or runs for too long. In both cases,
+//! the process waits for the stage to finish writing its outputs before exiting.
+
+use crate::write_errors;
+use log::error;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use signal_hook::low_level::{emulate_default_handler, signal_name};
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, MutexGuard, TryLockError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+type Cleanup = Box<dyn FnOnce() + Send>;
+
+static CLEANUPS: Mutex<Vec<Cleanup>> = Mutex::new(Vec::new());
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+static OUTPUTS: Mutex<()> = Mutex::new(());
+
+/// How long the process waits for the stage to finish writing an output file
+/// before exiting anyway
+const OUTPUTS_WAIT: Duration = Duration::from_secs(30);
+
+/// Register a callback which runs if the stage is terminated by SIGTERM or SIGINT,
+/// e.g to kill child processes or remove large temporary files. The callbacks run
+/// in the order in which they were registered, on a separate thread while the
+/// stage is still running, so they should not wait on the stage.
+pub fn register_cleanup(cleanup: impl FnOnce() + Send + 'static) {
+    CLEANUPS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .push(Box::new(cleanup));
+}
+
+/// Handle SIGTERM and SIGINT on a separate thread. Installing the handlers more
+/// than once is a no-op.
+pub(crate) fn install_handlers() -> io::Result<()> {
+    if INSTALLED.swap(true, Ordering::SeqCst) {
+        return Ok(());
+    }
+    let mut signals = Signals::new([SIGTERM, SIGINT])?;
+    thread::Builder::new()
+        .name("martian-signals".to_string())
+        .spawn(move || {
+            if let Some(signal) = signals.forever().next() {
+                terminate(signal);
+            }
+        })?;
+    Ok(())
+}
+
+/// Held while the stage writes an output file, e.g `_outs`, so that the process
+/// does not exit in the middle of it, see `abort`
+pub(crate) fn outputs_guard() -> MutexGuard<'static, ()> {
+    OUTPUTS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// Wait up to `timeout` for the stage to finish writing its outputs. The guard is
+// held until the process exits, so that no other write starts. This is done after
+// the cleanup callbacks, which may write files themselves.
+fn wait_for_outputs(timeout: Duration) -> Option<MutexGuard<'static, ()>> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        match OUTPUTS.try_lock() {
+            Ok(guard) => return Some(guard),
+            Err(TryLockError::Poisoned(e)) => return Some(e.into_inner()),
+            Err(TryLockError::WouldBlock) if Instant::now() >= deadline => return None,
+            Err(TryLockError::WouldBlock) => thread::sleep(Duration::from_millis(10)),
+        }
+    }
+}
+
+/// Shut down (see `shutdown`) and exit with `code`, once the stage is done
+/// writing its outputs, or after `OUTPUTS_WAIT`
+pub(crate) fn abort(msg: &str, code: i32) -> ! {
+    shutdown(msg);
+    let _guard = wait_for_outputs(OUTPUTS_WAIT);
+    std::process::exit(code)
+}
+
+/// Report `msg` in `_errors`, run the cleanup callbacks and flush the log,
+/// before the process exits
+pub(crate) fn shutdown(msg: &str) {
+    error!("{}", msg);
+    let _ = write_errors(msg);
+
+    let cleanups = std::mem::take(&mut *CLEANUPS.lock().unwrap_or_else(|e| e.into_inner()));
+    for cleanup in cleanups {
+        cleanup();
+    }
+    log::logger().flush();
+}
+
+fn terminate(signal: i32) -> ! {
+    shutdown(&format!(
+        "stage terminated by signal {}",
+        signal_name(signal).unwrap_or("unknown")
+    ));
+    let _guard = wait_for_outputs(OUTPUTS_WAIT);
+
+    // Terminate by the signal, so that the parent process sees what happened
+    let _ = emulate_default_handler(signal);
+    std::process::exit(128 + signal)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_wait_for_outputs() {
+        let (locked_tx, locked_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let writer = thread::spawn(move || {
+            let _guard = outputs_guard();
+            locked_tx.send(()).unwrap();
+            let _ = release_rx.recv();
+        });
+        locked_rx.recv().unwrap();
+
+        // Gives up while the outputs are being written
+        assert!(wait_for_outputs(Duration::from_millis(50)).is_none());
+
+        // Waits for the write to finish
+        let release = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            release_tx.send(()).unwrap();
+        });
+        assert!(wait_for_outputs(Duration::from_secs(10)).is_some());
+        writer.join().unwrap();
+        release.join().unwrap();
+    }
+}
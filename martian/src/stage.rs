@@ -1,15 +1,39 @@
-use crate::mro::{MartianStruct, MroMaker};
+use crate::adapter_io::IoSink;
+use crate::folder::FolderType;
+use crate::metadata::decode_json;
+use crate::mro::{validate_files, MartianStruct, MroMaker, MroUsing};
+use crate::temp_dir::ScopedTempDir;
 use crate::types::{MartianMakePath, MartianVoid};
-use crate::utils::{obj_decode, obj_encode};
-use crate::Metadata;
-use failure::Error;
+use crate::utils::obj_encode;
+use crate::{set_log_chunk_index, JsonDict, Metadata, StageError};
+use failure::{Error, Fail};
+use log::warn;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::iter::FromIterator;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
 
 /// Memory/ thread request can be negative in matrian
 /// http://martian-lang.org/advanced-features/#resource-consumption
-#[derive(Debug, Serialize, Deserialize, Copy, Clone, Default)]
+///
+/// The resources of a chunk or of the join, in `StageDef`. The fields which
+/// are not set take the values in the `using` section of the stage, which are
+/// given by `#[make_mro(mem_gb = .., threads = ..)]`. `Resource::from` converts
+/// the `MroUsing` of a stage, to start from the resources it declares:
+/// ```rust
+/// use martian::{MroUsing, Resource, Threads};
+/// let using = MroUsing::builder().mem_gb(4).threads(Threads::Fixed(2)).build().unwrap();
+/// let resource = Resource::from(&using).mem_gb(8);
+/// assert_eq!(resource.get_mem_gb(), Some(8));
+/// assert_eq!(resource.get_threads(), Some(2));
+/// assert_eq!(resource.get_vmem_gb(), None);
+/// ```
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, Default, PartialEq)]
 pub struct Resource {
     #[serde(rename = "__mem_gb")]
     mem_gb: Option<isize>,
@@ -49,6 +73,25 @@ impl Resource {
             vmem_gb: None,
         }
     }
+    pub fn get_mem_gb(&self) -> Option<isize> {
+        self.mem_gb
+    }
+    pub fn get_threads(&self) -> Option<isize> {
+        self.threads
+    }
+    pub fn get_vmem_gb(&self) -> Option<isize> {
+        self.vmem_gb
+    }
+}
+
+impl From<&MroUsing> for Resource {
+    fn from(using: &MroUsing) -> Resource {
+        Resource {
+            mem_gb: using.mem_gb.map(isize::from),
+            threads: using.threads.map(|threads| i16::from(threads) as isize),
+            vmem_gb: using.vmem_gb.map(isize::from),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -59,6 +102,16 @@ struct ChunkDef<T> {
     resource: Resource,
 }
 
+/// What the split returns: the inputs and resources of every chunk, along with
+/// the resources of the join. The adapter writes it to `_stage_defs` as
+/// `{"chunks": [{<inputs>, "__mem_gb": .., ..}, ..], "join": {"__mem_gb": .., ..}}`.
+/// A stage def can also be collected from the chunk inputs, using the default
+/// resources for every chunk:
+/// ```rust
+/// use martian::StageDef;
+/// let stage_def: StageDef<u32> = (0..4).collect();
+/// assert_eq!(stage_def.len(), 4);
+/// ```
 #[derive(Debug, Serialize, Deserialize)]
 pub struct StageDef<T> {
     chunks: Vec<ChunkDef<T>>,
@@ -66,6 +119,22 @@ pub struct StageDef<T> {
     join_resource: Resource,
 }
 
+impl<T> Default for StageDef<T> {
+    fn default() -> Self {
+        StageDef::new()
+    }
+}
+
+impl<T> FromIterator<T> for StageDef<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        let mut stage_def = StageDef::new();
+        for inputs in iter {
+            stage_def.add_chunk(inputs);
+        }
+        stage_def
+    }
+}
+
 impl<T> StageDef<T> {
     pub fn new() -> Self {
         StageDef {
@@ -74,6 +143,26 @@ impl<T> StageDef<T> {
         }
     }
 
+    /// Number of chunks
+    pub fn len(&self) -> usize {
+        self.chunks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+
+    /// The inputs and resources of every chunk, in order
+    pub fn chunks(&self) -> impl Iterator<Item = (&T, &Resource)> {
+        self.chunks
+            .iter()
+            .map(|chunk| (&chunk.inputs, &chunk.resource))
+    }
+
+    pub fn join_resource(&self) -> &Resource {
+        &self.join_resource
+    }
+
     pub fn with_join_resource(join_resource: Resource) -> Self {
         StageDef {
             chunks: Vec::new(),
@@ -99,20 +188,146 @@ impl<T> StageDef<T> {
     }
 }
 
+/// Marks an error of a stage as transient, e.g a hiccup of a network filesystem
+/// or a remote service which is briefly unavailable. The main of a chunk which
+/// fails with a `RetryableError`, or an error caused by one, is run again as
+/// set by the `retry_policy` of the stage.
+#[derive(Debug)]
+pub struct RetryableError {
+    pub message: String,
+}
+
+// Implemented by hand, since `#[derive(Fail)]` expands to a non-local impl
+impl std::fmt::Display for RetryableError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl Fail for RetryableError {}
+
+impl RetryableError {
+    pub fn new(message: impl ToString) -> Self {
+        RetryableError {
+            message: message.to_string(),
+        }
+    }
+}
+
+fn is_retryable(err: &Error) -> bool {
+    err.iter_chain()
+        .any(|cause| cause.downcast_ref::<RetryableError>().is_some())
+}
+
+/// How many times the main of a chunk is run when it fails with a `RetryableError`,
+/// and how long to wait before retrying. The wait doubles after each attempt.
+/// ```rust
+/// use martian::RetryPolicy;
+/// use std::time::Duration;
+/// let policy = RetryPolicy::new(3).backoff(Duration::from_secs(10));
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    /// A single attempt, without retries
+    fn default() -> Self {
+        RetryPolicy::new(1)
+    }
+}
+
+impl RetryPolicy {
+    /// Run the main at most `max_attempts` times, waiting 1 second before the
+    /// first retry
+    pub fn new(max_attempts: u32) -> Self {
+        RetryPolicy {
+            max_attempts: max_attempts.max(1),
+            backoff: Duration::from_secs(1),
+        }
+    }
+
+    /// Wait before the first retry
+    pub fn backoff(mut self, backoff: Duration) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    // Run `attempt` until it succeeds, fails with an error which is not retryable,
+    // or `max_attempts` is reached
+    fn run<R>(
+        &self,
+        stage_name: &str,
+        mut attempt: impl FnMut() -> Result<R, Error>,
+    ) -> Result<R, Error> {
+        let mut backoff = self.backoff;
+        let mut number = 1;
+        loop {
+            match attempt() {
+                Err(err) if number < self.max_attempts && is_retryable(&err) => {
+                    warn!(
+                        "Attempt {} of {} of {} failed with a transient error, retrying in {:?}: {}",
+                        number, self.max_attempts, stage_name, backoff, err
+                    );
+                    thread::sleep(backoff);
+                    backoff *= 2;
+                    number += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Versions of the software running a stage, e.g to record the provenance of
+/// its outputs. The martian and pipelines versions are read from `_jobinfo`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Versions {
+    pub martian: Option<String>,
+    pub pipelines: Option<String>,
+    /// Version of the adapter, as set using `set_adapter_version`
+    pub adapter: Option<String>,
+}
+
+static ADAPTER_VERSION: Mutex<Option<String>> = Mutex::new(None);
+
+/// Set the version of the adapter in the `Versions` of the stages. `martian_stages!`
+/// sets it to `adapter_version!()`, which can be overridden at build time, e.g
+/// with the git commit of the adapter.
+pub fn set_adapter_version(version: &str) {
+    *ADAPTER_VERSION.lock().unwrap_or_else(|e| e.into_inner()) = Some(version.to_string());
+}
+
+fn adapter_version() -> Option<String> {
+    ADAPTER_VERSION
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone()
+}
+
 pub struct MartianRover {
     files_path: PathBuf,
     mem_gb: usize,
     threads: usize,
     vmem_gb: usize,
+    versions: Versions,
 }
 
 impl<'a> From<&'a Metadata<'a>> for MartianRover {
     fn from(md: &Metadata) -> MartianRover {
+        let version = md.jobinfo().version.clone().unwrap_or_default();
         MartianRover {
             files_path: PathBuf::from(&md.files_path),
             mem_gb: md.get_memory_allocation(),
             threads: md.get_threads_allocation(),
             vmem_gb: md.get_virtual_memory_allocation(),
+            versions: Versions {
+                martian: version.martian,
+                pipelines: version.pipelines,
+                adapter: adapter_version(),
+            },
         }
     }
 }
@@ -131,6 +346,10 @@ impl MartianRover {
             mem_gb: resource.mem_gb.unwrap() as usize,
             threads: resource.threads.unwrap() as usize,
             vmem_gb: resource.vmem_gb.unwrap() as usize,
+            versions: Versions {
+                adapter: adapter_version(),
+                ..Versions::default()
+            },
         }
     }
     ///
@@ -157,18 +376,118 @@ impl MartianRover {
     {
         <T as MartianMakePath>::make_path(&self.files_path, filename)
     }
+    /// Memory in GB allotted to the stage. Size buffers and caches to fit in it,
+    /// the monitor warns when the stage goes over.
     pub fn get_mem_gb(&self) -> usize {
         self.mem_gb
     }
+    /// Number of threads allotted to the stage, e.g for the size of a thread pool
     pub fn get_threads(&self) -> usize {
         self.threads
     }
+    /// Virtual memory in GB allotted to the stage
     pub fn get_vmem_gb(&self) -> usize {
         self.vmem_gb
     }
+    /// Versions of martian, of the pipelines and of the adapter running the stage
+    pub fn versions(&self) -> &Versions {
+        &self.versions
+    }
+
+    /// Report a problem which does not fail the stage, but which the user should
+    /// know about. Same as the `alarm!` macro, see `Metadata::alarm`.
+    pub fn alarm(&self, message: &str) -> Result<(), Error> {
+        crate::alarm(message)
+    }
+
+    /// Report that `done` out of `total` units of work are done, for mrp to
+    /// display the progress of long running chunks. It is cheap to call often,
+    /// see `Metadata::report_progress`.
+    pub fn report_progress(&self, done: u64, total: u64) -> Result<(), Error> {
+        crate::report_progress(done, total)
+    }
     pub fn files_path(&self) -> &Path {
         self.files_path.as_path()
     }
+
+    /// Create the directory `<name>` within the files directory, for the output of
+    /// a tool which writes a whole directory. See `FolderType`.
+    pub fn create_folder<F: FolderType>(&self, name: impl AsRef<Path>) -> Result<F, Error> {
+        F::create(&self.files_path, name)
+    }
+
+    /// A new temporary directory within the files directory, which is removed
+    /// when it is dropped:
+    /// ```rust
+    /// # use martian::{MartianRover, Resource};
+    /// # let files = tempdir::TempDir::new("files").unwrap();
+    /// # let rover = MartianRover::new(files.path(), Resource::new().mem_gb(1).threads(1).vmem_gb(2));
+    /// let tmp = rover.temp_dir()?.keep_on_failure(true);
+    /// std::fs::write(tmp.path().join("sorted.bin"), b"...")?;
+    /// tmp.finish()?;
+    /// # Ok::<(), martian::Error>(())
+    /// ```
+    pub fn temp_dir(&self) -> Result<ScopedTempDir, Error> {
+        Ok(ScopedTempDir::new_in(&self.files_path)?)
+    }
+
+    /// Run another stage in-process with `args`, to reuse it as a step of this
+    /// stage without going through the pipeline. The split, the chunks and the join
+    /// run one after another as in `MartianStage::test_run`, within the directory
+    /// `<stage_name>` of the files directory, or `<stage_name>_<n>` if it is taken.
+    /// That directory is kept, since the outputs can refer to files in it.
+    pub fn run_stage<S>(&self, stage: &S, args: S::StageInputs) -> Result<S::StageOutputs, Error>
+    where
+        S: MartianStage,
+        S::StageInputs: Clone,
+        S::ChunkInputs: Clone,
+    {
+        let name = S::stage_name().to_lowercase();
+        let mut run_directory = self.files_path.join(&name);
+        let mut attempt = 1;
+        loop {
+            match std::fs::create_dir(&run_directory) {
+                Ok(()) => break,
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                    attempt += 1;
+                    run_directory = self.files_path.join(format!("{}_{}", name, attempt));
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        stage.test_run(run_directory, args)
+    }
+
+    /// A rayon thread pool with as many threads as allotted to the stage, at
+    /// least one. See `install`.
+    #[cfg(feature = "rayon")]
+    pub fn thread_pool(&self) -> Result<rayon::ThreadPool, Error> {
+        Ok(rayon::ThreadPoolBuilder::new()
+            .num_threads(self.threads.max(1))
+            .thread_name(|i| format!("martian-rayon-{}", i))
+            .build()?)
+    }
+
+    /// Run `op` in a rayon thread pool with as many threads as allotted to the
+    /// stage, which is dropped afterwards. The parallel iterators in `op` use
+    /// this pool instead of the global pool, which has a thread per core of the
+    /// node and would oversubscribe it when other jobs run on the same node:
+    /// ```rust
+    /// # use martian::{MartianRover, Resource};
+    /// use rayon::prelude::*;
+    /// # let rover = MartianRover::new("/files", Resource::new().mem_gb(1).threads(2).vmem_gb(2));
+    /// let sum: u64 = rover.install(|| (1..=100u64).into_par_iter().sum())?;
+    /// assert_eq!(sum, 5050);
+    /// # Ok::<(), martian::Error>(())
+    /// ```
+    #[cfg(feature = "rayon")]
+    pub fn install<R, F>(&self, op: F) -> Result<R, Error>
+    where
+        F: FnOnce() -> R + Send,
+        R: Send,
+    {
+        Ok(self.thread_pool()?.install(op))
+    }
 }
 
 #[derive(Debug)]
@@ -186,6 +505,31 @@ pub trait MartianMain: MroMaker {
         args: Self::StageInputs,
         rover: MartianRover,
     ) -> Result<Self::StageOutputs, Error>;
+
+    /// Called by the adapter with the outputs of `main` just before they are written.
+    /// See `MartianStage::finalize_outs`.
+    fn finalize_outs(
+        &self,
+        outs: Self::StageOutputs,
+        _md: &Metadata,
+    ) -> Result<Self::StageOutputs, Error> {
+        Ok(outs)
+    }
+
+    /// See `MartianStage::max_runtime`
+    fn max_runtime(&self) -> Option<Duration> {
+        None
+    }
+
+    /// See `MartianStage::retry_policy`
+    fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy::default()
+    }
+
+    /// See `MartianStage::canonical_outs`
+    fn canonical_outs(&self) -> bool {
+        false
+    }
 }
 
 pub trait MartianStage: MroMaker {
@@ -215,9 +559,56 @@ pub trait MartianStage: MroMaker {
         rover: MartianRover,
     ) -> Result<Self::StageOutputs, Error>;
 
+    /// Called by the adapter with the outputs of `join` just before they are written,
+    /// which makes it a central place to apply a policy to all the outputs, such as
+    /// converting absolute paths to relative paths. The default implementation
+    /// returns the outputs unchanged.
+    fn finalize_outs(
+        &self,
+        outs: Self::StageOutputs,
+        _md: &Metadata,
+    ) -> Result<Self::StageOutputs, Error> {
+        Ok(outs)
+    }
+
+    /// Same as `finalize_outs` for the outputs of a chunk `main`. For a `MartianMain`,
+    /// this calls `MartianMain::finalize_outs`.
+    fn finalize_chunk_outs(
+        &self,
+        outs: Self::ChunkOutputs,
+        _md: &Metadata,
+    ) -> Result<Self::ChunkOutputs, Error> {
+        Ok(outs)
+    }
+
+    /// Maximum time the split, each chunk main and the join may run for. If it is
+    /// exceeded, `martian_main` fails the stage with a timeout error in `_errors`,
+    /// instead of the stage hanging forever. No limit by default. For a
+    /// `MartianMain`, this calls `MartianMain::max_runtime`.
+    fn max_runtime(&self) -> Option<Duration> {
+        None
+    }
+
+    /// How many times the main of a chunk is run when it fails with a
+    /// `RetryableError`. Not retried by default.
+    fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy::default()
+    }
+
+    /// Write `_outs` with the keys of every object sorted (see
+    /// `utils::canonical_json_encode`), so that the outputs of the stage are
+    /// reproducible byte for byte, e.g. for golden file tests of a pipestance.
+    /// Applies to the outputs of the chunks and of the join. Off by default. For a
+    /// `MartianMain`, this calls `MartianMain::canonical_outs`.
+    fn canonical_outs(&self) -> bool {
+        false
+    }
+
     /// In-process stage runner, useful for writing unit tests that exercise one of more stages purely from Rust.
     /// Executes stage with arguments `args` in directory `run_directory`. The defaul implementation executes split
     /// to get the stage definition (chunks), executes each chunk one after another and finally calls the join function.
+    /// See `run_stage_parallel` to run the chunks on several threads, and `run_stage_with_metadata` to run the
+    /// stage through the adapter as martian does.
     fn test_run(
         &self,
         run_directory: impl AsRef<Path>,
@@ -241,8 +632,10 @@ pub trait MartianStage: MroMaker {
             println!("running chunk {}", chunk_idx);
             let chunk_path = prep_path(run_directory.as_ref(), &format!("chnk{}", chunk_idx))?;
             let rover = MartianRover::new(chunk_path, fill_defaults(chunk.resource));
-            let outs = self.main(args.clone(), chunk.inputs.clone(), rover)?;
-            chunk_outs.push(outs);
+            set_log_chunk_index(Some(chunk_idx));
+            let outs = self.main(args.clone(), chunk.inputs.clone(), rover);
+            set_log_chunk_index(None);
+            chunk_outs.push(outs?);
         }
 
         let join_path = prep_path(run_directory.as_ref(), "join")?;
@@ -275,6 +668,10 @@ pub trait RawMartianStage {
     fn split(&self, metadata: Metadata) -> Result<(), Error>;
     fn main(&self, metadata: Metadata) -> Result<(), Error>;
     fn join(&self, metadata: Metadata) -> Result<(), Error>;
+    /// See `MartianStage::max_runtime`
+    fn max_runtime(&self) -> Option<Duration> {
+        None
+    }
 }
 
 impl<T> MartianStage for T
@@ -287,7 +684,7 @@ where
     type ChunkOutputs = <T as MartianMain>::StageOutputs;
 
     fn split(&self, _: Self::StageInputs, _: MartianRover) -> Result<StageDef<MartianVoid>, Error> {
-        unimplemented!()
+        Err(no_split_or_join::<T>("split"))
     }
 
     fn main(
@@ -306,7 +703,27 @@ where
         _: Vec<Self::ChunkOutputs>,
         _: MartianRover,
     ) -> Result<Self::StageOutputs, Error> {
-        unimplemented!()
+        Err(no_split_or_join::<T>("join"))
+    }
+
+    fn finalize_chunk_outs(
+        &self,
+        outs: Self::ChunkOutputs,
+        md: &Metadata,
+    ) -> Result<Self::ChunkOutputs, Error> {
+        <T as MartianMain>::finalize_outs(self, outs, md)
+    }
+
+    fn max_runtime(&self) -> Option<Duration> {
+        <T as MartianMain>::max_runtime(self)
+    }
+
+    fn retry_policy(&self) -> RetryPolicy {
+        <T as MartianMain>::retry_policy(self)
+    }
+
+    fn canonical_outs(&self) -> bool {
+        <T as MartianMain>::canonical_outs(self)
     }
 
     fn test_run(
@@ -330,63 +747,297 @@ where
     }
 }
 
+// Error when martian runs the split or join of a `MartianMain`, which happens when
+// the mro declares a split which the stage does not have
+fn no_split_or_join<T: MroMaker>(phase: &str) -> Error {
+    StageError::InternalBug {
+        message: format!(
+            "{} has no {}: it is a MartianMain stage. Remove `split using` from its mro.",
+            T::stage_name(),
+            phase
+        ),
+    }
+    .into()
+}
+
 impl<T> RawMartianStage for T
 where
     T: MartianStage,
 {
     fn split(&self, mut md: Metadata) -> Result<(), Error> {
-        let args_obj = md.read_json_obj("args")?;
-        let args: <T as MartianStage>::StageInputs = obj_decode(&args_obj)?;
-        let rover = MartianRover::from(&md);
-        let stage_defs = MartianStage::split(self, args, rover)?;
-        let stage_def_obj = obj_encode(&stage_defs)?;
-        md.write_json_obj("stage_defs", &stage_def_obj)?;
+        run_split(self, &mut md)?;
         md.complete();
         Ok(())
     }
 
     fn main(&self, mut md: Metadata) -> Result<(), Error> {
-        let args_obj = md.read_json_obj("args")?;
-        let args: <T as MartianStage>::StageInputs = obj_decode(&args_obj)?;
-        let split_args: <T as MartianStage>::ChunkInputs = obj_decode(&args_obj)?;
-        let rover = MartianRover::from(&md);
-        // let outs = md.read_json_obj("outs")?;
-        let outs = MartianStage::main(self, args, split_args, rover)?;
-        let outs_obj = obj_encode(&outs)?;
-        md.write_json_obj("outs", &outs_obj)?;
+        run_main(self, &mut md)?;
         md.complete();
         Ok(())
     }
 
     fn join(&self, mut md: Metadata) -> Result<(), Error> {
-        let args_obj = md.read_json_obj("args")?;
-        let args: <T as MartianStage>::StageInputs = obj_decode(&args_obj)?;
-        let rover = MartianRover::from(&md);
-        // let outs = md.read_json_obj("outs")?;
-        let chunk_defs = {
-            let chunk_defs_obj = md.read_json_obj_array("chunk_defs")?;
-            let mut defs = Vec::new();
-            for obj in chunk_defs_obj {
-                let def: <T as MartianStage>::ChunkInputs = obj_decode(&obj)?;
-                defs.push(def);
-            }
-            defs
-        };
-        let chunk_outs = {
-            let chunk_outs_obj = md.read_json_obj_array("chunk_outs")?;
-            let mut outs = Vec::new();
-            for obj in chunk_outs_obj {
-                let out: <T as MartianStage>::ChunkOutputs = obj_decode(&obj)?;
-                outs.push(out);
-            }
-            outs
-        };
-        let outs = MartianStage::join(self, args, chunk_defs, chunk_outs, rover)?;
-        let outs_obj = obj_encode(&outs)?;
-        md.write_json_obj("outs", &outs_obj)?;
+        run_join(self, &mut md)?;
         md.complete();
         Ok(())
     }
+
+    fn max_runtime(&self) -> Option<Duration> {
+        MartianStage::max_runtime(self)
+    }
+}
+
+// Run the split and write the stage defs, without signaling completion to martian
+fn run_split<T: MartianStage>(stage: &T, md: &mut Metadata) -> Result<(), Error> {
+    let args: <T as MartianStage>::StageInputs = md.read_args()?;
+    let rover = MartianRover::from(&*md);
+    let stage_defs = MartianStage::split(stage, args, rover)?;
+    md.write_stage_defs(&stage_defs)?;
+    Ok(())
+}
+
+// Run the main of a chunk, check the files in the outs (see `MartianFileType::validate`)
+// and write them, without signaling completion to martian
+fn run_main<T: MartianStage>(stage: &T, md: &mut Metadata) -> Result<(), Error> {
+    if let StageKind::WithSplit = T::stage_kind() {
+        set_log_chunk_index(md.chunk_index());
+    }
+    let outs = stage.retry_policy().run(&md.stage_name, || {
+        let args: <T as MartianStage>::StageInputs = md.read_args()?;
+        let split_args: <T as MartianStage>::ChunkInputs = md.read_args()?;
+        let rover = MartianRover::from(&*md);
+        MartianStage::main(stage, args, split_args, rover)
+    })?;
+    let outs = stage.finalize_chunk_outs(outs, md)?;
+    let outs_obj = obj_encode(&outs)?;
+    validate_files(&<T as MartianStage>::ChunkOutputs::mro_fields(), &outs_obj)?;
+    md.write_outs(&outs_obj, stage.canonical_outs())?;
+    Ok(())
+}
+
+// Run the join, check the files in the outs and write them, without signaling
+// completion to martian
+fn run_join<T: MartianStage>(stage: &T, md: &mut Metadata) -> Result<(), Error> {
+    let args: <T as MartianStage>::StageInputs = md.read_args()?;
+    let rover = MartianRover::from(&*md);
+    let chunk_defs: Vec<<T as MartianStage>::ChunkInputs> = md.read_chunk_defs()?;
+    let chunk_outs: Vec<<T as MartianStage>::ChunkOutputs> = md.read_chunk_outs()?;
+    let outs = MartianStage::join(stage, args, chunk_defs, chunk_outs, rover)?;
+    let outs = MartianStage::finalize_outs(stage, outs, md)?;
+    let outs_obj = obj_encode(&outs)?;
+    validate_files(&<T as MartianStage>::StageOutputs::mro_fields(), &outs_obj)?;
+    md.write_outs(&outs_obj, stage.canonical_outs())?;
+    Ok(())
+}
+
+/// In-process stage runner similar to `MartianStage::test_run_tmpdir`, which runs the
+/// chunks concurrently on `num_threads` threads instead of one after another. Useful
+/// for surfacing bugs due to shared state or file name collisions between chunks.
+/// Each chunk runs in its own directory within a temporary directory that will always
+/// be cleaned up.
+pub fn run_stage_parallel<S>(
+    stage: &S,
+    args: S::StageInputs,
+    num_threads: usize,
+) -> Result<S::StageOutputs, Error>
+where
+    S: MartianStage + Sync,
+    S::StageInputs: Clone + Sync,
+    S::ChunkInputs: Clone + Sync,
+    S::ChunkOutputs: Send,
+{
+    assert!(num_threads > 0, "num_threads needs to be positive");
+    if let StageKind::MainOnly = S::stage_kind() {
+        return stage.test_run_tmpdir(args);
+    }
+    let tmp_dir = tempdir::TempDir::new("__test_stage_run_parallel__")?;
+    let run_directory = tmp_dir.path();
+
+    let default_resource = Resource::new().mem_gb(1).vmem_gb(2).threads(1);
+    let split_path = prep_path(run_directory, "split")?;
+    let rover = MartianRover::new(split_path, default_resource);
+    let stage_defs = stage.split(args.clone(), rover)?;
+
+    // Each worker picks up the next chunk that has not been started
+    let next_chunk = AtomicUsize::new(0);
+    let mut chunk_results = thread::scope(|scope| {
+        let workers: Vec<_> = (0..std::cmp::min(num_threads, stage_defs.chunks.len()))
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut results = Vec::new();
+                    loop {
+                        let chunk_idx = next_chunk.fetch_add(1, Ordering::SeqCst);
+                        let chunk = match stage_defs.chunks.get(chunk_idx) {
+                            Some(chunk) => chunk,
+                            None => break results,
+                        };
+                        let result = prep_path(run_directory, &format!("chnk{}", chunk_idx))
+                            .and_then(|chunk_path| {
+                                let rover =
+                                    MartianRover::new(chunk_path, fill_defaults(chunk.resource));
+                                set_log_chunk_index(Some(chunk_idx));
+                                let outs = stage.main(args.clone(), chunk.inputs.clone(), rover);
+                                set_log_chunk_index(None);
+                                outs
+                            });
+                        results.push((chunk_idx, result));
+                    }
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().unwrap())
+            .collect::<Vec<_>>()
+    });
+    chunk_results.sort_by_key(|(chunk_idx, _)| *chunk_idx);
+
+    let mut chunk_outs = Vec::new();
+    for (_, result) in chunk_results {
+        chunk_outs.push(result?);
+    }
+
+    let join_path = prep_path(run_directory, "join")?;
+    let rover = MartianRover::new(join_path, fill_defaults(stage_defs.join_resource));
+    let chunk_defs = stage_defs.chunks.into_iter().map(|c| c.inputs).collect();
+    stage.join(args, chunk_defs, chunk_outs, rover)
+}
+
+/// In-process stage runner like `MartianStage::test_run`, which runs the split, the
+/// chunks one after another and the join through the adapter, as martian does. Each
+/// of them gets a mocked `Metadata` in its own directory within `run_directory`
+/// (`split`, `chnk<N>` and `join`, or `main` for a stage without a split), with the
+/// `_args`, `_jobinfo`, `_chunk_defs` and `_chunk_outs` that martian would write and
+/// the files directory `files`. Unlike `test_run`, the inputs and outputs go through
+/// json, `finalize_outs` is applied and the files in the outputs are validated, which
+/// catches the bugs that otherwise only show up in a pipeline. `run_directory` is
+/// created if needed, and the metadata files are kept to debug the stage.
+pub fn run_stage_with_metadata<S: MartianStage>(
+    stage: &S,
+    run_directory: impl AsRef<Path>,
+    args: S::StageInputs,
+) -> Result<S::StageOutputs, Error> {
+    let run_directory = run_directory.as_ref();
+    std::fs::create_dir_all(run_directory)?;
+    let args = obj_encode(&args)?;
+    let default_resource = Resource::new().mem_gb(1).vmem_gb(2).threads(1);
+    let outs = if let StageKind::MainOnly = S::stage_kind() {
+        let files = vec![("args", Value::Object(args))];
+        run_mocked::<S>(run_directory, "main", files, default_resource, |md| {
+            run_main(stage, md)
+        })?
+    } else {
+        let files = vec![("args", Value::Object(args.clone()))];
+        let stage_defs = run_mocked::<S>(run_directory, "split", files, default_resource, |md| {
+            run_split(stage, md)
+        })?;
+        let stage_defs: MockedStageDefs = decode_json("stage_defs", stage_defs)?;
+
+        let mut chunk_outs = Vec::new();
+        for (chunk_idx, chunk) in stage_defs.chunks.iter().enumerate() {
+            // The chunk inputs are added to the stage inputs, along with the resources
+            let resource: Resource = decode_json("stage_defs", Value::Object(chunk.clone()))?;
+            let mut chunk_args = args.clone();
+            chunk_args.extend(chunk.clone());
+            let files = vec![("args", Value::Object(chunk_args))];
+            let name = format!("chnk{}", chunk_idx);
+            let outs = run_mocked::<S>(run_directory, &name, files, resource, |md| {
+                run_main(stage, md)
+            });
+            set_log_chunk_index(None);
+            chunk_outs.push(outs?);
+        }
+
+        let files = vec![
+            ("args", Value::Object(args)),
+            (
+                "chunk_defs",
+                Value::Array(stage_defs.chunks.into_iter().map(Value::Object).collect()),
+            ),
+            ("chunk_outs", Value::Array(chunk_outs)),
+        ];
+        run_mocked::<S>(run_directory, "join", files, stage_defs.join, |md| {
+            run_join(stage, md)
+        })?
+    };
+    decode_json("outs", outs)
+}
+
+// `_stage_defs` as written by `run_split`, keeping the chunks as json
+#[derive(Deserialize)]
+struct MockedStageDefs {
+    chunks: Vec<JsonDict>,
+    join: Resource,
+}
+
+// Run `phase` of the stage `S` with a mocked `Metadata` (see `mock_metadata`). Returns
+// what the phase wrote, `_stage_defs` for the split and `_outs` otherwise.
+fn run_mocked<S: MartianStage>(
+    run_directory: &Path,
+    name: &str,
+    files: Vec<(&str, Value)>,
+    resource: Resource,
+    phase: impl FnOnce(&mut Metadata) -> Result<(), Error>,
+) -> Result<Value, Error> {
+    let mut md = mock_metadata::<S>(run_directory, name, files, resource)?;
+    phase(&mut md)?;
+    md.read_json(if md.stage_type == "split" {
+        "stage_defs"
+    } else {
+        "outs"
+    })
+}
+
+// A mocked `Metadata` for running the phase `name` of the stage `S` (`split`, `join`
+// or else the main of a chunk) in `<run_directory>/<name>`, as martian would set it
+// up, after writing the metadata `files` and `_jobinfo` with the `resource`.
+pub(crate) fn mock_metadata<S: MroMaker>(
+    run_directory: &Path,
+    name: &str,
+    files: Vec<(&str, Value)>,
+    resource: Resource,
+) -> Result<Metadata<'static>, Error> {
+    let metadata_path = prep_path(run_directory, name)?;
+    let files_path = prep_path(&metadata_path, "files")?;
+    write_metadata_files(&metadata_path, files, resource)?;
+
+    let stage_type = match name {
+        "split" | "join" => name,
+        _ => "main",
+    };
+    let log_file = IoSink::new(std::fs::File::create(metadata_path.join("_log"))?);
+    let error_sink = IoSink::lazy_file(metadata_path.join("_errors"));
+    let to_string = |p: &Path| p.to_string_lossy().into_owned();
+    let args = vec![
+        S::stage_name(),
+        stage_type.to_string(),
+        to_string(&metadata_path),
+        to_string(&files_path),
+        to_string(&metadata_path.join("_run")),
+    ];
+    let mut md = Metadata::with_sinks(args, log_file, error_sink);
+    md.update_jobinfo()?;
+    Ok(md)
+}
+
+// Write the metadata `files` that martian writes before running a stage, e.g `_args`,
+// in `metadata_path`, along with `_jobinfo` with the `resource`
+pub(crate) fn write_metadata_files(
+    metadata_path: &Path,
+    files: Vec<(&str, Value)>,
+    resource: Resource,
+) -> Result<(), Error> {
+    let resource = fill_defaults(resource);
+    let jobinfo = json!({
+        "threads": resource.threads,
+        "memGB": resource.mem_gb,
+        "vmemGB": resource.vmem_gb,
+    });
+    for (file, value) in files.iter().chain(std::iter::once(&("jobinfo", jobinfo))) {
+        let path = metadata_path.join(format!("_{}", file));
+        std::fs::write(path, serde_json::to_string_pretty(value)?)?;
+    }
+    Ok(())
 }
 
 // Prep a path for a test run of a stage.
@@ -413,3 +1064,494 @@ fn fill_defaults(mut resource: Resource) -> Resource {
 
     resource
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mro::{InAndOut, MartianBlanketType, MartianPrimaryType, MroField, Threads};
+    use crate::types::{FiletypeValidator, MartianFileType};
+    use failure::format_err;
+    use std::fs;
+
+    // Every chunk writes its value to a file with the same name in its
+    // files directory, join sums up the values in the files.
+    struct SumFiles;
+
+    #[derive(Clone, Serialize, Deserialize)]
+    struct Values {
+        values: Vec<i64>,
+    }
+
+    #[derive(Clone, Serialize, Deserialize)]
+    struct Value {
+        value: i64,
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    struct ValueFile {
+        file: PathBuf,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct Sum {
+        sum: i64,
+    }
+
+    macro_rules! no_mro_fields {
+        ($($t:ty),*) => {
+            $(impl MartianStruct for $t {
+                fn mro_fields() -> Vec<MroField> {
+                    Vec::new()
+                }
+            })*
+        };
+    }
+    no_mro_fields!(Values, Value, Sum);
+
+    impl MroMaker for SumFiles {
+        fn stage_name() -> String {
+            "SUM_FILES".into()
+        }
+        fn stage_in_and_out() -> InAndOut {
+            InAndOut::default()
+        }
+        fn chunk_in_and_out() -> Option<InAndOut> {
+            Some(InAndOut::default())
+        }
+        fn using_attributes() -> MroUsing {
+            MroUsing::default()
+        }
+    }
+
+    impl MartianStage for SumFiles {
+        type StageInputs = Values;
+        type StageOutputs = Sum;
+        type ChunkInputs = Value;
+        type ChunkOutputs = ValueFile;
+
+        fn split(&self, args: Values, _: MartianRover) -> Result<StageDef<Value>, Error> {
+            let mut stage_def = StageDef::new();
+            for value in args.values {
+                stage_def.add_chunk(Value { value });
+            }
+            Ok(stage_def)
+        }
+
+        fn main(&self, _: Values, chunk: Value, rover: MartianRover) -> Result<ValueFile, Error> {
+            let file: PathBuf = rover.make_path("value.txt");
+            assert!(!file.exists());
+            fs::write(&file, chunk.value.to_string())?;
+            Ok(ValueFile { file })
+        }
+
+        fn join(
+            &self,
+            _: Values,
+            _: Vec<Value>,
+            chunk_outs: Vec<ValueFile>,
+            _: MartianRover,
+        ) -> Result<Sum, Error> {
+            let mut sum = 0;
+            for out in chunk_outs {
+                sum += fs::read_to_string(&out.file)?.parse::<i64>()?;
+            }
+            Ok(Sum { sum })
+        }
+    }
+
+    // Writes a file and reports its path relative to the files directory
+    struct RelativeOuts;
+
+    impl MroMaker for RelativeOuts {
+        fn stage_name() -> String {
+            "RELATIVE_OUTS".into()
+        }
+        fn stage_in_and_out() -> InAndOut {
+            InAndOut::default()
+        }
+        fn chunk_in_and_out() -> Option<InAndOut> {
+            None
+        }
+        fn using_attributes() -> MroUsing {
+            MroUsing::default()
+        }
+    }
+
+    impl MartianMain for RelativeOuts {
+        type StageInputs = Value;
+        type StageOutputs = ValueFile;
+
+        fn main(&self, args: Value, rover: MartianRover) -> Result<ValueFile, Error> {
+            let file: PathBuf = rover.make_path("value.txt");
+            fs::write(&file, args.value.to_string())?;
+            Ok(ValueFile { file })
+        }
+
+        fn finalize_outs(&self, outs: ValueFile, md: &Metadata) -> Result<ValueFile, Error> {
+            let file = outs.file.strip_prefix(&md.files_path)?.to_path_buf();
+            Ok(ValueFile { file })
+        }
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct Summary {
+        values: Vec<Value>,
+        count: usize,
+    }
+    no_mro_fields!(Summary);
+
+    // Writes its outputs in canonical json
+    struct Summarize;
+
+    impl MroMaker for Summarize {
+        fn stage_name() -> String {
+            "SUMMARIZE".into()
+        }
+        fn stage_in_and_out() -> InAndOut {
+            InAndOut::default()
+        }
+        fn chunk_in_and_out() -> Option<InAndOut> {
+            None
+        }
+        fn using_attributes() -> MroUsing {
+            MroUsing::default()
+        }
+    }
+
+    impl MartianMain for Summarize {
+        type StageInputs = Values;
+        type StageOutputs = Summary;
+
+        fn main(&self, args: Values, _: MartianRover) -> Result<Summary, Error> {
+            Ok(Summary {
+                count: args.values.len(),
+                values: args
+                    .values
+                    .into_iter()
+                    .map(|value| Value { value })
+                    .collect(),
+            })
+        }
+
+        fn canonical_outs(&self) -> bool {
+            true
+        }
+    }
+
+    // Fails with `error` until it was run `failures` times
+    struct Flaky {
+        failures: usize,
+        runs: AtomicUsize,
+        error: fn() -> Error,
+    }
+
+    impl MroMaker for Flaky {
+        fn stage_name() -> String {
+            "FLAKY".into()
+        }
+        fn stage_in_and_out() -> InAndOut {
+            InAndOut::default()
+        }
+        fn chunk_in_and_out() -> Option<InAndOut> {
+            None
+        }
+        fn using_attributes() -> MroUsing {
+            MroUsing::default()
+        }
+    }
+
+    impl MartianMain for Flaky {
+        type StageInputs = Value;
+        type StageOutputs = Value;
+
+        fn main(&self, args: Value, _: MartianRover) -> Result<Value, Error> {
+            if self.runs.fetch_add(1, Ordering::SeqCst) < self.failures {
+                return Err((self.error)());
+            }
+            Ok(args)
+        }
+
+        fn retry_policy(&self) -> RetryPolicy {
+            RetryPolicy::new(3).backoff(Duration::from_millis(1))
+        }
+    }
+
+    #[test]
+    fn test_retry_policy() {
+        let tmp_dir = tempdir::TempDir::new("test_retry_policy").unwrap();
+        let files = vec![("args", json!({"value": 3}))];
+        let mut md =
+            mock_metadata::<Flaky>(tmp_dir.path(), "main", files, Resource::new()).unwrap();
+
+        let transient = || RetryableError::new("503 Service Unavailable").into();
+        let caused_by_transient = || {
+            let err: Error = RetryableError::new("stale file handle").into();
+            err.context("reading the index").into()
+        };
+        let permanent = || format_err!("corrupt input");
+        // Failures before the stage succeeds, the error, the expected runs and
+        // whether the stage succeeds in the end
+        type Case = (usize, fn() -> Error, usize, bool);
+        let cases: [Case; 4] = [
+            (2, transient, 3, true),
+            (2, caused_by_transient, 3, true),
+            (3, transient, 3, false),
+            (2, permanent, 1, false),
+        ];
+        for &(failures, error, expected_runs, success) in &cases {
+            let stage = Flaky {
+                failures,
+                runs: AtomicUsize::new(0),
+                error,
+            };
+            assert_eq!(run_main(&stage, &mut md).is_ok(), success);
+            assert_eq!(stage.runs.load(Ordering::SeqCst), expected_runs);
+        }
+    }
+
+    #[test]
+    fn test_finalize_outs() {
+        let tmp_dir = tempdir::TempDir::new("test_finalize_outs").unwrap();
+        let dir = tmp_dir.path();
+        let files = vec![("args", json!({"value": 3}))];
+        let outs = run_mocked::<RelativeOuts>(dir, "main", files, Resource::new(), |md| {
+            run_main(&RelativeOuts, md)
+        })
+        .unwrap();
+
+        let outs: ValueFile = serde_json::from_value(outs).unwrap();
+        assert_eq!(outs.file, PathBuf::from("value.txt"));
+        assert_eq!(
+            fs::read_to_string(dir.join("main/files/value.txt")).unwrap(),
+            "3"
+        );
+    }
+
+    #[test]
+    fn test_rover_versions() {
+        let tmp_dir = tempdir::TempDir::new("test_rover_versions").unwrap();
+        let dir = tmp_dir.path();
+        let mut md = mock_metadata::<SumFiles>(dir, "main", vec![], Resource::new()).unwrap();
+        let versions = json!({"martian": "v4.0.0", "pipelines": "7.1.0"});
+        md.extend_jobinfo(JsonDict::from_iter(vec![("version".to_string(), versions)]))
+            .unwrap();
+
+        set_adapter_version("1.2.3-abcdef0");
+        let rover = MartianRover::from(&md);
+        assert_eq!(
+            rover.versions(),
+            &Versions {
+                martian: Some("v4.0.0".to_string()),
+                pipelines: Some("7.1.0".to_string()),
+                adapter: Some("1.2.3-abcdef0".to_string()),
+            }
+        );
+        let rover = MartianRover::new(dir, Resource::new().mem_gb(1).threads(1).vmem_gb(2));
+        assert_eq!(rover.versions().martian, None);
+        assert_eq!(crate::adapter_version!(), env!("CARGO_PKG_VERSION"));
+    }
+
+    #[test]
+    fn test_rover_run_stage() {
+        let tmp_dir = tempdir::TempDir::new("test_rover_run_stage").unwrap();
+        let resource = Resource::new().mem_gb(1).threads(1).vmem_gb(2);
+        let rover = MartianRover::new(tmp_dir.path(), resource);
+        let args = Values {
+            values: vec![1, 2, 3],
+        };
+        assert_eq!(rover.run_stage(&SumFiles, args.clone()).unwrap().sum, 6);
+        assert_eq!(rover.run_stage(&SumFiles, args).unwrap().sum, 6);
+        assert!(tmp_dir.path().join("sum_files/chnk2").is_dir());
+        assert!(tmp_dir.path().join("sum_files_2/join").is_dir());
+    }
+
+    #[test]
+    fn test_run_stage_parallel() {
+        let args = Values {
+            values: (1..=20).collect(),
+        };
+        let outs = run_stage_parallel(&SumFiles, args.clone(), 4).unwrap();
+        assert_eq!(outs.sum, 210);
+        let outs = run_stage_parallel(&SumFiles, args, 1).unwrap();
+        assert_eq!(outs.sum, 210);
+        let outs = run_stage_parallel(&SumFiles, Values { values: vec![] }, 4).unwrap();
+        assert_eq!(outs.sum, 0);
+    }
+
+    // Writes a gzip file of the given size, which is truncated when it is too small
+    struct WriteGzip;
+
+    struct GzFile(PathBuf);
+
+    impl MartianFileType for GzFile {
+        fn extension() -> &'static str {
+            "gz"
+        }
+        fn new(file_path: impl AsRef<Path>, file_name: impl AsRef<Path>) -> Self {
+            GzFile(crate::types::with_extension(
+                file_path.as_ref().join(file_name),
+                Self::extension(),
+            ))
+        }
+        fn validate(path: &Path) -> Result<(), Error> {
+            crate::types::validate_magic_bytes(path, &[0x1f, 0x8b])
+        }
+    }
+
+    impl MartianStruct for ValueFile {
+        fn mro_fields() -> Vec<MroField> {
+            vec![MroField::new(
+                "file",
+                MartianBlanketType::Primary(MartianPrimaryType::FileType("gz".into())),
+            )
+            .with_filetype_validators(vec![FiletypeValidator::of::<GzFile>()])]
+        }
+    }
+
+    impl MroMaker for WriteGzip {
+        fn stage_name() -> String {
+            "WRITE_GZIP".into()
+        }
+        fn stage_in_and_out() -> InAndOut {
+            InAndOut::default()
+        }
+        fn chunk_in_and_out() -> Option<InAndOut> {
+            None
+        }
+        fn using_attributes() -> MroUsing {
+            MroUsing::default()
+        }
+    }
+
+    impl MartianMain for WriteGzip {
+        type StageInputs = Value;
+        type StageOutputs = ValueFile;
+
+        fn main(&self, args: Value, rover: MartianRover) -> Result<ValueFile, Error> {
+            let file: GzFile = rover.make_path("value");
+            let bytes = [0x1f, 0x8b, 0x08, 0x00];
+            fs::write(&file.0, &bytes[..args.value as usize])?;
+            Ok(ValueFile { file: file.0 })
+        }
+    }
+
+    #[test]
+    fn test_run_stage_with_metadata() {
+        let tmp_dir = tempdir::TempDir::new("test_run_stage_with_metadata").unwrap();
+        let dir = tmp_dir.path();
+        let args = Values {
+            values: vec![1, 2, 3],
+        };
+        let outs = run_stage_with_metadata(&SumFiles, dir.join("sum_files"), args).unwrap();
+        assert_eq!(outs.sum, 6);
+        let read = |path: PathBuf| -> serde_json::Value {
+            serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap()
+        };
+        let chunk_args = read(dir.join("sum_files/chnk1/_args"));
+        assert_eq!(chunk_args["values"], json!([1, 2, 3]));
+        assert_eq!(chunk_args["value"], json!(2));
+        let chunk_outs = read(dir.join("sum_files/join/_chunk_outs"));
+        assert_eq!(
+            chunk_outs[2]["file"],
+            json!(dir.join("sum_files/chnk2/files/value.txt"))
+        );
+
+        // finalize_outs is applied
+        let outs = run_stage_with_metadata(&RelativeOuts, dir.join("relative"), Value { value: 3 })
+            .unwrap();
+        assert_eq!(outs.file, PathBuf::from("value.txt"));
+        assert_eq!(
+            fs::read_to_string(dir.join("relative/main/files/value.txt")).unwrap(),
+            "3"
+        );
+
+        // The files in the outputs are validated
+        let outs = run_stage_with_metadata(&WriteGzip, dir.join("gzip"), Value { value: 4 });
+        assert_eq!(outs.unwrap().file, dir.join("gzip/main/files/value.gz"));
+        let err = run_stage_with_metadata(&WriteGzip, dir.join("truncated"), Value { value: 1 })
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "The output file {} is not a valid gz file: the file does not start with the bytes [1f, 8b]",
+                dir.join("truncated/main/files/value.gz").display()
+            )
+        );
+
+        // The outputs of a stage with canonical_outs are written in canonical json
+        let args = Values { values: vec![5, 6] };
+        let outs = run_stage_with_metadata(&Summarize, dir.join("summarize"), args).unwrap();
+        assert_eq!(outs.count, 2);
+        let written = fs::read_to_string(dir.join("summarize/main/_outs")).unwrap();
+        assert_eq!(
+            written,
+            crate::utils::canonical_json_encode(&json!({
+                "values": [{"value": 5}, {"value": 6}],
+                "count": 2,
+            }))
+            .unwrap()
+        );
+        assert!(written.find("\"count\"").unwrap() < written.find("\"values\"").unwrap());
+    }
+
+    #[test]
+    fn test_stage_def_json() {
+        let mut stage_def: StageDef<Value> = (1..=2).map(|value| Value { value }).collect();
+        stage_def.add_chunk_with_resource(Value { value: 3 }, Resource::new().mem_gb(8));
+        stage_def.set_join_resource(Resource::new().threads(4));
+        assert_eq!(stage_def.len(), 3);
+        let values: Vec<_> = stage_def.chunks().map(|(inputs, _)| inputs.value).collect();
+        assert_eq!(values, vec![1, 2, 3]);
+
+        assert_eq!(
+            serde_json::to_value(&stage_def).unwrap(),
+            serde_json::json!({
+                "chunks": [
+                    {"value": 1, "__mem_gb": null, "__threads": null, "__vmem_gb": null},
+                    {"value": 2, "__mem_gb": null, "__threads": null, "__vmem_gb": null},
+                    {"value": 3, "__mem_gb": 8, "__threads": null, "__vmem_gb": null},
+                ],
+                "join": {"__mem_gb": null, "__threads": 4, "__vmem_gb": null},
+            })
+        );
+        assert!(StageDef::<Value>::default().is_empty());
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_rover_install() {
+        let rover = MartianRover::new("/files", Resource::new().mem_gb(1).threads(3).vmem_gb(2));
+        assert_eq!(rover.install(rayon::current_num_threads).unwrap(), 3);
+        let rover = MartianRover::new("/files", Resource::new().mem_gb(1).threads(0).vmem_gb(2));
+        assert_eq!(rover.install(rayon::current_num_threads).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_resource_from_using() {
+        let using = MroUsing::builder()
+            .mem_gb(4)
+            .vmem_gb(16)
+            .threads(Threads::AllCores)
+            .build()
+            .unwrap();
+        assert_eq!(
+            Resource::from(&using),
+            Resource::new().mem_gb(4).vmem_gb(16).threads(-1)
+        );
+        assert_eq!(Resource::from(&MroUsing::default()), Resource::new());
+    }
+
+    #[test]
+    fn test_martian_main_without_split() {
+        // A MartianMain has no chunks, its main runs on its own
+        let outs = run_stage_parallel(&RelativeOuts, Value { value: 3 }, 4).unwrap();
+        assert_eq!(outs.file.file_name().unwrap(), "value.txt");
+
+        let rover = MartianRover::new("/files", Resource::new().mem_gb(1).threads(1).vmem_gb(2));
+        let err = MartianStage::split(&RelativeOuts, Value { value: 3 }, rover).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "RELATIVE_OUTS has no split: it is a MartianMain stage. Remove `split using` from its mro."
+        );
+    }
+}
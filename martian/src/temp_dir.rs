@@ -0,0 +1,94 @@
+//! Temporary directories within the files directory of a chunk, instead of
+//! `/tmp`. The files directory is on the storage set aside for the pipestance,
+//! which is usually much larger than the local `/tmp` of a cluster node, and
+//! everything in it is accounted to the pipestance.
+
+use log::warn;
+use std::io;
+use std::path::{Path, PathBuf};
+use tempdir::TempDir;
+
+/// A temporary directory which is removed when it is dropped. With
+/// `keep_on_failure`, it is only removed after `finish` is called, so that the
+/// files of a failed stage are still there to debug it. See
+/// `Metadata::temp_dir` and `MartianRover::temp_dir`.
+#[derive(Debug)]
+pub struct ScopedTempDir {
+    dir: Option<TempDir>,
+    keep_on_failure: bool,
+}
+
+impl ScopedTempDir {
+    /// A new directory `tmp.<random>` within `parent`
+    pub(crate) fn new_in(parent: &Path) -> io::Result<Self> {
+        Ok(ScopedTempDir {
+            dir: Some(TempDir::new_in(parent, "tmp")?),
+            keep_on_failure: false,
+        })
+    }
+
+    /// Keep the directory if it is dropped without calling `finish`, e.g when
+    /// the stage returns an error or panics
+    pub fn keep_on_failure(mut self, keep: bool) -> Self {
+        self.keep_on_failure = keep;
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        self.dir.as_ref().unwrap().path()
+    }
+
+    /// Remove the directory, reporting any error
+    pub fn finish(mut self) -> io::Result<()> {
+        self.dir.take().unwrap().close()
+    }
+}
+
+impl AsRef<Path> for ScopedTempDir {
+    fn as_ref(&self) -> &Path {
+        self.path()
+    }
+}
+
+impl Drop for ScopedTempDir {
+    fn drop(&mut self) {
+        if let Some(dir) = self.dir.take() {
+            if self.keep_on_failure {
+                let path: PathBuf = dir.into_path();
+                warn!("Keeping the temporary directory {}", path.display());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scoped_temp_dir() {
+        let parent = TempDir::new("test_scoped_temp_dir").unwrap();
+
+        let tmp = ScopedTempDir::new_in(parent.path()).unwrap();
+        let path = tmp.path().to_path_buf();
+        assert!(path.starts_with(parent.path()));
+        std::fs::write(path.join("scratch.txt"), "scratch").unwrap();
+        drop(tmp);
+        assert!(!path.exists());
+
+        let tmp = ScopedTempDir::new_in(parent.path())
+            .unwrap()
+            .keep_on_failure(true);
+        let path = tmp.path().to_path_buf();
+        tmp.finish().unwrap();
+        assert!(!path.exists());
+
+        // Dropped without `finish`, as when the stage fails
+        let tmp = ScopedTempDir::new_in(parent.path())
+            .unwrap()
+            .keep_on_failure(true);
+        let path = tmp.path().to_path_buf();
+        drop(tmp);
+        assert!(path.exists());
+    }
+}
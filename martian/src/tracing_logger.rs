@@ -0,0 +1,166 @@
+//! Logging backend using the `tracing` ecosystem. Events are written to the
+//! martian `_log` file (and stdout) in the same formats as the default `log`
+//! backend (see `LogFormat`). Records emitted using the `log` crate are
+//! forwarded as well.
+
+use crate::adapter_io::IoSink;
+use crate::compressed_log::CompressedLog;
+use crate::LogFormatter;
+use log::LevelFilter;
+use std::fmt::{self, Write as _};
+use std::io::{self, Write};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+
+/// Formats an event using the `LogFormatter`, with the message being
+/// `span1: span2: fields`
+struct MartianFormat(LogFormatter);
+
+impl<S, N> FormatEvent<S, N> for MartianFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let mut message = String::new();
+        if let Some(scope) = ctx.event_scope() {
+            for span in scope.from_root() {
+                write!(message, "{}: ", span.name())?;
+            }
+        }
+        ctx.field_format()
+            .format_fields(Writer::new(&mut message), event)?;
+        let metadata = event.metadata();
+        writeln!(
+            writer,
+            "{}",
+            self.0.format(metadata.level(), metadata.target(), message)
+        )
+    }
+}
+
+/// Writes everything to the log file as well as stdout, and the compressed
+/// log if there is one
+struct LogFileWriter(IoSink, Option<CompressedLog>);
+
+impl Write for LogFileWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write_all(buf)?;
+        io::stdout().write_all(buf)?;
+        if let Some(ref mut compressed_log) = self.1 {
+            compressed_log.write_all(buf)?;
+        }
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()?;
+        io::stdout().flush()
+    }
+}
+
+fn to_tracing_level(level: LevelFilter) -> tracing_subscriber::filter::LevelFilter {
+    use tracing_subscriber::filter::LevelFilter as TracingLevel;
+    match level {
+        LevelFilter::Off => TracingLevel::OFF,
+        LevelFilter::Error => TracingLevel::ERROR,
+        LevelFilter::Warn => TracingLevel::WARN,
+        LevelFilter::Info => TracingLevel::INFO,
+        LevelFilter::Debug => TracingLevel::DEBUG,
+        LevelFilter::Trace => TracingLevel::TRACE,
+    }
+}
+
+pub(crate) fn make_subscriber(
+    log_file: &IoSink,
+    level: LevelFilter,
+    formatter: LogFormatter,
+    compressed_log: Option<CompressedLog>,
+) -> impl Subscriber + Send + Sync + 'static {
+    let log_file = log_file.clone();
+    tracing_subscriber::fmt()
+        // The log is a file, which should not contain terminal escape codes
+        .with_ansi(false)
+        .event_format(MartianFormat(formatter))
+        .with_max_level(to_tracing_level(level))
+        .with_writer(move || LogFileWriter(log_file.clone(), compressed_log.clone()))
+        .finish()
+}
+
+pub(crate) fn setup_logging(
+    log_file: &IoSink,
+    level: LevelFilter,
+    formatter: LogFormatter,
+    compressed_log: Option<CompressedLog>,
+) {
+    // This also forwards records from the `log` crate to tracing
+    if let Err(e) = make_subscriber(log_file, level, formatter, compressed_log).try_init() {
+        panic!("Failed to initialize global logger: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::LogFormat;
+    use std::fs::File;
+    use std::io::Read;
+
+    #[test]
+    fn test_tracing_event_in_log_file() {
+        let tmp_dir = tempdir::TempDir::new("test_tracing_logger").unwrap();
+        let log_path = tmp_dir.path().join("_log");
+        let log_file = File::create(&log_path).unwrap();
+
+        let formatter = LogFormatter::new(LogFormat::Text, "SUM_SQUARES");
+        let subscriber =
+            make_subscriber(&IoSink::new(log_file), LevelFilter::Info, formatter, None);
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("chunk");
+            let _guard = span.enter();
+            tracing::info!(count = 3, "hello from tracing");
+            tracing::debug!("filtered out");
+        });
+
+        let mut contents = String::new();
+        File::open(&log_path)
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        let lines: Vec<_> = contents.lines().collect();
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].starts_with('['));
+        assert!(lines[0].ends_with("][INFO] chunk: hello from tracing count=3"));
+        assert!(!contents.contains('\x1b'));
+    }
+
+    #[test]
+    fn test_tracing_event_json() {
+        let tmp_dir = tempdir::TempDir::new("test_tracing_event_json").unwrap();
+        let log_path = tmp_dir.path().join("_log");
+        let log_file = File::create(&log_path).unwrap();
+
+        let formatter = LogFormatter::new(LogFormat::Json, "SUM_SQUARES");
+        let subscriber =
+            make_subscriber(&IoSink::new(log_file), LevelFilter::Info, formatter, None);
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::warn!("hello from tracing");
+        });
+
+        let contents = std::fs::read_to_string(&log_path).unwrap();
+        let lines: Vec<_> = contents.lines().collect();
+        assert_eq!(lines.len(), 1);
+        let record: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(record["level"], "WARN");
+        assert_eq!(record["target"], module_path!());
+        assert_eq!(record["stage"], "SUM_SQUARES");
+        assert_eq!(record["message"], "hello from tracing");
+    }
+}
@@ -1,18 +1,424 @@
-use serde::{Deserialize, Serialize};
+use crate::metadata::Metadata;
+use failure::{format_err, Error};
+use serde::de::{DeserializeOwned, Error as DeError};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
 use std::path::{Path, PathBuf};
 
-#[derive(Clone, Serialize, Deserialize)]
+/// Use this as an associated type when a stage has no inputs or outputs.
+/// It serializes to an empty json object (`{}`)
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct MartianVoid {
     // Adding a field as a hack so that this can be deserialized
     // from the json args object martian creates
+    #[serde(skip_serializing_if = "Option::is_none")]
     __null__: Option<bool>,
 }
 
+impl MartianVoid {
+    pub fn new() -> Self {
+        MartianVoid::default()
+    }
+}
+
+/// A `Vec` which is guaranteed to be non-empty. Deserializing an empty
+/// array into it is an error, which lets a stage reject empty inputs
+/// upfront. In the mro it is an array of `T`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(transparent)]
+pub struct NonEmptyVec<T>(Vec<T>);
+
+impl<T> NonEmptyVec<T> {
+    /// Returns `None` if `values` is empty
+    pub fn new(values: Vec<T>) -> Option<Self> {
+        if values.is_empty() {
+            None
+        } else {
+            Some(NonEmptyVec(values))
+        }
+    }
+    pub fn into_inner(self) -> Vec<T> {
+        self.0
+    }
+}
+
+impl<T> Deref for NonEmptyVec<T> {
+    type Target = [T];
+    fn deref(&self) -> &[T] {
+        &self.0
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for NonEmptyVec<T> {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let values = Vec::<T>::deserialize(deserializer)?;
+        NonEmptyVec::new(values)
+            .ok_or_else(|| D::Error::custom("expected a non-empty array, found an empty array"))
+    }
+}
+
+/// A map from string keys to values of type `V`, which serializes as a json
+/// object. In the mro, it is a typed map `map<V>`, like `HashMap<String, V>`,
+/// and the values are checked when the stage deserializes it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct TypedMap<V>(HashMap<String, V>);
+
+impl<V> TypedMap<V> {
+    pub fn new() -> Self {
+        TypedMap(HashMap::new())
+    }
+    pub fn into_inner(self) -> HashMap<String, V> {
+        self.0
+    }
+}
+
+impl<V> Default for TypedMap<V> {
+    fn default() -> Self {
+        TypedMap::new()
+    }
+}
+
+impl<V> From<HashMap<String, V>> for TypedMap<V> {
+    fn from(map: HashMap<String, V>) -> Self {
+        TypedMap(map)
+    }
+}
+
+impl<V> Deref for TypedMap<V> {
+    type Target = HashMap<String, V>;
+    fn deref(&self) -> &HashMap<String, V> {
+        &self.0
+    }
+}
+
+impl<V> DerefMut for TypedMap<V> {
+    fn deref_mut(&mut self) -> &mut HashMap<String, V> {
+        &mut self.0
+    }
+}
+
 pub trait MartianFileType {
+    /// The extension without the leading dot, which can have several parts, e.g
+    /// `fastq.gz`. It is also the name of the filetype in the mro.
     fn extension() -> &'static str;
+    /// File `<file_path>/<file_name>.<extension>`, see `with_extension`
     fn new(file_path: impl AsRef<Path>, file_name: impl AsRef<Path>) -> Self;
+
+    /// Check a file of this type written by a stage, e.g its magic bytes, so that a
+    /// truncated or corrupt file fails the stage which wrote it instead of the stages
+    /// downstream. The adapter calls it on the files in the outputs of the main and
+    /// the join, see `FiletypeValidator`. Nothing is checked by default.
+    fn validate(_path: &Path) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// File `<dir>/<basename>.<extension>`
+    fn in_dir(dir: &Path, basename: &str) -> Self
+    where
+        Self: Sized,
+    {
+        Self::new(dir, basename)
+    }
+
+    /// A file in the files directory of the stage whose name is unique across
+    /// the split, the chunks and the join, so that they never clobber each other's
+    /// scratch files. The name is `<field>_chnk<N>.<extension>` within chunk `N`
+    /// and `<field>_<stage_type>.<extension>` otherwise (`_split`, `_join` or
+    /// `_main` for a stage without a split).
+    fn unique_name(md: &Metadata, field: &str) -> Self
+    where
+        Self: Sized,
+    {
+        let basename = match md.chunk_index() {
+            Some(index) => format!("{}_chnk{}", field, index),
+            None => format!("{}_{}", field, md.stage_type),
+        };
+        Self::in_dir(Path::new(&md.files_path), &basename)
+    }
+}
+
+/// Reading and writing a `T` from and to a file of this type, so that stages do
+/// not have to open, (de)serialize and flush the file themselves:
+/// ```ignore
+/// martian_filetype! { JsonFile, "json" }
+/// let summary = JsonFile::new(rover.files_path(), "summary");
+/// summary.write(&metrics)?;
+/// let metrics: Metrics = summary.read()?;
+/// ```
+/// `martian_filetype!` implements it for every `T` that can be serialized when
+/// the extension is `json` or ends with `.json`. Other filetypes implement
+/// `read_from` and `write_into` to use it.
+pub trait FileTypeIO<T>: MartianFileType + AsRef<Path> {
+    fn read_from<R: Read>(reader: R) -> Result<T, Error>;
+
+    fn write_into<W: Write>(writer: W, item: &T) -> Result<(), Error>;
+
+    /// Read the file, with its path in the error
+    fn read(&self) -> Result<T, Error> {
+        let path = self.as_ref();
+        File::open(path)
+            .map_err(Error::from)
+            .and_then(|file| Self::read_from(BufReader::new(file)))
+            .map_err(|e| format_err!("Failed to read {}: {}", path.display(), e))
+    }
+
+    /// Create or truncate the file and write `item` to it, with its path in the error
+    fn write(&self, item: &T) -> Result<(), Error> {
+        let path = self.as_ref();
+        let write = || -> Result<(), Error> {
+            let mut writer = BufWriter::new(File::create(path)?);
+            Self::write_into(&mut writer, item)?;
+            writer.flush()?;
+            Ok(())
+        };
+        write().map_err(|e| format_err!("Failed to write {}: {}", path.display(), e))
+    }
+}
+
+/// `FileTypeIO::read_from` for json files
+pub fn read_json<T: DeserializeOwned, R: Read>(reader: R) -> Result<T, Error> {
+    Ok(serde_json::from_reader(reader)?)
+}
+
+/// `FileTypeIO::write_into` for json files, pretty printed
+pub fn write_json<T: Serialize, W: Write>(writer: W, item: &T) -> Result<(), Error> {
+    Ok(serde_json::to_writer_pretty(writer, item)?)
+}
+
+/// `MartianFileType::validate` for files which cannot be empty
+pub fn validate_non_empty(path: &Path) -> Result<(), Error> {
+    if std::fs::metadata(path)?.len() == 0 {
+        return Err(format_err!("the file is empty"));
+    }
+    Ok(())
+}
+
+/// `MartianFileType::validate` for binary formats starting with the `magic` bytes
+pub fn validate_magic_bytes(path: &Path, magic: &[u8]) -> Result<(), Error> {
+    let mut start = Vec::with_capacity(magic.len());
+    File::open(path)?
+        .take(magic.len() as u64)
+        .read_to_end(&mut start)?;
+    if start != magic {
+        return Err(format_err!(
+            "the file does not start with the bytes {:02x?}",
+            magic
+        ));
+    }
+    Ok(())
+}
+
+/// `MartianFileType::validate` of the json filetypes, which checks that the file is
+/// well-formed json, e.g that it is not truncated
+pub fn validate_json(path: &Path) -> Result<(), Error> {
+    let _: serde::de::IgnoredAny = read_json(BufReader::new(File::open(path)?))?;
+    Ok(())
+}
+
+/// The `MartianFileType::validate` of a filetype. `AsMartianBlanketType::filetype_validators`
+/// lists them for the filetypes in a field, which the adapter runs on the paths in
+/// that field of the outputs.
+#[derive(Clone, Copy)]
+pub struct FiletypeValidator {
+    extension: &'static str,
+    validate: fn(&Path) -> Result<(), Error>,
+}
+
+impl FiletypeValidator {
+    pub fn of<F: MartianFileType>() -> Self {
+        FiletypeValidator {
+            extension: F::extension(),
+            validate: F::validate,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        self.extension
+    }
+
+    /// Whether `path` has the extension of the filetype
+    pub fn matches(&self, path: &Path) -> bool {
+        path.to_string_lossy()
+            .ends_with(&format!(".{}", self.extension))
+    }
+
+    pub fn validate(&self, path: &Path) -> Result<(), Error> {
+        (self.validate)(path)
+    }
+}
+
+impl std::fmt::Debug for FiletypeValidator {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_tuple("FiletypeValidator")
+            .field(&self.extension)
+            .finish()
+    }
 }
 
+/// A path to a file of type `F`, which behaves like a `Path` but is checked to
+/// have the extension `F::extension()` when it is created or deserialized. A
+/// stage taking a `TypedPath<FastqFile>` input fails upfront when it is given
+/// any other file. In the mro it is the filetype of `F`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TypedPath<F> {
+    path: PathBuf,
+    file_type: PhantomData<F>,
+}
+
+impl<F: MartianFileType> TypedPath<F> {
+    /// Fails unless the file name ends with `.<extension>`
+    pub fn new(path: impl Into<PathBuf>) -> Result<Self, Error> {
+        let path = path.into();
+        let suffix = format!(".{}", F::extension());
+        match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) if name.len() > suffix.len() && name.ends_with(&suffix) => Ok(TypedPath {
+                path,
+                file_type: PhantomData,
+            }),
+            _ => Err(format_err!(
+                "Expected a `{}` file, found {}",
+                F::extension(),
+                path.display()
+            )),
+        }
+    }
+    pub fn into_inner(self) -> PathBuf {
+        self.path
+    }
+}
+
+impl<F: MartianFileType + AsRef<Path>> From<F> for TypedPath<F> {
+    fn from(file: F) -> Self {
+        TypedPath {
+            path: file.as_ref().to_path_buf(),
+            file_type: PhantomData,
+        }
+    }
+}
+
+impl<F> Deref for TypedPath<F> {
+    type Target = Path;
+    fn deref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl<F> AsRef<Path> for TypedPath<F> {
+    fn as_ref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl<F> Serialize for TypedPath<F> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.path.serialize(serializer)
+    }
+}
+
+impl<'de, F: MartianFileType> Deserialize<'de> for TypedPath<F> {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let path = PathBuf::deserialize(deserializer)?;
+        TypedPath::new(path).map_err(D::Error::custom)
+    }
+}
+
+/// The path of a file with the (possibly multi-part) `extension`, e.g `fastq.gz`.
+/// `.<extension>` is added to the file name, unless it already ends with it. If
+/// the name ends with the first parts of the extension, only the other parts are
+/// added. Used by `MartianFileType::new` of the filetypes.
+/// ```rust
+/// use martian::types::with_extension;
+/// use std::path::Path;
+/// assert_eq!(with_extension("/files/reads", "fastq.gz"), Path::new("/files/reads.fastq.gz"));
+/// assert_eq!(with_extension("/files/reads.fastq", "fastq.gz"), Path::new("/files/reads.fastq.gz"));
+/// assert_eq!(with_extension("/files/reads.fastq.gz", "fastq.gz"), Path::new("/files/reads.fastq.gz"));
+/// assert_eq!(with_extension("/files/reads.gz", "fastq.gz"), Path::new("/files/reads.gz.fastq.gz"));
+/// ```
+pub fn with_extension(path: impl Into<PathBuf>, extension: &str) -> PathBuf {
+    let mut path = path.into();
+    let name = match path.file_name() {
+        Some(name) => name.to_string_lossy().into_owned(),
+        None => String::new(),
+    };
+    // Where the parts of the extension already in the name end, longest first
+    let ends =
+        std::iter::once(extension.len()).chain(extension.rmatch_indices('.').map(|(i, _)| i));
+    let missing = ends
+        .filter(|&end| end > 0)
+        .find(|&end| name.ends_with(&format!(".{}", &extension[..end])))
+        .map(|end| extension[end..].to_string())
+        .unwrap_or_else(|| format!(".{}", extension));
+    path.as_mut_os_string().push(missing);
+    path
+}
+
+// The traits of a filetype `$name<T>` with the fields `path: PathBuf` and
+// `$marker: PhantomData<_>`, which only depend on its path. `T` is the content
+// of the file, so none of them require anything of `T`.
+macro_rules! generic_filetype_impls {
+    ($name:ident, $marker:ident) => {
+        impl<T> From<::std::path::PathBuf> for $name<T> {
+            fn from(path: ::std::path::PathBuf) -> Self {
+                $name {
+                    path,
+                    $marker: ::std::marker::PhantomData,
+                }
+            }
+        }
+
+        impl<T> AsRef<::std::path::Path> for $name<T> {
+            fn as_ref(&self) -> &::std::path::Path {
+                &self.path
+            }
+        }
+
+        impl<T> Clone for $name<T> {
+            fn clone(&self) -> Self {
+                $name::from(self.path.clone())
+            }
+        }
+
+        impl<T> PartialEq for $name<T> {
+            fn eq(&self, other: &Self) -> bool {
+                self.path == other.path
+            }
+        }
+
+        impl<T> ::std::fmt::Debug for $name<T> {
+            fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+                f.debug_tuple(stringify!($name)).field(&self.path).finish()
+            }
+        }
+
+        impl<T> ::serde::Serialize for $name<T> {
+            fn serialize<S: ::serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                ::serde::Serialize::serialize(&self.path, serializer)
+            }
+        }
+
+        impl<'de, T> ::serde::Deserialize<'de> for $name<T> {
+            fn deserialize<D: ::serde::Deserializer<'de>>(
+                deserializer: D,
+            ) -> Result<Self, D::Error> {
+                let path: ::std::path::PathBuf = ::serde::Deserialize::deserialize(deserializer)?;
+                Ok($name::from(path))
+            }
+        }
+    };
+}
+pub(crate) use generic_filetype_impls;
+
 pub trait MartianMakePath {
     fn make_path(directory: impl AsRef<Path>, file_name: impl AsRef<Path>) -> Self;
 }
@@ -39,3 +445,222 @@ impl<T: MartianFileType> MartianMakePath for T {
         <T as MartianFileType>::new(directory, file_name)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    struct TxtFile(PathBuf);
+
+    impl AsRef<Path> for TxtFile {
+        fn as_ref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl MartianFileType for TxtFile {
+        fn extension() -> &'static str {
+            "txt"
+        }
+        fn new(file_path: impl AsRef<Path>, file_name: impl AsRef<Path>) -> Self {
+            let mut path = PathBuf::from(file_path.as_ref());
+            path.push(file_name);
+            path.set_extension(Self::extension());
+            TxtFile(path)
+        }
+    }
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq)]
+    struct Metrics {
+        reads: u64,
+        name: String,
+    }
+
+    impl FileTypeIO<Metrics> for TxtFile {
+        fn read_from<R: Read>(reader: R) -> Result<Metrics, Error> {
+            read_json(reader)
+        }
+        fn write_into<W: Write>(writer: W, item: &Metrics) -> Result<(), Error> {
+            write_json(writer, item)
+        }
+    }
+
+    #[test]
+    fn test_file_type_io() {
+        let tmp_dir = tempdir::TempDir::new("test_file_type_io").unwrap();
+        let file = TxtFile::in_dir(tmp_dir.path(), "metrics");
+        let metrics = Metrics {
+            reads: 10,
+            name: "sample".into(),
+        };
+        file.write(&metrics).unwrap();
+        assert_eq!(file.read().unwrap(), metrics);
+
+        std::fs::write(&file, "{\"reads\": 10}").unwrap();
+        assert_eq!(
+            file.read().unwrap_err().to_string(),
+            format!(
+                "Failed to read {}: missing field `name` at line 1 column 13",
+                file.as_ref().display()
+            )
+        );
+        let missing = TxtFile::in_dir(tmp_dir.path(), "missing");
+        assert!(missing
+            .read()
+            .unwrap_err()
+            .to_string()
+            .starts_with(&format!("Failed to read {}: ", missing.as_ref().display())));
+    }
+
+    #[test]
+    fn test_file_type_in_dir() {
+        assert_eq!(
+            TxtFile::in_dir(Path::new("/files"), "summary"),
+            TxtFile("/files/summary.txt".into())
+        );
+    }
+
+    #[test]
+    fn test_file_type_unique_name() {
+        let tmp_dir = tempdir::TempDir::new("test_file_type_unique_name").unwrap();
+        let log_file = std::fs::File::create(tmp_dir.path().join("_log")).unwrap();
+        let make_md = |stage_type: &str, dir: &str| {
+            let args = vec![
+                "stage".to_string(),
+                stage_type.to_string(),
+                format!("/STAGE/fork0/{}", dir),
+                "/STAGE/fork0/files".to_string(),
+                "/STAGE/fork0/_run".to_string(),
+            ];
+            Metadata::new(args, &log_file)
+        };
+
+        let chunk0 = TxtFile::unique_name(&make_md("main", "chnk0"), "scratch");
+        let chunk1 = TxtFile::unique_name(&make_md("main", "chnk1"), "scratch");
+        assert_eq!(
+            chunk0,
+            TxtFile("/STAGE/fork0/files/scratch_chnk0.txt".into())
+        );
+        assert_eq!(
+            chunk1,
+            TxtFile("/STAGE/fork0/files/scratch_chnk1.txt".into())
+        );
+        assert_ne!(chunk0, chunk1);
+        assert_eq!(chunk1.0.extension().unwrap(), "txt");
+
+        assert_eq!(
+            TxtFile::unique_name(&make_md("join", "join"), "scratch"),
+            TxtFile("/STAGE/fork0/files/scratch_join.txt".into())
+        );
+        // Deterministic
+        assert_eq!(
+            TxtFile::unique_name(&make_md("main", "chnk0"), "scratch"),
+            chunk0
+        );
+    }
+
+    #[test]
+    fn test_typed_path() {
+        let path = TypedPath::<TxtFile>::new("/files/summary.txt").unwrap();
+        assert_eq!(path.extension().unwrap(), "txt");
+        assert_eq!(
+            TypedPath::from(TxtFile::in_dir(Path::new("/files"), "summary")),
+            path
+        );
+        assert_eq!(
+            serde_json::to_string(&path).unwrap(),
+            r#""/files/summary.txt""#
+        );
+        let round_trip: TypedPath<TxtFile> =
+            serde_json::from_str(r#""/files/summary.txt""#).unwrap();
+        assert_eq!(round_trip, path);
+
+        for bad in &[
+            "/files/summary.json",
+            "/files/summary_txt",
+            "/files/.txt",
+            "/",
+        ] {
+            assert!(TypedPath::<TxtFile>::new(*bad).is_err(), "{}", bad);
+        }
+        let err =
+            serde_json::from_str::<TypedPath<TxtFile>>(r#""/files/reads.fastq""#).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Expected a `txt` file, found /files/reads.fastq"
+        );
+    }
+
+    #[test]
+    fn test_martian_void() {
+        assert_eq!(serde_json::to_string(&MartianVoid::new()).unwrap(), "{}");
+        let void: MartianVoid = serde_json::from_str("{}").unwrap();
+        assert_eq!(void, MartianVoid::new());
+        let _: MartianVoid = serde_json::from_str(r#"{"__null__": null, "foo": 1}"#).unwrap();
+    }
+
+    #[test]
+    fn test_non_empty_vec() {
+        let values: NonEmptyVec<i32> = serde_json::from_str("[1, 2, 3]").unwrap();
+        assert_eq!(&*values, &[1, 2, 3]);
+        assert_eq!(values.len(), 3);
+        assert_eq!(serde_json::to_string(&values).unwrap(), "[1,2,3]");
+        assert_eq!(values.into_inner(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_typed_map() {
+        let mut map = TypedMap::new();
+        map.insert("a".to_string(), vec![1, 2]);
+        assert_eq!(serde_json::to_string(&map).unwrap(), r#"{"a":[1,2]}"#);
+        let round_trip: TypedMap<Vec<i32>> =
+            serde_json::from_str(&serde_json::to_string(&map).unwrap()).unwrap();
+        assert_eq!(round_trip, map);
+        assert_eq!(round_trip["a"], vec![1, 2]);
+
+        // The values are checked
+        assert!(serde_json::from_str::<TypedMap<i32>>(r#"{"a": "b"}"#).is_err());
+    }
+
+    #[test]
+    fn test_non_empty_vec_empty() {
+        let err = serde_json::from_str::<NonEmptyVec<i32>>("[]").unwrap_err();
+        assert!(err.to_string().contains("non-empty array"));
+        assert!(NonEmptyVec::<i32>::new(Vec::new()).is_none());
+    }
+
+    #[test]
+    fn test_validate_helpers() {
+        let tmp_dir = tempdir::TempDir::new("test_validate_helpers").unwrap();
+        let path = tmp_dir.path().join("file");
+        std::fs::write(&path, "").unwrap();
+        assert_eq!(
+            validate_non_empty(&path).unwrap_err().to_string(),
+            "the file is empty"
+        );
+        assert!(validate_magic_bytes(&path, b"BAM\x01").is_err());
+        assert!(validate_json(&path).is_err());
+
+        std::fs::write(&path, b"BAM\x01\x00").unwrap();
+        assert!(validate_non_empty(&path).is_ok());
+        assert!(validate_magic_bytes(&path, b"BAM\x01").is_ok());
+        assert_eq!(
+            validate_magic_bytes(&path, &[0x1f, 0x8b])
+                .unwrap_err()
+                .to_string(),
+            "the file does not start with the bytes [1f, 8b]"
+        );
+
+        std::fs::write(&path, r#"{"reads": [1, 2]}"#).unwrap();
+        assert!(validate_json(&path).is_ok());
+        std::fs::write(&path, r#"{"reads": [1, "#).unwrap();
+        assert!(validate_json(&path).is_err());
+
+        let validator = FiletypeValidator::of::<TxtFile>();
+        assert_eq!(validator.extension(), "txt");
+        assert!(validator.matches(Path::new("/files/summary.txt")));
+        assert!(!validator.matches(Path::new("/files/summary.txt.gz")));
+        assert!(validator.validate(&path).is_ok());
+    }
+}
@@ -1,8 +1,17 @@
-use crate::{Json, JsonDict};
+use crate::{Json, JsonDict, MartianBlanketType, MartianPrimaryType, MartianStruct, StageError};
 use failure::Error;
+use log::{info, warn};
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 use serde_json::json;
+use std::io::{BufRead, BufReader, ErrorKind, Read};
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
+use std::thread;
+
+/// Number of trailing stderr lines reported when a command run using
+/// `run_logged` fails
+const STDERR_TAIL_LINES: usize = 20;
 
 /// Shortcut function to decode a JSON `&str` into an object
 pub fn obj_decode<T: DeserializeOwned>(s: &JsonDict) -> Result<T, Error> {
@@ -24,6 +33,43 @@ pub fn json_encode<T: Serialize>(v: &T) -> Result<Json, Error> {
     Ok(serde_json::to_value(v)?)
 }
 
+/// Shortcut function to encode an object into pretty printed canonical JSON,
+/// where the keys of every object are sorted recursively. The order of the
+/// elements of arrays is preserved. Useful for stable diffs of outputs in
+/// golden file tests.
+pub fn canonical_json_encode<T: Serialize>(v: &T) -> Result<String, Error> {
+    Ok(serde_json::to_string_pretty(&SortedKeys(&json_encode(v)?))?)
+}
+
+// Serializes the wrapped json with the object keys in sorted order, independent
+// of the key order of `serde_json::Map`
+struct SortedKeys<'a>(&'a Json);
+
+impl<'a> Serialize for SortedKeys<'a> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::{SerializeMap, SerializeSeq};
+        match self.0 {
+            Json::Object(obj) => {
+                let mut entries: Vec<_> = obj.iter().collect();
+                entries.sort_by(|a, b| a.0.cmp(b.0));
+                let mut map = serializer.serialize_map(Some(entries.len()))?;
+                for (key, value) in entries {
+                    map.serialize_entry(key, &SortedKeys(value))?;
+                }
+                map.end()
+            }
+            Json::Array(values) => {
+                let mut seq = serializer.serialize_seq(Some(values.len()))?;
+                for value in values {
+                    seq.serialize_element(&SortedKeys(value))?;
+                }
+                seq.end()
+            }
+            value => value.serialize(serializer),
+        }
+    }
+}
+
 pub fn to_exec_name(struct_name: &str) -> String {
     let last_name = struct_name
         .split("::")
@@ -57,3 +103,626 @@ pub fn current_executable() -> String {
         .to_string_lossy()
         .into_owned()
 }
+
+/// Forward each line from `reader` to the log with the given prefix, until the
+/// end of the stream, and return the bytes that were read unchanged. Lines which
+/// are not valid UTF-8 are logged lossily.
+fn log_lines(reader: impl Read, prefix: String, is_stderr: bool) -> Vec<u8> {
+    let mut reader = BufReader::new(reader);
+    let mut captured = Vec::new();
+    loop {
+        let start = captured.len();
+        match reader.read_until(b'\n', &mut captured) {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => {
+                warn!("[{}] failed to read the output: {}", prefix, e);
+                break;
+            }
+        }
+        let line = &captured[start..];
+        let line = String::from_utf8_lossy(line.strip_suffix(b"\n").unwrap_or(line));
+        if is_stderr {
+            warn!("[{}] {}", prefix, line);
+        } else {
+            info!("[{}] {}", prefix, line);
+        }
+    }
+    captured
+}
+
+/// Run an external command, streaming its stdout and stderr line by line into
+/// the log (which ends up in the martian `_log` file), prefixed with the
+/// command name. A non-zero exit is converted into a `StageError::DependencyFailure`
+/// containing the tail of the captured stderr.
+pub fn run_logged(cmd: &mut Command) -> Result<Output, Error> {
+    let name = Path::new(cmd.get_program())
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    let mut child = cmd
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // Drain both pipes concurrently so that the child never blocks on a full pipe
+    let stdout = child.stdout.take().unwrap();
+    let stdout_prefix = name.clone();
+    let stdout_thread = thread::spawn(move || log_lines(stdout, stdout_prefix, false));
+    let stderr = child.stderr.take().unwrap();
+    let stderr_prefix = name.clone();
+    let stderr_thread = thread::spawn(move || log_lines(stderr, stderr_prefix, true));
+
+    let status = child.wait()?;
+    let output = Output {
+        status,
+        stdout: stdout_thread.join().unwrap(),
+        stderr: stderr_thread.join().unwrap(),
+    };
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let lines: Vec<_> = stderr.lines().collect();
+        let tail = lines[lines.len().saturating_sub(STDERR_TAIL_LINES)..].join("\n");
+        return Err(StageError::DependencyFailure {
+            message: format!(
+                "Command `{}` failed with {}:\n{}",
+                name, output.status, tail
+            ),
+        }
+        .into());
+    }
+    Ok(output)
+}
+
+/// Check that an output file exists and is not empty. Use this at the end of
+/// a `main` to catch outputs that were never written at the chunk boundary,
+/// rather than in a downstream stage. Returns a `StageError::PipelineError`
+/// naming the offending file otherwise.
+pub fn assert_output_file(path: impl AsRef<Path>) -> Result<(), Error> {
+    let path = path.as_ref();
+    let message = match std::fs::metadata(path) {
+        Ok(ref meta) if meta.is_file() && meta.len() > 0 => return Ok(()),
+        Ok(ref meta) if meta.is_file() => format!("Output file {} is empty", path.display()),
+        Ok(_) => format!("Output {} is not a file", path.display()),
+        Err(_) => format!("Output file {} does not exist", path.display()),
+    };
+    Err(StageError::PipelineError { message }.into())
+}
+
+/// Run `assert_output_file` on every file typed field of `outs`, including
+/// (nested) arrays of files. Fields which are `null` are skipped.
+pub fn assert_output_files<T: MartianStruct + Serialize>(outs: &T) -> Result<(), Error> {
+    let outs = obj_encode(outs)?;
+    for field in T::mro_fields() {
+        if let MartianBlanketType::Primary(MartianPrimaryType::FileType(_))
+        | MartianBlanketType::Array(MartianPrimaryType::FileType(_))
+        | MartianBlanketType::NestedArray(MartianPrimaryType::FileType(_), _) = field.ty()
+        {
+            let mut paths = Vec::new();
+            if let Some(value) = outs.get(field.name()) {
+                collect_leaves(value, &mut paths);
+            }
+            for path in paths.into_iter().filter_map(Json::as_str) {
+                assert_output_file(path).map_err(|e| StageError::PipelineError {
+                    message: format!("{} (field {})", e, field.name()),
+                })?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// The values within (nested) arrays
+fn collect_leaves<'a>(value: &'a Json, leaves: &mut Vec<&'a Json>) {
+    match value {
+        Json::Array(values) => {
+            for value in values {
+                collect_leaves(value, leaves);
+            }
+        }
+        _ => leaves.push(value),
+    }
+}
+
+/// Build the outputs of the stage from the outputs of the chunks, for the
+/// common joins which only combine the chunk outputs field by field. Each field
+/// of the stage outputs `S` is computed from the field of the same name of the
+/// chunk outputs `C`, based on their mro types:
+/// - `T[]` from `T[]`: the arrays are concatenated
+/// - `T[]` from `T`: the values are collected, e.g all the files written by
+///   the chunks
+/// - `int` or `float` from the same type: the values are summed
+///
+/// `null` chunk values are skipped. Any other field is an error, in which case
+/// the `join` needs to be written by hand.
+/// ```ignore
+/// fn join(&self, _: SI, _: Vec<CI>, chunk_outs: Vec<CO>, _: MartianRover) -> Result<SO, Error> {
+///     join_chunk_outs(&chunk_outs)
+/// }
+/// ```
+pub fn join_chunk_outs<C, S>(chunk_outs: &[C]) -> Result<S, Error>
+where
+    C: MartianStruct + Serialize,
+    S: MartianStruct + DeserializeOwned,
+{
+    let chunk_fields = C::mro_fields();
+    let chunk_outs = chunk_outs
+        .iter()
+        .map(obj_encode)
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut outs = JsonDict::new();
+    for field in S::mro_fields() {
+        let bug = |reason: &str| StageError::InternalBug {
+            message: format!(
+                "Cannot join the field {} of the stage outputs: {}",
+                field.name(),
+                reason
+            ),
+        };
+        let chunk_field = chunk_fields
+            .iter()
+            .find(|f| f.name() == field.name())
+            .ok_or_else(|| bug("it is not in the chunk outputs"))?;
+        let (ty, chunk_ty) = (field.ty(), chunk_field.ty());
+        if ty.primary() != chunk_ty.primary() {
+            return Err(bug(&format!("it is a {} in the chunk outputs", chunk_ty)).into());
+        }
+        let values = chunk_outs
+            .iter()
+            .filter_map(|outs| outs.get(field.name()))
+            .filter(|value| !value.is_null());
+        let value = match (ty.dimensions(), chunk_ty.dimensions()) {
+            (dims, chunk_dims) if dims > 0 && chunk_dims + 1 == dims => {
+                Json::Array(values.cloned().collect())
+            }
+            (dims, chunk_dims) if dims > 0 && chunk_dims == dims => Json::Array(
+                values
+                    .filter_map(Json::as_array)
+                    .flatten()
+                    .cloned()
+                    .collect(),
+            ),
+            (0, 0) if *ty.primary() == MartianPrimaryType::Int => {
+                json!(values.filter_map(Json::as_i64).sum::<i64>())
+            }
+            (0, 0) if *ty.primary() == MartianPrimaryType::Float => {
+                json!(values.filter_map(Json::as_f64).sum::<f64>())
+            }
+            _ => return Err(bug(&format!("it cannot be built from a {}", chunk_ty)).into()),
+        };
+        outs.insert(field.name().to_string(), value);
+    }
+    obj_decode(&outs)
+}
+
+/// Number of unchanged lines shown around each change by `unified_diff`
+const DIFF_CONTEXT_LINES: usize = 3;
+
+/// A line by line diff from `old` to `new` in the unified diff format, computed
+/// using the longest common subsequence of lines. Empty if the lines are the same.
+pub(crate) fn unified_diff(
+    old: &[impl AsRef<str>],
+    new: &[impl AsRef<str>],
+    old_name: &str,
+    new_name: &str,
+) -> String {
+    let (n, m) = (old.len(), new.len());
+    // lcs[i][j] is the length of the longest common subsequence of old[i..] and new[j..]
+    let mut lcs = vec![vec![0usize; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i][j] = if old[i].as_ref() == new[j].as_ref() {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                std::cmp::max(lcs[i + 1][j], lcs[i][j + 1])
+            };
+        }
+    }
+
+    // Each entry is (tag, old index, new index, line)
+    let mut ops = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && old[i].as_ref() == new[j].as_ref() {
+            ops.push((' ', i, j, old[i].as_ref()));
+            i += 1;
+            j += 1;
+        } else if j == m || (i < n && lcs[i + 1][j] >= lcs[i][j + 1]) {
+            ops.push(('-', i, j, old[i].as_ref()));
+            i += 1;
+        } else {
+            ops.push(('+', i, j, new[j].as_ref()));
+            j += 1;
+        }
+    }
+
+    let mut result = String::new();
+    let changed: Vec<usize> = (0..ops.len()).filter(|&k| ops[k].0 != ' ').collect();
+    if changed.is_empty() {
+        return result;
+    }
+    result.push_str(&format!("--- {}\n+++ {}\n", old_name, new_name));
+
+    // Group the changes which are close enough to share context into hunks
+    let mut k = 0;
+    while k < changed.len() {
+        let start = changed[k].saturating_sub(DIFF_CONTEXT_LINES);
+        let mut end = changed[k];
+        while k < changed.len() && changed[k] <= end + 2 * DIFF_CONTEXT_LINES {
+            end = changed[k];
+            k += 1;
+        }
+        let end = std::cmp::min(end + DIFF_CONTEXT_LINES + 1, ops.len());
+        let hunk = &ops[start..end];
+        let old_count = hunk.iter().filter(|op| op.0 != '+').count();
+        let new_count = hunk.iter().filter(|op| op.0 != '-').count();
+        result.push_str(&format!(
+            "@@ -{},{} +{},{} @@\n",
+            hunk[0].1 + usize::from(old_count > 0),
+            old_count,
+            hunk[0].2 + usize::from(new_count > 0),
+            new_count
+        ));
+        for (tag, _, _, line) in hunk {
+            result.push_str(&format!("{}{}\n", tag, line));
+        }
+    }
+    result
+}
+
+/// Partition `items` into the fewest chunks with at most `max_per_chunk` items
+/// each, balancing the chunk sizes so that they differ by at most one. The
+/// order of the items is preserved. Useful for building the chunks in `split`.
+///
+/// ```rust
+/// use martian::utils::chunk_by_size;
+/// let chunks = chunk_by_size((0..5).collect(), 2);
+/// assert_eq!(chunks, vec![vec![0, 1], vec![2, 3], vec![4]]);
+/// let chunks = chunk_by_size((0..7).collect(), 4);
+/// assert_eq!(chunks, vec![vec![0, 1, 2, 3], vec![4, 5, 6]]);
+/// ```
+pub fn chunk_by_size<T>(items: Vec<T>, max_per_chunk: usize) -> Vec<Vec<T>> {
+    assert!(max_per_chunk > 0, "max_per_chunk needs to be positive");
+    let num_chunks = items.len().div_ceil(max_per_chunk);
+    let mut chunks = Vec::with_capacity(num_chunks);
+    let mut items = items.into_iter();
+    for chunks_left in (1..=num_chunks).rev() {
+        let chunk_size = items.len().div_ceil(chunks_left);
+        chunks.push(items.by_ref().take(chunk_size).collect());
+    }
+    chunks
+}
+
+/// Partition `items` into chunks whose total size, as computed by `size_of`,
+/// is at most `max_bytes`. The chunks are balanced by aiming for an even
+/// share of the total size. An item that is larger than `max_bytes` by
+/// itself ends up in a chunk of its own. The order of the items is preserved.
+pub fn chunk_by_bytes<T>(
+    items: Vec<T>,
+    max_bytes: usize,
+    size_of: impl Fn(&T) -> usize,
+) -> Vec<Vec<T>> {
+    assert!(max_bytes > 0, "max_bytes needs to be positive");
+    let total_bytes: usize = items.iter().map(&size_of).sum();
+    let num_chunks = total_bytes.div_ceil(max_bytes).max(1);
+    let target_bytes = total_bytes.div_ceil(num_chunks).max(1);
+
+    let mut chunks = Vec::new();
+    let mut chunk = Vec::new();
+    let mut chunk_bytes = 0;
+    for item in items {
+        let item_bytes = size_of(&item);
+        if !chunk.is_empty()
+            && (chunk_bytes >= target_bytes || chunk_bytes + item_bytes > max_bytes)
+        {
+            chunks.push(std::mem::take(&mut chunk));
+            chunk_bytes = 0;
+        }
+        chunk_bytes += item_bytes;
+        chunk.push(item);
+    }
+    if !chunk.is_empty() {
+        chunks.push(chunk);
+    }
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_canonical_json_encode() {
+        #[derive(Serialize)]
+        struct Summary {
+            name: String,
+            counts: Vec<Count>,
+            alpha: f64,
+        }
+        #[derive(Serialize)]
+        struct Count {
+            value: u32,
+            key: String,
+        }
+        let summary = Summary {
+            name: "sample".into(),
+            counts: vec![
+                Count {
+                    value: 2,
+                    key: "b".into(),
+                },
+                Count {
+                    value: 1,
+                    key: "a".into(),
+                },
+            ],
+            alpha: 0.5,
+        };
+
+        let default = serde_json::to_string(&summary).unwrap();
+        assert_eq!(
+            default,
+            r#"{"name":"sample","counts":[{"value":2,"key":"b"},{"value":1,"key":"a"}],"alpha":0.5}"#
+        );
+
+        let canonical = canonical_json_encode(&summary).unwrap();
+        let compact: String = canonical.split_whitespace().collect();
+        assert_eq!(
+            compact,
+            r#"{"alpha":0.5,"counts":[{"key":"b","value":2},{"key":"a","value":1}],"name":"sample"}"#
+        );
+        assert_eq!(canonical, canonical_json_encode(&summary).unwrap());
+    }
+
+    #[test]
+    fn test_chunk_by_size() {
+        assert_eq!(
+            chunk_by_size((0..6).collect(), 3),
+            vec![vec![0, 1, 2], vec![3, 4, 5]]
+        );
+        assert_eq!(
+            chunk_by_size((0..10).collect(), 4),
+            vec![vec![0, 1, 2, 3], vec![4, 5, 6], vec![7, 8, 9]]
+        );
+        assert_eq!(chunk_by_size((0..2).collect(), 5), vec![vec![0, 1]]);
+        assert_eq!(chunk_by_size(Vec::<i32>::new(), 5), Vec::<Vec<i32>>::new());
+    }
+
+    #[test]
+    fn test_chunk_by_bytes() {
+        let len = |s: &&str| s.len();
+        // 10 bytes in total, split evenly instead of 6 + 4
+        assert_eq!(
+            chunk_by_bytes(vec!["aa", "bb", "c", "dd", "eee"], 6, len),
+            vec![vec!["aa", "bb", "c"], vec!["dd", "eee"]]
+        );
+        assert_eq!(
+            chunk_by_bytes(vec!["aa", "bb", "cc"], 2, len),
+            vec![vec!["aa"], vec!["bb"], vec!["cc"]]
+        );
+        // Oversized item in a chunk of its own
+        assert_eq!(
+            chunk_by_bytes(vec!["a", "bbbbbbbb", "c"], 4, len),
+            vec![vec!["a"], vec!["bbbbbbbb"], vec!["c"]]
+        );
+        assert_eq!(
+            chunk_by_bytes(Vec::<&str>::new(), 4, len),
+            Vec::<Vec<&str>>::new()
+        );
+    }
+
+    #[test]
+    fn test_assert_output_file() {
+        let dir = tempdir::TempDir::new("test_assert_output_file").unwrap();
+        let present = dir.path().join("present.txt");
+        std::fs::write(&present, "data").unwrap();
+        let empty = dir.path().join("empty.txt");
+        std::fs::write(&empty, "").unwrap();
+        let missing = dir.path().join("missing.txt");
+
+        assert!(assert_output_file(&present).is_ok());
+        let err = assert_output_file(&empty).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("Output file {} is empty", empty.display())
+        );
+        let err = assert_output_file(&missing).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("Output file {} does not exist", missing.display())
+        );
+        assert!(assert_output_file(dir.path()).is_err());
+    }
+
+    #[test]
+    fn test_assert_output_files() {
+        use crate::mro::MroField;
+        use std::path::PathBuf;
+
+        #[derive(Serialize)]
+        struct Outs {
+            count: i32,
+            summary: Option<PathBuf>,
+            parts: Vec<PathBuf>,
+        }
+        impl MartianStruct for Outs {
+            fn mro_fields() -> Vec<MroField> {
+                vec![
+                    MroField::new(
+                        "count",
+                        MartianBlanketType::Primary(MartianPrimaryType::Int),
+                    ),
+                    MroField::new(
+                        "summary",
+                        MartianBlanketType::Primary(MartianPrimaryType::FileType("json".into())),
+                    ),
+                    MroField::new(
+                        "parts",
+                        MartianBlanketType::Array(MartianPrimaryType::FileType("txt".into())),
+                    ),
+                ]
+            }
+        }
+
+        let dir = tempdir::TempDir::new("test_assert_output_files").unwrap();
+        let part = dir.path().join("part.txt");
+        std::fs::write(&part, "data").unwrap();
+        let missing = dir.path().join("missing.txt");
+
+        let outs = Outs {
+            count: 1,
+            summary: None,
+            parts: vec![part.clone()],
+        };
+        assert!(assert_output_files(&outs).is_ok());
+
+        let outs = Outs {
+            count: 1,
+            summary: None,
+            parts: vec![part, missing.clone()],
+        };
+        assert_eq!(
+            assert_output_files(&outs).unwrap_err().to_string(),
+            format!(
+                "Output file {} does not exist (field parts)",
+                missing.display()
+            )
+        );
+    }
+
+    #[test]
+    fn test_join_chunk_outs() {
+        use crate::mro::MroField;
+        use serde::Deserialize;
+        use std::path::PathBuf;
+        use MartianBlanketType::{Array, Primary};
+        use MartianPrimaryType::{FileType, Float, Int, Str};
+
+        #[derive(Serialize)]
+        struct ChunkOuts {
+            reads: i64,
+            fraction: f64,
+            bam: Option<PathBuf>,
+            barcodes: Vec<String>,
+        }
+        impl MartianStruct for ChunkOuts {
+            fn mro_fields() -> Vec<MroField> {
+                vec![
+                    MroField::new("reads", Primary(Int)),
+                    MroField::new("fraction", Primary(Float)),
+                    MroField::new("bam", Primary(FileType("bam".into()))),
+                    MroField::new("barcodes", Array(Str)),
+                ]
+            }
+        }
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct StageOuts {
+            reads: i64,
+            fraction: f64,
+            bam: Vec<PathBuf>,
+            barcodes: Vec<String>,
+        }
+        impl MartianStruct for StageOuts {
+            fn mro_fields() -> Vec<MroField> {
+                vec![
+                    MroField::new("reads", Primary(Int)),
+                    MroField::new("fraction", Primary(Float)),
+                    MroField::new("bam", Array(FileType("bam".into()))),
+                    MroField::new("barcodes", Array(Str)),
+                ]
+            }
+        }
+
+        let chunk_outs = vec![
+            ChunkOuts {
+                reads: 3,
+                fraction: 0.25,
+                bam: Some("a.bam".into()),
+                barcodes: vec!["AC".into(), "GT".into()],
+            },
+            ChunkOuts {
+                reads: 4,
+                fraction: 0.5,
+                bam: None,
+                barcodes: vec!["TT".into()],
+            },
+        ];
+        let outs: StageOuts = join_chunk_outs(&chunk_outs).unwrap();
+        assert_eq!(
+            outs,
+            StageOuts {
+                reads: 7,
+                fraction: 0.75,
+                bam: vec!["a.bam".into()],
+                barcodes: vec!["AC".into(), "GT".into(), "TT".into()],
+            }
+        );
+
+        #[derive(Debug, Deserialize)]
+        struct Summary {}
+        impl MartianStruct for Summary {
+            fn mro_fields() -> Vec<MroField> {
+                vec![MroField::new("barcodes", Primary(Str))]
+            }
+        }
+        assert_eq!(
+            join_chunk_outs::<_, Summary>(&chunk_outs)
+                .unwrap_err()
+                .to_string(),
+            "Cannot join the field barcodes of the stage outputs: it cannot be built from a string[]"
+        );
+    }
+
+    #[test]
+    fn test_unified_diff() {
+        let old: Vec<_> = (1..=12).map(|i| i.to_string()).collect();
+        assert_eq!(unified_diff(&old, &old, "a", "b"), "");
+
+        let mut new = old.clone();
+        new[1] = "two".into();
+        new.remove(9);
+        assert_eq!(
+            unified_diff(&old, &new, "a", "b"),
+            "--- a\n+++ b\n\
+             @@ -1,5 +1,5 @@\n 1\n-2\n+two\n 3\n 4\n 5\n\
+             @@ -7,6 +7,5 @@\n 7\n 8\n 9\n-10\n 11\n 12\n"
+        );
+    }
+
+    #[test]
+    fn test_run_logged() {
+        let output =
+            run_logged(Command::new("sh").args(["-c", "echo hello; echo world >&2"])).unwrap();
+        assert_eq!(output.stdout, b"hello\n");
+        assert_eq!(output.stderr, b"world\n");
+    }
+
+    #[test]
+    fn test_run_logged_raw_output() {
+        // Invalid UTF-8 does not stop the logging, and the output is captured as is
+        let output = run_logged(
+            Command::new("sh").args(["-c", r"printf 'caf\351\nlast'; printf 'no newline' >&2"]),
+        )
+        .unwrap();
+        assert_eq!(output.stdout, b"caf\xe9\nlast");
+        assert_eq!(output.stderr, b"no newline");
+    }
+
+    #[test]
+    fn test_run_logged_failure() {
+        let err = run_logged(Command::new("sh").args(["-c", "echo ok; echo boom >&2; exit 3"]))
+            .unwrap_err();
+        let message = err.to_string();
+        assert!(message.contains("`sh`"));
+        assert!(message.contains("boom"));
+        assert!(!message.contains("ok"));
+        assert!(matches!(
+            err.downcast_ref::<StageError>(),
+            Some(StageError::DependencyFailure { .. })
+        ));
+    }
+}